@@ -7,9 +7,6 @@ extern crate log;
 extern crate env_logger;
 extern crate ansi_term;
 
-#[cfg(feature = "redis")]
-extern crate resp;
-
 use mio::*;
 use mio::tcp::{TcpListener, TcpStream};
 use std::thread;
@@ -30,8 +27,9 @@ use rs_proxy::connection::tcp_connection::TcpConnection;
 use rs_proxy::connection::poison::Throttler;
 
 #[cfg(feature = "redis")]
-use rs_proxy::connection::redis::RedisConnection;
-use rs_proxy::connection::redis::{ComposedProxy, LogProxy, PrefixProxy};
+use rs_proxy::connection::redis::{RedisConnection, RedisUpstream, Handshake, ShardedUpstream, Shard, ClusterUpstream};
+use rs_proxy::connection::redis::{RedisPool, PooledUpstream};
+use rs_proxy::connection::redis::{ComposedProxy, LogProxy, PrefixProxy, ReplyComparator};
 
 fn main() {
     initialize_logger();
@@ -52,6 +50,8 @@ struct MyHandler {
     proxy_locator: ProxyLocator,
     acceptors: HashMap<Token, TcpListener>,
     timers: HashMap<Token, Rc<RefCell<Timer>>>,
+    timeouts: HashMap<Token, Timeout>,
+    pool: Option<Rc<RefCell<RedisPool>>>,
     tokens: BitSet,
 }
 
@@ -62,6 +62,8 @@ impl MyHandler {
             proxy_locator: ProxyLocator::new(),
             acceptors: HashMap::new(),
             timers: HashMap::new(),
+            timeouts: HashMap::new(),
+            pool: None,
         }
     }
 
@@ -100,18 +102,43 @@ impl MyHandler {
         let downstream_token = try!(self.claim_token().ok_or("No more tokens available for downstream"));
         let upstream_token = try!(self.claim_token().ok_or("No more tokens available for upstream"));
         let downstream = TcpConnection::new(tcp_stream, downstream_token);
-        let stream = try!(TcpStream::connect(&addr).or(Err("Could not connect to upstream")));
-        let upstream = TcpConnection::new(stream, upstream_token);
+        let upstream: Rc<RefCell<Connection>> = match self.pool {
+            Some(ref pool) => {
+                let pooled = try!(PooledUpstream::new(pool.clone(), upstream_token).or(Err("Could not attach to the pool")));
+                Rc::new(RefCell::new(pooled))
+            },
+            None => try!(connect_upstream(&addr, upstream_token)),
+        };
         let log = ComposedProxy::new(LogProxy, PrefixProxy);
         let downstream = RedisConnection::new(downstream, log);
         // let downstream = RedisPrefixConnection::new(downstream);
 
         let downstream = Rc::new(RefCell::new(downstream));
-        let upstream = Rc::new(RefCell::new(upstream));
 
-        let proxy = Proxy::new(downstream, upstream.clone());
+        // Releases the commands held back by the interceptors
+        let frequency = downstream.borrow().get_frequency();
+        let timeout = try!(event_loop.timeout_ms(downstream_token, frequency).or(Err("Could not schedule the timer")));
+        self.timers.insert(downstream_token, downstream.clone());
+        self.timeouts.insert(downstream_token, timeout);
+
+        let mut proxy = Proxy::new(downstream, upstream.clone());
         let (downstream_token, upstream_token) = proxy.tokens();
 
+        // Mirrors the traffic to a second Redis, i.e. to try a new version
+        if let Ok(shadow_addr) = env::var("SHADOW_UPSTREAM") {
+            let shadow_addr: SocketAddr = try!(shadow_addr.parse().or(Err("Could not parse the shadow address")));
+            let shadow_token = try!(self.claim_token().ok_or("No more tokens available for the shadow"));
+            let stream = try!(TcpStream::connect(&shadow_addr).or(Err("Could not connect to the shadow")));
+            let shadow = RedisUpstream::new(TcpConnection::new(stream, shadow_token), upstream_handshake());
+
+            try!(event_loop.register(shadow.get_evented(), shadow_token, shadow.get_interest(), PollOpt::edge()).or(Err("Could not register the shadow")));
+
+            proxy = proxy.with_shadow(Rc::new(RefCell::new(shadow)));
+            if env::var("SHADOW_COMPARE").is_ok() {
+                proxy = proxy.with_comparator(Box::new(ReplyComparator::new()));
+            }
+        }
+
         let ds = proxy.get_downstream();
         let us = proxy.get_upstream();
 
@@ -121,6 +148,9 @@ impl MyHandler {
         let bp = Rc::new(RefCell::new(proxy));
         self.proxy_locator.link(downstream_token, Role::Downstream, bp.clone());
         self.proxy_locator.link(upstream_token, Role::Upstream, bp.clone());
+        if let Some(shadow_token) = bp.borrow().shadow_token() {
+            self.proxy_locator.link(shadow_token, Role::Shadow, bp.clone());
+        }
 
         info!("Registered downstream_connection {:?} and upstream_connection {:?}", downstream_token, upstream_token);
 
@@ -128,6 +158,10 @@ impl MyHandler {
     }
 
     pub fn handle_connection(&mut self, event_loop: &mut EventLoop<MyHandler>, token: Token, event_set: EventSet) -> Result<(), &str> {
+        if let Some((Role::Shadow, ref_proxy)) = self.proxy_locator.get(&token) {
+            return self.handle_shadow(event_loop, token, event_set, ref_proxy);
+        }
+
         if event_set.is_writable() {
             let has_to_close = {
                 let (role, ref_proxy) = try!{self.proxy_locator.get(&token).ok_or("Token not found")};
@@ -143,11 +177,19 @@ impl MyHandler {
                     Role::Downstream => {
                         (us.borrow(), ds.borrow_mut())
                     },
+                    Role::Shadow => unreachable!("Shadow events are handled apart"),
                 };
 
-                write_borrow.handle_write();
+                let action = write_borrow.handle_write();
 
                 let has_to_close = proxy.is_upstream_closed();
+                if let ConnectionAction::Halt = action {
+                    drop(write_borrow);
+                    drop(proxy);
+                    self.remove_proxy(event_loop, &token);
+
+                    return Ok(());
+                }
 
                 if !has_to_close {
                     try!{event_loop.reregister(write_borrow.get_evented(), write_borrow.get_token(), EventSet::readable() | EventSet::hup() | EventSet::error(), PollOpt::edge()).or(Err("Could not reregister the token"))};
@@ -180,6 +222,7 @@ impl MyHandler {
                 Role::Upstream => {
                     (us.borrow_mut(), ds.borrow())
                 },
+                Role::Shadow => unreachable!("Shadow events are handled apart"),
             };
 
             let action = read_borrow.handle_read();
@@ -187,12 +230,30 @@ impl MyHandler {
             // Add writable behaviour
             try!{event_loop.reregister(write_borrow.get_evented(), write_borrow.get_token(), EventSet::readable() | EventSet::writable() | EventSet::hup() | EventSet::error(), PollOpt::edge()).or(Err("Could not reregister the token"))};
 
-            drop(read_borrow);
             drop(write_borrow);
 
             match action {
+                ConnectionAction::Halt => {
+                    drop(read_borrow);
+                    drop(proxy);
+                    self.remove_proxy(event_loop, &token);
+
+                    return Ok(());
+                },
                 ConnectionAction::Forward => {
+                    drop(read_borrow);
                     proxy.forward(role);
+
+                    // The connection may have queued output of its own (i.e. replies answered locally)
+                    let rc = try!{proxy.get_from_token(token).ok_or("Token not found on proxy")};
+                    let read_borrow = rc.borrow();
+                    try!{event_loop.reregister(read_borrow.get_evented(), read_borrow.get_token(), EventSet::readable() | EventSet::writable() | EventSet::hup() | EventSet::error(), PollOpt::edge()).or(Err("Could not reregister the token"))};
+
+                    // The commands were mirrored to the shadow as well
+                    if let Some(shadow) = proxy.get_shadow() {
+                        let shadow = shadow.borrow();
+                        try!{event_loop.reregister(shadow.get_evented(), shadow.get_token(), EventSet::readable() | EventSet::writable() | EventSet::hup() | EventSet::error(), PollOpt::edge()).or(Err("Could not reregister the token"))};
+                    }
                 },
                 _ => {
                     ()
@@ -217,6 +278,42 @@ impl MyHandler {
         Ok(())
     }
 
+    fn handle_shadow(&mut self, event_loop: &mut EventLoop<MyHandler>, token: Token, event_set: EventSet, ref_proxy: Rc<RefCell<Proxy>>) -> Result<(), &str> {
+        let shadow = try!{ref_proxy.borrow().get_shadow().ok_or("Shadow not found on proxy")};
+        let mut lost = event_set.is_hup() || event_set.is_error();
+
+        if event_set.is_writable() {
+            if let ConnectionAction::Halt = shadow.borrow_mut().handle_write() {
+                lost = true;
+            }
+        }
+
+        if event_set.is_readable() {
+            let action = shadow.borrow_mut().handle_read();
+            match action {
+                ConnectionAction::Halt => lost = true,
+                ConnectionAction::Forward => ref_proxy.borrow_mut().forward(Role::Shadow),
+                _ => (),
+            }
+        }
+
+        if lost {
+            // Losing the shadow must not affect the client
+            warn!("Shadow {:?} went away, no longer mirroring", token);
+            ref_proxy.borrow_mut().remove_shadow();
+            let _ = event_loop.deregister(shadow.borrow().get_evented());
+            self.proxy_locator.unlink(&token);
+            self.return_token(token);
+
+            return Ok(());
+        }
+
+        let shadow = shadow.borrow();
+        try!{event_loop.reregister(shadow.get_evented(), token, EventSet::readable() | EventSet::hup() | EventSet::error(), PollOpt::edge()).or(Err("Could not reregister the token"))};
+
+        Ok(())
+    }
+
     fn handle_downstream_close(&mut self, event_loop: &mut EventLoop<MyHandler>, token: &Token) -> Result<(), &str> {
         let (role, ref_proxy) = try!{self.proxy_locator.get(&token).ok_or("Token not found")};
 
@@ -244,6 +341,13 @@ impl MyHandler {
                     let us = proxy.get_upstream();
                     event_loop.deregister(us.borrow().get_evented()).unwrap();
 
+                    if let Some(shadow) = proxy.get_shadow() {
+                        let shadow_token = shadow.borrow().get_token();
+                        let _ = event_loop.deregister(shadow.borrow().get_evented());
+                        self.proxy_locator.unlink(&shadow_token);
+                        self.return_token(shadow_token);
+                    }
+
                     Some(proxy.tokens())
                 },
                 None => {
@@ -257,6 +361,13 @@ impl MyHandler {
                 self.proxy_locator.unlink(&ds_token);
                 self.proxy_locator.unlink(&us_token);
 
+                for token in [ds_token, us_token].iter() {
+                    self.timers.remove(token);
+                    if let Some(timeout) = self.timeouts.remove(token) {
+                        event_loop.clear_timeout(timeout);
+                    }
+                }
+
                 self.return_token(ds_token);
                 self.return_token(us_token);
             },
@@ -264,15 +375,42 @@ impl MyHandler {
         }
     }
 
+    fn create_pool(&mut self, event_loop: &mut EventLoop<MyHandler>, size: usize) -> Result<(), &str> {
+        let addr: SocketAddr = try!("127.0.0.1:6379".parse().or(Err("Could not parse the upstream address")));
+        let mut connections: Vec<Box<Connection>> = Vec::new();
+
+        for _ in 0..size {
+            let token = try!(self.claim_token().ok_or("No more tokens available for the pool"));
+            let stream = try!(TcpStream::connect(&addr).or(Err("Could not connect to upstream")));
+            connections.push(Box::new(RedisUpstream::new(TcpConnection::new(stream, token), upstream_handshake())));
+        }
+
+        // Subscribed, blocked or stateful clients get a connection of their own
+        let pool = RedisPool::new(connections).with_connector(Box::new(move |token| {
+            let stream = try!(TcpStream::connect(&addr));
+            let connection: Box<Connection> = Box::new(RedisUpstream::new(TcpConnection::new(stream, token), upstream_handshake()));
+
+            Ok(connection)
+        }));
+        try!(pool.register(event_loop).or(Err("Could not register the pool")));
+        self.pool = Some(Rc::new(RefCell::new(pool)));
+
+        Ok(())
+    }
+
     pub fn handle_timer(&mut self, event_loop: &mut EventLoop<MyHandler>, token: Token) -> Result<(), &str> {
         match self.timers.get(&token) {
             Some(ref timer) => {
                 let mut timer = timer.borrow_mut();
                 match timer.handle_timer() {
                     TimerAction::Continue => {
-                        event_loop.timeout_ms(token, timer.get_frequency());
+                        if let Ok(timeout) = event_loop.timeout_ms(token, timer.get_frequency()) {
+                            self.timeouts.insert(token, timeout);
+                        }
+                    },
+                    _ => {
+                        self.timeouts.remove(&token);
                     },
-                    _ => (),
                 }
             },
             None => {
@@ -287,12 +425,23 @@ impl MyHandler {
     fn register_token(&mut self, event_loop: &mut EventLoop<MyHandler>, token: Token) -> Result<(), &str> {
         match self.proxy_locator.get(&token)
         {
-            Some((_, ref_proxy)) => {
-                let proxy = ref_proxy.borrow();
-                let connection = try!{proxy.get_from_token(token).ok_or("Token not found on proxy")};
+            Some((role, ref_proxy)) => {
+                let mut proxy = ref_proxy.borrow_mut();
+
+                // The timer may have released data held by the connection
+                proxy.forward(role);
 
-                info!("Reregistering token {:?} with interest: {:?}", token, connection.borrow().get_interest());
-                event_loop.register(connection.borrow().get_evented(), token, connection.borrow().get_interest(), PollOpt::edge());
+                let (downstream_token, upstream_token) = proxy.tokens();
+                let mut tokens = vec![downstream_token, upstream_token];
+                tokens.extend(proxy.shadow_token());
+
+                for token in tokens.iter() {
+                    let connection = try!{proxy.get_from_token(*token).ok_or("Token not found on proxy")};
+                    let connection = connection.borrow();
+
+                    info!("Reregistering token {:?} with interest: {:?}", token, connection.get_interest());
+                    try!{event_loop.reregister(connection.get_evented(), *token, connection.get_interest(), PollOpt::edge()).or(Err("Could not reregister the token"))};
+                }
             },
             None => {
                 ()
@@ -308,6 +457,14 @@ impl Handler for MyHandler {
     type Message = u32;
 
     fn ready(&mut self, event_loop: &mut EventLoop<MyHandler>, token: Token, event_set: EventSet) {
+        let pool = self.pool.clone();
+        if let Some(pool) = pool {
+            if pool.borrow().has(&token) {
+                pool.borrow_mut().ready(token, event_set);
+                return;
+            }
+        }
+
         if self.proxy_locator.has(&token) {
             let handle_result = self.handle_connection(event_loop, token, event_set);
             match handle_result {
@@ -345,10 +502,56 @@ impl Handler for MyHandler {
         ).unwrap();
 
         self.acceptors.insert(token, server);
+
+        if let Ok(size) = env::var("UPSTREAM_POOL") {
+            match self.create_pool(event_loop, size.parse().unwrap_or(1)) {
+                Err(e) => error!("Could not create the upstream pool: {}", e),
+                _ => (),
+            }
+        }
     }
 }
 
 
+fn connect_upstream(addr: &SocketAddr, upstream_token: Token) -> Result<Rc<RefCell<Connection>>, &'static str> {
+    if let Ok(nodes) = env::var("UPSTREAM_CLUSTER") {
+        let seeds: Vec<String> = nodes.split(',').map(|node| node.to_string()).collect();
+        let cluster = try!(ClusterUpstream::new(upstream_token, &seeds, upstream_handshake()).or(Err("Could not connect to the cluster")));
+
+        return Ok(Rc::new(RefCell::new(cluster)));
+    }
+
+    if let Ok(shards) = env::var("UPSTREAM_SHARDS") {
+        let mut upstream_shards = Vec::new();
+        for shard in shards.split(',') {
+            let shard_addr: SocketAddr = try!(shard.parse().or(Err("Could not parse the shard address")));
+            let stream = try!(TcpStream::connect(&shard_addr).or(Err("Could not connect to shard")));
+            let connection = TcpConnection::new(stream, upstream_token);
+
+            upstream_shards.push(Shard {
+                name: shard.to_string(),
+                weight: 1,
+                connection: Box::new(RedisUpstream::new(connection, upstream_handshake())),
+            });
+        }
+
+        return Ok(Rc::new(RefCell::new(ShardedUpstream::new(upstream_token, upstream_shards))));
+    }
+
+    let stream = try!(TcpStream::connect(addr).or(Err("Could not connect to upstream")));
+    let upstream = TcpConnection::new(stream, upstream_token);
+
+    Ok(Rc::new(RefCell::new(RedisUpstream::new(upstream, upstream_handshake()))))
+}
+
+fn upstream_handshake() -> Handshake {
+    Handshake {
+        username: env::var("UPSTREAM_USER").ok(),
+        password: env::var("UPSTREAM_PASSWORD").ok(),
+        database: env::var("UPSTREAM_DB").ok().and_then(|db| db.parse().ok()),
+    }
+}
+
 fn initialize_logger() {
 	let format = |record: &LogRecord| {
         let level = match record.level() {
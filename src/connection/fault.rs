@@ -0,0 +1,98 @@
+//! Rules picking the requests the fault proxies misbehave on, whatever the
+//! protocol. Each protocol defines its faults, and what the name and the
+//! pattern of a rule match: a command and its keys, a method and a path, or
+//! the SQL of a statement.
+
+use connection::glob;
+use rand;
+use std::ascii::AsciiExt;
+
+/// Injects a fault on the requests matching a name and a pattern, with the
+/// given probability.
+#[derive(Clone)]
+pub struct Rule<F> {
+    name: Option<String>,
+    pattern: Option<String>,
+    probability: f64,
+    fault: F,
+}
+
+impl<F: Clone> Rule<F> {
+    /// Rule matching every request, always applied.
+    pub fn new(fault: F) -> Self {
+        Rule {
+            name: None,
+            pattern: None,
+            probability: 1.0,
+            fault: fault,
+        }
+    }
+
+    /// Only match the requests with the given name, compared in uppercase.
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_ascii_uppercase());
+        self
+    }
+
+    /// Only match the requests with a subject matching the glob-style
+    /// pattern.
+    pub fn pattern(mut self, pattern: &str) -> Self {
+        self.pattern = Some(pattern.to_string());
+        self
+    }
+
+    /// Apply the fault on the given fraction (between 0 and 1) of the
+    /// matching requests.
+    pub fn probability(mut self, probability: f64) -> Self {
+        self.probability = probability;
+        self
+    }
+
+    fn matches(&self, name: Option<&str>, subjects: &[&str]) -> bool {
+        if let Some(ref expected) = self.name {
+            if name != Some(&**expected) {
+                return false;
+            }
+        }
+
+        if let Some(ref pattern) = self.pattern {
+            if !subjects.iter().any(|subject| glob::matches(pattern, subject)) {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    fn applies(&self) -> bool {
+        self.probability >= 1.0 || rand::random::<f64>() < self.probability
+    }
+}
+
+/// Fault of the first rule matching the request with the name and the
+/// subjects whose probability check passes.
+pub fn select<F: Clone>(rules: &[Rule<F>], name: Option<&str>, subjects: &[&str]) -> Option<F> {
+    rules.iter()
+        .filter(|rule| rule.matches(name, subjects))
+        .find(|rule| rule.applies())
+        .map(|rule| rule.fault.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Rule, select};
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let rules = vec![
+            Rule::new(1).name("get").pattern("user:*"),
+            Rule::new(2).probability(0.0),
+            Rule::new(3).pattern("session:*"),
+        ];
+
+        assert_eq!(select(&rules, Some("GET"), &["user:1"]), Some(1));
+        assert_eq!(select(&rules, Some("SET"), &["user:1"]), None);
+        assert_eq!(select(&rules, Some("SET"), &["user:1", "session:1"]), Some(3));
+        assert_eq!(select(&rules, None, &[]), None);
+    }
+}
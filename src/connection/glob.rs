@@ -0,0 +1,72 @@
+//! Glob-style patterns as understood by Redis' `KEYS`: `*`, `?`, `[abc]`,
+//! `[^abc]`, `[a-z]` and `\\` to escape a special character.
+
+pub fn matches(pattern: &str, text: &str) -> bool {
+    matches_bytes(pattern.as_bytes(), text.as_bytes())
+}
+
+fn matches_bytes(pattern: &[u8], text: &[u8]) -> bool {
+    if pattern.is_empty() {
+        return text.is_empty();
+    }
+
+    match pattern[0] {
+        b'*' => {
+            let rest = &pattern[1..];
+            (0..text.len() + 1).any(|skip| matches_bytes(rest, &text[skip..]))
+        },
+        b'?' => {
+            !text.is_empty() && matches_bytes(&pattern[1..], &text[1..])
+        },
+        b'[' => {
+            if text.is_empty() {
+                return false;
+            }
+
+            match match_class(&pattern[1..], text[0]) {
+                Some((matched, consumed)) => matched && matches_bytes(&pattern[1 + consumed..], &text[1..]),
+                None => false,
+            }
+        },
+        b'\\' if pattern.len() > 1 => {
+            !text.is_empty() && pattern[1] == text[0] && matches_bytes(&pattern[2..], &text[1..])
+        },
+        c => {
+            !text.is_empty() && c == text[0] && matches_bytes(&pattern[1..], &text[1..])
+        },
+    }
+}
+
+/// Matches a character against a class whose opening `[` has already been
+/// consumed. Returns whether it matched and the bytes consumed up to and
+/// including the closing `]`, or `None` when the class is not closed.
+fn match_class(class: &[u8], c: u8) -> Option<(bool, usize)> {
+    let mut i = 0;
+    let negated = class.first() == Some(&b'^');
+    if negated {
+        i += 1;
+    }
+
+    let mut matched = false;
+    while i < class.len() {
+        match class[i] {
+            b']' => return Some((matched != negated, i + 1)),
+            b'\\' if i + 1 < class.len() => {
+                matched = matched || class[i + 1] == c;
+                i += 2;
+            },
+            start if i + 2 < class.len() && class[i + 1] == b'-' && class[i + 2] != b']' => {
+                let end = class[i + 2];
+                let (low, high) = if start <= end { (start, end) } else { (end, start) };
+                matched = matched || (low <= c && c <= high);
+                i += 3;
+            },
+            other => {
+                matched = matched || other == c;
+                i += 1;
+            },
+        }
+    }
+
+    None
+}
@@ -0,0 +1,318 @@
+use mio::{Token, Evented, EventSet};
+use connection::{Connection, Timer};
+use connection::tcp_connection::TcpConnection;
+use connection::{ConnectionAction, TimerAction};
+use std::io;
+use std::cmp::min;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use connection::http::{HttpProxy, RequestAction};
+use connection::http::protocol::{Request, Response, RequestDecoder, ResponseDecoder};
+use netbuf::Buf;
+
+/// Slot reserved for the response of each request, in the order the
+/// requests were received, so pipelined clients get them in order.
+enum Reply {
+    /// Forwarded request, with its response once received.
+    Upstream(Request, Option<Response>),
+    Local(Response),
+}
+
+impl Reply {
+    fn is_waiting(&self) -> bool {
+        match *self {
+            Reply::Upstream(_, None) => true,
+            _ => false,
+        }
+    }
+}
+
+/// Parses the HTTP/1.1 requests of a client and the responses of its
+/// upstream, so an `HttpProxy` can intercept them. Bodies are buffered
+/// whole, so clients sending `Expect: 100-continue` only go on once they
+/// give up waiting for the interim response.
+///
+/// Once the upstream switches protocols (i.e. to WebSocket), or sends a
+/// response ending with the connection, the bytes go through untouched.
+pub struct HttpConnection<P> where P: HttpProxy {
+    connection: TcpConnection,
+    proxy: P,
+    requests: RequestDecoder,
+    responses: ResponseDecoder,
+    forward: Buf,
+    replies: VecDeque<Reply>,
+    delayed: Option<(Instant, RequestAction)>,
+    /// Bytes go through as they come, in both directions.
+    tunnel: bool,
+    /// Response bytes go through as they come, until the upstream closes.
+    streaming: bool,
+    closing: bool,
+}
+
+impl<P> HttpConnection<P> where P: HttpProxy {
+    pub fn new(connection: TcpConnection, proxy: P) -> Self {
+        HttpConnection {
+            connection: connection,
+            proxy: proxy,
+            requests: RequestDecoder::new(),
+            responses: ResponseDecoder::new(),
+            forward: Buf::new(),
+            replies: VecDeque::new(),
+            delayed: None,
+            tunnel: false,
+            streaming: false,
+            closing: false,
+        }
+    }
+
+    fn process_requests(&mut self) {
+        let len = self.connection.get_input().len();
+        if len == 0 {
+            return;
+        }
+
+        if self.tunnel {
+            self.forward.extend(&self.connection.get_input()[0..len]);
+            self.connection.get_mut_input().consume(len);
+            return;
+        }
+
+        if self.closing {
+            self.connection.get_mut_input().consume(len);
+            return;
+        }
+
+        let feed_result = self.requests.feed(&self.connection.get_input()[0..len]);
+        self.connection.get_mut_input().consume(len);
+
+        if let Err(e) = feed_result {
+            error!("{:?}: Could not parse request: {}", self.get_token(), e);
+            self.replies.push_back(Reply::Local(Response::new(400, b"")));
+            self.closing = true;
+            self.flush_replies();
+            return;
+        }
+
+        self.release_requests();
+        self.flush_replies();
+    }
+
+    /// Intercepts the decoded requests, stopping at the first delayed one
+    /// until its time has come.
+    fn release_requests(&mut self) {
+        while !self.closing {
+            let due = match self.delayed {
+                Some((deadline, _)) => deadline <= Instant::now(),
+                None => true,
+            };
+
+            if !due {
+                break;
+            }
+
+            if let Some((_, action)) = self.delayed.take() {
+                self.apply(action);
+                continue;
+            }
+
+            match self.requests.read() {
+                Some(request) => {
+                    let action = self.proxy.on_request(request);
+                    self.apply(action);
+                },
+                None => break,
+            }
+        }
+    }
+
+    fn apply(&mut self, action: RequestAction) {
+        match action {
+            RequestAction::Forward(mut request) => {
+                // The whole body is here already
+                if request.headers.has_token("Expect", "100-continue") {
+                    request.headers.remove("Expect");
+                }
+
+                if request.closes() {
+                    self.closing = true;
+                }
+
+                self.forward.extend(&request.encode());
+                self.replies.push_back(Reply::Upstream(request, None));
+            },
+            RequestAction::Respond(response) => {
+                self.replies.push_back(Reply::Local(response));
+            },
+            RequestAction::Drop => {
+                info!("{:?}: Dropping request", self.get_token());
+            },
+            RequestAction::Close => {
+                info!("{:?}: Closing connection", self.get_token());
+                self.closing = true;
+            },
+            RequestAction::Delay(delay, action) => {
+                self.delayed = Some((Instant::now() + Duration::from_millis(delay), *action));
+            },
+        }
+    }
+
+    fn process_responses(&mut self) -> io::Result<()> {
+        loop {
+            let position = match self.replies.iter().position(Reply::is_waiting) {
+                Some(position) => position,
+                None => {
+                    if self.responses.buffer_len() > 0 {
+                        warn!("{:?}: Received a response without a pending request", self.get_token());
+                        self.responses.take_buffer();
+                    }
+                    return Ok(());
+                },
+            };
+
+            let response = {
+                let method = match self.replies[position] {
+                    Reply::Upstream(ref request, _) => request.method.clone(),
+                    _ => unreachable!(),
+                };
+
+                match try!(self.responses.read(&method)) {
+                    Some(response) => response,
+                    None => return Ok(()),
+                }
+            };
+
+            if response.is_interim() && response.status != 101 {
+                // Only meaningful while nothing else is on its way
+                if position == 0 {
+                    self.connection.get_mut_output().extend(&response.encode());
+                }
+                continue;
+            }
+
+            if response.status == 101 {
+                self.tunnel = true;
+            } else if response.is_until_close() {
+                self.streaming = true;
+            }
+
+            if response.closes() {
+                self.closing = true;
+            }
+
+            let proxy = &mut self.proxy;
+            if let Reply::Upstream(ref request, ref mut slot) = self.replies[position] {
+                *slot = Some(proxy.on_response(request, response));
+            }
+
+            if self.tunnel || self.streaming {
+                return Ok(());
+            }
+        }
+    }
+
+    fn flush_replies(&mut self) {
+        loop {
+            let ready = match self.replies.front() {
+                Some(&Reply::Local(_)) | Some(&Reply::Upstream(_, Some(_))) => true,
+                _ => false,
+            };
+
+            if !ready {
+                break;
+            }
+
+            match self.replies.pop_front() {
+                Some(Reply::Local(response)) | Some(Reply::Upstream(_, Some(response))) => {
+                    self.connection.get_mut_output().extend(&response.encode());
+                },
+                _ => (),
+            }
+        }
+
+        if (self.tunnel || self.streaming) && self.replies.is_empty() {
+            let rest = self.responses.take_buffer();
+            self.connection.get_mut_output().extend(&rest);
+
+            if self.tunnel {
+                let rest = self.requests.take_buffer();
+                self.forward.extend(&rest);
+            }
+        }
+    }
+}
+
+impl<P> Timer for HttpConnection<P> where P: HttpProxy {
+    fn handle_timer(&mut self) -> TimerAction {
+        self.release_requests();
+        self.flush_replies();
+
+        TimerAction::Continue
+    }
+
+    fn get_frequency(&self) -> u64 {
+        10
+    }
+}
+
+impl<P> io::Read for HttpConnection<P> where P: HttpProxy {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.process_requests();
+
+        let read_size = min(buf.len(), self.forward.len());
+        buf[0..read_size].clone_from_slice(&self.forward[0..read_size]);
+        self.forward.consume(read_size);
+
+        Ok(read_size)
+    }
+}
+
+impl<P> io::Write for HttpConnection<P> where P: HttpProxy {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if (self.tunnel || self.streaming) && self.replies.is_empty() {
+            self.connection.get_mut_output().extend(buf);
+            return Ok(buf.len());
+        }
+
+        self.responses.feed(buf);
+        try!(self.process_responses());
+        self.flush_replies();
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<P> Connection for HttpConnection<P> where P: HttpProxy {
+    fn get_evented(&self) -> &Evented {
+        return &*self.connection.get_evented();
+    }
+
+    fn get_token(&self) -> Token {
+        return self.connection.get_token();
+    }
+
+    fn get_interest(&self) -> EventSet {
+        return self.connection.get_interest();
+    }
+
+    fn handle_read(&mut self) -> ConnectionAction {
+        let read_response = self.connection.handle_read();
+
+        read_response
+    }
+
+    fn handle_write(&mut self) -> ConnectionAction {
+        let write_response = self.connection.handle_write();
+
+        // Passed through bytes end when the upstream hangs up
+        let passing_through = self.tunnel || self.streaming;
+        if self.closing && !passing_through && self.replies.is_empty() && self.connection.get_output().is_empty() {
+            return ConnectionAction::Halt;
+        }
+
+        write_response
+    }
+}
@@ -0,0 +1,160 @@
+use connection::http::{HttpProxy, RequestAction, forwards};
+use connection::http::protocol::{Request, Response, reason_phrase};
+use connection::fault;
+use std::collections::VecDeque;
+
+/// Misbehaviour injected on a matching request.
+#[derive(Clone)]
+pub enum Fault {
+    /// Answer with the status instead of forwarding the request.
+    Status(u16),
+    /// Hold the request for the given milliseconds before forwarding it.
+    Delay(u64),
+    /// Answer with the status after the given milliseconds.
+    DelayedStatus(u64, u16),
+    /// Forward the request but replace the status of its response.
+    ResponseStatus(u16),
+}
+
+/// Injects a fault on the requests matching a method and a path pattern,
+/// with the given probability.
+pub type FaultRule = fault::Rule<Fault>;
+
+impl FaultRule {
+    /// Only match the requests with the given method.
+    pub fn method(self, method: &str) -> Self {
+        self.name(method)
+    }
+
+    /// Only match the requests whose path matches the glob-style pattern.
+    pub fn path_pattern(self, pattern: &str) -> Self {
+        self.pattern(pattern)
+    }
+}
+
+/// Injects faults per route, so client retry and timeout logic can be
+/// exercised on specific endpoints. The first matching rule whose
+/// probability check passes wins.
+pub struct FaultProxy {
+    rules: Vec<FaultRule>,
+    /// Status to give the response of each forwarded request, in order.
+    overrides: VecDeque<Option<u16>>,
+}
+
+impl FaultProxy {
+    pub fn new(rules: Vec<FaultRule>) -> Self {
+        FaultProxy {
+            rules: rules,
+            overrides: VecDeque::new(),
+        }
+    }
+}
+
+impl HttpProxy for FaultProxy {
+    fn on_request(&mut self, request: Request) -> RequestAction {
+        let fault = fault::select(&self.rules, Some(&request.method), &[request.path()]);
+
+        let status = match fault {
+            Some(Fault::ResponseStatus(status)) => Some(status),
+            _ => None,
+        };
+
+        let action = match fault {
+            Some(Fault::Status(status)) => {
+                RequestAction::Respond(Response::new(status, b""))
+            },
+            Some(Fault::Delay(delay)) => {
+                RequestAction::Delay(delay, Box::new(RequestAction::Forward(request)))
+            },
+            Some(Fault::DelayedStatus(delay, status)) => {
+                RequestAction::Delay(delay, Box::new(RequestAction::Respond(Response::new(status, b""))))
+            },
+            Some(Fault::ResponseStatus(_)) | None => {
+                RequestAction::Forward(request)
+            },
+        };
+
+        if forwards(&action) {
+            self.overrides.push_back(status);
+        }
+
+        action
+    }
+
+    fn on_response(&mut self, _: &Request, mut response: Response) -> Response {
+        if let Some(Some(status)) = self.overrides.pop_front() {
+            response.status = status;
+            response.reason = reason_phrase(status).to_string();
+        }
+
+        response
+    }
+
+    fn on_unanswered(&mut self) {
+        self.overrides.pop_back();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use connection::http::{HttpProxy, RequestAction, ComposedProxy};
+    use connection::http::protocol::{Request, RequestDecoder, Response};
+    use super::{FaultProxy, FaultRule, Fault};
+
+    /// Answers the requests to `/local` itself.
+    struct LocalProxy;
+
+    impl HttpProxy for LocalProxy {
+        fn on_request(&mut self, request: Request) -> RequestAction {
+            if request.path() == "/local" {
+                return RequestAction::Respond(Response::new(204, b""));
+            }
+
+            RequestAction::Forward(request)
+        }
+
+        fn on_response(&mut self, _: &Request, response: Response) -> Response {
+            response
+        }
+    }
+
+    #[test]
+    fn overrides_responses_in_request_order() {
+        let rules = vec![FaultRule::new(Fault::ResponseStatus(503)).path_pattern("/local")];
+        let mut proxy = ComposedProxy::new(LocalProxy, FaultProxy::new(rules));
+
+        let mut decoder = RequestDecoder::new();
+        decoder.feed(b"GET /local HTTP/1.1\r\n\r\nGET /a HTTP/1.1\r\n\r\nGET /a HTTP/1.1\r\n\r\n").unwrap();
+
+        let mut forwarded = Vec::new();
+        while let Some(request) = decoder.read() {
+            if let RequestAction::Forward(request) = proxy.on_request(request) {
+                forwarded.push(request);
+            }
+        }
+
+        assert_eq!(forwarded.len(), 2);
+        for request in &forwarded {
+            assert_eq!(proxy.on_response(request, Response::new(200, b"")).status, 200);
+        }
+    }
+
+    #[test]
+    fn overrides_only_the_faulty_one_of_identical_requests() {
+        let rules = vec![FaultRule::new(Fault::ResponseStatus(503)).method("POST")];
+        let mut proxy = FaultProxy::new(rules);
+
+        let mut decoder = RequestDecoder::new();
+        decoder.feed(b"POST /a HTTP/1.1\r\nContent-Length: 0\r\n\r\nGET /a HTTP/1.1\r\n\r\n").unwrap();
+
+        let mut forwarded = Vec::new();
+        while let Some(request) = decoder.read() {
+            if let RequestAction::Forward(request) = proxy.on_request(request) {
+                forwarded.push(request);
+            }
+        }
+
+        let statuses: Vec<u16> = forwarded.iter().map(|request| proxy.on_response(request, Response::new(200, b"")).status).collect();
+        assert_eq!(statuses, vec![503, 200]);
+    }
+}
@@ -0,0 +1,124 @@
+pub use self::connection::HttpConnection;
+pub use self::fault::{FaultProxy, FaultRule, Fault};
+
+use connection::http::protocol::{Request, Response};
+
+pub mod protocol;
+
+mod connection;
+mod fault;
+
+/// Outcome of intercepting a request sent by a client.
+pub enum RequestAction {
+    /// Send the (possibly rewritten) request to the upstream.
+    Forward(Request),
+    /// Answer the client locally; the request never reaches the upstream and
+    /// the response does not go through `on_response`.
+    Respond(Response),
+    /// Discard the request without answering it.
+    Drop,
+    /// Close the client connection once the responses of the requests
+    /// received before this one have been sent.
+    Close,
+    /// Apply the action after the given milliseconds. The requests sent
+    /// after this one wait for it, so the pipeline order is kept.
+    Delay(u64, Box<RequestAction>),
+}
+
+/// Intercepts requests and responses, headers and body at once.
+pub trait HttpProxy {
+    fn on_request(&mut self, request: Request) -> RequestAction;
+    fn on_response(&mut self, request: &Request, response: Response) -> Response;
+
+    /// The last request this proxy forwarded gets no response through
+    /// `on_response`, as it was answered further down the chain.
+    fn on_unanswered(&mut self) {}
+}
+
+/// Whether the action sends the request to the upstream, now or later.
+fn forwards(action: &RequestAction) -> bool {
+    match *action {
+        RequestAction::Forward(_) => true,
+        RequestAction::Delay(_, ref action) => forwards(action),
+        _ => false,
+    }
+}
+
+pub struct NoopProxy;
+
+impl HttpProxy for NoopProxy {
+    fn on_request(&mut self, request: Request) -> RequestAction {
+        RequestAction::Forward(request)
+    }
+
+    fn on_response(&mut self, _: &Request, response: Response) -> Response {
+        response
+    }
+}
+
+pub struct ComposedProxy<A: HttpProxy, B: HttpProxy> {
+    proxy_a: A,
+    proxy_b: B,
+}
+
+impl<A: HttpProxy, B: HttpProxy> ComposedProxy<A, B> {
+    pub fn new(proxy_a: A, proxy_b: B) -> Self {
+        ComposedProxy {
+            proxy_a: proxy_a,
+            proxy_b: proxy_b,
+        }
+    }
+
+    /// Lets `proxy_a` intercept the requests `proxy_b` decided to forward.
+    fn chain(&mut self, action: RequestAction) -> RequestAction {
+        let action = match action {
+            RequestAction::Forward(request) => self.proxy_a.on_request(request),
+            RequestAction::Delay(delay, action) => {
+                return RequestAction::Delay(delay, Box::new(self.chain(*action)));
+            },
+            action => return action,
+        };
+
+        if !forwards(&action) {
+            self.proxy_b.on_unanswered();
+        }
+
+        action
+    }
+}
+
+impl<A: HttpProxy, B: HttpProxy> HttpProxy for ComposedProxy<A, B> {
+    fn on_request(&mut self, request: Request) -> RequestAction {
+        let action = self.proxy_b.on_request(request);
+
+        self.chain(action)
+    }
+
+    fn on_response(&mut self, request: &Request, response: Response) -> Response {
+        self.proxy_b.on_response(
+            request,
+            self.proxy_a.on_response(request, response)
+        )
+    }
+
+    fn on_unanswered(&mut self) {
+        self.proxy_a.on_unanswered();
+        self.proxy_b.on_unanswered();
+    }
+}
+
+pub struct LogProxy;
+
+impl HttpProxy for LogProxy {
+    fn on_request(&mut self, request: Request) -> RequestAction {
+        warn!("Received request: {}", request.to_beautify_string());
+
+        RequestAction::Forward(request)
+    }
+
+    fn on_response(&mut self, _: &Request, response: Response) -> Response {
+        warn!("Response: {}", response.to_beautify_string());
+
+        response
+    }
+}
@@ -0,0 +1,613 @@
+//! HTTP/1.1 messages, with bodies framed by `Content-Length`, chunked
+//! encoding or, for responses, the end of the connection.
+
+use std::io;
+use std::ascii::AsciiExt;
+
+/// Longest request or status line plus headers.
+const MAX_HEAD: usize = 64 * 1024;
+/// Largest body buffered by the proxy.
+const MAX_BODY: usize = 64 * 1024 * 1024;
+
+/// Header fields, in the order they were received.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Headers {
+    fields: Vec<(String, String)>,
+}
+
+impl Headers {
+    pub fn new() -> Self {
+        Headers {
+            fields: Vec::new(),
+        }
+    }
+
+    /// First value of the field, whatever the case of its name.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.fields.iter().find(|&&(ref field, _)| field.eq_ignore_ascii_case(name)).map(|&(_, ref value)| &**value)
+    }
+
+    /// Replaces every value of the field with the given one.
+    pub fn set(&mut self, name: &str, value: &str) {
+        self.remove(name);
+        self.fields.push((name.to_string(), value.to_string()));
+    }
+
+    pub fn add(&mut self, name: &str, value: &str) {
+        self.fields.push((name.to_string(), value.to_string()));
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.fields.retain(|&(ref field, _)| !field.eq_ignore_ascii_case(name));
+    }
+
+    pub fn iter(&self) -> ::std::slice::Iter<(String, String)> {
+        self.fields.iter()
+    }
+
+    /// Whether the comma separated values of the field hold the token.
+    pub fn has_token(&self, name: &str, token: &str) -> bool {
+        self.fields.iter()
+            .filter(|&&(ref field, _)| field.eq_ignore_ascii_case(name))
+            .any(|&(_, ref value)| value.split(',').any(|item| item.trim().eq_ignore_ascii_case(token)))
+    }
+
+    fn is_chunked(&self) -> bool {
+        self.has_token("Transfer-Encoding", "chunked")
+    }
+
+    /// Writes the fields, with `Content-Length` set to the body length when
+    /// it applies.
+    fn encode(&self, buf: &mut Vec<u8>, content_length: Option<usize>) {
+        let mut length_written = false;
+
+        for &(ref name, ref value) in self.fields.iter() {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                match content_length {
+                    Some(length) if !length_written => {
+                        buf.extend_from_slice(format!("{}: {}\r\n", name, length).as_bytes());
+                        length_written = true;
+                    },
+                    Some(_) => (),
+                    None => buf.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes()),
+                }
+                continue;
+            }
+
+            buf.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+        }
+
+        if let Some(length) = content_length {
+            if !length_written {
+                buf.extend_from_slice(format!("Content-Length: {}\r\n", length).as_bytes());
+            }
+        }
+
+        buf.extend_from_slice(b"\r\n");
+    }
+}
+
+/// Writes the body, chunked when the headers say so, and gives the
+/// `Content-Length` to write otherwise.
+fn encode_body(buf: &mut Vec<u8>, headers: &Headers, body: &[u8], has_length: bool) {
+    let content_length = if headers.is_chunked() {
+        None
+    } else if has_length || !body.is_empty() {
+        Some(body.len())
+    } else {
+        None
+    };
+
+    headers.encode(buf, content_length);
+
+    if headers.is_chunked() {
+        if !body.is_empty() {
+            buf.extend_from_slice(format!("{:x}\r\n", body.len()).as_bytes());
+            buf.extend_from_slice(body);
+            buf.extend_from_slice(b"\r\n");
+        }
+        buf.extend_from_slice(b"0\r\n\r\n");
+    } else {
+        buf.extend_from_slice(body);
+    }
+}
+
+/// Whether the message ends the connection, following its version and its
+/// `Connection` header.
+fn closes(version: &str, headers: &Headers) -> bool {
+    if headers.has_token("Connection", "close") {
+        return true;
+    }
+
+    version == "HTTP/1.0" && !headers.has_token("Connection", "keep-alive")
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Request {
+    pub method: String,
+    pub target: String,
+    pub version: String,
+    pub headers: Headers,
+    /// Body, without its chunked encoding.
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// Path of the target, without its query.
+    pub fn path(&self) -> &str {
+        match self.target.find('?') {
+            Some(end) => &self.target[..end],
+            None => &self.target,
+        }
+    }
+
+    /// Whether the client closes the connection after this request.
+    pub fn closes(&self) -> bool {
+        closes(&self.version, &self.headers)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = format!("{} {} {}\r\n", self.method, self.target, self.version).into_bytes();
+        encode_body(&mut buf, &self.headers, &self.body, self.headers.get("Content-Length").is_some());
+
+        buf
+    }
+
+    pub fn to_beautify_string(&self) -> String {
+        format!("{} {} {} ({} bytes)", self.method, self.target, self.version, self.body.len())
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Response {
+    pub version: String,
+    pub status: u16,
+    pub reason: String,
+    pub headers: Headers,
+    /// Body, without its chunked encoding.
+    pub body: Vec<u8>,
+    /// Answers a `HEAD` request, or has a status without body.
+    bodiless: bool,
+    /// The body goes on until the upstream closes the connection; it is
+    /// not part of the response and goes through as it comes.
+    until_close: bool,
+}
+
+impl Response {
+    /// Response with the standard reason phrase of the status.
+    pub fn new(status: u16, body: &[u8]) -> Self {
+        let mut headers = Headers::new();
+        headers.set("Content-Length", &body.len().to_string());
+
+        Response {
+            version: "HTTP/1.1".to_string(),
+            status: status,
+            reason: reason_phrase(status).to_string(),
+            headers: headers,
+            body: body.to_vec(),
+            bodiless: false,
+            until_close: false,
+        }
+    }
+
+    pub fn is_interim(&self) -> bool {
+        self.status >= 100 && self.status < 200
+    }
+
+    pub fn is_until_close(&self) -> bool {
+        self.until_close
+    }
+
+    /// Whether the upstream closes the connection after this response.
+    pub fn closes(&self) -> bool {
+        self.until_close || closes(&self.version, &self.headers)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = format!("{} {} {}\r\n", self.version, self.status, self.reason).into_bytes();
+
+        if self.bodiless || self.until_close {
+            // Framing headers describe a body that is not here
+            self.headers.encode(&mut buf, None);
+        } else {
+            encode_body(&mut buf, &self.headers, &self.body, self.headers.get("Content-Length").is_some());
+        }
+
+        buf
+    }
+
+    pub fn to_beautify_string(&self) -> String {
+        format!("{} {} {} ({} bytes)", self.version, self.status, self.reason, self.body.len())
+    }
+}
+
+pub fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        413 => "Payload Too Large",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "Unknown",
+    }
+}
+
+type Head = (Vec<String>, Headers, usize);
+
+/// Start line words and headers, with the position after the empty line.
+fn parse_head(buf: &[u8], start: usize) -> Result<Option<Head>, String> {
+    let end = match buf[start..].windows(4).position(|window| window == b"\r\n\r\n") {
+        Some(end) => start + end,
+        None if buf.len() - start > MAX_HEAD => return Err("Header section too long".to_string()),
+        None => return Ok(None),
+    };
+
+    let head = match ::std::str::from_utf8(&buf[start..end]) {
+        Ok(head) => head,
+        Err(_) => return Err("Invalid header encoding".to_string()),
+    };
+
+    let mut lines = head.split("\r\n");
+    let start_line: Vec<String> = lines.next().unwrap_or("").splitn(3, ' ').map(|word| word.to_string()).collect();
+    if start_line.len() < 2 {
+        return Err("Invalid start line".to_string());
+    }
+
+    let mut headers = Headers::new();
+    for line in lines {
+        if line.starts_with(' ') || line.starts_with('\t') {
+            return Err("Folded header lines are not supported".to_string());
+        }
+
+        match line.find(':') {
+            Some(colon) if colon > 0 => headers.add(&line[..colon], line[colon + 1..].trim()),
+            _ => return Err(format!("Invalid header line: {}", line)),
+        }
+    }
+
+    Ok(Some((start_line, headers, end + 4)))
+}
+
+/// How the body following the head ends.
+enum Framing {
+    Length(usize),
+    Chunked,
+    UntilClose,
+}
+
+fn framing(headers: &Headers, is_request: bool) -> Result<Framing, String> {
+    if headers.get("Transfer-Encoding").is_some() {
+        // Both would let the two ends disagree on where the message ends
+        if headers.get("Content-Length").is_some() {
+            return Err("Both Transfer-Encoding and Content-Length".to_string());
+        }
+
+        return match (headers.is_chunked(), is_request) {
+            (true, _) => Ok(Framing::Chunked),
+            (false, true) => Err("Unsupported transfer coding".to_string()),
+            (false, false) => Ok(Framing::UntilClose),
+        };
+    }
+
+    let lengths: Vec<&str> = headers.iter()
+        .filter(|&&(ref name, _)| name.eq_ignore_ascii_case("Content-Length"))
+        .map(|&(_, ref value)| &**value)
+        .collect();
+
+    match lengths.first() {
+        Some(length) => {
+            if lengths.iter().any(|other| other != length) {
+                return Err("Conflicting Content-Length".to_string());
+            }
+
+            match length.parse::<usize>() {
+                Ok(length) if length <= MAX_BODY => Ok(Framing::Length(length)),
+                _ => Err("Invalid Content-Length".to_string()),
+            }
+        },
+        None if is_request => Ok(Framing::Length(0)),
+        None => Ok(Framing::UntilClose),
+    }
+}
+
+/// Decodes a chunked body, trailers ignored.
+fn parse_chunked(buf: &[u8], start: usize) -> Result<Option<(Vec<u8>, usize)>, String> {
+    let mut body = Vec::new();
+    let mut position = start;
+
+    loop {
+        let line_end = match buf[position..].windows(2).position(|window| window == b"\r\n") {
+            Some(end) => position + end,
+            None if buf.len() - position > MAX_HEAD => return Err("Chunk size line too long".to_string()),
+            None => return Ok(None),
+        };
+
+        let line = String::from_utf8_lossy(&buf[position..line_end]).into_owned();
+        let size = line.split(';').next().unwrap_or("").trim();
+        let size = match usize::from_str_radix(size, 16) {
+            Ok(size) if size <= MAX_BODY - body.len() => size,
+            _ => return Err(format!("Invalid chunk size: {}", line)),
+        };
+        position = line_end + 2;
+
+        if size == 0 {
+            // Trailer fields up to an empty line
+            loop {
+                match buf[position..].windows(2).position(|window| window == b"\r\n") {
+                    Some(0) => return Ok(Some((body, position + 2))),
+                    Some(end) => position += end + 2,
+                    None => return Ok(None),
+                }
+            }
+        }
+
+        let end = match position.checked_add(size).and_then(|end| end.checked_add(2)) {
+            Some(end) => end,
+            None => return Err(format!("Invalid chunk size: {}", line)),
+        };
+
+        if buf.len() < end {
+            return Ok(None);
+        }
+
+        if &buf[end - 2..end] != b"\r\n" {
+            return Err("Chunk not terminated".to_string());
+        }
+
+        body.extend_from_slice(&buf[position..end - 2]);
+        position = end;
+    }
+}
+
+fn parse_body(buf: &[u8], start: usize, framing: &Framing) -> Result<Option<(Vec<u8>, usize)>, String> {
+    match *framing {
+        Framing::Length(length) => {
+            if buf.len() < start + length {
+                Ok(None)
+            } else {
+                Ok(Some((buf[start..start + length].to_vec(), start + length)))
+            }
+        },
+        Framing::Chunked => parse_chunked(buf, start),
+        Framing::UntilClose => Ok(Some((Vec::new(), start))),
+    }
+}
+
+fn parse_request(buf: &[u8], start: usize) -> Result<Option<(Request, usize)>, String> {
+    let (start_line, headers, body_start) = match try!(parse_head(buf, start)) {
+        Some(head) => head,
+        None => return Ok(None),
+    };
+
+    if start_line.len() != 3 || !start_line[2].starts_with("HTTP/1.") {
+        return Err("Invalid request line".to_string());
+    }
+
+    let framing = try!(framing(&headers, true));
+    let (body, next) = match try!(parse_body(buf, body_start, &framing)) {
+        Some(body) => body,
+        None => return Ok(None),
+    };
+
+    let request = Request {
+        method: start_line[0].clone(),
+        target: start_line[1].clone(),
+        version: start_line[2].clone(),
+        headers: headers,
+        body: body,
+    };
+
+    Ok(Some((request, next)))
+}
+
+fn parse_response(buf: &[u8], start: usize, method: &str) -> Result<Option<(Response, usize)>, String> {
+    let (start_line, headers, body_start) = match try!(parse_head(buf, start)) {
+        Some(head) => head,
+        None => return Ok(None),
+    };
+
+    let status = match start_line[1].parse::<u16>() {
+        Ok(status) if start_line[0].starts_with("HTTP/1.") && status >= 100 && status < 1000 => status,
+        _ => return Err("Invalid status line".to_string()),
+    };
+
+    let bodiless = method == "HEAD" || status < 200 || status == 204 || status == 304;
+    let framing = if bodiless { Framing::Length(0) } else { try!(framing(&headers, false)) };
+
+    let (body, next) = match try!(parse_body(buf, body_start, &framing)) {
+        Some(body) => body,
+        None => return Ok(None),
+    };
+
+    let response = Response {
+        version: start_line[0].clone(),
+        status: status,
+        reason: start_line.get(2).cloned().unwrap_or(String::new()),
+        headers: headers,
+        body: body,
+        bodiless: bodiless,
+        until_close: match framing {
+            Framing::UntilClose => true,
+            _ => false,
+        },
+    };
+
+    Ok(Some((response, next)))
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Incremental decoder of the requests sent by a client.
+pub struct RequestDecoder {
+    buf: Vec<u8>,
+    requests: Vec<Request>,
+}
+
+impl RequestDecoder {
+    pub fn new() -> Self {
+        RequestDecoder {
+            buf: Vec::new(),
+            requests: Vec::new(),
+        }
+    }
+
+    /// Feeds bytes to the decoder. On a protocol error the buffered bytes
+    /// are discarded.
+    pub fn feed(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.buf.extend_from_slice(buf);
+
+        let mut position = 0;
+        while position < self.buf.len() {
+            match parse_request(&self.buf, position) {
+                Ok(Some((request, next))) => {
+                    self.requests.push(request);
+                    position = next;
+                },
+                Ok(None) => break,
+                Err(message) => {
+                    self.buf.clear();
+                    return Err(invalid(message));
+                },
+            }
+        }
+
+        self.buf.drain(0..position);
+
+        Ok(())
+    }
+
+    /// Reads the next decoded request.
+    pub fn read(&mut self) -> Option<Request> {
+        if self.requests.is_empty() {
+            return None;
+        }
+
+        Some(self.requests.remove(0))
+    }
+
+    /// Takes the bytes not decoded yet, once the connection stops speaking
+    /// HTTP.
+    pub fn take_buffer(&mut self) -> Vec<u8> {
+        self.buf.split_off(0)
+    }
+}
+
+/// Incremental decoder of the responses of an upstream. How a response is
+/// framed depends on its request, so they are decoded as they are read.
+pub struct ResponseDecoder {
+    buf: Vec<u8>,
+}
+
+impl ResponseDecoder {
+    pub fn new() -> Self {
+        ResponseDecoder {
+            buf: Vec::new(),
+        }
+    }
+
+    pub fn feed(&mut self, buf: &[u8]) {
+        self.buf.extend_from_slice(buf);
+    }
+
+    /// Reads the next response, answering a request with the given method.
+    /// On a protocol error the buffered bytes are discarded.
+    pub fn read(&mut self, method: &str) -> io::Result<Option<Response>> {
+        if self.buf.is_empty() {
+            return Ok(None);
+        }
+
+        match parse_response(&self.buf, 0, method) {
+            Ok(Some((response, next))) => {
+                self.buf.drain(0..next);
+                Ok(Some(response))
+            },
+            Ok(None) => Ok(None),
+            Err(message) => {
+                self.buf.clear();
+                Err(invalid(message))
+            },
+        }
+    }
+
+    pub fn buffer_len(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Takes the bytes not decoded yet, once responses are not framed
+    /// anymore.
+    pub fn take_buffer(&mut self) -> Vec<u8> {
+        self.buf.split_off(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RequestDecoder, ResponseDecoder};
+
+    #[test]
+    fn chunked_request() {
+        let mut decoder = RequestDecoder::new();
+        decoder.feed(b"POST /items HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n2;ext=1\r\nde\r\n0\r\nTrailer: x\r\n\r\n").unwrap();
+
+        let request = decoder.read().unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path(), "/items");
+        assert_eq!(request.body, b"abcde".to_vec());
+        assert!(decoder.read().is_none());
+    }
+
+    #[test]
+    fn oversized_chunk_is_an_error() {
+        let mut decoder = RequestDecoder::new();
+        let result = decoder.feed(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n1\r\na\r\nffffffffffffffff\r\n");
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn responses_are_framed_by_their_request() {
+        let mut decoder = ResponseDecoder::new();
+        decoder.feed(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n");
+        decoder.feed(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\n");
+        decoder.feed(b"HTTP/1.1 204 No Content\r\n\r\nHTTP/1.0 200 OK\r\n\r\nrest");
+
+        let head = decoder.read("HEAD").unwrap().unwrap();
+        assert!(head.body.is_empty());
+        assert_eq!(head.encode(), b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n".to_vec());
+
+        assert_eq!(decoder.read("GET").unwrap().unwrap().body, b"abc".to_vec());
+        assert_eq!(decoder.read("DELETE").unwrap().unwrap().status, 204);
+
+        let last = decoder.read("GET").unwrap().unwrap();
+        assert!(last.is_until_close());
+        assert_eq!(decoder.take_buffer(), b"rest".to_vec());
+    }
+
+    #[test]
+    fn invalid_status_line_is_an_error() {
+        let mut decoder = ResponseDecoder::new();
+        decoder.feed(b"HTTP/1.1 OK\r\n\r\n");
+
+        assert!(decoder.read("GET").is_err());
+        assert_eq!(decoder.buffer_len(), 0);
+    }
+}
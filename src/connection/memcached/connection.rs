@@ -0,0 +1,291 @@
+use mio::{Token, Evented, EventSet};
+use connection::Connection;
+use connection::tcp_connection::TcpConnection;
+use connection::ConnectionAction;
+use std::io;
+use std::cmp::min;
+use std::collections::VecDeque;
+use connection::memcached::{MemcachedProxy, CommandAction};
+use connection::memcached::protocol::{Command, Response, Decoder};
+use netbuf::Buf;
+
+/// Slot reserved for the response of each command, in the order the commands
+/// were received.
+enum Reply {
+    /// Forwarded command, with its response once received.
+    Upstream(Command, Option<Response>),
+    Local(Response),
+}
+
+impl Reply {
+    fn is_waiting(&self) -> bool {
+        match *self {
+            Reply::Upstream(_, None) => true,
+            _ => false,
+        }
+    }
+}
+
+/// Whether the response may be the one of the command. Binary responses
+/// carry the opcode and opaque of their request, text ones come in order,
+/// quiet meta commands only answering with the codes they can give.
+fn answers(command: &Command, response: &Response) -> bool {
+    match (command, response.opaque()) {
+        (&Command::Binary(ref packet), Some((opcode, opaque))) => packet.opcode == opcode && packet.opaque == opaque,
+        (&Command::Text(ref command), None) => {
+            let quiet_codes = command.quiet_codes();
+            if quiet_codes.is_empty() {
+                return true;
+            }
+
+            let code = response.code().unwrap_or("");
+            let given = response.is_error() || command.response_codes().iter().any(|given| *given == code);
+
+            given && !quiet_codes.iter().any(|quiet| *quiet == code)
+        },
+        _ => false,
+    }
+}
+
+/// Whether the command may never get a response.
+fn is_quiet(command: &Command) -> bool {
+    match *command {
+        Command::Binary(ref packet) => packet.is_quiet(),
+        Command::Text(ref command) => !command.quiet_codes().is_empty(),
+    }
+}
+
+pub struct MemcachedConnection<P> where P: MemcachedProxy {
+    connection: TcpConnection,
+    proxy: P,
+    commands: Decoder<Command>,
+    responses: Decoder<Response>,
+    forward: Buf,
+    replies: VecDeque<Reply>,
+    closing: bool,
+}
+
+impl<P> MemcachedConnection<P> where P: MemcachedProxy {
+    pub fn new(connection: TcpConnection, proxy: P) -> Self {
+        MemcachedConnection {
+            connection: connection,
+            proxy: proxy,
+            commands: Decoder::new(),
+            responses: Decoder::new(),
+            forward: Buf::new(),
+            replies: VecDeque::new(),
+            closing: false,
+        }
+    }
+
+    /// Client side buffers, to feed and read in tests.
+    #[cfg(test)]
+    pub fn tcp_connection(&mut self) -> &mut TcpConnection {
+        &mut self.connection
+    }
+
+    fn process_commands(&mut self) {
+        let len = self.connection.get_input().len();
+        if len == 0 {
+            return;
+        }
+
+        if self.closing {
+            self.connection.get_mut_input().consume(len);
+            return;
+        }
+
+        let feed_result = self.commands.feed(&self.connection.get_input()[0..len]);
+        self.connection.get_mut_input().consume(len);
+
+        if let Err(e) = feed_result {
+            error!("{:?}: Could not parse command: {}", self.get_token(), e);
+            self.closing = true;
+            return;
+        }
+
+        while !self.closing {
+            match self.commands.read() {
+                Some(command) => {
+                    let action = self.proxy.on_command(command);
+                    self.apply(action);
+                },
+                None => break,
+            }
+        }
+
+        self.flush_replies();
+    }
+
+    fn apply(&mut self, action: CommandAction) {
+        match action {
+            CommandAction::Forward(command) => {
+                self.forward.extend(&command.encode());
+
+                let noreply = match command {
+                    Command::Text(ref command) => command.is_noreply(),
+                    Command::Binary(_) => false,
+                };
+
+                if !noreply {
+                    self.replies.push_back(Reply::Upstream(command, None));
+                }
+            },
+            CommandAction::Respond(response) => {
+                self.replies.push_back(Reply::Local(response));
+            },
+            CommandAction::Drop => {
+                info!("{:?}: Dropping command", self.get_token());
+            },
+            CommandAction::Close => {
+                info!("{:?}: Closing connection", self.get_token());
+                self.closing = true;
+            },
+        }
+    }
+
+    fn push_response(&mut self, response: Response) {
+        // Quiet commands before the one answered got no response
+        loop {
+            let position = match self.replies.iter().position(Reply::is_waiting) {
+                Some(position) => position,
+                None => {
+                    warn!("{:?}: Received a response without a pending command", self.get_token());
+                    return;
+                },
+            };
+
+            let matched = match self.replies[position] {
+                Reply::Upstream(ref command, _) => answers(command, &response),
+                _ => false,
+            };
+
+            if matched {
+                let proxy = &mut self.proxy;
+                if let Reply::Upstream(ref command, ref mut slot) = self.replies[position] {
+                    *slot = Some(proxy.on_response(command, response));
+                }
+
+                return;
+            }
+
+            let quiet = match self.replies[position] {
+                Reply::Upstream(ref command, _) => is_quiet(command),
+                _ => false,
+            };
+
+            if !quiet {
+                warn!("{:?}: Received a response not matching the pending command", self.get_token());
+                return;
+            }
+
+            self.replies.remove(position);
+        }
+    }
+
+    fn flush_replies(&mut self) {
+        loop {
+            let ready = match self.replies.front() {
+                Some(&Reply::Local(_)) | Some(&Reply::Upstream(_, Some(_))) => true,
+                _ => false,
+            };
+
+            if !ready {
+                break;
+            }
+
+            match self.replies.pop_front() {
+                Some(Reply::Local(response)) | Some(Reply::Upstream(_, Some(response))) => {
+                    self.connection.get_mut_output().extend(&response.encode());
+                },
+                _ => (),
+            }
+        }
+    }
+}
+
+impl<P> io::Read for MemcachedConnection<P> where P: MemcachedProxy {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.process_commands();
+
+        let read_size = min(buf.len(), self.forward.len());
+        buf[0..read_size].clone_from_slice(&self.forward[0..read_size]);
+        self.forward.consume(read_size);
+
+        Ok(read_size)
+    }
+}
+
+impl<P> io::Write for MemcachedConnection<P> where P: MemcachedProxy {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        try!(self.responses.feed(buf));
+
+        while let Some(response) = self.responses.read() {
+            self.push_response(response);
+        }
+
+        self.flush_replies();
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<P> Connection for MemcachedConnection<P> where P: MemcachedProxy {
+    fn get_evented(&self) -> &Evented {
+        return &*self.connection.get_evented();
+    }
+
+    fn get_token(&self) -> Token {
+        return self.connection.get_token();
+    }
+
+    fn get_interest(&self) -> EventSet {
+        return self.connection.get_interest();
+    }
+
+    fn handle_read(&mut self) -> ConnectionAction {
+        let read_response = self.connection.handle_read();
+
+        read_response
+    }
+
+    fn handle_write(&mut self) -> ConnectionAction {
+        let write_response = self.connection.handle_write();
+
+        if self.closing && self.replies.is_empty() && self.connection.get_output().is_empty() {
+            return ConnectionAction::Halt;
+        }
+
+        write_response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mio::Token;
+    use connection::testing::{tcp_connection, take_output};
+    use connection::memcached::NoopProxy;
+    use super::MemcachedConnection;
+    use std::io::{Read, Write};
+
+    #[test]
+    fn quiet_meta_miss_before_noop() {
+        let (connection, _socket) = tcp_connection(Token(1));
+        let mut connection = MemcachedConnection::new(connection, NoopProxy);
+
+        connection.tcp_connection().get_mut_input().extend(b"mg missing v q\r\nmn\r\nmg foo v\r\n");
+        let mut buf = [0u8; 256];
+        let size = connection.read(&mut buf).unwrap();
+        assert_eq!(&buf[0..size], &b"mg missing v q\r\nmn\r\nmg foo v\r\n"[..]);
+
+        // The miss of the quiet command is left out
+        connection.write_all(b"MN\r\nVA 1\r\nx\r\n").unwrap();
+
+        assert_eq!(take_output(connection.tcp_connection()), b"MN\r\nVA 1\r\nx\r\n".to_vec());
+        assert!(connection.replies.is_empty());
+    }
+}
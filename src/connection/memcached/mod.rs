@@ -0,0 +1,109 @@
+pub use self::connection::MemcachedConnection;
+
+use connection::memcached::protocol::{Command, Response};
+
+pub mod protocol;
+
+mod connection;
+
+/// Outcome of intercepting a command sent by a client.
+pub enum CommandAction {
+    /// Send the (possibly rewritten) command to the upstream.
+    Forward(Command),
+    /// Answer the client locally; the command never reaches the upstream and
+    /// the response does not go through `on_response`.
+    Respond(Response),
+    /// Discard the command without answering it.
+    Drop,
+    /// Close the client connection once the responses of the commands
+    /// received before this one have been sent.
+    Close,
+}
+
+pub trait MemcachedProxy {
+    fn on_command(&mut self, command: Command) -> CommandAction;
+    fn on_response(&mut self, command: &Command, response: Response) -> Response;
+}
+
+pub struct NoopProxy;
+
+impl MemcachedProxy for NoopProxy {
+    fn on_command(&mut self, command: Command) -> CommandAction {
+        CommandAction::Forward(command)
+    }
+
+    fn on_response(&mut self, _: &Command, response: Response) -> Response {
+        response
+    }
+}
+
+pub struct ComposedProxy<A: MemcachedProxy, B: MemcachedProxy> {
+    proxy_a: A,
+    proxy_b: B,
+}
+
+impl<A: MemcachedProxy, B: MemcachedProxy> ComposedProxy<A, B> {
+    pub fn new(proxy_a: A, proxy_b: B) -> Self {
+        ComposedProxy {
+            proxy_a: proxy_a,
+            proxy_b: proxy_b,
+        }
+    }
+}
+
+impl<A: MemcachedProxy, B: MemcachedProxy> MemcachedProxy for ComposedProxy<A, B> {
+    fn on_command(&mut self, command: Command) -> CommandAction {
+        match self.proxy_b.on_command(command) {
+            CommandAction::Forward(command) => self.proxy_a.on_command(command),
+            action => action,
+        }
+    }
+
+    fn on_response(&mut self, command: &Command, response: Response) -> Response {
+        self.proxy_b.on_response(
+            command,
+            self.proxy_a.on_response(command, response)
+        )
+    }
+}
+
+const PREFIX: &'static [u8] = b"prefix:";
+
+/// Prefixes the item keys, and takes the prefix out of the keys given back.
+pub struct PrefixProxy;
+
+impl MemcachedProxy for PrefixProxy {
+    fn on_command(&mut self, command: Command) -> CommandAction {
+        CommandAction::Forward(command.map_keys(|key| {
+            let mut prefixed = PREFIX.to_vec();
+            prefixed.extend_from_slice(key);
+            prefixed
+        }))
+    }
+
+    fn on_response(&mut self, _: &Command, response: Response) -> Response {
+        response.map_keys(|key| {
+            if key.starts_with(PREFIX) {
+                key[PREFIX.len()..].to_vec()
+            } else {
+                key.to_vec()
+            }
+        })
+    }
+}
+
+pub struct LogProxy;
+
+impl MemcachedProxy for LogProxy {
+    fn on_command(&mut self, command: Command) -> CommandAction {
+        warn!("Received command: {}", command.to_beautify_string());
+
+        CommandAction::Forward(command)
+    }
+
+    fn on_response(&mut self, _: &Command, response: Response) -> Response {
+        warn!("Response: {}", response.to_beautify_string());
+
+        response
+    }
+}
@@ -0,0 +1,727 @@
+//! Memcached text and binary protocols. A connection may switch from one to
+//! the other at any message: binary ones start with their magic byte.
+
+use std::io;
+use std::ascii::AsciiExt;
+
+const REQUEST_MAGIC: u8 = 0x80;
+const RESPONSE_MAGIC: u8 = 0x81;
+const HEADER_LEN: usize = 24;
+
+/// Largest value accepted, as memcached limits items to 1GB.
+const MAX_DATA: usize = 1024 * 1024 * 1024;
+/// Longest text line awaited before giving up on the peer.
+const MAX_LINE: usize = 64 * 1024;
+
+/// Binary status of an internal error, used to answer locally.
+pub const STATUS_INTERNAL_ERROR: u16 = 0x0084;
+
+/// Binary protocol message, request or response.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Packet {
+    pub opcode: u8,
+    pub data_type: u8,
+    /// Virtual bucket in requests, status in responses.
+    pub status: u16,
+    pub opaque: u32,
+    pub cas: u64,
+    pub extras: Vec<u8>,
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+}
+
+impl Packet {
+    /// Response to the request, with the given status and value.
+    pub fn response_to(request: &Packet, status: u16, value: &[u8]) -> Packet {
+        Packet {
+            opcode: request.opcode,
+            data_type: 0,
+            status: status,
+            opaque: request.opaque,
+            cas: 0,
+            extras: Vec::new(),
+            key: Vec::new(),
+            value: value.to_vec(),
+        }
+    }
+
+    fn encode(&self, magic: u8) -> Vec<u8> {
+        let body = self.extras.len() + self.key.len() + self.value.len();
+        let mut buf = Vec::with_capacity(HEADER_LEN + body);
+
+        buf.push(magic);
+        buf.push(self.opcode);
+        push_be(&mut buf, self.key.len() as u64, 2);
+        buf.push(self.extras.len() as u8);
+        buf.push(self.data_type);
+        push_be(&mut buf, self.status as u64, 2);
+        push_be(&mut buf, body as u64, 4);
+        push_be(&mut buf, self.opaque as u64, 4);
+        push_be(&mut buf, self.cas, 8);
+        buf.extend_from_slice(&self.extras);
+        buf.extend_from_slice(&self.key);
+        buf.extend_from_slice(&self.value);
+
+        buf
+    }
+
+    /// Whether the opcode is one of the quiet ones, which leave out some of
+    /// their responses.
+    pub fn is_quiet(&self) -> bool {
+        match self.opcode {
+            0x09 | 0x0d | 0x11...0x1a | 0x1e | 0x24 => true,
+            _ => false,
+        }
+    }
+
+    /// Whether the key is an item key (and not i.e. a stat name).
+    fn has_item_key(&self) -> bool {
+        match self.opcode {
+            0x00...0x06 | 0x09 | 0x0c...0x0f | 0x11...0x16 | 0x19 | 0x1a | 0x1c...0x1e | 0x23 | 0x24 => true,
+            _ => false,
+        }
+    }
+}
+
+fn push_be(buf: &mut Vec<u8>, value: u64, bytes: usize) {
+    for i in (0..bytes).rev() {
+        buf.push((value >> (8 * i)) as u8);
+    }
+}
+
+fn read_be(buf: &[u8]) -> u64 {
+    buf.iter().fold(0, |value, byte| value << 8 | *byte as u64)
+}
+
+/// Name of a binary opcode, for logging.
+pub fn opcode_name(opcode: u8) -> &'static str {
+    match opcode {
+        0x00 => "GET",
+        0x01 => "SET",
+        0x02 => "ADD",
+        0x03 => "REPLACE",
+        0x04 => "DELETE",
+        0x05 => "INCREMENT",
+        0x06 => "DECREMENT",
+        0x07 => "QUIT",
+        0x08 => "FLUSH",
+        0x09 => "GETQ",
+        0x0a => "NOOP",
+        0x0b => "VERSION",
+        0x0c => "GETK",
+        0x0d => "GETKQ",
+        0x0e => "APPEND",
+        0x0f => "PREPEND",
+        0x10 => "STAT",
+        0x11 => "SETQ",
+        0x12 => "ADDQ",
+        0x13 => "REPLACEQ",
+        0x14 => "DELETEQ",
+        0x15 => "INCREMENTQ",
+        0x16 => "DECREMENTQ",
+        0x17 => "QUITQ",
+        0x18 => "FLUSHQ",
+        0x19 => "APPENDQ",
+        0x1a => "PREPENDQ",
+        0x1b => "VERBOSITY",
+        0x1c => "TOUCH",
+        0x1d => "GAT",
+        0x1e => "GATQ",
+        0x20 => "SASL_LIST_MECHS",
+        0x21 => "SASL_AUTH",
+        0x22 => "SASL_STEP",
+        0x23 => "GATK",
+        0x24 => "GATKQ",
+        _ => "UNKNOWN",
+    }
+}
+
+/// Text protocol command: its name, the words after it and the data block
+/// of storage commands.
+#[derive(Clone, PartialEq, Debug)]
+pub struct TextCommand {
+    pub name: String,
+    pub arguments: Vec<String>,
+    pub data: Option<Vec<u8>>,
+}
+
+impl TextCommand {
+    /// Positions of the keys among the arguments.
+    pub fn key_positions(&self) -> Vec<usize> {
+        match &*self.name {
+            "get" | "gets" => (0..self.arguments.len()).collect(),
+            "gat" | "gats" => (1..self.arguments.len()).collect(),
+            "set" | "add" | "replace" | "append" | "prepend" | "cas" |
+            "delete" | "incr" | "decr" | "touch" if !self.arguments.is_empty() => vec![0],
+            // Base64 keys are left alone
+            "mg" | "ms" | "md" | "ma" | "me" if !self.arguments.is_empty() && !self.arguments.iter().any(|flag| flag == "b") => vec![0],
+            _ => Vec::new(),
+        }
+    }
+
+    /// Whether the client asked not to get any response.
+    pub fn is_noreply(&self) -> bool {
+        match &*self.name {
+            "set" | "add" | "replace" | "append" | "prepend" | "cas" | "delete" |
+            "incr" | "decr" | "touch" | "verbosity" | "flush_all" => {
+                self.arguments.last().map(|last| last == "noreply").unwrap_or(false)
+            },
+            _ => false,
+        }
+    }
+
+    /// Response codes left out by a meta command with the `q` flag.
+    pub fn quiet_codes(&self) -> &'static [&'static str] {
+        if !self.arguments.iter().skip(1).any(|flag| flag == "q") {
+            return &[];
+        }
+
+        match &*self.name {
+            "mg" => &["EN"],
+            "ms" | "md" | "ma" => &["HD", "NF"],
+            _ => &[],
+        }
+    }
+
+    /// Response codes a meta command can give, besides errors.
+    pub fn response_codes(&self) -> &'static [&'static str] {
+        match &*self.name {
+            "mg" => &["VA", "HD", "EN"],
+            "ms" => &["HD", "NS", "EX", "NF"],
+            "md" => &["HD", "NF", "EX"],
+            "ma" => &["VA", "HD", "NF", "NS", "EX"],
+            _ => &[],
+        }
+    }
+
+    /// Length of the data block following the command line.
+    fn data_length(&self) -> Result<Option<usize>, String> {
+        let position = match &*self.name {
+            "set" | "add" | "replace" | "append" | "prepend" | "cas" => 3,
+            "ms" => 1,
+            _ => return Ok(None),
+        };
+
+        match self.arguments.get(position).map(|length| length.parse::<usize>()) {
+            Some(Ok(length)) if length <= MAX_DATA => Ok(Some(length)),
+            _ => Err(format!("Invalid data length for {}", self.name)),
+        }
+    }
+}
+
+/// Line of a text protocol response, with the data block following `VALUE`
+/// and `VA` lines.
+#[derive(Clone, PartialEq, Debug)]
+pub struct TextLine {
+    pub words: Vec<String>,
+    pub data: Option<Vec<u8>>,
+}
+
+impl TextLine {
+    pub fn new(line: &str) -> Self {
+        TextLine {
+            words: line.split(' ').filter(|word| !word.is_empty()).map(|word| word.to_string()).collect(),
+            data: None,
+        }
+    }
+
+    fn code(&self) -> &str {
+        self.words.first().map(|code| &**code).unwrap_or("")
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Command {
+    Text(TextCommand),
+    Binary(Packet),
+}
+
+impl Command {
+    /// Lowercased name of the command.
+    pub fn name(&self) -> String {
+        match *self {
+            Command::Text(ref command) => command.name.clone(),
+            Command::Binary(ref packet) => opcode_name(packet.opcode).to_ascii_lowercase(),
+        }
+    }
+
+    pub fn keys(&self) -> Vec<Vec<u8>> {
+        match *self {
+            Command::Text(ref command) => {
+                command.key_positions().into_iter().map(|position| command.arguments[position].as_bytes().to_vec()).collect()
+            },
+            Command::Binary(ref packet) if packet.has_item_key() => vec![packet.key.clone()],
+            Command::Binary(_) => Vec::new(),
+        }
+    }
+
+    /// Rewrites every item key of the command.
+    pub fn map_keys<F>(self, map: F) -> Command where F: Fn(&[u8]) -> Vec<u8> {
+        match self {
+            Command::Text(mut command) => {
+                for position in command.key_positions() {
+                    let key = map(command.arguments[position].as_bytes());
+                    command.arguments[position] = String::from_utf8_lossy(&key).into_owned();
+                }
+
+                Command::Text(command)
+            },
+            Command::Binary(mut packet) => {
+                if packet.has_item_key() {
+                    packet.key = map(&packet.key);
+                }
+
+                Command::Binary(packet)
+            },
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        match *self {
+            Command::Text(ref command) => {
+                let mut line = command.name.clone();
+                for argument in command.arguments.iter() {
+                    line.push(' ');
+                    line.push_str(argument);
+                }
+
+                let mut buf = line.into_bytes();
+                buf.extend_from_slice(b"\r\n");
+                if let Some(ref data) = command.data {
+                    buf.extend_from_slice(data);
+                    buf.extend_from_slice(b"\r\n");
+                }
+
+                buf
+            },
+            Command::Binary(ref packet) => packet.encode(REQUEST_MAGIC),
+        }
+    }
+
+    pub fn to_beautify_string(&self) -> String {
+        match *self {
+            Command::Text(ref command) => {
+                let mut line = command.name.clone();
+                for argument in command.arguments.iter() {
+                    line.push(' ');
+                    line.push_str(argument);
+                }
+                if let Some(ref data) = command.data {
+                    line.push_str(&format!(" ({} bytes)", data.len()));
+                }
+
+                line
+            },
+            Command::Binary(ref packet) => {
+                format!("{} {} ({} bytes)", opcode_name(packet.opcode), String::from_utf8_lossy(&packet.key), packet.value.len())
+            },
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Response {
+    /// Lines up to the one ending the response, like `END`.
+    Text(Vec<TextLine>),
+    Binary(Packet),
+}
+
+impl Response {
+    /// Error answering the command, in its protocol.
+    pub fn error(command: &Command, message: &str) -> Response {
+        match *command {
+            Command::Text(_) => Response::Text(vec![TextLine::new(&format!("SERVER_ERROR {}", message))]),
+            Command::Binary(ref packet) => {
+                Response::Binary(Packet::response_to(packet, STATUS_INTERNAL_ERROR, message.as_bytes()))
+            },
+        }
+    }
+
+    pub fn is_error(&self) -> bool {
+        match *self {
+            Response::Text(ref lines) => {
+                match lines.first().map(TextLine::code) {
+                    Some("ERROR") | Some("CLIENT_ERROR") | Some("SERVER_ERROR") => true,
+                    _ => false,
+                }
+            },
+            // Misses (key not found) are not errors
+            Response::Binary(ref packet) => packet.status != 0 && packet.status != 1,
+        }
+    }
+
+    /// Text response code, like `STORED` or `VALUE`.
+    pub fn code(&self) -> Option<&str> {
+        match *self {
+            Response::Text(ref lines) => lines.first().map(TextLine::code),
+            Response::Binary(_) => None,
+        }
+    }
+
+    pub fn opaque(&self) -> Option<(u8, u32)> {
+        match *self {
+            Response::Binary(ref packet) => Some((packet.opcode, packet.opaque)),
+            Response::Text(_) => None,
+        }
+    }
+
+    /// Rewrites the item keys given back, by `VALUE` lines, meta flags and
+    /// binary responses.
+    pub fn map_keys<F>(self, map: F) -> Response where F: Fn(&[u8]) -> Vec<u8> {
+        match self {
+            Response::Text(mut lines) => {
+                for line in lines.iter_mut() {
+                    let positions: Vec<usize> = match line.code() {
+                        "VALUE" | "ME" if line.words.len() > 1 => vec![1],
+                        "VA" | "HD" | "EN" | "NF" | "NS" | "EX" => {
+                            (1..line.words.len()).filter(|&position| line.words[position].starts_with('k')).collect()
+                        },
+                        _ => Vec::new(),
+                    };
+
+                    for position in positions {
+                        let word = line.words[position].clone();
+                        line.words[position] = if line.code() == "VALUE" || line.code() == "ME" {
+                            String::from_utf8_lossy(&map(word.as_bytes())).into_owned()
+                        } else {
+                            format!("k{}", String::from_utf8_lossy(&map(word[1..].as_bytes())))
+                        };
+                    }
+                }
+
+                Response::Text(lines)
+            },
+            Response::Binary(mut packet) => {
+                if !packet.key.is_empty() && packet.has_item_key() {
+                    packet.key = map(&packet.key);
+                }
+
+                Response::Binary(packet)
+            },
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        match *self {
+            Response::Text(ref lines) => {
+                let mut buf = Vec::new();
+                for line in lines.iter() {
+                    buf.extend_from_slice(line.words.join(" ").as_bytes());
+                    buf.extend_from_slice(b"\r\n");
+                    if let Some(ref data) = line.data {
+                        buf.extend_from_slice(data);
+                        buf.extend_from_slice(b"\r\n");
+                    }
+                }
+
+                buf
+            },
+            Response::Binary(ref packet) => packet.encode(RESPONSE_MAGIC),
+        }
+    }
+
+    pub fn to_beautify_string(&self) -> String {
+        match *self {
+            Response::Text(ref lines) => {
+                lines.iter().map(|line| {
+                    match line.data {
+                        Some(ref data) => format!("{} ({} bytes)", line.words.join(" "), data.len()),
+                        None => line.words.join(" "),
+                    }
+                }).collect::<Vec<String>>().join(", ")
+            },
+            Response::Binary(ref packet) => {
+                format!("{} status {} ({} bytes)", opcode_name(packet.opcode), packet.status, packet.value.len())
+            },
+        }
+    }
+}
+
+pub trait Message: Sized {
+    /// Parses the message starting at the position, giving it with the
+    /// position of the next one, or nothing if it is incomplete.
+    fn parse(buf: &[u8], start: usize) -> Result<Option<(Self, usize)>, String>;
+}
+
+/// Text line ending at `\n`, with an optional `\r` before.
+fn read_line(buf: &[u8], start: usize) -> Result<Option<(String, usize)>, String> {
+    match buf[start..].iter().position(|&byte| byte == b'\n') {
+        Some(end) => {
+            let mut line = &buf[start..start + end];
+            if line.last() == Some(&b'\r') {
+                line = &line[..line.len() - 1];
+            }
+
+            match String::from_utf8(line.to_vec()) {
+                Ok(line) => Ok(Some((line, start + end + 1))),
+                Err(_) => Err("Invalid text line".to_string()),
+            }
+        },
+        None if buf.len() - start > MAX_LINE => Err("Line too long".to_string()),
+        None => Ok(None),
+    }
+}
+
+/// Data block of the given length, followed by `\r\n`.
+fn read_data(buf: &[u8], start: usize, length: usize) -> Result<Option<(Vec<u8>, usize)>, String> {
+    if buf.len() < start + length + 2 {
+        return Ok(None);
+    }
+
+    if &buf[start + length..start + length + 2] != b"\r\n" {
+        return Err("Data block not terminated".to_string());
+    }
+
+    Ok(Some((buf[start..start + length].to_vec(), start + length + 2)))
+}
+
+fn parse_packet(buf: &[u8], start: usize) -> Result<Option<(Packet, usize)>, String> {
+    if buf.len() < start + HEADER_LEN {
+        return Ok(None);
+    }
+
+    let header = &buf[start..start + HEADER_LEN];
+    let key_length = read_be(&header[2..4]) as usize;
+    let extras_length = header[4] as usize;
+    let body_length = read_be(&header[8..12]) as usize;
+
+    if body_length > MAX_DATA || key_length + extras_length > body_length {
+        return Err("Invalid packet lengths".to_string());
+    }
+
+    let end = start + HEADER_LEN + body_length;
+    if buf.len() < end {
+        return Ok(None);
+    }
+
+    let body = &buf[start + HEADER_LEN..end];
+    let packet = Packet {
+        opcode: header[1],
+        data_type: header[5],
+        status: read_be(&header[6..8]) as u16,
+        opaque: read_be(&header[12..16]) as u32,
+        cas: read_be(&header[16..24]),
+        extras: body[..extras_length].to_vec(),
+        key: body[extras_length..extras_length + key_length].to_vec(),
+        value: body[extras_length + key_length..].to_vec(),
+    };
+
+    Ok(Some((packet, end)))
+}
+
+impl Message for Command {
+    fn parse(buf: &[u8], start: usize) -> Result<Option<(Command, usize)>, String> {
+        if buf[start] == REQUEST_MAGIC {
+            return parse_packet(buf, start).map(|parsed| parsed.map(|(packet, next)| (Command::Binary(packet), next)));
+        }
+
+        let (line, mut next) = match try!(read_line(buf, start)) {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+
+        let mut words = line.split(' ').filter(|word| !word.is_empty());
+        let mut command = TextCommand {
+            name: words.next().unwrap_or("").to_ascii_lowercase(),
+            arguments: words.map(|word| word.to_string()).collect(),
+            data: None,
+        };
+
+        if let Some(length) = try!(command.data_length()) {
+            match try!(read_data(buf, next, length)) {
+                Some((data, after)) => {
+                    command.data = Some(data);
+                    next = after;
+                },
+                None => return Ok(None),
+            }
+        }
+
+        Ok(Some((Command::Text(command), next)))
+    }
+}
+
+impl Message for Response {
+    fn parse(buf: &[u8], start: usize) -> Result<Option<(Response, usize)>, String> {
+        if buf[start] == RESPONSE_MAGIC {
+            return parse_packet(buf, start).map(|parsed| parsed.map(|(packet, next)| (Response::Binary(packet), next)));
+        }
+
+        let mut lines = Vec::new();
+        let mut position = start;
+
+        loop {
+            if position >= buf.len() {
+                return Ok(None);
+            }
+
+            let (line, next) = match try!(read_line(buf, position)) {
+                Some(line) => line,
+                None => return Ok(None),
+            };
+            position = next;
+
+            let mut line = TextLine::new(&line);
+            let length = match line.code() {
+                "VALUE" => line.words.get(3),
+                "VA" => line.words.get(1),
+                _ => None,
+            }.map(|length| length.parse::<usize>());
+
+            match length {
+                Some(Ok(length)) if length <= MAX_DATA => {
+                    match try!(read_data(buf, position, length)) {
+                        Some((data, after)) => {
+                            line.data = Some(data);
+                            position = after;
+                        },
+                        None => return Ok(None),
+                    }
+                },
+                Some(_) => return Err("Invalid data length".to_string()),
+                None => (),
+            }
+
+            // Retrievals and stats go on until END
+            let more = match line.code() {
+                "VALUE" | "STAT" | "ITEM" | "PREFIX" => true,
+                code => code.starts_with("key="),
+            };
+
+            lines.push(line);
+            if !more {
+                break;
+            }
+        }
+
+        Ok(Some((Response::Text(lines), position)))
+    }
+}
+
+/// Incremental decoder of commands or responses.
+pub struct Decoder<M> where M: Message {
+    buf: Vec<u8>,
+    messages: Vec<M>,
+}
+
+impl<M> Decoder<M> where M: Message {
+    pub fn new() -> Self {
+        Decoder {
+            buf: Vec::new(),
+            messages: Vec::new(),
+        }
+    }
+
+    /// Feeds bytes to the decoder. On a protocol error the buffered bytes
+    /// are discarded.
+    pub fn feed(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.buf.extend_from_slice(buf);
+
+        let mut position = 0;
+        while position < self.buf.len() {
+            match M::parse(&self.buf, position) {
+                Ok(Some((message, next))) => {
+                    self.messages.push(message);
+                    position = next;
+                },
+                Ok(None) => break,
+                Err(message) => {
+                    self.buf.clear();
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+                },
+            }
+        }
+
+        self.buf.drain(0..position);
+
+        Ok(())
+    }
+
+    /// Reads the next decoded message.
+    pub fn read(&mut self) -> Option<M> {
+        if self.messages.is_empty() {
+            return None;
+        }
+
+        Some(self.messages.remove(0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Decoder, Command, Response, Packet};
+
+    fn binary_get(key: &[u8], opaque: u32) -> Packet {
+        Packet {
+            opcode: 0x00,
+            data_type: 0,
+            status: 0,
+            opaque: opaque,
+            cas: 0,
+            extras: Vec::new(),
+            key: key.to_vec(),
+            value: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn text_and_binary_commands_byte_by_byte() {
+        let mut input = b"set a 0 0 5\r\nhello\r\n".to_vec();
+        input.extend(Command::Binary(binary_get(b"b", 7)).encode());
+        input.extend_from_slice(b"get a b\r\n");
+
+        let mut decoder = Decoder::<Command>::new();
+        let mut commands = Vec::new();
+        for byte in &input {
+            decoder.feed(&[*byte]).unwrap();
+            while let Some(command) = decoder.read() {
+                commands.push(command);
+            }
+        }
+
+        assert_eq!(commands.len(), 3);
+        match commands[0] {
+            Command::Text(ref command) => {
+                assert_eq!(command.name, "set");
+                assert_eq!(command.data, Some(b"hello".to_vec()));
+            },
+            ref command => panic!("unexpected command {:?}", command),
+        }
+        assert_eq!(commands[1], Command::Binary(binary_get(b"b", 7)));
+        assert_eq!(commands[2].keys(), vec![b"a".to_vec(), b"b".to_vec()]);
+
+        let encoded: Vec<u8> = commands.iter().flat_map(|command| command.encode()).collect();
+        assert_eq!(encoded, input);
+    }
+
+    #[test]
+    fn retrieval_goes_on_until_end() {
+        let mut decoder = Decoder::<Response>::new();
+        decoder.feed(b"VALUE a 0 5\r\nhello\r\nVALUE b 0 2\r\nhi\r\n").unwrap();
+        assert!(decoder.read().is_none());
+
+        decoder.feed(b"END\r\nSTORED\r\n").unwrap();
+        match decoder.read() {
+            Some(Response::Text(lines)) => {
+                assert_eq!(lines.len(), 3);
+                assert_eq!(lines[1].data, Some(b"hi".to_vec()));
+            },
+            response => panic!("unexpected response {:?}", response),
+        }
+        assert_eq!(decoder.read().and_then(|response| response.code().map(|code| code.to_string())), Some("STORED".to_string()));
+    }
+
+    #[test]
+    fn protocol_errors() {
+        let mut decoder = Decoder::<Command>::new();
+        assert!(decoder.feed(b"set a 0 0 5\r\nhello!!").is_err());
+
+        let mut decoder = Decoder::<Response>::new();
+        assert!(decoder.feed(b"VALUE a 0 x\r\n").is_err());
+
+        let mut header = vec![0x80, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01];
+        header.extend_from_slice(&[0; 12]);
+        let mut decoder = Decoder::<Command>::new();
+        assert!(decoder.feed(&header).is_err());
+    }
+}
@@ -3,8 +3,17 @@ use std::io;
 
 pub mod tcp_connection;
 pub mod poison;
+pub mod glob;
+pub mod fault;
 
 pub mod redis;
+pub mod memcached;
+pub mod http;
+pub mod postgres;
+pub mod mysql;
+
+#[cfg(test)]
+pub mod testing;
 
 pub trait Connection: io::Read + io::Write {
     fn get_evented(&self) -> &Evented;
@@ -12,6 +21,12 @@ pub trait Connection: io::Read + io::Write {
     fn get_interest(&self) -> EventSet;
     fn handle_read(&mut self) -> ConnectionAction;
     fn handle_write(&mut self) -> ConnectionAction;
+
+    /// Bytes written to the connection and not sent yet, for those
+    /// buffering them.
+    fn pending_output(&self) -> usize {
+        0
+    }
 }
 
 pub trait Timer {
@@ -23,6 +38,8 @@ pub trait Timer {
 pub enum Role {
     Downstream,
     Upstream,
+    /// Secondary upstream receiving a copy of the traffic.
+    Shadow,
 }
 
 #[derive(Debug)]
@@ -0,0 +1,500 @@
+use mio::{Token, Evented, EventSet};
+use connection::{Connection, Timer};
+use connection::tcp_connection::TcpConnection;
+use connection::{ConnectionAction, TimerAction};
+use std::io;
+use std::cmp::min;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+use connection::mysql::{MysqlProxy, CommandAction};
+use connection::mysql::protocol::*;
+use netbuf::Buf;
+
+enum Phase {
+    /// Greeting and authentication, passed through.
+    Handshake,
+    Command,
+    /// Encrypted or compressed, the bytes go through untouched.
+    PassThrough,
+}
+
+/// Progress of the response to a command.
+enum Expect {
+    /// A single packet.
+    Status,
+    /// Authentication exchange, up to `OK` or `ERR`.
+    Auth,
+    /// `OK` or `ERR`, or a result set (column count, columns, rows), maybe
+    /// followed by more.
+    Results,
+    Columns(u64),
+    ColumnsEof,
+    Rows,
+    /// `OK` of the prepared statement, or `ERR`.
+    Prepare,
+    /// Parameter and column definitions of a prepared statement.
+    Definitions(u64),
+    /// Never ends, like a binlog dump.
+    Stream,
+}
+
+/// Slot reserved for the response of each command, in the order the
+/// commands were received.
+enum Reply {
+    Upstream(Command, Expect),
+    Local(Vec<Packet>),
+}
+
+/// Follows the MySQL packets of a client and its upstream so a `MysqlProxy`
+/// can intercept the commands once the client is authenticated. Encrypted
+/// and compressed connections go through untouched.
+pub struct MysqlConnection<P> where P: MysqlProxy {
+    connection: TcpConnection,
+    proxy: P,
+    commands: PacketDecoder,
+    responses: PacketDecoder,
+    forward: Buf,
+    replies: VecDeque<Reply>,
+    /// Sequence id of the answer, with the action.
+    delayed: Option<(Instant, u8, CommandAction)>,
+    /// SQL of the statements prepared by id.
+    statements: HashMap<u32, String>,
+    phase: Phase,
+    /// Capabilities announced by the server in its greeting.
+    server_capabilities: u32,
+    /// Capabilities of the client that the server supports too.
+    capabilities: u32,
+    closing: bool,
+}
+
+impl<P> MysqlConnection<P> where P: MysqlProxy {
+    pub fn new(connection: TcpConnection, proxy: P) -> Self {
+        MysqlConnection {
+            connection: connection,
+            proxy: proxy,
+            commands: PacketDecoder::new(),
+            responses: PacketDecoder::new(),
+            forward: Buf::new(),
+            replies: VecDeque::new(),
+            delayed: None,
+            statements: HashMap::new(),
+            phase: Phase::Handshake,
+            server_capabilities: 0,
+            capabilities: 0,
+            closing: false,
+        }
+    }
+
+    fn is_passing_through(&self) -> bool {
+        match self.phase {
+            Phase::PassThrough => true,
+            _ => false,
+        }
+    }
+
+    fn pass_through(&mut self) {
+        info!("{:?}: Connection encrypted or compressed, passing it through", self.get_token());
+        self.phase = Phase::PassThrough;
+
+        let rest = self.responses.take_buffer();
+        self.connection.get_mut_output().extend(&rest);
+        let rest = self.commands.take_buffer();
+        self.forward.extend(&rest);
+    }
+
+    fn process_commands(&mut self) {
+        let len = self.connection.get_input().len();
+        if len == 0 {
+            return;
+        }
+
+        if self.is_passing_through() {
+            self.forward.extend(&self.connection.get_input()[0..len]);
+            self.connection.get_mut_input().consume(len);
+            return;
+        }
+
+        if self.closing {
+            self.connection.get_mut_input().consume(len);
+            return;
+        }
+
+        self.commands.feed(&self.connection.get_input()[0..len]);
+        self.connection.get_mut_input().consume(len);
+
+        self.release_commands();
+        self.flush_replies();
+    }
+
+    /// Intercepts the decoded commands, stopping at the first delayed one
+    /// until its time has come.
+    fn release_commands(&mut self) {
+        while !self.closing && !self.is_passing_through() {
+            let due = match self.delayed {
+                Some((deadline, _, _)) => deadline <= Instant::now(),
+                None => true,
+            };
+
+            if !due {
+                break;
+            }
+
+            if let Some((_, sequence, action)) = self.delayed.take() {
+                self.apply(sequence, action);
+                continue;
+            }
+
+            let packet = match self.commands.read() {
+                Ok(Some(packet)) => packet,
+                Ok(None) => break,
+                Err(e) => {
+                    error!("{:?}: Could not parse packet: {}", self.get_token(), e);
+                    self.closing = true;
+                    break;
+                },
+            };
+
+            let handshake = match self.phase {
+                Phase::Handshake => true,
+                _ => false,
+            };
+
+            if handshake {
+                self.forward_handshake(packet);
+                continue;
+            }
+
+            // Authentication or LOCAL INFILE data, answering the server
+            if packet.sequence != 0 {
+                self.forward.extend(&packet.encode());
+                continue;
+            }
+
+            let mut command = Command::new(packet);
+            command.query_attributes = self.capabilities & CLIENT_QUERY_ATTRIBUTES != 0;
+            if let Some(id) = command.statement_id() {
+                command.statement = self.statements.get(&id).cloned();
+            }
+
+            let sequence = command.packet.next_sequence();
+            let action = self.proxy.on_command(command);
+            self.apply(sequence, action);
+        }
+    }
+
+    fn forward_handshake(&mut self, packet: Packet) {
+        self.forward.extend(&packet.encode());
+
+        // Handshake response, or the SSL request standing for its start
+        if packet.sequence != 1 || packet.payload.len() < 4 {
+            return;
+        }
+
+        let capabilities = packet.payload[0] as u32 | (packet.payload[1] as u32) << 8 |
+            (packet.payload[2] as u32) << 16 | (packet.payload[3] as u32) << 24;
+        self.capabilities = capabilities & self.server_capabilities;
+
+        if self.capabilities & CLIENT_SSL != 0 {
+            self.pass_through();
+        }
+    }
+
+    /// Applies the action taken on a command, answering locally with the
+    /// given sequence id.
+    fn apply(&mut self, sequence: u8, action: CommandAction) {
+        match action {
+            CommandAction::Forward(command) => {
+                self.forward.extend(&command.packet.encode());
+
+                let expect = match command.code() {
+                    COM_QUIT => {
+                        self.closing = true;
+                        return;
+                    },
+                    COM_STMT_CLOSE => {
+                        if let Some(id) = command.statement_id() {
+                            self.statements.remove(&id);
+                        }
+                        return;
+                    },
+                    _ if command.is_silent() => return,
+                    COM_QUERY | COM_STMT_EXECUTE => Expect::Results,
+                    COM_STMT_PREPARE => Expect::Prepare,
+                    COM_STMT_FETCH | COM_FIELD_LIST => Expect::Rows,
+                    COM_CHANGE_USER => {
+                        // The session and its statements are reset
+                        self.statements.clear();
+                        Expect::Auth
+                    },
+                    COM_BINLOG_DUMP | COM_BINLOG_DUMP_GTID => Expect::Stream,
+                    _ => Expect::Status,
+                };
+
+                self.replies.push_back(Reply::Upstream(command, expect));
+            },
+            CommandAction::Respond(packets) => {
+                let mut sequence = sequence;
+                let packets = packets.into_iter().map(|mut packet| {
+                    packet.sequence = sequence;
+                    sequence = packet.next_sequence();
+                    packet
+                }).collect();
+
+                self.replies.push_back(Reply::Local(packets));
+            },
+            CommandAction::Drop => {
+                info!("{:?}: Dropping command", self.get_token());
+            },
+            CommandAction::Close => {
+                info!("{:?}: Closing connection", self.get_token());
+                self.closing = true;
+            },
+            CommandAction::Delay(delay, action) => {
+                self.delayed = Some((Instant::now() + Duration::from_millis(delay), sequence, *action));
+            },
+        }
+    }
+
+    fn process_responses(&mut self) -> io::Result<()> {
+        while let Some(packet) = try!(self.responses.read()) {
+            let handshake = match self.phase {
+                Phase::Handshake => true,
+                _ => false,
+            };
+
+            if handshake {
+                self.connection.get_mut_output().extend(&packet.encode());
+
+                if packet.sequence == 0 {
+                    if let Some(capabilities) = packet.greeting_capabilities() {
+                        self.server_capabilities = capabilities;
+                    }
+                    continue;
+                }
+
+                if packet.is_ok() {
+                    if self.capabilities & CLIENT_COMPRESS != 0 {
+                        self.pass_through();
+                        return Ok(());
+                    }
+
+                    self.phase = Phase::Command;
+                }
+                continue;
+            }
+
+            let deprecate_eof = self.capabilities & CLIENT_DEPRECATE_EOF != 0;
+            let (packet, done) = match self.replies.front_mut() {
+                Some(&mut Reply::Upstream(ref command, ref mut expect)) => {
+                    if command.code() == COM_STMT_PREPARE && packet.is_ok() && packet.payload.len() >= 5 {
+                        let id = packet.payload[1] as u32 | (packet.payload[2] as u32) << 8 |
+                            (packet.payload[3] as u32) << 16 | (packet.payload[4] as u32) << 24;
+                        if let Some(query) = command.query() {
+                            self.statements.insert(id, query);
+                        }
+                    }
+
+                    let done = advance(expect, &packet, deprecate_eof);
+                    (self.proxy.on_response(command, packet), done)
+                },
+                _ => {
+                    warn!("{:?}: Received a response without a pending command", self.get_token());
+                    (packet, false)
+                },
+            };
+
+            self.connection.get_mut_output().extend(&packet.encode());
+
+            if done {
+                self.replies.pop_front();
+                self.flush_replies();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes the local answers whose turn has come.
+    fn flush_replies(&mut self) {
+        loop {
+            let ready = match self.replies.front() {
+                Some(&Reply::Local(_)) => true,
+                _ => false,
+            };
+
+            if !ready {
+                break;
+            }
+
+            if let Some(Reply::Local(packets)) = self.replies.pop_front() {
+                for packet in packets {
+                    self.connection.get_mut_output().extend(&packet.encode());
+                }
+            }
+        }
+    }
+}
+
+fn more_results(packet: &Packet) -> bool {
+    packet.status_flags().map(|flags| flags & SERVER_MORE_RESULTS_EXISTS != 0).unwrap_or(false)
+}
+
+/// Follows the response with the packet, telling whether it is complete.
+fn advance(expect: &mut Expect, packet: &Packet, deprecate_eof: bool) -> bool {
+    let next = match *expect {
+        Expect::Status => return true,
+        Expect::Auth => return packet.is_ok() || packet.is_error(),
+        Expect::Stream => return false,
+        Expect::Results => {
+            match packet.header() {
+                Some(0x00) => {
+                    if !more_results(packet) {
+                        return true;
+                    }
+                    Expect::Results
+                },
+                Some(0xff) => return true,
+                // LOCAL INFILE request, the client sends the file
+                Some(0xfb) => Expect::Results,
+                _ => {
+                    match read_lenenc(&packet.payload, 0) {
+                        Some((0, _)) | None => return true,
+                        Some((count, _)) => Expect::Columns(count),
+                    }
+                },
+            }
+        },
+        Expect::Columns(count) => {
+            if count > 1 {
+                Expect::Columns(count - 1)
+            } else if deprecate_eof {
+                Expect::Rows
+            } else {
+                Expect::ColumnsEof
+            }
+        },
+        Expect::ColumnsEof => {
+            // The rows of a cursor come with COM_STMT_FETCH
+            let cursor = packet.status_flags().map(|flags| flags & SERVER_STATUS_CURSOR_EXISTS != 0);
+            if packet.is_error() || cursor.unwrap_or(false) {
+                return true;
+            }
+            Expect::Rows
+        },
+        Expect::Rows => {
+            if packet.is_error() {
+                return true;
+            }
+
+            if packet.is_eof() {
+                if !more_results(packet) {
+                    return true;
+                }
+                Expect::Results
+            } else {
+                Expect::Rows
+            }
+        },
+        Expect::Prepare => {
+            if !packet.is_ok() || packet.payload.len() < 9 {
+                return true;
+            }
+
+            let columns = packet.payload[5] as u64 | (packet.payload[6] as u64) << 8;
+            let params = packet.payload[7] as u64 | (packet.payload[8] as u64) << 8;
+            let eofs = if deprecate_eof {
+                0
+            } else {
+                (columns > 0) as u64 + (params > 0) as u64
+            };
+
+            if columns + params + eofs == 0 {
+                return true;
+            }
+            Expect::Definitions(columns + params + eofs)
+        },
+        Expect::Definitions(count) => {
+            if count <= 1 {
+                return true;
+            }
+            Expect::Definitions(count - 1)
+        },
+    };
+
+    *expect = next;
+
+    false
+}
+
+impl<P> Timer for MysqlConnection<P> where P: MysqlProxy {
+    fn handle_timer(&mut self) -> TimerAction {
+        self.release_commands();
+        self.flush_replies();
+
+        TimerAction::Continue
+    }
+
+    fn get_frequency(&self) -> u64 {
+        10
+    }
+}
+
+impl<P> io::Read for MysqlConnection<P> where P: MysqlProxy {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.process_commands();
+
+        let read_size = min(buf.len(), self.forward.len());
+        buf[0..read_size].clone_from_slice(&self.forward[0..read_size]);
+        self.forward.consume(read_size);
+
+        Ok(read_size)
+    }
+}
+
+impl<P> io::Write for MysqlConnection<P> where P: MysqlProxy {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.is_passing_through() {
+            self.connection.get_mut_output().extend(buf);
+            return Ok(buf.len());
+        }
+
+        self.responses.feed(buf);
+        try!(self.process_responses());
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<P> Connection for MysqlConnection<P> where P: MysqlProxy {
+    fn get_evented(&self) -> &Evented {
+        return &*self.connection.get_evented();
+    }
+
+    fn get_token(&self) -> Token {
+        return self.connection.get_token();
+    }
+
+    fn get_interest(&self) -> EventSet {
+        return self.connection.get_interest();
+    }
+
+    fn handle_read(&mut self) -> ConnectionAction {
+        let read_response = self.connection.handle_read();
+
+        read_response
+    }
+
+    fn handle_write(&mut self) -> ConnectionAction {
+        let write_response = self.connection.handle_write();
+
+        if self.closing && !self.is_passing_through() && self.replies.is_empty() && self.connection.get_output().is_empty() {
+            return ConnectionAction::Halt;
+        }
+
+        write_response
+    }
+}
@@ -0,0 +1,81 @@
+use connection::mysql::{MysqlProxy, CommandAction};
+use connection::mysql::protocol::{Command, Packet};
+use connection::fault;
+
+/// ER_SPECIFIC_ACCESS_DENIED_ERROR, given to blocked statements.
+const BLOCKED_CODE: u16 = 1227;
+
+/// Misbehaviour injected on a matching statement.
+#[derive(Clone)]
+pub enum Fault {
+    /// Answer with an `ERR` packet of the error code and message instead of
+    /// forwarding the statement.
+    Error(u16, String),
+    /// Refuse the statement as if access was denied.
+    Block,
+    /// Hold the statement for the given milliseconds before forwarding it.
+    Delay(u64),
+    /// Answer with the error after the given milliseconds.
+    DelayedError(u64, u16, String),
+}
+
+/// Injects a fault on the statements (queries, prepared or executed
+/// statements) whose SQL matches a pattern, with the given probability.
+pub type FaultRule = fault::Rule<Fault>;
+
+impl FaultRule {
+    /// Only match the statements whose SQL matches the glob-style pattern.
+    pub fn query_pattern(self, pattern: &str) -> Self {
+        self.pattern(pattern)
+    }
+}
+
+/// Injects faults per statement, so database chaos tests can exercise the
+/// client error handling on specific queries. The first matching rule whose
+/// probability check passes wins.
+pub struct FaultProxy {
+    rules: Vec<FaultRule>,
+}
+
+impl FaultProxy {
+    pub fn new(rules: Vec<FaultRule>) -> Self {
+        FaultProxy {
+            rules: rules,
+        }
+    }
+}
+
+impl MysqlProxy for FaultProxy {
+    fn on_command(&mut self, command: Command) -> CommandAction {
+        // Commands without an answer can not fail
+        let fault = match command.query() {
+            Some(ref query) if !command.is_silent() => {
+                fault::select(&self.rules, None, &[query.trim()])
+            },
+            _ => None,
+        };
+
+        match fault {
+            Some(Fault::Error(code, message)) => {
+                CommandAction::Respond(vec![Packet::error(code, "HY000", &message)])
+            },
+            Some(Fault::Block) => {
+                CommandAction::Respond(vec![Packet::error(BLOCKED_CODE, "42000", "Statement blocked by proxy")])
+            },
+            Some(Fault::Delay(delay)) => {
+                CommandAction::Delay(delay, Box::new(CommandAction::Forward(command)))
+            },
+            Some(Fault::DelayedError(delay, code, message)) => {
+                let action = CommandAction::Respond(vec![Packet::error(code, "HY000", &message)]);
+                CommandAction::Delay(delay, Box::new(action))
+            },
+            None => {
+                CommandAction::Forward(command)
+            },
+        }
+    }
+
+    fn on_response(&mut self, _: &Command, packet: Packet) -> Packet {
+        packet
+    }
+}
@@ -0,0 +1,104 @@
+pub use self::connection::MysqlConnection;
+pub use self::fault::{FaultProxy, FaultRule, Fault};
+
+use connection::mysql::protocol::{Command, Packet};
+
+pub mod protocol;
+
+mod connection;
+mod fault;
+
+/// Outcome of intercepting a command sent by a client.
+pub enum CommandAction {
+    /// Send the (possibly rewritten) command to the upstream.
+    Forward(Command),
+    /// Answer the client locally, i.e. with an `ERR` packet; the command
+    /// never reaches the upstream. The connection numbers the packets.
+    Respond(Vec<Packet>),
+    /// Discard the command without answering it.
+    Drop,
+    /// Close the client connection once the responses of the commands
+    /// received before this one have been sent.
+    Close,
+    /// Apply the action after the given milliseconds. The commands sent
+    /// after this one wait for it, so their order is kept.
+    Delay(u64, Box<CommandAction>),
+}
+
+/// Intercepts the commands of an authenticated client, and each packet of
+/// their responses.
+pub trait MysqlProxy {
+    fn on_command(&mut self, command: Command) -> CommandAction;
+    fn on_response(&mut self, command: &Command, packet: Packet) -> Packet;
+}
+
+pub struct NoopProxy;
+
+impl MysqlProxy for NoopProxy {
+    fn on_command(&mut self, command: Command) -> CommandAction {
+        CommandAction::Forward(command)
+    }
+
+    fn on_response(&mut self, _: &Command, packet: Packet) -> Packet {
+        packet
+    }
+}
+
+pub struct ComposedProxy<A: MysqlProxy, B: MysqlProxy> {
+    proxy_a: A,
+    proxy_b: B,
+}
+
+impl<A: MysqlProxy, B: MysqlProxy> ComposedProxy<A, B> {
+    pub fn new(proxy_a: A, proxy_b: B) -> Self {
+        ComposedProxy {
+            proxy_a: proxy_a,
+            proxy_b: proxy_b,
+        }
+    }
+
+    /// Lets `proxy_a` intercept the commands `proxy_b` decided to forward.
+    fn chain(&mut self, action: CommandAction) -> CommandAction {
+        match action {
+            CommandAction::Forward(command) => self.proxy_a.on_command(command),
+            CommandAction::Delay(delay, action) => {
+                CommandAction::Delay(delay, Box::new(self.chain(*action)))
+            },
+            action => action,
+        }
+    }
+}
+
+impl<A: MysqlProxy, B: MysqlProxy> MysqlProxy for ComposedProxy<A, B> {
+    fn on_command(&mut self, command: Command) -> CommandAction {
+        let action = self.proxy_b.on_command(command);
+
+        self.chain(action)
+    }
+
+    fn on_response(&mut self, command: &Command, packet: Packet) -> Packet {
+        self.proxy_b.on_response(
+            command,
+            self.proxy_a.on_response(command, packet)
+        )
+    }
+}
+
+/// Logs the commands and the errors.
+pub struct LogProxy;
+
+impl MysqlProxy for LogProxy {
+    fn on_command(&mut self, command: Command) -> CommandAction {
+        warn!("Received command: {}", command.to_beautify_string());
+
+        CommandAction::Forward(command)
+    }
+
+    fn on_response(&mut self, command: &Command, packet: Packet) -> Packet {
+        if let Some((code, message)) = packet.error_message() {
+            warn!("Error {} for {}: {}", code, command.to_beautify_string(), message);
+        }
+
+        packet
+    }
+}
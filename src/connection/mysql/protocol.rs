@@ -0,0 +1,518 @@
+//! MySQL client/server protocol packets.
+
+use std::io;
+use std::cmp::min;
+
+pub const COM_QUIT: u8 = 0x01;
+pub const COM_INIT_DB: u8 = 0x02;
+pub const COM_QUERY: u8 = 0x03;
+pub const COM_FIELD_LIST: u8 = 0x04;
+pub const COM_CHANGE_USER: u8 = 0x11;
+pub const COM_BINLOG_DUMP: u8 = 0x12;
+pub const COM_STMT_PREPARE: u8 = 0x16;
+pub const COM_STMT_EXECUTE: u8 = 0x17;
+pub const COM_STMT_SEND_LONG_DATA: u8 = 0x18;
+pub const COM_STMT_CLOSE: u8 = 0x19;
+pub const COM_STMT_RESET: u8 = 0x1a;
+pub const COM_STMT_FETCH: u8 = 0x1c;
+pub const COM_BINLOG_DUMP_GTID: u8 = 0x1e;
+
+pub const CLIENT_COMPRESS: u32 = 0x0000_0020;
+pub const CLIENT_SSL: u32 = 0x0000_0800;
+pub const CLIENT_DEPRECATE_EOF: u32 = 0x0100_0000;
+pub const CLIENT_QUERY_ATTRIBUTES: u32 = 0x0800_0000;
+
+pub const SERVER_MORE_RESULTS_EXISTS: u16 = 0x0008;
+pub const SERVER_STATUS_CURSOR_EXISTS: u16 = 0x0040;
+
+/// Payloads this long continue in the next packet.
+const MAX_PAYLOAD: usize = 0xFF_FFFF;
+
+/// Payload with its sequence id. Payloads split over several packets are
+/// joined back.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Packet {
+    pub sequence: u8,
+    pub payload: Vec<u8>,
+}
+
+impl Packet {
+    pub fn new(sequence: u8, payload: Vec<u8>) -> Self {
+        Packet {
+            sequence: sequence,
+            payload: payload,
+        }
+    }
+
+    /// `ERR` packet with the error code, the SQLSTATE and the message.
+    pub fn error(code: u16, state: &str, message: &str) -> Self {
+        let mut payload = vec![0xff, code as u8, (code >> 8) as u8, b'#'];
+        payload.extend_from_slice(state.as_bytes());
+        payload.extend_from_slice(message.as_bytes());
+
+        Packet::new(0, payload)
+    }
+
+    /// `OK` packet with no affected rows.
+    pub fn ok() -> Self {
+        Packet::new(0, vec![0x00, 0, 0, 0x02, 0, 0, 0])
+    }
+
+    /// First byte of the payload, telling `OK` (0x00), `ERR` (0xff),
+    /// `EOF` (0xfe) or the command.
+    pub fn header(&self) -> Option<u8> {
+        self.payload.first().cloned()
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.header() == Some(0xff)
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.header() == Some(0x00)
+    }
+
+    /// Whether this is an `EOF` packet, or an `OK` packet ending a result
+    /// set when `CLIENT_DEPRECATE_EOF` is set.
+    pub fn is_eof(&self) -> bool {
+        self.header() == Some(0xfe) && self.payload.len() < MAX_PAYLOAD
+    }
+
+    /// Error code and message of an `ERR` packet.
+    pub fn error_message(&self) -> Option<(u16, String)> {
+        if !self.is_error() || self.payload.len() < 3 {
+            return None;
+        }
+
+        let code = self.payload[1] as u16 | (self.payload[2] as u16) << 8;
+        // The SQLSTATE marker and state are optional
+        let start = if self.payload.get(3) == Some(&b'#') { 9 } else { 3 };
+        let message = String::from_utf8_lossy(&self.payload[min(start, self.payload.len())..]).into_owned();
+
+        Some((code, message))
+    }
+
+    /// Server status flags of an `OK` or `EOF` packet.
+    pub fn status_flags(&self) -> Option<u16> {
+        match self.header() {
+            Some(0xfe) if self.payload.len() == 5 => {
+                // Before CLIENT_DEPRECATE_EOF, after the warning count
+                Some(read_u16(&self.payload, 3))
+            },
+            Some(0x00) | Some(0xfe) => {
+                // After the affected rows and the last insert id
+                let mut position = 1;
+                for _ in 0..2 {
+                    match read_lenenc(&self.payload, position) {
+                        Some((_, next)) => position = next,
+                        None => return None,
+                    }
+                }
+
+                if self.payload.len() >= position + 2 {
+                    Some(read_u16(&self.payload, position))
+                } else {
+                    None
+                }
+            },
+            _ => None,
+        }
+    }
+
+    /// Capabilities the server announces in its greeting.
+    pub fn greeting_capabilities(&self) -> Option<u32> {
+        // Protocol version 10, then the server version
+        if self.header() != Some(10) {
+            return None;
+        }
+
+        let version_end = match self.payload.iter().position(|&byte| byte == 0) {
+            Some(end) => end,
+            None => return None,
+        };
+
+        // After the thread id, the first part of the auth data and a filler
+        let position = version_end + 1 + 4 + 8 + 1;
+        if self.payload.len() < position + 2 {
+            return None;
+        }
+
+        let lower = read_u16(&self.payload, position) as u32;
+        // The upper part follows the character set and the status flags
+        if self.payload.len() < position + 7 {
+            return Some(lower);
+        }
+
+        Some(lower | (read_u16(&self.payload, position + 5) as u32) << 16)
+    }
+
+    /// Sequence id of the packet following this one.
+    pub fn next_sequence(&self) -> u8 {
+        let frames = self.payload.len() / MAX_PAYLOAD + 1;
+
+        self.sequence.wrapping_add(frames as u8)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.payload.len() + 4);
+        let mut sequence = self.sequence;
+        let mut position = 0;
+
+        loop {
+            let length = min(self.payload.len() - position, MAX_PAYLOAD);
+            buf.extend_from_slice(&[length as u8, (length >> 8) as u8, (length >> 16) as u8, sequence]);
+            buf.extend_from_slice(&self.payload[position..position + length]);
+
+            position += length;
+            sequence = sequence.wrapping_add(1);
+
+            // A full payload is followed by a (possibly empty) continuation
+            if length < MAX_PAYLOAD {
+                break;
+            }
+        }
+
+        buf
+    }
+}
+
+/// Command sent by a client once authenticated.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Command {
+    pub packet: Packet,
+    /// SQL of the prepared statement a `COM_STMT_*` command refers to,
+    /// when the connection saw it prepared.
+    pub statement: Option<String>,
+    /// Whether a `COM_QUERY` starts with query attributes, as it does once
+    /// `CLIENT_QUERY_ATTRIBUTES` is negotiated.
+    pub query_attributes: bool,
+}
+
+impl Command {
+    pub fn new(packet: Packet) -> Self {
+        Command {
+            packet: packet,
+            statement: None,
+            query_attributes: false,
+        }
+    }
+
+    /// Command byte, like `COM_QUERY`.
+    pub fn code(&self) -> u8 {
+        self.packet.header().unwrap_or(0)
+    }
+
+    /// SQL of a `COM_QUERY` or `COM_STMT_PREPARE`, or of the prepared
+    /// statement of a `COM_STMT_*` command.
+    pub fn query(&self) -> Option<String> {
+        match self.code() {
+            COM_QUERY if self.query_attributes => {
+                self.skip_attributes().map(|start| String::from_utf8_lossy(&self.packet.payload[start..]).into_owned())
+            },
+            COM_QUERY | COM_STMT_PREPARE => {
+                Some(String::from_utf8_lossy(&self.packet.payload[1..]).into_owned())
+            },
+            _ => self.statement.clone(),
+        }
+    }
+
+    /// Position of the SQL of a `COM_QUERY`, after its query attributes.
+    fn skip_attributes(&self) -> Option<usize> {
+        let payload = &self.packet.payload;
+
+        // Parameter count, then the parameter set count that is always 1
+        let (count, position) = match read_lenenc(payload, 1) {
+            Some((count, position)) => (count as usize, position),
+            None => return None,
+        };
+        let mut position = match read_lenenc(payload, position) {
+            Some((_, position)) => position,
+            None => return None,
+        };
+
+        if count == 0 {
+            return Some(position);
+        }
+        if count > payload.len() {
+            return None;
+        }
+
+        let nulls = position;
+        position += (count + 7) / 8;
+
+        // Without the types bound, the values can not be told apart
+        if payload.get(position) != Some(&1) {
+            return None;
+        }
+        position += 1;
+
+        let mut types = Vec::with_capacity(count);
+        for _ in 0..count {
+            if payload.len() < position + 2 {
+                return None;
+            }
+            types.push(payload[position]);
+
+            // Type and flag, then the name
+            position = match read_lenenc(payload, position + 2) {
+                Some((length, next)) => next + length as usize,
+                None => return None,
+            };
+        }
+
+        for (i, kind) in types.into_iter().enumerate() {
+            if payload[nulls + i / 8] & (1 << (i % 8)) != 0 {
+                continue;
+            }
+
+            position = match skip_binary_value(payload, position, kind) {
+                Some(next) => next,
+                None => return None,
+            };
+        }
+
+        if position > payload.len() {
+            return None;
+        }
+
+        Some(position)
+    }
+
+    /// Statement id of a `COM_STMT_*` command other than prepare.
+    pub fn statement_id(&self) -> Option<u32> {
+        match self.code() {
+            COM_STMT_EXECUTE | COM_STMT_SEND_LONG_DATA | COM_STMT_CLOSE |
+            COM_STMT_RESET | COM_STMT_FETCH if self.packet.payload.len() >= 5 => {
+                Some(read_u32(&self.packet.payload, 1))
+            },
+            _ => None,
+        }
+    }
+
+    /// Whether the server sends no response to the command.
+    pub fn is_silent(&self) -> bool {
+        match self.code() {
+            COM_QUIT | COM_STMT_SEND_LONG_DATA | COM_STMT_CLOSE => true,
+            _ => false,
+        }
+    }
+
+    pub fn to_beautify_string(&self) -> String {
+        let name = match self.code() {
+            COM_QUIT => "COM_QUIT",
+            COM_INIT_DB => "COM_INIT_DB",
+            COM_QUERY => "COM_QUERY",
+            COM_FIELD_LIST => "COM_FIELD_LIST",
+            COM_CHANGE_USER => "COM_CHANGE_USER",
+            COM_STMT_PREPARE => "COM_STMT_PREPARE",
+            COM_STMT_EXECUTE => "COM_STMT_EXECUTE",
+            COM_STMT_SEND_LONG_DATA => "COM_STMT_SEND_LONG_DATA",
+            COM_STMT_CLOSE => "COM_STMT_CLOSE",
+            COM_STMT_RESET => "COM_STMT_RESET",
+            COM_STMT_FETCH => "COM_STMT_FETCH",
+            _ => return format!("Command 0x{:02x} ({} bytes)", self.code(), self.packet.payload.len()),
+        };
+
+        match self.query() {
+            Some(query) => format!("{} {}", name, query),
+            None => name.to_string(),
+        }
+    }
+}
+
+fn read_u16(buf: &[u8], position: usize) -> u16 {
+    buf[position] as u16 | (buf[position + 1] as u16) << 8
+}
+
+fn read_u32(buf: &[u8], position: usize) -> u32 {
+    buf[position] as u32 | (buf[position + 1] as u32) << 8 |
+        (buf[position + 2] as u32) << 16 | (buf[position + 3] as u32) << 24
+}
+
+/// Position after the value of the given type, in the binary protocol.
+fn skip_binary_value(buf: &[u8], position: usize, kind: u8) -> Option<usize> {
+    match kind {
+        // NULL
+        0x06 => Some(position),
+        // TINY
+        0x01 => Some(position + 1),
+        // SHORT, YEAR
+        0x02 | 0x0d => Some(position + 2),
+        // LONG, INT24, FLOAT
+        0x03 | 0x09 | 0x04 => Some(position + 4),
+        // LONGLONG, DOUBLE
+        0x08 | 0x05 => Some(position + 8),
+        // TIMESTAMP, DATE, TIME, DATETIME, prefixed with their length
+        0x07 | 0x0a | 0x0b | 0x0c => buf.get(position).map(|&length| position + 1 + length as usize),
+        // Strings, blobs and decimals
+        _ => read_lenenc(buf, position).map(|(length, next)| next + length as usize),
+    }
+}
+
+/// Length-encoded integer at the position, with the position after it.
+pub fn read_lenenc(buf: &[u8], position: usize) -> Option<(u64, usize)> {
+    let first = match buf.get(position) {
+        Some(&first) => first,
+        None => return None,
+    };
+
+    let size = match first {
+        0xfc => 2,
+        0xfd => 3,
+        0xfe => 8,
+        0xfb | 0xff => return None,
+        _ => return Some((first as u64, position + 1)),
+    };
+
+    if buf.len() < position + 1 + size {
+        return None;
+    }
+
+    let mut value = 0;
+    for i in 0..size {
+        value |= (buf[position + 1 + i] as u64) << (8 * i);
+    }
+
+    Some((value, position + 1 + size))
+}
+
+/// Incremental decoder of packets, in either direction.
+pub struct PacketDecoder {
+    buf: Vec<u8>,
+}
+
+impl PacketDecoder {
+    pub fn new() -> Self {
+        PacketDecoder {
+            buf: Vec::new(),
+        }
+    }
+
+    pub fn feed(&mut self, buf: &[u8]) {
+        self.buf.extend_from_slice(buf);
+    }
+
+    /// Reads the next packet, once all of its continuations are there.
+    pub fn read(&mut self) -> io::Result<Option<Packet>> {
+        let mut position = 0;
+        let mut packet: Option<Packet> = None;
+
+        loop {
+            if self.buf.len() < position + 4 {
+                return Ok(None);
+            }
+
+            let length = self.buf[position] as usize | (self.buf[position + 1] as usize) << 8 |
+                (self.buf[position + 2] as usize) << 16;
+            let end = position + 4 + length;
+            if self.buf.len() < end {
+                return Ok(None);
+            }
+
+            match packet {
+                Some(ref mut packet) => {
+                    let expected = packet.sequence.wrapping_add((packet.payload.len() / MAX_PAYLOAD) as u8);
+                    if self.buf[position + 3] != expected {
+                        self.buf.clear();
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, "Out of order continuation packet"));
+                    }
+
+                    packet.payload.extend_from_slice(&self.buf[position + 4..end]);
+                },
+                None => {
+                    packet = Some(Packet::new(self.buf[position + 3], self.buf[position + 4..end].to_vec()));
+                },
+            }
+
+            position = end;
+            if length < MAX_PAYLOAD {
+                break;
+            }
+        }
+
+        self.buf.drain(0..position);
+
+        Ok(packet)
+    }
+
+    /// Takes the bytes not decoded yet, once the connection is encrypted
+    /// or compressed.
+    pub fn take_buffer(&mut self) -> Vec<u8> {
+        self.buf.split_off(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Packet, PacketDecoder, Command, MAX_PAYLOAD, CLIENT_DEPRECATE_EOF, CLIENT_QUERY_ATTRIBUTES};
+
+    #[test]
+    fn continuation_packets_are_joined() {
+        let large = Packet::new(3, vec![7; MAX_PAYLOAD + 10]);
+        let exact = Packet::new(0, vec![8; MAX_PAYLOAD]);
+        let mut input = large.encode();
+        input.extend(exact.encode());
+        input.extend(Packet::ok().encode());
+
+        let mut decoder = PacketDecoder::new();
+        decoder.feed(&input[0..MAX_PAYLOAD]);
+        assert_eq!(decoder.read().unwrap(), None);
+
+        decoder.feed(&input[MAX_PAYLOAD..]);
+        assert_eq!(decoder.read().unwrap(), Some(large.clone()));
+        assert_eq!(large.next_sequence(), 5);
+        assert_eq!(decoder.read().unwrap(), Some(exact));
+        assert_eq!(decoder.read().unwrap(), Some(Packet::ok()));
+        assert_eq!(decoder.read().unwrap(), None);
+    }
+
+    #[test]
+    fn out_of_order_continuation_is_an_error() {
+        let mut input = Packet::new(0, vec![0; MAX_PAYLOAD + 1]).encode();
+        input[MAX_PAYLOAD + 7] = 5;
+
+        let mut decoder = PacketDecoder::new();
+        decoder.feed(&input);
+        assert!(decoder.read().is_err());
+    }
+
+    #[test]
+    fn greeting_capabilities() {
+        let mut payload = vec![10];
+        payload.extend_from_slice(b"8.0.36\0");
+        payload.extend_from_slice(&[1, 0, 0, 0]);
+        payload.extend_from_slice(b"abcdefgh\0");
+        payload.extend_from_slice(&[0xff, 0xf7, 0xff, 0x02, 0x00, 0xff, 0x09]);
+
+        let capabilities = Packet::new(0, payload).greeting_capabilities().unwrap();
+        assert!(capabilities & CLIENT_DEPRECATE_EOF != 0);
+        assert!(capabilities & CLIENT_QUERY_ATTRIBUTES != 0);
+        assert_eq!(Packet::ok().greeting_capabilities(), None);
+    }
+
+    #[test]
+    fn query_after_attributes() {
+        // No attributes
+        let mut command = Command::new(Packet::new(0, b"\x03\x00\x01SELECT 1".to_vec()));
+        command.query_attributes = true;
+        assert_eq!(command.query(), Some("SELECT 1".to_string()));
+
+        // A LONGLONG and a VARCHAR attribute, then a NULL one
+        let mut payload = vec![0x03, 3, 1, 0b100, 1];
+        payload.extend_from_slice(&[0x08, 0x00, 1, b'a', 0x0f, 0x00, 1, b'b', 0x06, 0x00, 1, b'c']);
+        payload.extend_from_slice(&[42, 0, 0, 0, 0, 0, 0, 0, 2, b'h', b'i']);
+        payload.extend_from_slice(b"SELECT 2");
+
+        let mut command = Command::new(Packet::new(0, payload.clone()));
+        command.query_attributes = true;
+        assert_eq!(command.query(), Some("SELECT 2".to_string()));
+
+        command.query_attributes = false;
+        assert!(command.query().unwrap().ends_with("SELECT 2"));
+
+        // Truncated in the values
+        let mut command = Command::new(Packet::new(0, payload[0..20].to_vec()));
+        command.query_attributes = true;
+        assert_eq!(command.query(), None);
+    }
+}
@@ -0,0 +1,390 @@
+use mio::{Token, Evented, EventSet};
+use connection::{Connection, Timer};
+use connection::tcp_connection::TcpConnection;
+use connection::{ConnectionAction, TimerAction};
+use std::io;
+use std::cmp::min;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use connection::postgres::{PostgresProxy, MessageAction};
+use connection::postgres::protocol::{Message, FrontendDecoder, BackendDecoder};
+use connection::postgres::protocol::{PROTOCOL_VERSION, SSL_REQUEST, GSSENC_REQUEST};
+use netbuf::Buf;
+
+/// Exchange ended by a `ReadyForQuery`: the startup, a simple query, a
+/// function call or an extended query batch up to its `Sync`.
+enum Cycle {
+    /// Answered by the upstream, with the messages to give before its
+    /// `ReadyForQuery` when the batch failed locally.
+    Upstream(Option<Vec<Message>>),
+    /// Answered by the proxy. Tells whether to end with `ReadyForQuery`.
+    Local(Vec<Message>, bool),
+}
+
+/// Extended query messages since the last `Sync`.
+struct Batch {
+    forwarded: bool,
+    /// Local answer; like the server, the batch is skipped up to `Sync`.
+    failed: Option<Vec<Message>>,
+}
+
+fn is_extended(kind: Option<u8>) -> bool {
+    match kind {
+        Some(b'P') | Some(b'B') | Some(b'D') | Some(b'E') | Some(b'C') | Some(b'H') => true,
+        _ => false,
+    }
+}
+
+/// Follows the PostgreSQL messages of a client and its upstream so a
+/// `PostgresProxy` can intercept them. Once the connection is encrypted, the
+/// bytes go through untouched.
+pub struct PostgresConnection<P> where P: PostgresProxy {
+    connection: TcpConnection,
+    proxy: P,
+    messages: FrontendDecoder,
+    responses: BackendDecoder,
+    forward: Buf,
+    cycles: VecDeque<Cycle>,
+    batch: Option<Batch>,
+    /// Type of the message an action was taken on.
+    delayed: Option<(Instant, Option<u8>, MessageAction)>,
+    /// Transaction status of the last `ReadyForQuery`.
+    status: u8,
+    encrypted: bool,
+    closing: bool,
+}
+
+impl<P> PostgresConnection<P> where P: PostgresProxy {
+    pub fn new(connection: TcpConnection, proxy: P) -> Self {
+        PostgresConnection {
+            connection: connection,
+            proxy: proxy,
+            messages: FrontendDecoder::new(),
+            responses: BackendDecoder::new(),
+            forward: Buf::new(),
+            cycles: VecDeque::new(),
+            batch: None,
+            delayed: None,
+            status: b'I',
+            encrypted: false,
+            closing: false,
+        }
+    }
+
+    /// Transaction status reported by the last `ReadyForQuery`: `I`, `T`
+    /// or `E`.
+    pub fn transaction_status(&self) -> u8 {
+        self.status
+    }
+
+    fn process_messages(&mut self) {
+        let len = self.connection.get_input().len();
+        if len == 0 {
+            return;
+        }
+
+        if self.encrypted {
+            self.forward.extend(&self.connection.get_input()[0..len]);
+            self.connection.get_mut_input().consume(len);
+            return;
+        }
+
+        if self.closing {
+            self.connection.get_mut_input().consume(len);
+            return;
+        }
+
+        let feed_result = self.messages.feed(&self.connection.get_input()[0..len]);
+        self.connection.get_mut_input().consume(len);
+
+        if let Err(e) = feed_result {
+            error!("{:?}: Could not parse message: {}", self.get_token(), e);
+            self.closing = true;
+            return;
+        }
+
+        self.release_messages();
+        self.flush_cycles();
+    }
+
+    /// Intercepts the decoded messages, stopping at the first delayed one
+    /// until its time has come.
+    fn release_messages(&mut self) {
+        while !self.closing && !self.encrypted {
+            let due = match self.delayed {
+                Some((deadline, _, _)) => deadline <= Instant::now(),
+                None => true,
+            };
+
+            if !due {
+                break;
+            }
+
+            if let Some((_, kind, action)) = self.delayed.take() {
+                self.apply(kind, action);
+                continue;
+            }
+
+            let message = match self.messages.read() {
+                Some(message) => message,
+                None => break,
+            };
+
+            let skipped = match self.batch {
+                Some(Batch { failed: Some(_), .. }) => message.kind != Some(b'S'),
+                _ => false,
+            };
+
+            if skipped {
+                continue;
+            }
+
+            let kind = message.kind;
+            let action = self.proxy.on_message(message);
+            self.apply(kind, action);
+        }
+    }
+
+    /// Applies the action taken on a message of the given type.
+    fn apply(&mut self, kind: Option<u8>, action: MessageAction) {
+        match action {
+            MessageAction::Forward(message) => {
+                match message.kind {
+                    None => {
+                        match message.startup_code() {
+                            Some(SSL_REQUEST) | Some(GSSENC_REQUEST) => self.responses.expect_answer(),
+                            Some(PROTOCOL_VERSION) => self.cycles.push_back(Cycle::Upstream(None)),
+                            _ => (),
+                        }
+                    },
+                    Some(b'Q') | Some(b'F') => self.cycles.push_back(Cycle::Upstream(None)),
+                    Some(b'S') => {
+                        match self.batch.take() {
+                            Some(Batch { forwarded: false, failed: Some(messages) }) => {
+                                // Nothing reached the server, it has nothing to end
+                                self.cycles.push_back(Cycle::Local(messages, true));
+                                return;
+                            },
+                            Some(Batch { failed, .. }) => self.cycles.push_back(Cycle::Upstream(failed)),
+                            None => self.cycles.push_back(Cycle::Upstream(None)),
+                        }
+                    },
+                    Some(b'X') => self.closing = true,
+                    kind if is_extended(kind) => self.batch_mut().forwarded = true,
+                    _ => (),
+                }
+
+                self.forward.extend(&message.encode());
+            },
+            MessageAction::Respond(messages) => {
+                match kind {
+                    kind if is_extended(kind) => {
+                        self.batch_mut().failed = Some(messages);
+                    },
+                    Some(b'S') => {
+                        match self.batch.take() {
+                            Some(Batch { forwarded: true, .. }) => {
+                                // The server still waits for the end of the batch
+                                self.forward.extend(&Message::new(b'S', Vec::new()).encode());
+                                self.cycles.push_back(Cycle::Upstream(Some(messages)));
+                            },
+                            _ => self.cycles.push_back(Cycle::Local(messages, true)),
+                        }
+                    },
+                    Some(b'Q') | Some(b'F') => self.cycles.push_back(Cycle::Local(messages, true)),
+                    None => {
+                        // Refusing the startup ends the connection
+                        self.cycles.push_back(Cycle::Local(messages, false));
+                        self.closing = true;
+                    },
+                    _ => self.cycles.push_back(Cycle::Local(messages, false)),
+                }
+            },
+            MessageAction::Drop => {
+                info!("{:?}: Dropping message", self.get_token());
+            },
+            MessageAction::Close => {
+                info!("{:?}: Closing connection", self.get_token());
+                self.closing = true;
+            },
+            MessageAction::Delay(delay, action) => {
+                self.delayed = Some((Instant::now() + Duration::from_millis(delay), kind, *action));
+            },
+        }
+    }
+
+    fn batch_mut(&mut self) -> &mut Batch {
+        if self.batch.is_none() {
+            self.batch = Some(Batch {
+                forwarded: false,
+                failed: None,
+            });
+        }
+
+        self.batch.as_mut().unwrap()
+    }
+
+    fn process_responses(&mut self) -> io::Result<()> {
+        while let Some(message) = try!(self.responses.read()) {
+            if message.kind.is_none() {
+                // One byte answer to an encryption request
+                let answer = message.payload[0];
+                self.connection.get_mut_output().extend(&[answer]);
+
+                if answer == b'S' || answer == b'G' {
+                    info!("{:?}: Connection encrypted, passing it through", self.get_token());
+                    self.encrypted = true;
+
+                    let rest = self.responses.take_buffer();
+                    self.connection.get_mut_output().extend(&rest);
+                    let rest = self.messages.take_buffer();
+                    self.forward.extend(&rest);
+
+                    return Ok(());
+                }
+
+                continue;
+            }
+
+            if message.kind != Some(b'Z') {
+                let message = self.proxy.on_response(message);
+                self.connection.get_mut_output().extend(&message.encode());
+                continue;
+            }
+
+            if let Some(&status) = message.payload.first() {
+                self.status = status;
+            }
+
+            let failed = match self.cycles.pop_front() {
+                Some(Cycle::Upstream(failed)) => failed,
+                _ => {
+                    warn!("{:?}: Received ReadyForQuery without a pending query", self.get_token());
+                    None
+                },
+            };
+
+            let message = match failed {
+                Some(messages) => {
+                    for message in messages {
+                        self.connection.get_mut_output().extend(&message.encode());
+                    }
+
+                    Message::ready_for_query(self.failed_status())
+                },
+                None => message,
+            };
+
+            let message = self.proxy.on_response(message);
+            self.connection.get_mut_output().extend(&message.encode());
+            self.flush_cycles();
+        }
+
+        Ok(())
+    }
+
+    /// Status given after a local error, as the server would have failed
+    /// the transaction.
+    fn failed_status(&self) -> u8 {
+        if self.status == b'T' { b'E' } else { self.status }
+    }
+
+    /// Writes the local answers whose turn has come.
+    fn flush_cycles(&mut self) {
+        loop {
+            let ready = match self.cycles.front() {
+                Some(&Cycle::Local(_, _)) => true,
+                _ => false,
+            };
+
+            if !ready {
+                break;
+            }
+
+            if let Some(Cycle::Local(messages, ends)) = self.cycles.pop_front() {
+                let failed = messages.iter().any(|message| message.kind == Some(b'E'));
+                for message in messages {
+                    self.connection.get_mut_output().extend(&message.encode());
+                }
+
+                if ends {
+                    let status = if failed { self.failed_status() } else { self.status };
+                    self.connection.get_mut_output().extend(&Message::ready_for_query(status).encode());
+                }
+            }
+        }
+    }
+}
+
+impl<P> Timer for PostgresConnection<P> where P: PostgresProxy {
+    fn handle_timer(&mut self) -> TimerAction {
+        self.release_messages();
+        self.flush_cycles();
+
+        TimerAction::Continue
+    }
+
+    fn get_frequency(&self) -> u64 {
+        10
+    }
+}
+
+impl<P> io::Read for PostgresConnection<P> where P: PostgresProxy {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.process_messages();
+
+        let read_size = min(buf.len(), self.forward.len());
+        buf[0..read_size].clone_from_slice(&self.forward[0..read_size]);
+        self.forward.consume(read_size);
+
+        Ok(read_size)
+    }
+}
+
+impl<P> io::Write for PostgresConnection<P> where P: PostgresProxy {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.encrypted {
+            self.connection.get_mut_output().extend(buf);
+            return Ok(buf.len());
+        }
+
+        self.responses.feed(buf);
+        try!(self.process_responses());
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<P> Connection for PostgresConnection<P> where P: PostgresProxy {
+    fn get_evented(&self) -> &Evented {
+        return &*self.connection.get_evented();
+    }
+
+    fn get_token(&self) -> Token {
+        return self.connection.get_token();
+    }
+
+    fn get_interest(&self) -> EventSet {
+        return self.connection.get_interest();
+    }
+
+    fn handle_read(&mut self) -> ConnectionAction {
+        let read_response = self.connection.handle_read();
+
+        read_response
+    }
+
+    fn handle_write(&mut self) -> ConnectionAction {
+        let write_response = self.connection.handle_write();
+
+        if self.closing && !self.encrypted && self.cycles.is_empty() && self.connection.get_output().is_empty() {
+            return ConnectionAction::Halt;
+        }
+
+        write_response
+    }
+}
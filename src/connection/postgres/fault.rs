@@ -0,0 +1,72 @@
+use connection::postgres::{PostgresProxy, MessageAction};
+use connection::postgres::protocol::Message;
+use connection::fault;
+
+/// Misbehaviour injected on a matching statement.
+#[derive(Clone)]
+pub enum Fault {
+    /// Answer with an `ErrorResponse` of the SQLSTATE code and message
+    /// instead of forwarding the statement.
+    Error(String, String),
+    /// Hold the statement for the given milliseconds before forwarding it.
+    Delay(u64),
+    /// Answer with the error after the given milliseconds.
+    DelayedError(u64, String, String),
+}
+
+/// Injects a fault on the statements (`Query` or `Parse`) whose SQL matches
+/// a pattern, with the given probability.
+pub type FaultRule = fault::Rule<Fault>;
+
+impl FaultRule {
+    /// Only match the statements whose SQL matches the glob-style pattern.
+    pub fn query_pattern(self, pattern: &str) -> Self {
+        self.pattern(pattern)
+    }
+}
+
+/// Injects faults per statement, so client retry and timeout logic can be
+/// exercised on specific queries. The first matching rule whose probability
+/// check passes wins.
+pub struct FaultProxy {
+    rules: Vec<FaultRule>,
+}
+
+impl FaultProxy {
+    pub fn new(rules: Vec<FaultRule>) -> Self {
+        FaultProxy {
+            rules: rules,
+        }
+    }
+}
+
+impl PostgresProxy for FaultProxy {
+    fn on_message(&mut self, message: Message) -> MessageAction {
+        let fault = match message.query() {
+            Some(query) => {
+                fault::select(&self.rules, None, &[query.trim()])
+            },
+            None => None,
+        };
+
+        match fault {
+            Some(Fault::Error(code, error)) => {
+                MessageAction::Respond(vec![Message::error_response(&code, &error)])
+            },
+            Some(Fault::Delay(delay)) => {
+                MessageAction::Delay(delay, Box::new(MessageAction::Forward(message)))
+            },
+            Some(Fault::DelayedError(delay, code, error)) => {
+                let action = MessageAction::Respond(vec![Message::error_response(&code, &error)]);
+                MessageAction::Delay(delay, Box::new(action))
+            },
+            None => {
+                MessageAction::Forward(message)
+            },
+        }
+    }
+
+    fn on_response(&mut self, response: Message) -> Message {
+        response
+    }
+}
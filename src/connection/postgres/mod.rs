@@ -0,0 +1,103 @@
+pub use self::connection::PostgresConnection;
+pub use self::fault::{FaultProxy, FaultRule, Fault};
+
+use connection::postgres::protocol::Message;
+
+pub mod protocol;
+
+mod connection;
+mod fault;
+
+/// Outcome of intercepting a message sent by a client.
+pub enum MessageAction {
+    /// Send the (possibly rewritten) message to the upstream.
+    Forward(Message),
+    /// Answer the client locally, i.e. with an `ErrorResponse`. The
+    /// connection ends the answer with `ReadyForQuery`, and skips the rest
+    /// of an extended query batch up to its `Sync`, as the server would.
+    Respond(Vec<Message>),
+    /// Discard the message without answering it.
+    Drop,
+    /// Close the client connection once the answers to the messages
+    /// received before this one have been sent.
+    Close,
+    /// Apply the action after the given milliseconds. The messages sent
+    /// after this one wait for it, so their order is kept.
+    Delay(u64, Box<MessageAction>),
+}
+
+/// Intercepts the messages of a client and the responses of the server.
+pub trait PostgresProxy {
+    fn on_message(&mut self, message: Message) -> MessageAction;
+    fn on_response(&mut self, response: Message) -> Message;
+}
+
+pub struct NoopProxy;
+
+impl PostgresProxy for NoopProxy {
+    fn on_message(&mut self, message: Message) -> MessageAction {
+        MessageAction::Forward(message)
+    }
+
+    fn on_response(&mut self, response: Message) -> Message {
+        response
+    }
+}
+
+pub struct ComposedProxy<A: PostgresProxy, B: PostgresProxy> {
+    proxy_a: A,
+    proxy_b: B,
+}
+
+impl<A: PostgresProxy, B: PostgresProxy> ComposedProxy<A, B> {
+    pub fn new(proxy_a: A, proxy_b: B) -> Self {
+        ComposedProxy {
+            proxy_a: proxy_a,
+            proxy_b: proxy_b,
+        }
+    }
+
+    /// Lets `proxy_a` intercept the messages `proxy_b` decided to forward.
+    fn chain(&mut self, action: MessageAction) -> MessageAction {
+        match action {
+            MessageAction::Forward(message) => self.proxy_a.on_message(message),
+            MessageAction::Delay(delay, action) => {
+                MessageAction::Delay(delay, Box::new(self.chain(*action)))
+            },
+            action => action,
+        }
+    }
+}
+
+impl<A: PostgresProxy, B: PostgresProxy> PostgresProxy for ComposedProxy<A, B> {
+    fn on_message(&mut self, message: Message) -> MessageAction {
+        let action = self.proxy_b.on_message(message);
+
+        self.chain(action)
+    }
+
+    fn on_response(&mut self, response: Message) -> Message {
+        self.proxy_b.on_response(self.proxy_a.on_response(response))
+    }
+}
+
+/// Logs the startup, the queries and the errors.
+pub struct LogProxy;
+
+impl PostgresProxy for LogProxy {
+    fn on_message(&mut self, message: Message) -> MessageAction {
+        if message.kind.is_none() || message.query().is_some() {
+            warn!("Received message: {}", message.to_beautify_string());
+        }
+
+        MessageAction::Forward(message)
+    }
+
+    fn on_response(&mut self, response: Message) -> Message {
+        if response.kind == Some(b'E') {
+            warn!("Response: {}", response.to_beautify_string());
+        }
+
+        response
+    }
+}
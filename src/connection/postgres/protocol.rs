@@ -0,0 +1,393 @@
+//! PostgreSQL frontend/backend protocol 3.0 messages.
+
+use std::io;
+
+/// Startup packets carry no type byte, only a length and this code.
+pub const PROTOCOL_VERSION: u32 = 196608;
+pub const SSL_REQUEST: u32 = 80877103;
+pub const GSSENC_REQUEST: u32 = 80877104;
+pub const CANCEL_REQUEST: u32 = 80877102;
+
+/// Largest startup packet, as the server accepts.
+const MAX_STARTUP: usize = 10000;
+/// Largest message, as the server allocates at most 1GB.
+const MAX_MESSAGE: usize = 1024 * 1024 * 1024;
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Message {
+    /// Type byte, none for startup packets.
+    pub kind: Option<u8>,
+    pub payload: Vec<u8>,
+}
+
+impl Message {
+    pub fn new(kind: u8, payload: Vec<u8>) -> Self {
+        Message {
+            kind: Some(kind),
+            payload: payload,
+        }
+    }
+
+    /// `ErrorResponse` with the SQLSTATE code and the message.
+    pub fn error_response(code: &str, message: &str) -> Self {
+        let mut payload = Vec::new();
+        for &(field, value) in [(b'S', "ERROR"), (b'V', "ERROR"), (b'C', code), (b'M', message)].iter() {
+            payload.push(field);
+            payload.extend_from_slice(value.as_bytes());
+            payload.push(0);
+        }
+        payload.push(0);
+
+        Message::new(b'E', payload)
+    }
+
+    /// `ReadyForQuery` with the transaction status: `I`, `T` or `E`.
+    pub fn ready_for_query(status: u8) -> Self {
+        Message::new(b'Z', vec![status])
+    }
+
+    /// Code of a startup packet, like `PROTOCOL_VERSION` or `SSL_REQUEST`.
+    pub fn startup_code(&self) -> Option<u32> {
+        match self.kind {
+            None if self.payload.len() >= 4 => Some(read_u32(&self.payload, 0)),
+            _ => None,
+        }
+    }
+
+    /// Parameters (user, database, ...) of a `StartupMessage`.
+    pub fn startup_parameters(&self) -> Vec<(String, String)> {
+        if self.startup_code() != Some(PROTOCOL_VERSION) {
+            return Vec::new();
+        }
+
+        let strings: Vec<String> = self.payload[4..].split(|&byte| byte == 0)
+            .map(|string| String::from_utf8_lossy(string).into_owned())
+            .collect();
+
+        strings.chunks(2)
+            .filter(|pair| pair.len() == 2 && !pair[0].is_empty())
+            .map(|pair| (pair[0].clone(), pair[1].clone()))
+            .collect()
+    }
+
+    /// SQL of a `Query`, or of the statement prepared by a `Parse`.
+    pub fn query(&self) -> Option<String> {
+        let position = match self.kind {
+            Some(b'Q') => 0,
+            // After the name of the statement
+            Some(b'P') => match self.payload.iter().position(|&byte| byte == 0) {
+                Some(end) => end + 1,
+                None => return None,
+            },
+            _ => return None,
+        };
+
+        read_cstring(&self.payload, position)
+    }
+
+    /// Field of an `ErrorResponse` or `NoticeResponse`, like `C` for the
+    /// SQLSTATE code or `M` for the message.
+    pub fn error_field(&self, field: u8) -> Option<String> {
+        match self.kind {
+            Some(b'E') | Some(b'N') => (),
+            _ => return None,
+        }
+
+        let mut position = 0;
+        while position < self.payload.len() && self.payload[position] != 0 {
+            let value = match read_cstring(&self.payload, position + 1) {
+                Some(value) => value,
+                None => return None,
+            };
+
+            if self.payload[position] == field {
+                return Some(value);
+            }
+
+            position += value.len() + 2;
+        }
+
+        None
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.payload.len() + 5);
+        if let Some(kind) = self.kind {
+            buf.push(kind);
+        }
+
+        let length = (self.payload.len() + 4) as u32;
+        for shift in (0..4).rev() {
+            buf.push((length >> (8 * shift)) as u8);
+        }
+        buf.extend_from_slice(&self.payload);
+
+        buf
+    }
+
+    pub fn to_beautify_string(&self) -> String {
+        match self.kind {
+            None => {
+                match self.startup_code() {
+                    Some(PROTOCOL_VERSION) => {
+                        let parameters: Vec<String> = self.startup_parameters().iter()
+                            .map(|&(ref name, ref value)| format!("{}={}", name, value))
+                            .collect();
+                        format!("StartupMessage {}", parameters.join(" "))
+                    },
+                    Some(SSL_REQUEST) => "SSLRequest".to_string(),
+                    Some(GSSENC_REQUEST) => "GSSENCRequest".to_string(),
+                    Some(CANCEL_REQUEST) => "CancelRequest".to_string(),
+                    _ => "Unknown startup packet".to_string(),
+                }
+            },
+            Some(b'E') => {
+                format!("ErrorResponse {} {}", self.error_field(b'C').unwrap_or(String::new()), self.error_field(b'M').unwrap_or(String::new()))
+            },
+            Some(kind) => {
+                match self.query() {
+                    Some(query) => format!("{} {}", kind as char, query),
+                    None => format!("{} ({} bytes)", kind as char, self.payload.len()),
+                }
+            },
+        }
+    }
+}
+
+fn read_u32(buf: &[u8], position: usize) -> u32 {
+    (buf[position] as u32) << 24 | (buf[position + 1] as u32) << 16 |
+        (buf[position + 2] as u32) << 8 | buf[position + 3] as u32
+}
+
+fn read_cstring(buf: &[u8], position: usize) -> Option<String> {
+    if position > buf.len() {
+        return None;
+    }
+
+    buf[position..].iter().position(|&byte| byte == 0).map(|end| {
+        String::from_utf8_lossy(&buf[position..position + end]).into_owned()
+    })
+}
+
+fn parse(buf: &[u8], start: usize, typed: bool) -> Result<Option<(Message, usize)>, String> {
+    let header = if typed { 5 } else { 4 };
+    if buf.len() < start + header {
+        return Ok(None);
+    }
+
+    let length = read_u32(buf, start + header - 4) as usize;
+    let max = if typed { MAX_MESSAGE } else { MAX_STARTUP };
+    if length < 4 || length > max {
+        return Err(format!("Invalid message length: {}", length));
+    }
+
+    let end = start + header - 4 + length;
+    if buf.len() < end {
+        return Ok(None);
+    }
+
+    let message = Message {
+        kind: if typed { Some(buf[start]) } else { None },
+        payload: buf[start + header..end].to_vec(),
+    };
+
+    Ok(Some((message, end)))
+}
+
+/// Incremental decoder of the messages sent by a client, startup packets
+/// first.
+pub struct FrontendDecoder {
+    buf: Vec<u8>,
+    messages: Vec<Message>,
+    started: bool,
+}
+
+impl FrontendDecoder {
+    pub fn new() -> Self {
+        FrontendDecoder {
+            buf: Vec::new(),
+            messages: Vec::new(),
+            started: false,
+        }
+    }
+
+    /// Feeds bytes to the decoder. On a protocol error the buffered bytes
+    /// are discarded.
+    pub fn feed(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.buf.extend_from_slice(buf);
+
+        let mut position = 0;
+        while position < self.buf.len() {
+            match parse(&self.buf, position, self.started) {
+                Ok(Some((message, next))) => {
+                    // Encryption and cancel requests come before a startup
+                    // message, or instead of it
+                    if message.startup_code().map(|code| code >> 16 == 3).unwrap_or(false) {
+                        self.started = true;
+                    }
+
+                    self.messages.push(message);
+                    position = next;
+                },
+                Ok(None) => break,
+                Err(message) => {
+                    self.buf.clear();
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+                },
+            }
+        }
+
+        self.buf.drain(0..position);
+
+        Ok(())
+    }
+
+    /// Reads the next decoded message.
+    pub fn read(&mut self) -> Option<Message> {
+        if self.messages.is_empty() {
+            return None;
+        }
+
+        Some(self.messages.remove(0))
+    }
+
+    /// Takes the bytes not decoded yet, once the connection is encrypted.
+    pub fn take_buffer(&mut self) -> Vec<u8> {
+        self.buf.split_off(0)
+    }
+}
+
+/// Incremental decoder of the messages sent by a server.
+pub struct BackendDecoder {
+    buf: Vec<u8>,
+    /// Encryption requests awaiting their one byte answer.
+    pending_answers: usize,
+}
+
+impl BackendDecoder {
+    pub fn new() -> Self {
+        BackendDecoder {
+            buf: Vec::new(),
+            pending_answers: 0,
+        }
+    }
+
+    /// The next response is the one byte answer to an encryption request.
+    pub fn expect_answer(&mut self) {
+        self.pending_answers += 1;
+    }
+
+    pub fn feed(&mut self, buf: &[u8]) {
+        self.buf.extend_from_slice(buf);
+    }
+
+    /// Reads the next message. The answer to an encryption request comes as
+    /// a message without type, whose payload is the answer byte.
+    pub fn read(&mut self) -> io::Result<Option<Message>> {
+        if self.buf.is_empty() {
+            return Ok(None);
+        }
+
+        if self.pending_answers > 0 {
+            self.pending_answers -= 1;
+            let answer = self.buf.remove(0);
+
+            return Ok(Some(Message {
+                kind: None,
+                payload: vec![answer],
+            }));
+        }
+
+        match parse(&self.buf, 0, true) {
+            Ok(Some((message, next))) => {
+                self.buf.drain(0..next);
+                Ok(Some(message))
+            },
+            Ok(None) => Ok(None),
+            Err(message) => {
+                self.buf.clear();
+                Err(io::Error::new(io::ErrorKind::InvalidData, message))
+            },
+        }
+    }
+
+    /// Takes the bytes not decoded yet, once the connection is encrypted.
+    pub fn take_buffer(&mut self) -> Vec<u8> {
+        self.buf.split_off(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Message, FrontendDecoder, BackendDecoder, PROTOCOL_VERSION, SSL_REQUEST};
+
+    fn startup(code: u32, payload: &[u8]) -> Vec<u8> {
+        let length = (payload.len() + 8) as u32;
+        let mut buf = Vec::new();
+        for &value in [length, code].iter() {
+            for shift in (0..4).rev() {
+                buf.push((value >> (8 * shift)) as u8);
+            }
+        }
+        buf.extend_from_slice(payload);
+
+        buf
+    }
+
+    #[test]
+    fn frontend_byte_by_byte() {
+        let mut input = startup(SSL_REQUEST, b"");
+        input.extend(startup(PROTOCOL_VERSION, b"user\0alice\0database\0shop\0\0"));
+        input.extend(Message::new(b'Q', b"SELECT 1\0".to_vec()).encode());
+        input.extend(Message::new(b'P', b"s1\0SELECT $1\0\0\0".to_vec()).encode());
+
+        let mut decoder = FrontendDecoder::new();
+        let mut messages = Vec::new();
+        for byte in input {
+            decoder.feed(&[byte]).unwrap();
+            while let Some(message) = decoder.read() {
+                messages.push(message);
+            }
+        }
+
+        assert_eq!(messages.len(), 4);
+        assert_eq!(messages[0].startup_code(), Some(SSL_REQUEST));
+        assert_eq!(messages[1].startup_parameters(), vec![
+            ("user".to_string(), "alice".to_string()),
+            ("database".to_string(), "shop".to_string()),
+        ]);
+        assert_eq!(messages[2].query(), Some("SELECT 1".to_string()));
+        assert_eq!(messages[3].query(), Some("SELECT $1".to_string()));
+    }
+
+    #[test]
+    fn frontend_lengths_are_checked() {
+        let mut decoder = FrontendDecoder::new();
+        assert!(decoder.feed(&[0, 0, 0, 2, 0, 3, 0, 0]).is_err());
+
+        let mut decoder = FrontendDecoder::new();
+        decoder.feed(&startup(PROTOCOL_VERSION, b"user\0a\0\0")).unwrap();
+        assert!(decoder.feed(&[b'Q', 0x7f, 0xff, 0xff, 0xff]).is_err());
+    }
+
+    #[test]
+    fn backend_answer_then_messages() {
+        let mut input = vec![b'N'];
+        input.extend(Message::error_response("57P01", "terminating connection").encode());
+        input.extend(Message::ready_for_query(b'I').encode());
+
+        let mut decoder = BackendDecoder::new();
+        decoder.expect_answer();
+        decoder.feed(&input[0..10]);
+
+        assert_eq!(decoder.read().unwrap(), Some(Message { kind: None, payload: vec![b'N'] }));
+        assert_eq!(decoder.read().unwrap(), None);
+
+        decoder.feed(&input[10..]);
+        let error = decoder.read().unwrap().unwrap();
+        assert_eq!(error.error_field(b'C'), Some("57P01".to_string()));
+        assert_eq!(error.error_field(b'M'), Some("terminating connection".to_string()));
+        assert_eq!(decoder.read().unwrap(), Some(Message::ready_for_query(b'I')));
+        assert_eq!(decoder.read().unwrap(), None);
+    }
+}
@@ -0,0 +1,183 @@
+use connection::redis::{RedisProxy, CommandAction, Origin, command_name};
+use connection::redis::resp::Value;
+use std::ascii::AsciiExt;
+
+/// Authenticates clients against a proxy-side credential. `AUTH` is always
+/// answered locally, so it never reaches the upstream, and every other
+/// command but `QUIT` is rejected until the client is authenticated.
+/// `HELLO` may authenticate too: its `AUTH` option is checked the same way
+/// and removed before the command is forwarded.
+pub struct AuthProxy {
+    username: String,
+    password: String,
+    authenticated: bool,
+}
+
+impl AuthProxy {
+    pub fn new(password: &str) -> Self {
+        AuthProxy::with_user("default", password)
+    }
+
+    pub fn with_user(username: &str, password: &str) -> Self {
+        AuthProxy {
+            username: username.to_string(),
+            password: password.to_string(),
+            authenticated: false,
+        }
+    }
+
+    /// Forwards the command when the client is authenticated.
+    fn on_authenticated(&self, command: Value) -> CommandAction {
+        if self.authenticated {
+            CommandAction::Forward(command)
+        } else {
+            CommandAction::Respond(Value::Error("NOAUTH Authentication required.".to_string()))
+        }
+    }
+
+    fn authenticate(&mut self, arguments: &[Value]) -> Value {
+        let credentials = match arguments.len() {
+            1 => (Some("default"), bulk_str(&arguments[0])),
+            2 => (bulk_str(&arguments[0]), bulk_str(&arguments[1])),
+            _ => return Value::Error("ERR wrong number of arguments for 'auth' command".to_string()),
+        };
+
+        match credentials {
+            (Some(username), Some(password)) if username == self.username && constant_time_eq(password, &self.password) => {
+                self.authenticated = true;
+                Value::String("OK".to_string())
+            },
+            _ => {
+                Value::Error("WRONGPASS invalid username-password pair or user is disabled.".to_string())
+            },
+        }
+    }
+}
+
+/// Position of the `AUTH` option of a `HELLO` command, which comes after the
+/// protocol version.
+fn hello_auth(input: &[Value]) -> Option<usize> {
+    let mut position = 2;
+
+    while position < input.len() {
+        match bulk_str(&input[position]).map(|option| option.to_ascii_uppercase()) {
+            Some(ref option) if option == "AUTH" => return Some(position),
+            Some(ref option) if option == "SETNAME" => position += 2,
+            _ => return None,
+        }
+    }
+
+    None
+}
+
+fn bulk_str(value: &Value) -> Option<&str> {
+    match *value {
+        Value::Bulk(ref value) => Some(value),
+        _ => None,
+    }
+}
+
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.bytes().zip(b.bytes()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+impl RedisProxy for AuthProxy {
+    fn on_command(&mut self, command: Value) -> CommandAction {
+        match command_name(&command) {
+            Some(ref name) if name == "AUTH" => {
+                if let Value::Array(ref input) = command {
+                    return CommandAction::Respond(self.authenticate(&input[1..]));
+                }
+            },
+            Some(ref name) if name == "QUIT" => {
+                return CommandAction::Forward(command);
+            },
+            Some(ref name) if name == "HELLO" => {
+                if let Value::Array(mut input) = command {
+                    match hello_auth(&input) {
+                        Some(position) if position + 2 < input.len() => {
+                            let credentials: Vec<Value> = input.drain(position..position + 3).skip(1).collect();
+                            let reply = self.authenticate(&credentials);
+                            if reply.is_error() {
+                                return CommandAction::Respond(reply);
+                            }
+                        },
+                        Some(_) => {
+                            return CommandAction::Respond(Value::Error("ERR syntax error".to_string()));
+                        },
+                        None => (),
+                    }
+
+                    return self.on_authenticated(Value::Array(input));
+                }
+            },
+            _ => (),
+        }
+
+        self.on_authenticated(command)
+    }
+
+    fn on_response(&mut self, _: Origin, response: Value) -> Value {
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use connection::redis::{RedisProxy, CommandAction};
+    use connection::redis::resp::Value;
+    use super::AuthProxy;
+
+    fn command(arguments: &[&str]) -> Value {
+        Value::Array(arguments.iter().map(|argument| Value::Bulk(argument.to_string())).collect())
+    }
+
+    fn reply(action: CommandAction) -> Option<Value> {
+        match action {
+            CommandAction::Respond(reply) => Some(reply),
+            CommandAction::Forward(_) => None,
+            _ => panic!("unexpected action"),
+        }
+    }
+
+    #[test]
+    fn commands_wait_for_authentication() {
+        let mut proxy = AuthProxy::with_user("app", "secret");
+
+        assert_eq!(reply(proxy.on_command(command(&["GET", "a"]))), Some(Value::Error("NOAUTH Authentication required.".to_string())));
+        assert_eq!(reply(proxy.on_command(command(&["QUIT"]))), None);
+        assert!(reply(proxy.on_command(command(&["AUTH", "secret"]))).unwrap().is_error());
+        assert!(reply(proxy.on_command(command(&["AUTH", "app", "wrong"]))).unwrap().is_error());
+        assert_eq!(reply(proxy.on_command(command(&["GET", "a"]))), Some(Value::Error("NOAUTH Authentication required.".to_string())));
+
+        assert_eq!(reply(proxy.on_command(command(&["AUTH", "app", "secret"]))), Some(Value::String("OK".to_string())));
+        assert_eq!(reply(proxy.on_command(command(&["GET", "a"]))), None);
+    }
+
+    #[test]
+    fn password_alone_is_for_the_default_user() {
+        let mut proxy = AuthProxy::new("secret");
+
+        assert_eq!(reply(proxy.on_command(command(&["auth", "secret"]))), Some(Value::String("OK".to_string())));
+    }
+
+    #[test]
+    fn hello_authenticates_too() {
+        let mut proxy = AuthProxy::with_user("app", "secret");
+
+        assert_eq!(reply(proxy.on_command(command(&["HELLO", "3"]))), Some(Value::Error("NOAUTH Authentication required.".to_string())));
+        assert!(reply(proxy.on_command(command(&["HELLO", "3", "AUTH", "app", "wrong"]))).unwrap().is_error());
+        assert!(reply(proxy.on_command(command(&["HELLO", "3", "AUTH", "app"]))).unwrap().is_error());
+        assert_eq!(reply(proxy.on_command(command(&["GET", "a"]))), Some(Value::Error("NOAUTH Authentication required.".to_string())));
+
+        match proxy.on_command(command(&["hello", "3", "SETNAME", "worker", "auth", "app", "secret"])) {
+            CommandAction::Forward(forwarded) => assert_eq!(forwarded, command(&["hello", "3", "SETNAME", "worker"])),
+            _ => panic!("HELLO should be forwarded without the credentials"),
+        }
+        assert_eq!(reply(proxy.on_command(command(&["GET", "a"]))), None);
+    }
+}
@@ -0,0 +1,375 @@
+use connection::redis::{RedisProxy, CommandAction, Origin, InFlight, command_name};
+use connection::redis::commands;
+use connection::redis::resp::Value;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+struct Entry {
+    response: Value,
+    expires: Instant,
+    size: usize,
+    keys: Vec<(i64, String)>,
+}
+
+/// Command forwarded on a miss, waiting for its response. Its reply is
+/// matched by order, as the command may be rewritten further down the chain.
+struct Miss {
+    command: Vec<u8>,
+    database: i64,
+    keys: Vec<(i64, String)>,
+}
+
+struct CacheState {
+    ttl: Duration,
+    max_size: usize,
+    size: usize,
+    /// Responses by database and encoded command.
+    entries: HashMap<(i64, Vec<u8>), Entry>,
+    /// Entries depending on each key.
+    index: HashMap<(i64, String), HashSet<Vec<u8>>>,
+    /// Insertion order, for eviction. May name entries already gone.
+    order: VecDeque<(i64, Vec<u8>)>,
+    /// Misses waiting for their response, by key.
+    in_flight: HashMap<(i64, String), usize>,
+    /// Keys written while a miss was in flight: its response may be stale.
+    stale: HashSet<(i64, String)>,
+}
+
+impl CacheState {
+    fn remove(&mut self, entry_key: &(i64, Vec<u8>)) {
+        if let Some(entry) = self.entries.remove(entry_key) {
+            self.size -= entry.size;
+
+            for key in entry.keys.iter() {
+                let empty = match self.index.get_mut(key) {
+                    Some(commands) => {
+                        commands.remove(&entry_key.1);
+                        commands.is_empty()
+                    },
+                    None => false,
+                };
+
+                if empty {
+                    self.index.remove(key);
+                }
+            }
+        }
+    }
+
+    fn invalidate(&mut self, key: &(i64, String)) {
+        if self.in_flight.contains_key(key) {
+            self.stale.insert(key.clone());
+        }
+
+        let commands = match self.index.remove(key) {
+            Some(commands) => commands,
+            None => return,
+        };
+
+        for command in commands {
+            self.remove(&(key.0, command));
+        }
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.index.clear();
+        self.order.clear();
+        self.size = 0;
+
+        let in_flight: Vec<(i64, String)> = self.in_flight.keys().cloned().collect();
+        self.stale.extend(in_flight);
+    }
+
+    fn insert(&mut self, entry_key: (i64, Vec<u8>), response: Value, keys: Vec<(i64, String)>) {
+        let size = entry_key.1.len() + response.encode().len();
+        if size > self.max_size {
+            return;
+        }
+
+        self.remove(&entry_key);
+
+        while self.size + size > self.max_size {
+            match self.order.pop_front() {
+                Some(oldest) => self.remove(&oldest),
+                None => break,
+            }
+        }
+
+        for key in keys.iter() {
+            self.index.entry(key.clone()).or_insert_with(HashSet::new).insert(entry_key.1.clone());
+        }
+
+        self.entries.insert(entry_key.clone(), Entry {
+            response: response,
+            expires: Instant::now() + self.ttl,
+            size: size,
+            keys: keys,
+        });
+        self.order.push_back(entry_key);
+        self.size += size;
+
+        // Forget the order of entries invalidated since
+        if self.order.len() > 2 * self.entries.len() + 64 {
+            let entries = &self.entries;
+            self.order.retain(|entry_key| entries.contains_key(entry_key));
+        }
+    }
+
+    fn get(&mut self, entry_key: &(i64, Vec<u8>)) -> Option<Value> {
+        let expired = match self.entries.get(entry_key) {
+            Some(entry) => entry.expires <= Instant::now(),
+            None => return None,
+        };
+
+        if expired {
+            self.remove(entry_key);
+            return None;
+        }
+
+        self.entries.get(entry_key).map(|entry| entry.response.clone())
+    }
+
+    fn start_miss(&mut self, keys: &[(i64, String)]) {
+        for key in keys {
+            *self.in_flight.entry(key.clone()).or_insert(0) += 1;
+        }
+    }
+
+    /// Ends a miss, telling whether its response can be cached.
+    fn end_miss(&mut self, keys: &[(i64, String)]) -> bool {
+        let mut fresh = true;
+
+        for key in keys {
+            if self.stale.contains(key) {
+                fresh = false;
+            }
+
+            let done = match self.in_flight.get_mut(key) {
+                Some(count) => {
+                    *count -= 1;
+                    *count == 0
+                },
+                None => false,
+            };
+
+            if done {
+                self.in_flight.remove(key);
+                self.stale.remove(key);
+            }
+        }
+
+        fresh
+    }
+}
+
+/// Responses cached by `CacheProxy`. Clones share the same entries, so one
+/// cache serves every client connection.
+#[derive(Clone)]
+pub struct ResponseCache {
+    state: Rc<RefCell<CacheState>>,
+}
+
+impl ResponseCache {
+    /// Creates a cache keeping responses for `ttl` milliseconds and up to
+    /// `max_size` bytes, counting the encoded commands and responses.
+    pub fn new(ttl: u64, max_size: usize) -> Self {
+        ResponseCache {
+            state: Rc::new(RefCell::new(CacheState {
+                ttl: Duration::from_millis(ttl),
+                max_size: max_size,
+                size: 0,
+                entries: HashMap::new(),
+                index: HashMap::new(),
+                order: VecDeque::new(),
+                in_flight: HashMap::new(),
+                stale: HashSet::new(),
+            })),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.borrow().entries.len()
+    }
+
+    /// Size of the cached commands and responses, in bytes.
+    pub fn size(&self) -> usize {
+        self.state.borrow().size
+    }
+
+    pub fn clear(&self) {
+        self.state.borrow_mut().clear();
+    }
+}
+
+/// Read-through cache: answers the configured read commands from the cache
+/// when possible, and caches their responses otherwise. Commands that may
+/// write invalidate the entries of their keys, or the whole cache when their
+/// keys are unknown (`FLUSHALL`, scripts...). Transactions are not cached,
+/// nor are the replies to clients that switched to RESP3, which differ from
+/// those of RESP2 clients sharing the cache.
+pub struct CacheProxy {
+    cache: ResponseCache,
+    commands: HashSet<String>,
+    database: i64,
+    transaction: bool,
+    resp3: bool,
+    /// One entry per forwarded command, set for misses.
+    misses: InFlight<Option<Miss>>,
+}
+
+impl CacheProxy {
+    /// Caches the responses of `commands`, like `GET` or `HGETALL`.
+    pub fn new(cache: ResponseCache, commands: &[&str]) -> Self {
+        CacheProxy {
+            cache: cache,
+            commands: commands.iter().map(|name| name.to_uppercase()).collect(),
+            database: 0,
+            transaction: false,
+            resp3: false,
+            misses: InFlight::new(),
+        }
+    }
+
+    fn keys_of(&self, command: &Value) -> Vec<(i64, String)> {
+        commands::keys(command).into_iter().map(|key| (self.database, key.to_string())).collect()
+    }
+
+    fn is_cacheable(&self, name: &str, command: &Value) -> bool {
+        !self.transaction && !self.resp3 && self.commands.contains(name) && !commands::keys(command).is_empty()
+    }
+}
+
+impl RedisProxy for CacheProxy {
+    fn on_command(&mut self, command: Value) -> CommandAction {
+        let name = match command_name(&command) {
+            Some(name) => name,
+            None => {
+                self.misses.push(None);
+                return CommandAction::Forward(command);
+            },
+        };
+
+        match &*name {
+            "MULTI" => self.transaction = true,
+            "EXEC" | "DISCARD" => self.transaction = false,
+            "HELLO" => {
+                if let Value::Array(ref input) = command {
+                    if let Some(&Value::Bulk(ref protocol)) = input.get(1) {
+                        self.resp3 = protocol == "3";
+                    }
+                }
+            },
+            "SELECT" => {
+                if let Value::Array(ref input) = command {
+                    if let Some(&Value::Bulk(ref database)) = input.get(1) {
+                        self.database = database.parse().unwrap_or(self.database);
+                    }
+                }
+            },
+            _ => (),
+        }
+
+        if commands::is_write(&command) {
+            let keys = self.keys_of(&command);
+            let mut state = self.cache.state.borrow_mut();
+
+            if keys.is_empty() {
+                state.clear();
+            } else {
+                for key in keys.iter() {
+                    state.invalidate(key);
+                }
+            }
+
+            self.misses.push(None);
+            return CommandAction::Forward(command);
+        }
+
+        if !self.is_cacheable(&name, &command) {
+            self.misses.push(None);
+            return CommandAction::Forward(command);
+        }
+
+        let encoded = command.encode();
+        let cached = self.cache.state.borrow_mut().get(&(self.database, encoded.clone()));
+
+        match cached {
+            Some(response) => {
+                debug!("Cache hit for {}", name);
+                CommandAction::Respond(response)
+            },
+            None => {
+                let keys = self.keys_of(&command);
+                self.cache.state.borrow_mut().start_miss(&keys);
+                self.misses.push(Some(Miss {
+                    command: encoded,
+                    database: self.database,
+                    keys: keys,
+                }));
+
+                CommandAction::Forward(command)
+            },
+        }
+    }
+
+    fn on_response(&mut self, origin: Origin, response: Value) -> Value {
+        let miss = match self.misses.answer(origin) {
+            Some(Some(miss)) => miss,
+            _ => return response,
+        };
+
+        let mut state = self.cache.state.borrow_mut();
+        if state.end_miss(&miss.keys) && !response.is_error() {
+            state.insert((miss.database, miss.command), response.clone(), miss.keys);
+        }
+
+        response
+    }
+
+    fn on_unanswered(&mut self) {
+        if let Some(Some(miss)) = self.misses.cancel_last() {
+            self.cache.state.borrow_mut().end_miss(&miss.keys);
+        }
+    }
+}
+
+impl Drop for CacheProxy {
+    fn drop(&mut self) {
+        // Misses never answered must not hold their keys in flight
+        let mut state = self.cache.state.borrow_mut();
+        while let Some(miss) = self.misses.cancel_last() {
+            if let Some(miss) = miss {
+                state.end_miss(&miss.keys);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use connection::redis::{ComposedProxy, PrefixProxy, MockKeyspace};
+    use connection::redis::resp::Value;
+    use connection::redis::testing::{Harness, ok};
+    use super::{CacheProxy, ResponseCache};
+
+    #[test]
+    fn caches_commands_rewritten_down_the_chain() {
+        let cache = ResponseCache::new(60000, 1024 * 1024);
+        let keyspace = MockKeyspace::new();
+        let proxy = ComposedProxy::new(PrefixProxy, CacheProxy::new(cache.clone(), &["GET"]));
+        let mut harness = Harness::new(proxy, keyspace.clone());
+
+        let replies = harness.send(&[&["SET", "a", "1"], &["GET", "a"]]);
+        assert_eq!(replies, vec![ok(), Value::Bulk("1".to_string())]);
+        assert_eq!(cache.len(), 1);
+
+        // Answered from the cache, without reaching the upstream
+        keyspace.set(b"prefix:a", b"2");
+        assert_eq!(harness.send(&[&["GET", "a"]]), vec![Value::Bulk("1".to_string())]);
+
+        assert_eq!(harness.send(&[&["SET", "a", "3"], &["GET", "a"]]), vec![ok(), Value::Bulk("3".to_string())]);
+    }
+}
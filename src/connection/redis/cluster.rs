@@ -0,0 +1,695 @@
+use mio::{Token, Evented, EventSet, PollOpt, Selector};
+use mio::tcp::TcpStream;
+use connection::Connection;
+use connection::ConnectionAction;
+use connection::tcp_connection::TcpConnection;
+use connection::redis::command_name;
+use connection::redis::commands::{self, lookup_command};
+use connection::redis::shard::hash_tag;
+use connection::redis::upstream::{RedisUpstream, Handshake};
+use std::io;
+use std::cmp::min;
+use std::cell::Cell;
+use std::collections::{HashMap, VecDeque};
+use std::net::{SocketAddr, ToSocketAddrs};
+use connection::redis::resp::{Decoder, Value};
+use netbuf::Buf;
+
+pub const SLOTS: usize = 16384;
+const MAX_REDIRECTIONS: u32 = 5;
+
+/// CRC16-CCITT (XMODEM), as used by Redis Cluster to compute key slots.
+pub fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+
+    for byte in bytes {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+
+    crc
+}
+
+pub fn key_slot(key: &[u8]) -> usize {
+    crc16(hash_tag(key)) as usize % SLOTS
+}
+
+/// What a node reply answers to.
+enum Route {
+    /// Command sent on behalf of the client with the given sequence.
+    Command(u64, Value, u32),
+    /// `ASKING` preceding a redirected command, its reply is discarded.
+    Asking,
+    /// `CLUSTER SLOTS` sent to refresh the slot map.
+    Slots,
+}
+
+struct Node {
+    address: String,
+    connection: Box<Connection>,
+    responses: Decoder,
+    routes: VecDeque<Route>,
+    registered: Cell<bool>,
+    /// The connection was lost or sent unparsable replies, the node is no
+    /// longer used.
+    lost: bool,
+}
+
+/// Upstream fronting a Redis Cluster for clients unaware of it. Commands are
+/// routed to the node serving the slot of their first key, following the
+/// `MOVED` and `ASK` redirections and refreshing the slot map with
+/// `CLUSTER SLOTS` when it changes. Nodes are connected as they are
+/// discovered and registered on the event loop with the token of this
+/// connection on the next reregistration. A node whose connection is lost
+/// fails its pending commands and is reconnected once the refreshed slot map
+/// or a redirection names it again.
+///
+/// Transactions, Pub/Sub and `SELECT` are not supported.
+pub struct ClusterUpstream {
+    token: Token,
+    connector: Box<Fn(&str, Token) -> io::Result<Box<Connection>>>,
+    nodes: Vec<Node>,
+    addresses: HashMap<String, usize>,
+    slots: Vec<Option<usize>>,
+    refreshing: bool,
+    commands: Decoder,
+    pending: VecDeque<Option<Value>>,
+    first_sequence: u64,
+    replies: Buf,
+}
+
+impl ClusterUpstream {
+    /// Connects to the seed nodes and asks the first one for the slot map.
+    pub fn new(token: Token, seeds: &[String], handshake: Handshake) -> io::Result<Self> {
+        ClusterUpstream::with_connector(token, seeds, Box::new(move |address: &str, token: Token| connect(address, token, &handshake)))
+    }
+
+    /// Opens the node connections with the connector, given the address of
+    /// the node and the token of this connection, instead of over TCP.
+    pub fn with_connector(token: Token, seeds: &[String], connector: Box<Fn(&str, Token) -> io::Result<Box<Connection>>>) -> io::Result<Self> {
+        let mut cluster = ClusterUpstream {
+            token: token,
+            connector: connector,
+            nodes: Vec::new(),
+            addresses: HashMap::new(),
+            slots: vec![None; SLOTS],
+            refreshing: false,
+            commands: Decoder::new(),
+            pending: VecDeque::new(),
+            first_sequence: 0,
+            replies: Buf::new(),
+        };
+
+        for seed in seeds {
+            try!(cluster.node(seed));
+        }
+
+        if cluster.nodes.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "No seed nodes given"));
+        }
+
+        cluster.refresh_slots(0);
+
+        Ok(cluster)
+    }
+
+    /// Position of the node with the given address, connecting to it when unknown.
+    fn node(&mut self, address: &str) -> io::Result<usize> {
+        if let Some(node) = self.addresses.get(address) {
+            return Ok(*node);
+        }
+
+        info!("{:?}: Connecting to cluster node {}", self.token, address);
+        let connection = try!((self.connector)(address, self.token));
+
+        self.nodes.push(Node {
+            address: address.to_string(),
+            connection: connection,
+            responses: Decoder::new(),
+            routes: VecDeque::new(),
+            registered: Cell::new(false),
+            lost: false,
+        });
+        self.addresses.insert(address.to_string(), self.nodes.len() - 1);
+
+        Ok(self.nodes.len() - 1)
+    }
+
+    /// First node still in use, for the commands whose slot has no node.
+    fn live_node(&self) -> Option<usize> {
+        self.nodes.iter().position(|node| !node.lost)
+    }
+
+    fn send(&mut self, node: usize, command: &Value, route: Route) {
+        self.nodes[node].routes.push_back(route);
+
+        if self.nodes[node].lost {
+            return self.drop_node(node);
+        }
+
+        if let Err(e) = self.nodes[node].connection.write(&command.encode()) {
+            error!("{:?}: Could not write to node {}: {}", self.token, self.nodes[node].address, e);
+            self.drop_node(node);
+        }
+    }
+
+    /// Stops using a node whose replies can not be matched with the commands
+    /// anymore, failing those still waiting for one, and asks another node
+    /// for the slot map.
+    fn drop_node(&mut self, node: usize) {
+        self.nodes[node].lost = true;
+        self.nodes[node].responses = Decoder::new();
+        self.addresses.remove(&self.nodes[node].address);
+
+        for slot in self.slots.iter_mut() {
+            if *slot == Some(node) {
+                *slot = None;
+            }
+        }
+
+        let routes: Vec<Route> = self.nodes[node].routes.drain(..).collect();
+        for route in routes {
+            match route {
+                Route::Command(sequence, _, _) => {
+                    let error = Value::Error(format!("ERR lost the connection with cluster node {}", self.nodes[node].address));
+                    self.fill(sequence, error);
+                },
+                Route::Slots => self.refreshing = false,
+                Route::Asking => (),
+            }
+        }
+
+        if let Some(live) = self.live_node() {
+            self.refresh_slots(live);
+        }
+    }
+
+    fn refresh_slots(&mut self, node: usize) {
+        if self.refreshing {
+            return;
+        }
+
+        self.refreshing = true;
+        let command = Value::Array(vec![
+            Value::Bulk("CLUSTER".to_string()),
+            Value::Bulk("SLOTS".to_string()),
+        ]);
+
+        self.send(node, &command, Route::Slots);
+    }
+
+    fn update_slots(&mut self, node: usize, response: Value) {
+        self.refreshing = false;
+
+        let ranges = match response {
+            Value::Array(ranges) => ranges,
+            other => {
+                error!("{:?}: Could not refresh the slot map: {:?}", self.token, other);
+                return;
+            },
+        };
+
+        for range in ranges {
+            let range = match range {
+                Value::Array(range) => range,
+                _ => continue,
+            };
+
+            let (start, end, master) = match (range.get(0), range.get(1), range.get(2)) {
+                (Some(&Value::Integer(start)), Some(&Value::Integer(end)), Some(&Value::Array(ref master))) => (start as usize, end as usize, master.clone()),
+                _ => continue,
+            };
+
+            let host = match master.get(0) {
+                Some(&Value::Bulk(ref host)) if !host.is_empty() => host.clone(),
+                _ => {
+                    // Nodes announce an empty host for the one answering
+                    let address = self.nodes[node].address.clone();
+                    address.rsplitn(2, ':').last().unwrap_or("").to_string()
+                },
+            };
+
+            let port = match master.get(1) {
+                Some(&Value::Integer(port)) => port,
+                _ => continue,
+            };
+
+            match self.node(&format!("{}:{}", host, port)) {
+                Ok(owner) => {
+                    for slot in start..min(end + 1, SLOTS) {
+                        self.slots[slot] = Some(owner);
+                    }
+                },
+                Err(e) => error!("{:?}: Could not connect to {}:{}: {}", self.token, host, port, e),
+            }
+        }
+    }
+
+    fn route(&mut self, command: Value) {
+        let name = command_name(&command).unwrap_or(String::new());
+        let pubsub = lookup_command(&command).map(|info| info.has_flag(commands::PUBSUB)).unwrap_or(false);
+
+        let sequence = self.first_sequence + self.pending.len() as u64;
+        self.pending.push_back(None);
+
+        match &*name {
+            "MULTI" | "EXEC" | "DISCARD" | "WATCH" | "UNWATCH" | "SELECT" => {
+                return self.fill(sequence, Value::Error(format!("ERR {} is not supported by the cluster proxy", name)));
+            },
+            _ if pubsub => {
+                return self.fill(sequence, Value::Error(format!("ERR {} is not supported by the cluster proxy", name)));
+            },
+            _ => (),
+        }
+
+        let owner = commands::key_bytes(&command).first().and_then(|key| self.slots[key_slot(key)]);
+        let node = match owner.or_else(|| self.live_node()) {
+            Some(node) => node,
+            None => return self.fill(sequence, Value::Error("ERR no cluster node is reachable".to_string())),
+        };
+
+        self.send(node, &command, Route::Command(sequence, command.clone(), 0));
+    }
+
+    /// Follows `MOVED` and `ASK` redirections. Returns the response back when
+    /// it is not a redirection.
+    fn redirect(&mut self, node: usize, sequence: u64, command: Value, redirections: u32, response: Value) -> Option<Value> {
+        let (kind, slot, address) = match response {
+            Value::Error(ref message) if message.starts_with("MOVED ") || message.starts_with("ASK ") => {
+                let parts: Vec<&str> = message.split(' ').collect();
+                if parts.len() != 3 {
+                    return Some(response.clone());
+                }
+
+                (parts[0].to_string(), parts[1].parse::<usize>().unwrap_or(SLOTS), parts[2].to_string())
+            },
+            _ => return Some(response),
+        };
+
+        if redirections >= MAX_REDIRECTIONS {
+            warn!("{:?}: Too many redirections for command {:?}", self.token, command);
+            return Some(response);
+        }
+
+        let target = match self.node(&address) {
+            Ok(target) => target,
+            Err(e) => {
+                error!("{:?}: Could not connect to {}: {}", self.token, address, e);
+                return Some(response);
+            },
+        };
+
+        if kind == "MOVED" {
+            if slot < SLOTS {
+                self.slots[slot] = Some(target);
+            }
+            self.refresh_slots(node);
+        } else {
+            let asking = Value::Array(vec![Value::Bulk("ASKING".to_string())]);
+            self.send(target, &asking, Route::Asking);
+        }
+
+        self.send(target, &command, Route::Command(sequence, command.clone(), redirections + 1));
+
+        None
+    }
+
+    fn fill(&mut self, sequence: u64, response: Value) {
+        let index = (sequence - self.first_sequence) as usize;
+        self.pending[index] = Some(response);
+
+        while self.pending.front().map(|reply| reply.is_some()).unwrap_or(false) {
+            if let Some(Some(reply)) = self.pending.pop_front() {
+                self.first_sequence += 1;
+                self.replies.extend(&reply.encode());
+            }
+        }
+    }
+
+    fn read_nodes(&mut self) {
+        let mut buf = [0u8; 1024];
+
+        for node in 0..self.nodes.len() {
+            if self.nodes[node].lost {
+                continue;
+            }
+
+            let mut corrupted = false;
+            loop {
+                let amount = match self.nodes[node].connection.read(&mut buf) {
+                    Ok(amount) => amount,
+                    Err(e) => {
+                        error!("{:?}: Could not read from node {}: {}", self.token, self.nodes[node].address, e);
+                        break;
+                    },
+                };
+
+                if amount == 0 {
+                    break;
+                }
+
+                if let Err(e) = self.nodes[node].responses.feed(&buf[0..amount]) {
+                    error!("{:?}: Could not parse the response of node {}: {}", self.token, self.nodes[node].address, e);
+                    corrupted = true;
+                    break;
+                }
+            }
+
+            while let Some(response) = self.nodes[node].responses.read() {
+                match self.nodes[node].routes.pop_front() {
+                    Some(Route::Command(sequence, command, redirections)) => {
+                        if let Some(response) = self.redirect(node, sequence, command, redirections, response) {
+                            self.fill(sequence, response);
+                        }
+                    },
+                    Some(Route::Slots) => {
+                        self.update_slots(node, response);
+                    },
+                    Some(Route::Asking) => (),
+                    None => {
+                        warn!("{:?}: Node {} sent a response without a pending command", self.token, self.nodes[node].address);
+                    },
+                }
+            }
+
+            if corrupted {
+                self.drop_node(node);
+            }
+        }
+    }
+}
+
+fn connect(address: &str, token: Token, handshake: &Handshake) -> io::Result<Box<Connection>> {
+    let addr: SocketAddr = match try!(address.to_socket_addrs()).next() {
+        Some(addr) => addr,
+        None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "Could not resolve the node address")),
+    };
+
+    let stream = try!(TcpStream::connect(&addr));
+
+    Ok(Box::new(RedisUpstream::new(TcpConnection::new(stream, token), handshake.clone())))
+}
+
+impl Evented for ClusterUpstream {
+    fn register(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        for node in self.nodes.iter().filter(|node| !node.lost) {
+            try!(node.connection.get_evented().register(selector, token, interest, opts));
+            node.registered.set(true);
+        }
+
+        Ok(())
+    }
+
+    /// Registers the nodes connected since, and deregisters the lost ones.
+    fn reregister(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        for node in self.nodes.iter() {
+            match (node.lost, node.registered.get()) {
+                (true, true) => try!(node.connection.get_evented().deregister(selector)),
+                (false, true) => try!(node.connection.get_evented().reregister(selector, token, interest, opts)),
+                (false, false) => try!(node.connection.get_evented().register(selector, token, interest, opts)),
+                (true, false) => (),
+            }
+            node.registered.set(!node.lost);
+        }
+
+        Ok(())
+    }
+
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        for node in self.nodes.iter().filter(|node| node.registered.get()) {
+            try!(node.connection.get_evented().deregister(selector));
+            node.registered.set(false);
+        }
+
+        Ok(())
+    }
+}
+
+impl io::Read for ClusterUpstream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read_size = min(buf.len(), self.replies.len());
+        buf[0..read_size].clone_from_slice(&self.replies[0..read_size]);
+        self.replies.consume(read_size);
+
+        Ok(read_size)
+    }
+}
+
+impl io::Write for ClusterUpstream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        try!(self.commands.feed(buf));
+
+        while let Some(command) = self.commands.read() {
+            self.route(command);
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Connection for ClusterUpstream {
+    fn get_evented(&self) -> &Evented {
+        return self;
+    }
+
+    fn get_token(&self) -> Token {
+        return self.token;
+    }
+
+    fn get_interest(&self) -> EventSet {
+        self.nodes.iter().filter(|node| !node.lost).fold(EventSet::none(), |interest, node| interest | node.connection.get_interest())
+    }
+
+    fn handle_read(&mut self) -> ConnectionAction {
+        let mut halted = Vec::new();
+        for (position, node) in self.nodes.iter_mut().enumerate().filter(|&(_, ref node)| !node.lost) {
+            if let ConnectionAction::Halt = node.connection.handle_read() {
+                error!("{:?}: Lost connection with node {}", self.token, node.address);
+                halted.push(position);
+            }
+        }
+
+        let nodes = self.nodes.len();
+        // Replies received before the connection was lost are still given
+        self.read_nodes();
+
+        for node in halted {
+            self.drop_node(node);
+        }
+
+        // New nodes or redirected commands need a reregistration to be
+        // written, and lost nodes one to be deregistered
+        if self.replies.len() > 0 || self.nodes.len() > nodes || self.nodes.iter().any(|node| !node.routes.is_empty() || node.lost && node.registered.get()) {
+            ConnectionAction::Forward
+        } else {
+            ConnectionAction::Noop
+        }
+    }
+
+    fn handle_write(&mut self) -> ConnectionAction {
+        for node in self.nodes.iter_mut().filter(|node| !node.lost) {
+            node.connection.handle_write();
+        }
+
+        ConnectionAction::Noop
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mio::Token;
+    use connection::{Connection, ConnectionAction};
+    use connection::redis::{MockRedis, MockKeyspace};
+    use connection::redis::resp::{Decoder, Value};
+    use connection::redis::testing::FaultyRedis;
+    use std::io::{self, Read, Write};
+    use super::{ClusterUpstream, crc16, key_slot};
+
+    /// `CLUSTER SLOTS` reply giving each range of slots to a node.
+    fn slots(ranges: &[(i64, i64, &str, i64)]) -> Value {
+        Value::Array(ranges.iter().map(|&(start, end, host, port)| {
+            Value::Array(vec![
+                Value::Integer(start),
+                Value::Integer(end),
+                Value::Array(vec![Value::Bulk(host.to_string()), Value::Integer(port)]),
+            ])
+        }).collect())
+    }
+
+    /// Cluster whose nodes are mocks, `a:1` being the seed, with the
+    /// connection of `b:2` given by `second`.
+    fn cluster<F>(seed: &MockKeyspace, second: F) -> ClusterUpstream where F: Fn(Token) -> Box<Connection> + 'static {
+        let seed = seed.clone();
+        let mut cluster = ClusterUpstream::with_connector(Token(1), &["a:1".to_string()], Box::new(move |address: &str, token: Token| {
+            match address {
+                "a:1" => Ok(Box::new(try!(MockRedis::new(token, seed.clone()))) as Box<Connection>),
+                "b:2" => Ok(second(token)),
+                _ => Err(io::Error::new(io::ErrorKind::ConnectionRefused, "unknown node")),
+            }
+        })).unwrap();
+        cluster.handle_read();
+
+        cluster
+    }
+
+    fn mock(keyspace: &MockKeyspace) -> Box<Fn(Token) -> Box<Connection>> {
+        let keyspace = keyspace.clone();
+        Box::new(move |token| Box::new(MockRedis::new(token, keyspace.clone()).unwrap()) as Box<Connection>)
+    }
+
+    fn send(cluster: &mut ClusterUpstream, commands: &[&[&str]]) -> Vec<Value> {
+        for command in commands {
+            let command = Value::Array(command.iter().map(|argument| Value::Bulk(argument.to_string())).collect());
+            cluster.write_all(&command.encode()).unwrap();
+        }
+
+        // Redirected commands are only answered on a later read
+        loop {
+            cluster.handle_read();
+            if cluster.nodes.iter().all(|node| node.routes.is_empty()) {
+                break;
+            }
+        }
+
+        let mut output = Vec::new();
+        cluster.read_to_end(&mut output).unwrap();
+
+        let mut decoder = Decoder::new();
+        decoder.feed(&output).unwrap();
+
+        let mut replies = Vec::new();
+        while let Some(reply) = decoder.read() {
+            replies.push(reply);
+        }
+
+        replies
+    }
+
+    /// Commands the node received, as text.
+    fn names(keyspace: &MockKeyspace) -> Vec<String> {
+        keyspace.received().iter().map(|command| {
+            match *command {
+                Value::Array(ref input) => input.iter().map(|argument| {
+                    match *argument {
+                        Value::Bulk(ref argument) => argument.clone(),
+                        ref argument => argument.to_beautify_string(),
+                    }
+                }).collect::<Vec<String>>().join(" "),
+                _ => String::new(),
+            }
+        }).collect()
+    }
+
+    #[test]
+    fn crc16_is_xmodem() {
+        assert_eq!(crc16(b"123456789"), 0x31c3);
+    }
+
+    #[test]
+    fn keys_sharing_a_hash_tag_share_a_slot() {
+        assert_eq!(key_slot(b"foo"), 12182);
+        assert_eq!(key_slot(b"{user1000}.following"), key_slot(b"{user1000}.followers"));
+        assert_eq!(key_slot(b"{user1000}.following"), key_slot(b"user1000"));
+        assert_eq!(key_slot(b"foo{}{bar}"), crc16(b"foo{}{bar}") as usize % 16384);
+        assert_eq!(key_slot(b"\xff{user1000}"), key_slot(b"user1000"));
+    }
+
+    #[test]
+    fn commands_go_to_the_node_of_their_slot() {
+        let (first, second) = (MockKeyspace::new(), MockKeyspace::new());
+        first.reply_to("CLUSTER", slots(&[(0, 8191, "a", 1), (8192, 16383, "b", 2)]));
+        let mut cluster = cluster(&first, mock(&second));
+
+        // foo is in slot 12182, bar in 5061
+        assert_eq!(send(&mut cluster, &[&["SET", "foo", "1"], &["SET", "bar", "2"], &["GET", "foo"]]), vec![
+            Value::String("OK".to_string()),
+            Value::String("OK".to_string()),
+            Value::Bulk("1".to_string()),
+        ]);
+        assert_eq!(names(&first), vec!["CLUSTER SLOTS", "SET bar 2"]);
+        assert_eq!(names(&second), vec!["SET foo 1", "GET foo"]);
+    }
+
+    #[test]
+    fn follows_moved_and_refreshes_the_slots() {
+        let (first, second) = (MockKeyspace::new(), MockKeyspace::new());
+        first.reply_to("CLUSTER", slots(&[(0, 16383, "a", 1)]));
+        first.reply_to("GET", Value::Error("MOVED 12182 b:2".to_string()));
+        first.reply_to("CLUSTER", slots(&[(0, 8191, "a", 1), (8192, 16383, "b", 2)]));
+        second.set(b"foo", b"1");
+        let mut cluster = cluster(&first, mock(&second));
+
+        assert_eq!(send(&mut cluster, &[&["GET", "foo"]]), vec![Value::Bulk("1".to_string())]);
+        assert_eq!(send(&mut cluster, &[&["GET", "foo"]]), vec![Value::Bulk("1".to_string())]);
+        assert_eq!(names(&first), vec!["CLUSTER SLOTS", "GET foo", "CLUSTER SLOTS"]);
+        assert_eq!(names(&second), vec!["GET foo", "GET foo"]);
+    }
+
+    #[test]
+    fn follows_ask_once() {
+        let (first, second) = (MockKeyspace::new(), MockKeyspace::new());
+        first.reply_to("CLUSTER", slots(&[(0, 16383, "a", 1)]));
+        first.reply_to("GET", Value::Error("ASK 12182 b:2".to_string()));
+        second.reply_to("ASKING", Value::String("OK".to_string()));
+        second.set(b"foo", b"1");
+        first.set(b"foo", b"2");
+        let mut cluster = cluster(&first, mock(&second));
+
+        assert_eq!(send(&mut cluster, &[&["GET", "foo"]]), vec![Value::Bulk("1".to_string())]);
+        assert_eq!(send(&mut cluster, &[&["GET", "foo"]]), vec![Value::Bulk("2".to_string())]);
+        assert_eq!(names(&second), vec!["ASKING", "GET foo"]);
+    }
+
+    #[test]
+    fn commands_of_a_lost_node_fail() {
+        let first = MockKeyspace::new();
+        first.reply_to("CLUSTER", slots(&[(0, 8191, "a", 1), (8192, 16383, "b", 2)]));
+        first.reply_to("CLUSTER", slots(&[(0, 16383, "a", 1)]));
+        let mut cluster = cluster(&first, Box::new(|token| Box::new(FaultyRedis::broken(token)) as Box<Connection>));
+
+        assert_eq!(send(&mut cluster, &[&["SET", "foo", "1"], &["SET", "foo", "2"]]), vec![
+            Value::Error("ERR lost the connection with cluster node b:2".to_string()),
+            Value::String("OK".to_string()),
+        ]);
+        assert_eq!(first.get(b"foo"), Some(b"2".to_vec()));
+    }
+
+    #[test]
+    fn stops_using_a_node_sending_garbage() {
+        let first = MockKeyspace::new();
+        first.reply_to("CLUSTER", slots(&[(0, 8191, "a", 1), (8192, 16383, "b", 2)]));
+        first.reply_to("CLUSTER", slots(&[(0, 16383, "a", 1)]));
+        let mut cluster = cluster(&first, Box::new(|token| Box::new(FaultyRedis::garbage(token, b"?garbage\r\n")) as Box<Connection>));
+
+        assert_eq!(send(&mut cluster, &[&["SET", "foo", "1"]]), vec![
+            Value::Error("ERR lost the connection with cluster node b:2".to_string()),
+        ]);
+        assert!(cluster.nodes[1].lost);
+        assert_eq!(send(&mut cluster, &[&["SET", "foo", "2"]]), vec![Value::String("OK".to_string())]);
+    }
+
+    #[test]
+    fn halted_nodes_are_dropped() {
+        let first = MockKeyspace::new();
+        first.reply_to("CLUSTER", slots(&[(0, 16383, "a", 1)]));
+        let mut cluster = cluster(&first, Box::new(|token| Box::new(FaultyRedis::broken(token)) as Box<Connection>));
+        cluster.node("b:2").unwrap();
+
+        match cluster.handle_read() {
+            ConnectionAction::Forward => (),
+            _ => panic!("the lost node should be deregistered"),
+        }
+        assert!(cluster.nodes[1].lost);
+        assert!(cluster.addresses.get("b:2").is_none());
+    }
+}
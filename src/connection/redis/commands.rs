@@ -0,0 +1,529 @@
+//! Table of the Redis commands known by the proxy, following the layout of
+//! the reply of the `COMMAND` command: arity, flags and key positions.
+
+use connection::redis::resp::Value;
+use std::ascii::AsciiExt;
+use std::cmp::min;
+
+/// The command may modify the keyspace.
+pub const WRITE: u32 = 1;
+/// The command only reads from the keyspace.
+pub const READONLY: u32 = 1 << 1;
+/// Administrative command.
+pub const ADMIN: u32 = 1 << 2;
+/// Pub/Sub related command.
+pub const PUBSUB: u32 = 1 << 3;
+/// The command may block the connection waiting for data.
+pub const BLOCKING: u32 = 1 << 4;
+/// Key positions depend on the arguments and are not described by
+/// `first_key`, `last_key` and `step`.
+pub const MOVABLE_KEYS: u32 = 1 << 5;
+/// Scripts and functions, which may write depending on what they run.
+pub const MAY_WRITE: u32 = 1 << 6;
+
+pub struct CommandInfo {
+    pub name: &'static str,
+    /// Number of arguments including the command name, negative when it is
+    /// the minimum number of arguments.
+    pub arity: i32,
+    pub flags: u32,
+    pub first_key: i32,
+    pub last_key: i32,
+    pub step: i32,
+}
+
+impl CommandInfo {
+    pub fn has_flag(&self, flag: u32) -> bool {
+        self.flags & flag != 0
+    }
+
+    pub fn is_write(&self) -> bool {
+        self.has_flag(WRITE | MAY_WRITE)
+    }
+
+    pub fn is_readonly(&self) -> bool {
+        self.has_flag(READONLY)
+    }
+
+    /// Checks the number of arguments (command name included) against the arity.
+    pub fn accepts(&self, arguments: usize) -> bool {
+        if self.arity >= 0 {
+            arguments == self.arity as usize
+        } else {
+            arguments >= (-self.arity) as usize
+        }
+    }
+}
+
+impl CommandInfo {
+    /// Positions of the keys on a command, given all its arguments
+    /// (command name included).
+    pub fn key_positions(&self, arguments: &[Value]) -> Vec<usize> {
+        if !self.has_flag(MOVABLE_KEYS) {
+            return fixed_key_positions(self.first_key, self.last_key, self.step, arguments.len());
+        }
+
+        match self.name {
+            "EVAL" | "EVALSHA" | "EVAL_RO" | "EVALSHA_RO" | "FCALL" | "FCALL_RO" |
+            "BLMPOP" | "BZMPOP" => {
+                numkeys_positions(arguments, 2)
+            },
+            "ZUNION" | "ZINTER" | "ZDIFF" | "SINTERCARD" | "ZINTERCARD" | "LMPOP" | "ZMPOP" => {
+                numkeys_positions(arguments, 1)
+            },
+            "ZUNIONSTORE" | "ZINTERSTORE" | "ZDIFFSTORE" => {
+                let mut positions = vec![1];
+                positions.extend(numkeys_positions(arguments, 2));
+                positions
+            },
+            "XREAD" | "XREADGROUP" => {
+                match arguments.iter().position(|argument| is_token(argument, "STREAMS")) {
+                    Some(streams) => {
+                        let count = (arguments.len() - streams - 1) / 2;
+                        (streams + 1..streams + 1 + count).collect()
+                    },
+                    None => Vec::new(),
+                }
+            },
+            "MIGRATE" => {
+                match arguments.iter().position(|argument| is_token(argument, "KEYS")) {
+                    Some(keys) => (keys + 1..arguments.len()).collect(),
+                    None if arguments.len() > 3 => vec![3],
+                    None => Vec::new(),
+                }
+            },
+            "SORT" | "GEORADIUS" | "GEORADIUSBYMEMBER" => {
+                let mut positions = fixed_key_positions(self.first_key, self.last_key, self.step, arguments.len());
+                for i in 2..arguments.len() {
+                    if (is_token(&arguments[i - 1], "STORE") || is_token(&arguments[i - 1], "STOREDIST")) && !positions.contains(&i) {
+                        positions.push(i);
+                    }
+                }
+                positions
+            },
+            _ => Vec::new(),
+        }
+    }
+}
+
+fn fixed_key_positions(first_key: i32, last_key: i32, step: i32, arguments: usize) -> Vec<usize> {
+    if first_key <= 0 || step <= 0 {
+        return Vec::new();
+    }
+
+    let last_key = if last_key < 0 {
+        arguments as i32 + last_key
+    } else {
+        last_key
+    };
+
+    let mut positions = Vec::new();
+    let mut position = first_key;
+    while position <= last_key && (position as usize) < arguments {
+        positions.push(position as usize);
+        position += step;
+    }
+
+    positions
+}
+
+/// Keys following a `numkeys` argument placed at the given position.
+fn numkeys_positions(arguments: &[Value], numkeys: usize) -> Vec<usize> {
+    let count = match arguments.get(numkeys) {
+        Some(&Value::Bulk(ref count)) => count.parse::<usize>().unwrap_or(0),
+        _ => 0,
+    };
+
+    (numkeys + 1..min(numkeys + 1 + count, arguments.len())).collect()
+}
+
+fn is_token(argument: &Value, token: &str) -> bool {
+    match *argument {
+        Value::Bulk(ref argument) => argument.eq_ignore_ascii_case(token),
+        _ => false,
+    }
+}
+
+/// Looks up a command by name, case insensitive.
+pub fn lookup(name: &str) -> Option<&'static CommandInfo> {
+    let name = name.to_ascii_uppercase();
+
+    COMMANDS.binary_search_by(|info| info.name.cmp(&*name))
+        .ok()
+        .map(|position| &COMMANDS[position])
+}
+
+/// Looks up the command sent as an array of bulk strings.
+pub fn lookup_command(command: &Value) -> Option<&'static CommandInfo> {
+    match *command {
+        Value::Array(ref input) if input.len() > 0 => {
+            match input[0] {
+                Value::Bulk(ref name) => lookup(name),
+                _ => None,
+            }
+        },
+        _ => None,
+    }
+}
+
+/// Keys of the command sent as an array of bulk strings. Commands missing
+/// from the table have no known keys.
+pub fn keys(command: &Value) -> Vec<&str> {
+    let input = match *command {
+        Value::Array(ref input) => input,
+        _ => return Vec::new(),
+    };
+
+    match lookup_command(command) {
+        Some(info) => {
+            info.key_positions(input).into_iter()
+                .filter_map(|position| {
+                    match input[position] {
+                        Value::Bulk(ref key) => Some(key.as_str()),
+                        _ => None,
+                    }
+                })
+                .collect()
+        },
+        None => Vec::new(),
+    }
+}
+
+/// Keys of the command as raw bytes, binary ones included, for hashing.
+pub fn key_bytes(command: &Value) -> Vec<&[u8]> {
+    let input = match *command {
+        Value::Array(ref input) => input,
+        _ => return Vec::new(),
+    };
+
+    match lookup_command(command) {
+        Some(info) => {
+            info.key_positions(input).into_iter()
+                .filter_map(|position| bytes(&input[position]))
+                .collect()
+        },
+        None => Vec::new(),
+    }
+}
+
+/// Content of a bulk string argument, binary or not.
+pub fn bytes(argument: &Value) -> Option<&[u8]> {
+    match *argument {
+        Value::Bulk(ref argument) => Some(argument.as_bytes()),
+        Value::BufBulk(ref argument) => Some(&argument[..]),
+        _ => None,
+    }
+}
+
+/// Subscription commands, which are answered with pushed messages instead
+/// of a single reply.
+pub fn is_subscription(command: &Value) -> bool {
+    match lookup_command(command) {
+        Some(info) => {
+            match info.name {
+                "SUBSCRIBE" | "PSUBSCRIBE" | "SSUBSCRIBE" |
+                "UNSUBSCRIBE" | "PUNSUBSCRIBE" | "SUNSUBSCRIBE" => true,
+                _ => false,
+            }
+        },
+        None => false,
+    }
+}
+
+/// Commands which may modify the keyspace. `FUNCTION` only does with `LOAD`,
+/// `DELETE`, `FLUSH` and `RESTORE`; `SCRIPT` never does, its script cache
+/// being apart from the keyspace.
+pub fn is_write(command: &Value) -> bool {
+    let info = match lookup_command(command) {
+        Some(info) if info.is_write() => info,
+        _ => return false,
+    };
+
+    match (info.name, command) {
+        ("FUNCTION", &Value::Array(ref input)) => {
+            input.get(1).map(|subcommand| {
+                ["LOAD", "DELETE", "FLUSH", "RESTORE"].iter().any(|name| is_token(subcommand, name))
+            }).unwrap_or(false)
+        },
+        _ => true,
+    }
+}
+
+/// Commands which may block the connection waiting for data. `XREAD` and
+/// `XREADGROUP` only block with the `BLOCK` option.
+pub fn is_blocking(command: &Value) -> bool {
+    let info = match lookup_command(command) {
+        Some(info) if info.has_flag(BLOCKING) => info,
+        _ => return false,
+    };
+
+    match (info.name, command) {
+        ("XREAD", &Value::Array(ref input)) | ("XREADGROUP", &Value::Array(ref input)) => {
+            input.iter().any(|argument| is_token(argument, "BLOCK"))
+        },
+        _ => true,
+    }
+}
+
+// Sorted by name, `lookup` relies on it.
+static COMMANDS: &'static [CommandInfo] = &[
+    CommandInfo { name: "APPEND", arity: 3, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "ASKING", arity: 1, flags: 0, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "AUTH", arity: -2, flags: 0, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "BGREWRITEAOF", arity: 1, flags: ADMIN, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "BGSAVE", arity: -1, flags: ADMIN, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "BITCOUNT", arity: -2, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "BITFIELD", arity: -2, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "BITFIELD_RO", arity: -2, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "BITOP", arity: -4, flags: WRITE, first_key: 2, last_key: -1, step: 1 },
+    CommandInfo { name: "BITPOS", arity: -3, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "BLMOVE", arity: 6, flags: WRITE | BLOCKING, first_key: 1, last_key: 2, step: 1 },
+    CommandInfo { name: "BLMPOP", arity: -5, flags: WRITE | BLOCKING | MOVABLE_KEYS, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "BLPOP", arity: -3, flags: WRITE | BLOCKING, first_key: 1, last_key: -2, step: 1 },
+    CommandInfo { name: "BRPOP", arity: -3, flags: WRITE | BLOCKING, first_key: 1, last_key: -2, step: 1 },
+    CommandInfo { name: "BRPOPLPUSH", arity: 4, flags: WRITE | BLOCKING, first_key: 1, last_key: 2, step: 1 },
+    CommandInfo { name: "BZMPOP", arity: -5, flags: WRITE | BLOCKING | MOVABLE_KEYS, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "BZPOPMAX", arity: -3, flags: WRITE | BLOCKING, first_key: 1, last_key: -2, step: 1 },
+    CommandInfo { name: "BZPOPMIN", arity: -3, flags: WRITE | BLOCKING, first_key: 1, last_key: -2, step: 1 },
+    CommandInfo { name: "CLIENT", arity: -2, flags: ADMIN, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "CLUSTER", arity: -2, flags: 0, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "COMMAND", arity: -1, flags: 0, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "CONFIG", arity: -2, flags: ADMIN, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "COPY", arity: -3, flags: WRITE, first_key: 1, last_key: 2, step: 1 },
+    CommandInfo { name: "DBSIZE", arity: 1, flags: READONLY, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "DEBUG", arity: -2, flags: ADMIN, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "DECR", arity: 2, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "DECRBY", arity: 3, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "DEL", arity: -2, flags: WRITE, first_key: 1, last_key: -1, step: 1 },
+    CommandInfo { name: "DISCARD", arity: 1, flags: 0, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "DUMP", arity: 2, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "ECHO", arity: 2, flags: 0, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "EVAL", arity: -3, flags: MAY_WRITE | MOVABLE_KEYS, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "EVALSHA", arity: -3, flags: MAY_WRITE | MOVABLE_KEYS, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "EVALSHA_RO", arity: -3, flags: READONLY | MOVABLE_KEYS, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "EVAL_RO", arity: -3, flags: READONLY | MOVABLE_KEYS, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "EXEC", arity: 1, flags: 0, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "EXISTS", arity: -2, flags: READONLY, first_key: 1, last_key: -1, step: 1 },
+    CommandInfo { name: "EXPIRE", arity: -3, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "EXPIREAT", arity: -3, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "EXPIRETIME", arity: 2, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "FAILOVER", arity: -1, flags: ADMIN, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "FCALL", arity: -3, flags: MAY_WRITE | MOVABLE_KEYS, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "FCALL_RO", arity: -3, flags: READONLY | MOVABLE_KEYS, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "FLUSHALL", arity: -1, flags: WRITE, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "FLUSHDB", arity: -1, flags: WRITE, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "FUNCTION", arity: -2, flags: MAY_WRITE, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "GEOADD", arity: -5, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "GEODIST", arity: -4, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "GEOHASH", arity: -2, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "GEOPOS", arity: -2, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "GEORADIUS", arity: -6, flags: WRITE | MOVABLE_KEYS, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "GEORADIUSBYMEMBER", arity: -5, flags: WRITE | MOVABLE_KEYS, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "GEORADIUSBYMEMBER_RO", arity: -5, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "GEORADIUS_RO", arity: -6, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "GEOSEARCH", arity: -7, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "GEOSEARCHSTORE", arity: -8, flags: WRITE, first_key: 1, last_key: 2, step: 1 },
+    CommandInfo { name: "GET", arity: 2, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "GETBIT", arity: 3, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "GETDEL", arity: 2, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "GETEX", arity: -2, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "GETRANGE", arity: 4, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "GETSET", arity: 3, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "HDEL", arity: -3, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "HELLO", arity: -1, flags: 0, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "HEXISTS", arity: 3, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "HGET", arity: 3, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "HGETALL", arity: 2, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "HINCRBY", arity: 4, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "HINCRBYFLOAT", arity: 4, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "HKEYS", arity: 2, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "HLEN", arity: 2, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "HMGET", arity: -3, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "HMSET", arity: -4, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "HRANDFIELD", arity: -2, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "HSCAN", arity: -3, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "HSET", arity: -4, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "HSETNX", arity: 4, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "HSTRLEN", arity: 3, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "HVALS", arity: 2, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "INCR", arity: 2, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "INCRBY", arity: 3, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "INCRBYFLOAT", arity: 3, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "INFO", arity: -1, flags: 0, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "KEYS", arity: 2, flags: READONLY, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "LASTSAVE", arity: 1, flags: 0, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "LCS", arity: -3, flags: READONLY, first_key: 1, last_key: 2, step: 1 },
+    CommandInfo { name: "LINDEX", arity: 3, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "LINSERT", arity: 5, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "LLEN", arity: 2, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "LMOVE", arity: 5, flags: WRITE, first_key: 1, last_key: 2, step: 1 },
+    CommandInfo { name: "LMPOP", arity: -4, flags: WRITE | MOVABLE_KEYS, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "LOLWUT", arity: -1, flags: READONLY, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "LPOP", arity: -2, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "LPOS", arity: -3, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "LPUSH", arity: -3, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "LPUSHX", arity: -3, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "LRANGE", arity: 4, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "LREM", arity: 4, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "LSET", arity: 4, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "LTRIM", arity: 4, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "MEMORY", arity: -2, flags: READONLY, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "MGET", arity: -2, flags: READONLY, first_key: 1, last_key: -1, step: 1 },
+    CommandInfo { name: "MIGRATE", arity: -6, flags: WRITE | MOVABLE_KEYS, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "MONITOR", arity: 1, flags: ADMIN, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "MOVE", arity: 3, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "MSET", arity: -3, flags: WRITE, first_key: 1, last_key: -1, step: 2 },
+    CommandInfo { name: "MSETNX", arity: -3, flags: WRITE, first_key: 1, last_key: -1, step: 2 },
+    CommandInfo { name: "MULTI", arity: 1, flags: 0, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "OBJECT", arity: -2, flags: READONLY, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "PERSIST", arity: 2, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "PEXPIRE", arity: -3, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "PEXPIREAT", arity: -3, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "PEXPIRETIME", arity: 2, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "PFADD", arity: -2, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "PFCOUNT", arity: -2, flags: READONLY, first_key: 1, last_key: -1, step: 1 },
+    CommandInfo { name: "PFMERGE", arity: -2, flags: WRITE, first_key: 1, last_key: -1, step: 1 },
+    CommandInfo { name: "PING", arity: -1, flags: 0, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "PSETEX", arity: 4, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "PSUBSCRIBE", arity: -2, flags: PUBSUB, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "PSYNC", arity: -3, flags: ADMIN, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "PTTL", arity: 2, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "PUBLISH", arity: 3, flags: PUBSUB, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "PUBSUB", arity: -2, flags: PUBSUB, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "PUNSUBSCRIBE", arity: -1, flags: PUBSUB, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "QUIT", arity: -1, flags: 0, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "RANDOMKEY", arity: 1, flags: READONLY, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "READONLY", arity: 1, flags: 0, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "READWRITE", arity: 1, flags: 0, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "RENAME", arity: 3, flags: WRITE, first_key: 1, last_key: 2, step: 1 },
+    CommandInfo { name: "RENAMENX", arity: 3, flags: WRITE, first_key: 1, last_key: 2, step: 1 },
+    CommandInfo { name: "REPLICAOF", arity: 3, flags: ADMIN, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "RESET", arity: 1, flags: 0, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "RESTORE", arity: -4, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "ROLE", arity: 1, flags: 0, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "RPOP", arity: -2, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "RPOPLPUSH", arity: 3, flags: WRITE, first_key: 1, last_key: 2, step: 1 },
+    CommandInfo { name: "RPUSH", arity: -3, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "RPUSHX", arity: -3, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "SADD", arity: -3, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "SAVE", arity: 1, flags: ADMIN, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "SCAN", arity: -2, flags: READONLY, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "SCARD", arity: 2, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "SCRIPT", arity: -2, flags: 0, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "SDIFF", arity: -2, flags: READONLY, first_key: 1, last_key: -1, step: 1 },
+    CommandInfo { name: "SDIFFSTORE", arity: -3, flags: WRITE, first_key: 1, last_key: -1, step: 1 },
+    CommandInfo { name: "SELECT", arity: 2, flags: 0, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "SET", arity: -3, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "SETBIT", arity: 4, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "SETEX", arity: 4, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "SETNX", arity: 3, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "SETRANGE", arity: 4, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "SHUTDOWN", arity: -1, flags: ADMIN, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "SINTER", arity: -2, flags: READONLY, first_key: 1, last_key: -1, step: 1 },
+    CommandInfo { name: "SINTERCARD", arity: -3, flags: READONLY | MOVABLE_KEYS, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "SINTERSTORE", arity: -3, flags: WRITE, first_key: 1, last_key: -1, step: 1 },
+    CommandInfo { name: "SISMEMBER", arity: 3, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "SLAVEOF", arity: 3, flags: ADMIN, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "SLOWLOG", arity: -2, flags: ADMIN, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "SMEMBERS", arity: 2, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "SMISMEMBER", arity: -3, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "SMOVE", arity: 4, flags: WRITE, first_key: 1, last_key: 2, step: 1 },
+    CommandInfo { name: "SORT", arity: -2, flags: WRITE | MOVABLE_KEYS, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "SORT_RO", arity: -2, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "SPOP", arity: -2, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "SPUBLISH", arity: 3, flags: PUBSUB, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "SRANDMEMBER", arity: -2, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "SREM", arity: -3, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "SSCAN", arity: -3, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "SSUBSCRIBE", arity: -2, flags: PUBSUB, first_key: 1, last_key: -1, step: 1 },
+    CommandInfo { name: "STRLEN", arity: 2, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "SUBSCRIBE", arity: -2, flags: PUBSUB, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "SUBSTR", arity: 4, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "SUNION", arity: -2, flags: READONLY, first_key: 1, last_key: -1, step: 1 },
+    CommandInfo { name: "SUNIONSTORE", arity: -3, flags: WRITE, first_key: 1, last_key: -1, step: 1 },
+    CommandInfo { name: "SUNSUBSCRIBE", arity: -1, flags: PUBSUB, first_key: 1, last_key: -1, step: 1 },
+    CommandInfo { name: "SWAPDB", arity: 3, flags: WRITE, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "SYNC", arity: 1, flags: ADMIN, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "TIME", arity: 1, flags: 0, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "TOUCH", arity: -2, flags: READONLY, first_key: 1, last_key: -1, step: 1 },
+    CommandInfo { name: "TTL", arity: 2, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "TYPE", arity: 2, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "UNLINK", arity: -2, flags: WRITE, first_key: 1, last_key: -1, step: 1 },
+    CommandInfo { name: "UNSUBSCRIBE", arity: -1, flags: PUBSUB, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "UNWATCH", arity: 1, flags: 0, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "WAIT", arity: 3, flags: 0, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "WATCH", arity: -2, flags: 0, first_key: 1, last_key: -1, step: 1 },
+    CommandInfo { name: "XACK", arity: -4, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "XADD", arity: -5, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "XAUTOCLAIM", arity: -6, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "XCLAIM", arity: -6, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "XDEL", arity: -3, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "XGROUP", arity: -2, flags: WRITE, first_key: 2, last_key: 2, step: 1 },
+    CommandInfo { name: "XINFO", arity: -2, flags: READONLY, first_key: 2, last_key: 2, step: 1 },
+    CommandInfo { name: "XLEN", arity: 2, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "XPENDING", arity: -3, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "XRANGE", arity: -4, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "XREAD", arity: -4, flags: READONLY | BLOCKING | MOVABLE_KEYS, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "XREADGROUP", arity: -7, flags: WRITE | BLOCKING | MOVABLE_KEYS, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "XREVRANGE", arity: -4, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "XSETID", arity: -3, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "XTRIM", arity: -4, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "ZADD", arity: -4, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "ZCARD", arity: 2, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "ZCOUNT", arity: 4, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "ZDIFF", arity: -3, flags: READONLY | MOVABLE_KEYS, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "ZDIFFSTORE", arity: -4, flags: WRITE | MOVABLE_KEYS, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "ZINCRBY", arity: 4, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "ZINTER", arity: -3, flags: READONLY | MOVABLE_KEYS, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "ZINTERCARD", arity: -3, flags: READONLY | MOVABLE_KEYS, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "ZINTERSTORE", arity: -4, flags: WRITE | MOVABLE_KEYS, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "ZLEXCOUNT", arity: 4, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "ZMPOP", arity: -4, flags: WRITE | MOVABLE_KEYS, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "ZMSCORE", arity: -3, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "ZPOPMAX", arity: -2, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "ZPOPMIN", arity: -2, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "ZRANDMEMBER", arity: -2, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "ZRANGE", arity: -4, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "ZRANGEBYLEX", arity: -4, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "ZRANGEBYSCORE", arity: -4, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "ZRANGESTORE", arity: -5, flags: WRITE, first_key: 1, last_key: 2, step: 1 },
+    CommandInfo { name: "ZRANK", arity: -3, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "ZREM", arity: -3, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "ZREMRANGEBYLEX", arity: 4, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "ZREMRANGEBYRANK", arity: 4, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "ZREMRANGEBYSCORE", arity: 4, flags: WRITE, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "ZREVRANGE", arity: -4, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "ZREVRANGEBYLEX", arity: -4, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "ZREVRANGEBYSCORE", arity: -4, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "ZREVRANK", arity: -3, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "ZSCAN", arity: -3, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "ZSCORE", arity: 3, flags: READONLY, first_key: 1, last_key: 1, step: 1 },
+    CommandInfo { name: "ZUNION", arity: -3, flags: READONLY | MOVABLE_KEYS, first_key: 0, last_key: 0, step: 0 },
+    CommandInfo { name: "ZUNIONSTORE", arity: -4, flags: WRITE | MOVABLE_KEYS, first_key: 1, last_key: 1, step: 1 },
+];
+
+#[cfg(test)]
+mod tests {
+    use connection::redis::resp::Value;
+    use super::{COMMANDS, lookup, lookup_command};
+
+    #[test]
+    fn table_is_sorted() {
+        for pair in COMMANDS.windows(2) {
+            assert!(pair[0].name < pair[1].name, "{} before {}", pair[0].name, pair[1].name);
+        }
+    }
+
+    #[test]
+    fn lookup_ignores_case() {
+        let info = lookup("hset").unwrap();
+        assert_eq!(info.name, "HSET");
+        assert!(info.is_write());
+        assert!(info.accepts(4));
+        assert!(!info.accepts(3));
+
+        let get = Value::Array(vec![Value::Bulk("get".to_string()), Value::Bulk("a".to_string())]);
+        assert!(lookup_command(&get).unwrap().is_readonly());
+        assert!(lookup("NOSUCHCOMMAND").is_none());
+    }
+}
@@ -0,0 +1,452 @@
+use connection::redis::{RedisProxy, CommandAction, Origin, InFlight, command_name, value_positions, forwards};
+use connection::redis::resp::Value;
+
+/// Header of the compressed values, followed by the original length as a
+/// little endian `u32` and an LZ4 block.
+const MAGIC: &'static [u8] = b"\x1fRPZ";
+const HEADER_LEN: usize = 8;
+
+/// Largest value we accept to decompress, as Redis limits bulk strings.
+const MAX_LENGTH: usize = 512 * 1024 * 1024;
+
+const MIN_MATCH: usize = 4;
+/// The last match has to start this far from the end of the block...
+const MF_LIMIT: usize = 12;
+/// ... and the last literals have to be at least this long.
+const LAST_LITERALS: usize = 5;
+const HASH_LOG: usize = 12;
+/// Each input byte gives at most this many bytes, in runs of 255 lengths.
+const MAX_RATIO: usize = 255;
+
+macro_rules! try_opt {
+    ($e:expr) => (match $e { Some(value) => value, None => return None })
+}
+
+/// Compresses large string values with LZ4 when clients write them, and
+/// decompresses them when they are read back, so Redis keeps big blobs (i.e.
+/// JSON documents) in less memory without clients knowing.
+///
+/// Values are compressed on `SET`, `SETNX`, `SETEX`, `PSETEX`, `GETSET`,
+/// `MSET`, `MSETNX`, `HSET`, `HSETNX` and `HMSET`, and decompressed in the
+/// replies of `GET`, `GETDEL`, `GETEX`, `GETSET`, `SET ... GET`, `MGET`,
+/// `HGET`, `HMGET`, `HGETALL` and `HVALS`. Commands working on the stored
+/// bytes, like `APPEND`, `STRLEN` or `GETRANGE`, see the compressed value.
+pub struct CompressionProxy {
+    threshold: usize,
+    /// One entry per forwarded command, telling whether it reads values.
+    readings: InFlight<bool>,
+}
+
+impl CompressionProxy {
+    /// Compresses the values of at least `threshold` bytes.
+    pub fn new(threshold: usize) -> Self {
+        CompressionProxy {
+            threshold: threshold,
+            readings: InFlight::new(),
+        }
+    }
+
+    /// Compresses the values the command writes.
+    fn compress_values(&self, command: Value) -> CommandAction {
+        let positions = match (command_name(&command), &command) {
+            (Some(name), &Value::Array(ref input)) => value_positions(&name, input.len()),
+            _ => Vec::new(),
+        };
+
+        if positions.is_empty() {
+            return CommandAction::Forward(command);
+        }
+
+        match command {
+            Value::Array(input) => {
+                let compressed = input.into_iter().enumerate().map(|(position, argument)| {
+                    if positions.iter().any(|&(value, _)| value == position) {
+                        self.compress_value(argument)
+                    } else {
+                        argument
+                    }
+                }).collect();
+
+                CommandAction::Forward(Value::Array(compressed))
+            },
+            command => CommandAction::Forward(command),
+        }
+    }
+
+    fn compress_value(&self, value: Value) -> Value {
+        let compressed = {
+            let data = match value {
+                Value::Bulk(ref data) => data.as_bytes(),
+                Value::BufBulk(ref data) => &data[..],
+                _ => return value,
+            };
+
+            if data.len() < self.threshold || data.len() > MAX_LENGTH {
+                return value;
+            }
+
+            let mut compressed = Vec::with_capacity(HEADER_LEN + data.len() / 2);
+            compressed.extend_from_slice(MAGIC);
+            for shift in 0..4 {
+                compressed.push((data.len() >> (shift * 8)) as u8);
+            }
+            compress(data, &mut compressed);
+
+            if compressed.len() >= data.len() {
+                return value;
+            }
+
+            compressed
+        };
+
+        Value::BufBulk(compressed)
+    }
+}
+
+/// Whether the reply of the command holds stored values.
+fn reads_values(command: &Value) -> bool {
+    match command_name(command) {
+        Some(name) => {
+            match &*name {
+                "GET" | "GETDEL" | "GETEX" | "GETSET" | "SET" | "MGET" | "HGET" | "HMGET" | "HGETALL" | "HVALS" => true,
+                _ => false,
+            }
+        },
+        None => false,
+    }
+}
+
+/// Decompresses every value carrying the header, in nested replies too.
+fn decompress_value(value: Value) -> Value {
+    match value {
+        Value::BufBulk(data) => decompress_data(&data).unwrap_or(Value::BufBulk(data)),
+        // Compressed bytes may happen to be valid UTF-8
+        Value::Bulk(data) => decompress_data(data.as_bytes()).unwrap_or(Value::Bulk(data)),
+        Value::Array(values) => Value::Array(values.into_iter().map(decompress_value).collect()),
+        Value::Set(values) => Value::Set(values.into_iter().map(decompress_value).collect()),
+        Value::Push(values) => Value::Push(values.into_iter().map(decompress_value).collect()),
+        Value::Map(pairs) => {
+            Value::Map(pairs.into_iter().map(|(key, value)| (key, decompress_value(value))).collect())
+        },
+        Value::Attribute(attributes, value) => Value::Attribute(attributes, Box::new(decompress_value(*value))),
+        value => value,
+    }
+}
+
+/// Original value of the data when it carries the header and decompresses.
+fn decompress_data(data: &[u8]) -> Option<Value> {
+    if !data.starts_with(MAGIC) {
+        return None;
+    }
+
+    match decompress_payload(data) {
+        Some(original) => {
+            match String::from_utf8(original) {
+                Ok(original) => Some(Value::Bulk(original)),
+                Err(error) => Some(Value::BufBulk(error.into_bytes())),
+            }
+        },
+        None => {
+            warn!("Could not decompress a value of {} bytes", data.len());
+            None
+        },
+    }
+}
+
+fn decompress_payload(data: &[u8]) -> Option<Vec<u8>> {
+    if data.len() < HEADER_LEN {
+        return None;
+    }
+
+    let length = (0..4).fold(0usize, |length, i| length | (data[MAGIC.len() + i] as usize) << (i * 8));
+    if length > MAX_LENGTH {
+        return None;
+    }
+
+    decompress(&data[HEADER_LEN..], length)
+}
+
+impl RedisProxy for CompressionProxy {
+    fn on_command(&mut self, command: Value) -> CommandAction {
+        let reading = reads_values(&command);
+        let action = self.compress_values(command);
+
+        if forwards(&action) {
+            self.readings.push(reading);
+        }
+
+        action
+    }
+
+    fn on_response(&mut self, origin: Origin, response: Value) -> Value {
+        match self.readings.answer(origin) {
+            Some(true) => decompress_value(response),
+            _ => response,
+        }
+    }
+
+    fn on_unanswered(&mut self) {
+        self.readings.cancel_last();
+    }
+}
+
+fn read_u32(data: &[u8], position: usize) -> u32 {
+    (data[position] as u32) | (data[position + 1] as u32) << 8 |
+        (data[position + 2] as u32) << 16 | (data[position + 3] as u32) << 24
+}
+
+fn write_length(output: &mut Vec<u8>, mut length: usize) {
+    while length >= 255 {
+        output.push(255);
+        length -= 255;
+    }
+    output.push(length as u8);
+}
+
+/// Writes the literals and the match after them, if any, as an LZ4 sequence.
+fn write_sequence(output: &mut Vec<u8>, literals: &[u8], offset: usize, match_length: usize) {
+    let literal_nibble = if literals.len() >= 15 { 15 } else { literals.len() };
+    let match_nibble = if match_length == 0 {
+        0
+    } else if match_length - MIN_MATCH >= 15 {
+        15
+    } else {
+        match_length - MIN_MATCH
+    };
+
+    output.push((literal_nibble << 4 | match_nibble) as u8);
+    if literals.len() >= 15 {
+        write_length(output, literals.len() - 15);
+    }
+    output.extend_from_slice(literals);
+
+    if match_length == 0 {
+        return;
+    }
+
+    output.push(offset as u8);
+    output.push((offset >> 8) as u8);
+    if match_length - MIN_MATCH >= 15 {
+        write_length(output, match_length - MIN_MATCH - 15);
+    }
+}
+
+/// Compresses the input as an LZ4 block, appended to the output.
+fn compress(input: &[u8], output: &mut Vec<u8>) {
+    // Positions plus one of the last sequence of each hash, zero when unset
+    let mut table = vec![0usize; 1 << HASH_LOG];
+    let mut anchor = 0;
+    let mut position = 0;
+
+    while input.len() >= MF_LIMIT && position + MF_LIMIT <= input.len() {
+        let sequence = read_u32(input, position);
+        let hash = (sequence.wrapping_mul(2654435761) >> (32 - HASH_LOG)) as usize;
+        let candidate = table[hash];
+        table[hash] = position + 1;
+
+        if candidate == 0 || position - (candidate - 1) > 65535 || read_u32(input, candidate - 1) != sequence {
+            position += 1;
+            continue;
+        }
+
+        let start = candidate - 1;
+        let mut length = MIN_MATCH;
+        while position + length < input.len() - LAST_LITERALS && input[start + length] == input[position + length] {
+            length += 1;
+        }
+
+        write_sequence(output, &input[anchor..position], position - start, length);
+        position += length;
+        anchor = position;
+    }
+
+    write_sequence(output, &input[anchor..], 0, 0);
+}
+
+fn read_length(input: &[u8], position: &mut usize, nibble: usize) -> Option<usize> {
+    let mut length = nibble;
+    if nibble != 15 {
+        return Some(length);
+    }
+
+    loop {
+        let byte = *try_opt!(input.get(*position)) as usize;
+        *position += 1;
+        length += byte;
+
+        if byte != 255 {
+            return Some(length);
+        }
+    }
+}
+
+/// Decompresses an LZ4 block which should give `length` bytes.
+fn decompress(input: &[u8], length: usize) -> Option<Vec<u8>> {
+    // The header may claim more than the block can give
+    if length > input.len().saturating_mul(MAX_RATIO) {
+        return None;
+    }
+
+    let mut output = Vec::with_capacity(length);
+    let mut position = 0;
+
+    loop {
+        let token = *try_opt!(input.get(position)) as usize;
+        position += 1;
+
+        let literals = try_opt!(read_length(input, &mut position, token >> 4));
+        if position + literals > input.len() || output.len() + literals > length {
+            return None;
+        }
+        output.extend_from_slice(&input[position..position + literals]);
+        position += literals;
+
+        if position == input.len() {
+            break;
+        }
+
+        if position + 2 > input.len() {
+            return None;
+        }
+        let offset = input[position] as usize | (input[position + 1] as usize) << 8;
+        position += 2;
+
+        let match_length = try_opt!(read_length(input, &mut position, token & 15)) + MIN_MATCH;
+        if offset == 0 || offset > output.len() || output.len() + match_length > length {
+            return None;
+        }
+
+        // Matches may overlap what they copy, so byte by byte
+        let start = output.len() - offset;
+        for i in 0..match_length {
+            let byte = output[start + i];
+            output.push(byte);
+        }
+    }
+
+    if output.len() == length {
+        Some(output)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use connection::redis::{RedisProxy, CommandAction, Origin};
+    use connection::redis::resp::Value;
+    use super::{CompressionProxy, MAGIC, compress, decompress, decompress_value};
+
+    /// Deterministic bytes, random or repeating by stretches.
+    fn sample(seed: u32, len: usize) -> Vec<u8> {
+        let mut state = seed | 1;
+        let mut data = Vec::with_capacity(len);
+
+        while data.len() < len {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+
+            if state % 3 == 0 && data.len() > 8 {
+                let start = data.len() - 1 - (state as usize >> 8) % data.len();
+                let repeat = (state as usize >> 4) % 40;
+                for i in 0..repeat {
+                    let byte = data[start + i % (data.len() - start)];
+                    data.push(byte);
+                }
+            } else {
+                data.push((state >> 24) as u8 % 8);
+            }
+        }
+
+        data.truncate(len);
+        data
+    }
+
+    #[test]
+    fn compresses_large_values_and_restores_them() {
+        let mut proxy = CompressionProxy::new(64);
+        let large = "abcd".repeat(64);
+        let set = Value::Array(vec![Value::Bulk("SET".to_string()), Value::Bulk("a".to_string()), Value::Bulk(large.clone())]);
+
+        let stored = match proxy.on_command(set.clone()) {
+            CommandAction::Forward(Value::Array(mut input)) => input.remove(2),
+            _ => panic!("SET should be forwarded"),
+        };
+        match stored {
+            Value::BufBulk(ref data) => assert!(data.starts_with(MAGIC) && data.len() < large.len()),
+            _ => panic!("the value should be compressed"),
+        }
+        proxy.on_response(Origin::Command(&set), Value::String("OK".to_string()));
+
+        let get = Value::Array(vec![Value::Bulk("GET".to_string()), Value::Bulk("a".to_string())]);
+        proxy.on_command(get.clone());
+        proxy.on_command(get.clone());
+        assert_eq!(proxy.on_response(Origin::Command(&get), stored), Value::Bulk(large));
+        assert_eq!(proxy.on_response(Origin::Command(&get), Value::Bulk("small".to_string())), Value::Bulk("small".to_string()));
+    }
+
+    #[test]
+    fn only_replies_to_reads_are_decompressed() {
+        let mut proxy = CompressionProxy::new(64);
+        let mut compressed = MAGIC.to_vec();
+        compressed.extend_from_slice(&[4, 0, 0, 0, 0x40, b'a', b'b', b'c', b'd']);
+
+        let echo = Value::Array(vec![Value::Bulk("ECHO".to_string()), Value::BufBulk(compressed.clone())]);
+        let get = Value::Array(vec![Value::Bulk("GET".to_string()), Value::Bulk("a".to_string())]);
+        proxy.on_command(echo.clone());
+        proxy.on_command(get.clone());
+
+        assert_eq!(proxy.on_response(Origin::Command(&echo), Value::BufBulk(compressed.clone())), Value::BufBulk(compressed.clone()));
+        assert_eq!(proxy.on_response(Origin::Command(&get), Value::BufBulk(compressed)), Value::Bulk("abcd".to_string()));
+    }
+
+    #[test]
+    fn roundtrip() {
+        for seed in 0..200 {
+            let input = sample(seed, seed as usize * 37 % 3000);
+            let mut block = Vec::new();
+            compress(&input, &mut block);
+
+            assert_eq!(decompress(&block, input.len()), Some(input));
+        }
+    }
+
+    #[test]
+    fn garbage_does_not_panic() {
+        for seed in 0..2000 {
+            let mut block = Vec::new();
+            compress(&sample(seed, 64), &mut block);
+
+            let mut state = seed | 1;
+            for _ in 0..4 {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                let position = (state >> 8) as usize % block.len();
+                block[position] ^= (state >> 16) as u8;
+            }
+
+            if let Some(output) = decompress(&block, 64) {
+                assert_eq!(output.len(), 64);
+            }
+            decompress(&block[0..block.len() / 2], 64);
+        }
+    }
+
+    #[test]
+    fn decompresses_text_bulks() {
+        // "abcd", a match of 56 bytes 4 bytes back, and "abcd" again
+        let text = "\x1fRPZ\x40\x00\x00\x00\x4fabcd\x04\x00\x25\x40abcd";
+
+        assert_eq!(decompress_value(Value::Bulk(text.to_string())), Value::Bulk("abcd".repeat(16)));
+    }
+
+    #[test]
+    fn refuses_lengths_the_block_can_not_give() {
+        assert_eq!(decompress(&[0x10, b'a'], 1), Some(b"a".to_vec()));
+        assert_eq!(decompress(&[0x10, b'a'], 512 * 1024 * 1024), None);
+
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&[0, 0, 0, 0x20, 0x10, b'a']);
+        assert_eq!(decompress_value(Value::BufBulk(data.clone())), Value::BufBulk(data));
+    }
+}
@@ -1,15 +1,70 @@
 use mio::{Token, Evented, EventSet};
-use connection::Connection;
+use connection::{Connection, Timer};
 use connection::tcp_connection::TcpConnection;
-use connection::ConnectionAction;
+use connection::{ConnectionAction, TimerAction};
 use std::io;
-use connection::redis::RedisProxy;
-use resp::{Decoder, Value};
-use std::ascii::AsciiExt;
+use std::cmp::min;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use connection::redis::{RedisProxy, CommandAction, Origin, command_name};
+use connection::redis::commands::{is_subscription, is_blocking};
+use connection::redis::resp::{Decoder, Value};
+use netbuf::Buf;
+
+/// Slot reserved for the reply of each command, in the order the commands
+/// were received, so pipelined clients get their replies in order even when
+/// some of them are answered locally.
+enum Reply {
+    /// Forwarded command, with its reply once received.
+    Upstream(Value, Option<Value>),
+    /// Command sent inside a transaction, with its `+QUEUED` acknowledgement
+    /// once received.
+    Queued(Value, Option<Value>),
+    Local(Value),
+    /// Reply of a forwarded command that must never reach the client; it
+    /// holds back every later reply. Tells whether it has been received.
+    Lost(Value, bool),
+    /// `EXEC` or `DISCARD` of a tracked transaction, with the queued
+    /// commands.
+    Exec(Value, Vec<Value>, Option<Value>),
+    /// `DISCARD` sent instead of the `EXEC` of an aborted transaction, with
+    /// the queued commands. Its reply is absorbed and the client gets
+    /// `EXECABORT` in its place.
+    Discard(Value, Vec<Value>, Option<Value>),
+}
+
+impl Reply {
+    fn is_waiting(&self) -> bool {
+        match *self {
+            Reply::Upstream(_, None) | Reply::Queued(_, None) | Reply::Lost(_, false) | Reply::Exec(_, _, None) | Reply::Discard(_, _, None) => true,
+            _ => false,
+        }
+    }
+}
+
+/// Commands sent since `MULTI`.
+struct Transaction {
+    commands: Vec<Value>,
+    /// A command was rejected locally, so `EXEC` has to fail.
+    aborted: bool,
+}
 
 pub struct RedisConnection<P> where P: RedisProxy {
     connection: TcpConnection,
     proxy: P,
+    commands: Decoder,
+    responses: Decoder,
+    forward: Buf,
+    replies: VecDeque<Reply>,
+    delayed: Option<(Instant, CommandAction)>,
+    /// A subscription command has been sent, so Pub/Sub messages may come.
+    subscribing: bool,
+    subscriptions: i64,
+    blocked: usize,
+    transaction: Option<Transaction>,
+    /// Protocol version negotiated with `HELLO`.
+    protocol: i64,
+    closing: bool,
 }
 
 impl<P> RedisConnection<P> where P: RedisProxy {
@@ -17,50 +72,460 @@ impl<P> RedisConnection<P> where P: RedisProxy {
         RedisConnection {
             connection: connection,
             proxy: proxy,
+            commands: Decoder::new(),
+            responses: Decoder::new(),
+            forward: Buf::new(),
+            replies: VecDeque::new(),
+            delayed: None,
+            subscribing: false,
+            subscriptions: 0,
+            blocked: 0,
+            transaction: None,
+            protocol: 2,
+            closing: false,
         }
     }
-}
 
-impl<P> io::Read for RedisConnection<P> where P: RedisProxy {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    /// Whether the client is subscribed to any channel or pattern.
+    pub fn is_subscribed(&self) -> bool {
+        self.subscriptions > 0
+    }
+
+    /// Whether the client waits for the reply of a blocking command.
+    pub fn is_blocked(&self) -> bool {
+        self.blocked > 0
+    }
+
+    /// RESP version spoken with the client, 3 after a successful `HELLO 3`.
+    pub fn protocol(&self) -> i64 {
+        self.protocol
+    }
+
+    /// Whether the client is between `MULTI` and `EXEC`.
+    pub fn in_transaction(&self) -> bool {
+        self.transaction.is_some()
+    }
+
+    /// Client side buffers, to feed and read in tests.
+    #[cfg(test)]
+    pub fn tcp_connection(&mut self) -> &mut TcpConnection {
+        &mut self.connection
+    }
+
+    fn process_commands(&mut self) {
         let len = self.connection.get_input().len();
-        let mut decoder = Decoder::new();
-        decoder.feed(&self.connection.get_mut_input()[0..len]).unwrap();
-        let parsed = decoder.read();
-        match parsed {
-            Some(command) => {
-                let command: Value = self.proxy.on_command(command);
+        if len == 0 {
+            return;
+        }
+
+        if self.closing {
+            self.connection.get_mut_input().consume(len);
+            return;
+        }
 
-                self.connection.get_mut_input().consume(len);
-                self.connection.get_mut_input().extend(command.to_encoded_string().unwrap().as_str().as_bytes());
+        let feed_result = self.commands.feed(&self.connection.get_input()[0..len]);
+        self.connection.get_mut_input().consume(len);
 
-                self.connection.read(buf)
+        if let Err(e) = feed_result {
+            error!("{:?}: Could not parse command: {}", self.get_token(), e);
+            self.closing = true;
+            return;
+        }
+
+        self.release_commands();
+        self.flush_replies();
+    }
+
+    /// Intercepts the decoded commands, stopping at the first delayed one
+    /// until its time has come.
+    fn release_commands(&mut self) {
+        while !self.closing {
+            let due = match self.delayed {
+                Some((deadline, _)) => deadline <= Instant::now(),
+                None => true,
+            };
+
+            if !due {
+                break;
             }
-            None => {
-                Ok(0)
+
+            if let Some((_, action)) = self.delayed.take() {
+                self.apply(action);
+                continue;
+            }
+
+            match self.commands.read() {
+                Some(command) => {
+                    let action = self.proxy.on_command(command);
+                    self.apply(action);
+                },
+                None => break,
+            }
+        }
+    }
+
+    fn apply(&mut self, action: CommandAction) {
+        match action {
+            CommandAction::Forward(command) => {
+                if self.track_transaction(&command) {
+                    return;
+                }
+
+                self.forward.extend(&command.encode());
+
+                if self.transaction.is_some() {
+                    // Only queued, it neither subscribes nor blocks
+                    self.replies.push_back(Reply::Queued(command, None));
+                } else if is_subscription(&command) {
+                    // Confirmations are pushed, one per channel
+                    self.subscribing = true;
+                    self.proxy.on_unanswered();
+                } else {
+                    if is_blocking(&command) {
+                        self.blocked += 1;
+                    }
+
+                    self.replies.push_back(Reply::Upstream(command, None));
+                }
+            },
+            CommandAction::Respond(response) => {
+                if let Some(ref mut transaction) = self.transaction {
+                    if response.is_error() {
+                        transaction.aborted = true;
+                    }
+                }
+
+                self.replies.push_back(Reply::Local(response));
+            },
+            CommandAction::LoseReply(command) => {
+                info!("{:?}: Losing the reply of the command", self.get_token());
+                if let Some(ref mut transaction) = self.transaction {
+                    transaction.commands.push(command.clone());
+                }
+                self.forward.extend(&command.encode());
+                self.replies.push_back(Reply::Lost(command, false));
+            },
+            CommandAction::Drop => {
+                info!("{:?}: Dropping command", self.get_token());
+            },
+            CommandAction::Close => {
+                info!("{:?}: Closing connection", self.get_token());
+                self.closing = true;
+            },
+            CommandAction::Delay(delay, action) => {
+                self.delayed = Some((Instant::now() + Duration::from_millis(delay), *action));
             },
         }
     }
-}
 
-impl<P> io::Write for RedisConnection<P> where P: RedisProxy {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let mut decoder = Decoder::new();
-        decoder.feed(buf).unwrap();
-        let parsed = decoder.read();
+    /// Follows `MULTI`, `EXEC` and `DISCARD`, and records the commands of the
+    /// transaction. Tells whether the command has been handled already.
+    fn track_transaction(&mut self, command: &Value) -> bool {
+        let name = command_name(command).unwrap_or(String::new());
+
+        match &*name {
+            "MULTI" => {
+                if self.transaction.is_none() {
+                    self.transaction = Some(Transaction {
+                        commands: Vec::new(),
+                        aborted: false,
+                    });
+                }
+            },
+            "EXEC" => {
+                match self.transaction.take() {
+                    Some(Transaction { aborted: true, commands }) => {
+                        info!("{:?}: Discarding the transaction", self.get_token());
+                        let discard = Value::Array(vec![Value::Bulk("DISCARD".to_string())]);
+                        self.forward.extend(&discard.encode());
+                        self.replies.push_back(Reply::Discard(command.clone(), commands, None));
 
-        match parsed {
-            Some(response) => {
-                info!("Parsed!");
-                let response = self.proxy.on_response(response);
+                        return true;
+                    },
+                    Some(transaction) => {
+                        self.forward.extend(&command.encode());
+                        self.replies.push_back(Reply::Exec(command.clone(), transaction.commands, None));
 
-                self.connection.write(response.to_encoded_string().unwrap().as_str().as_bytes())
+                        return true;
+                    },
+                    None => (),
+                }
+            },
+            "DISCARD" => {
+                if let Some(transaction) = self.transaction.take() {
+                    self.forward.extend(&command.encode());
+                    self.replies.push_back(Reply::Exec(command.clone(), transaction.commands, None));
+
+                    return true;
+                }
+            },
+            _ => {
+                if let Some(ref mut transaction) = self.transaction {
+                    transaction.commands.push(command.clone());
+                }
             },
+        }
+
+        false
+    }
+
+    fn push_response(&mut self, response: Value) {
+        // RESP3 marks out of band messages, RESP2 only has their shape
+        let pushed = match response {
+            Value::Push(_) => true,
+            _ => self.subscribing && push_kind(&response).is_some(),
+        };
+
+        if pushed {
+            self.track_subscriptions(&response);
+
+            let response = self.proxy.on_response(Origin::Push, response);
+            return self.push_message(response);
+        }
+
+        let position = match self.replies.iter().position(Reply::is_waiting) {
+            Some(position) => position,
             None => {
-                info!("No parsed!");
-                Ok(0)
+                warn!("{:?}: Received a response without a pending command", self.get_token());
+                let response = self.proxy.on_response(Origin::Push, response);
+                return self.push_message(response);
+            },
+        };
+
+        let mut rejected = None;
+        let proxy = &mut self.proxy;
+        match self.replies[position] {
+            Reply::Upstream(ref command, ref mut slot) => {
+                if !is_queued(&response) {
+                    if is_blocking(command) {
+                        self.blocked -= 1;
+                    }
+
+                    if let Some(protocol) = hello_protocol(command, &response) {
+                        self.protocol = protocol;
+                    }
+                }
+
+                *slot = Some(answer(proxy, command, response));
+            },
+            Reply::Queued(ref command, ref mut slot) => {
+                if response.is_error() {
+                    rejected = Some(command.clone());
+                }
+
+                *slot = Some(answer(proxy, command, response));
+            },
+            Reply::Exec(ref exec, ref commands, ref mut slot) => {
+                let response = match response {
+                    Value::Array(ref results) if results.len() == commands.len() => {
+                        Value::Array(commands.iter().zip(results.iter()).map(|(command, result)| {
+                            proxy.on_response(Origin::Command(command), result.clone())
+                        }).collect())
+                    },
+                    // Discarded, or the watched keys changed
+                    response => {
+                        for command in commands {
+                            proxy.on_response(Origin::Command(command), Value::Error("ERR Transaction not executed".to_string()));
+                        }
+
+                        response
+                    },
+                };
+
+                *slot = Some(proxy.on_response(Origin::Command(exec), response));
+            },
+            Reply::Lost(ref command, ref mut received) => {
+                answer(proxy, command, response);
+                *received = true;
+            },
+            Reply::Discard(ref exec, ref commands, ref mut slot) => {
+                let error = Value::Error("EXECABORT Transaction discarded because of previous errors.".to_string());
+                for command in commands {
+                    proxy.on_response(Origin::Command(command), error.clone());
+                }
+
+                *slot = Some(proxy.on_response(Origin::Command(exec), error));
+            },
+            _ => (),
+        }
+
+        if let Some(command) = rejected {
+            self.forget_queued(position, &command);
+        }
+    }
+
+    /// Removes a command the upstream refused to queue from its transaction,
+    /// since the proxy already had its reply: `EXEC` must not report it.
+    fn forget_queued(&mut self, position: usize, command: &Value) {
+        let ending = self.replies.iter_mut().skip(position + 1).filter_map(|reply| {
+            match *reply {
+                Reply::Exec(_, ref mut commands, _) | Reply::Discard(_, ref mut commands, _) => Some(commands),
+                _ => None,
+            }
+        }).next();
+
+        let commands = match (ending, self.transaction.as_mut()) {
+            (Some(commands), _) => commands,
+            (None, Some(transaction)) => &mut transaction.commands,
+            (None, None) => return,
+        };
+
+        if let Some(index) = commands.iter().position(|queued| queued == command) {
+            commands.remove(index);
+        }
+    }
+
+    /// Queues a message that does not answer any command, ahead of the
+    /// replies still to come from the upstream.
+    fn push_message(&mut self, message: Value) {
+        match self.replies.iter().position(Reply::is_waiting) {
+            Some(position) => self.replies.insert(position, Reply::Local(message)),
+            None => self.replies.push_back(Reply::Local(message)),
+        }
+    }
+
+    fn track_subscriptions(&mut self, message: &Value) {
+        let count = match *message {
+            Value::Array(ref parts) | Value::Push(ref parts) if parts.len() == 3 => {
+                match parts[2] {
+                    Value::Integer(count) => count,
+                    _ => return,
+                }
+            },
+            _ => return,
+        };
+
+        match push_kind(message) {
+            Some("subscribe") | Some("psubscribe") | Some("ssubscribe") => {
+                self.subscriptions = count;
+            },
+            Some("unsubscribe") | Some("punsubscribe") | Some("sunsubscribe") => {
+                self.subscriptions = count;
+                if count == 0 {
+                    self.subscribing = false;
+                }
+            },
+            _ => (),
+        }
+    }
+
+    fn flush_replies(&mut self) {
+        loop {
+            let ready = match self.replies.front() {
+                Some(&Reply::Local(_)) | Some(&Reply::Upstream(_, Some(_))) | Some(&Reply::Queued(_, Some(_))) |
+                Some(&Reply::Exec(_, _, Some(_))) | Some(&Reply::Discard(_, _, Some(_))) => true,
+                _ => false,
+            };
+
+            if !ready {
+                break;
+            }
+
+            match self.replies.pop_front() {
+                Some(Reply::Local(response)) | Some(Reply::Upstream(_, Some(response))) | Some(Reply::Queued(_, Some(response))) |
+                Some(Reply::Exec(_, _, Some(response))) | Some(Reply::Discard(_, _, Some(response))) => {
+                    self.connection.get_mut_output().extend(&response.encode());
+                },
+                _ => (),
+            }
+        }
+    }
+}
+
+fn is_queued(response: &Value) -> bool {
+    match *response {
+        Value::String(ref status) => status == "QUEUED",
+        _ => false,
+    }
+}
+
+/// Gives the proxy the reply to a forwarded command, or its `+QUEUED`
+/// acknowledgement inside a transaction.
+fn answer<P: RedisProxy>(proxy: &mut P, command: &Value, response: Value) -> Value {
+    if is_queued(&response) {
+        proxy.on_response(Origin::Queued(command), response)
+    } else {
+        proxy.on_response(Origin::Command(command), response)
+    }
+}
+
+/// Kind of a Pub/Sub message (`message`, `subscribe`, ...): arrays, or RESP3
+/// pushes, whose first element is the kind.
+fn push_kind(message: &Value) -> Option<&str> {
+    match *message {
+        Value::Array(ref parts) | Value::Push(ref parts) if parts.len() >= 3 => {
+            match parts[0] {
+                Value::Bulk(ref kind) => {
+                    match &**kind {
+                        "message" | "pmessage" | "smessage" |
+                        "subscribe" | "psubscribe" | "ssubscribe" |
+                        "unsubscribe" | "punsubscribe" | "sunsubscribe" => Some(kind),
+                        _ => None,
+                    }
+                },
+                _ => None,
             }
+        },
+        _ => None,
+    }
+}
+
+/// Protocol version agreed on by a successful `HELLO`, as found in its reply.
+fn hello_protocol(command: &Value, response: &Value) -> Option<i64> {
+    if command_name(command).map(|name| name != "HELLO").unwrap_or(true) {
+        return None;
+    }
+
+    let fields: Vec<(&Value, &Value)> = match *response {
+        Value::Map(ref pairs) => pairs.iter().map(|&(ref key, ref value)| (key, value)).collect(),
+        Value::Array(ref values) => values.chunks(2).filter(|pair| pair.len() == 2).map(|pair| (&pair[0], &pair[1])).collect(),
+        _ => return None,
+    };
+
+    fields.into_iter().filter_map(|(key, value)| {
+        match (key, value) {
+            (&Value::Bulk(ref key), &Value::Integer(protocol)) if key == "proto" => Some(protocol),
+            _ => None,
+        }
+    }).next()
+}
+
+impl<P> Timer for RedisConnection<P> where P: RedisProxy {
+    fn handle_timer(&mut self) -> TimerAction {
+        self.release_commands();
+        self.flush_replies();
+
+        TimerAction::Continue
+    }
+
+    fn get_frequency(&self) -> u64 {
+        10
+    }
+}
+
+impl<P> io::Read for RedisConnection<P> where P: RedisProxy {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.process_commands();
+
+        let read_size = min(buf.len(), self.forward.len());
+        buf[0..read_size].clone_from_slice(&self.forward[0..read_size]);
+        self.forward.consume(read_size);
+
+        Ok(read_size)
+    }
+}
+
+impl<P> io::Write for RedisConnection<P> where P: RedisProxy {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        try!(self.responses.feed(buf));
+
+        while let Some(response) = self.responses.read() {
+            self.push_response(response);
         }
+
+        self.flush_replies();
+
+        Ok(buf.len())
     }
 
     fn flush(&mut self) -> io::Result<()> {
@@ -88,6 +553,186 @@ impl<P> Connection for RedisConnection<P> where P: RedisProxy {
     }
 
     fn handle_write(&mut self) -> ConnectionAction {
-        return self.connection.handle_write()
+        let write_response = self.connection.handle_write();
+
+        if self.closing && self.replies.is_empty() && self.connection.get_output().is_empty() {
+            return ConnectionAction::Halt;
+        }
+
+        write_response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mio::Token;
+    use connection::testing::{tcp_connection, take_output};
+    use connection::redis::{RedisProxy, CommandAction, Origin, MockKeyspace, command_name};
+    use connection::redis::resp::Value;
+    use connection::redis::testing::{Harness, ok};
+    use super::RedisConnection;
+    use std::io::{Read, Write};
+    use std::rc::Rc;
+    use std::cell::RefCell;
+
+    /// Answers `PING` itself, drops `DROP` and closes on `QUIT`.
+    struct LocalProxy;
+
+    impl RedisProxy for LocalProxy {
+        fn on_command(&mut self, command: Value) -> CommandAction {
+            let name = match command {
+                Value::Array(ref input) => input[0].clone(),
+                _ => Value::Null,
+            };
+
+            match name {
+                Value::Bulk(ref name) if name == "PING" => CommandAction::Respond(Value::String("PONG".to_string())),
+                Value::Bulk(ref name) if name == "DROP" => CommandAction::Drop,
+                Value::Bulk(ref name) if name == "QUIT" => CommandAction::Close,
+                _ => CommandAction::Forward(command),
+            }
+        }
+
+        fn on_response(&mut self, _: Origin, response: Value) -> Value {
+            response
+        }
+    }
+
+    /// Rejects `FLUSHALL`, as an interceptor answering locally would.
+    struct RejectFlushProxy;
+
+    impl RedisProxy for RejectFlushProxy {
+        fn on_command(&mut self, command: Value) -> CommandAction {
+            match command_name(&command).as_ref().map(|name| &**name) {
+                Some("FLUSHALL") => CommandAction::Respond(Value::Error("ERR rejected".to_string())),
+                _ => CommandAction::Forward(command),
+            }
+        }
+
+        fn on_response(&mut self, _: Origin, response: Value) -> Value {
+            response
+        }
+    }
+
+    /// Records the commands whose final reply it receives.
+    struct RecordingProxy {
+        answered: Rc<RefCell<Vec<(String, Value)>>>,
+    }
+
+    impl RedisProxy for RecordingProxy {
+        fn on_command(&mut self, command: Value) -> CommandAction {
+            CommandAction::Forward(command)
+        }
+
+        fn on_response(&mut self, origin: Origin, response: Value) -> Value {
+            if let Origin::Command(command) = origin {
+                self.answered.borrow_mut().push((command_name(command).unwrap(), response.clone()));
+            }
+
+            response
+        }
+    }
+
+    fn command(arguments: &[&str]) -> Vec<u8> {
+        Value::Array(arguments.iter().map(|argument| Value::Bulk(argument.to_string())).collect()).encode()
+    }
+
+    #[test]
+    fn local_replies_keep_the_pipeline_order() {
+        let (connection, _socket) = tcp_connection(Token(1));
+        let mut connection = RedisConnection::new(connection, LocalProxy);
+
+        for arguments in [&["GET", "a"][..], &["PING"], &["DROP"], &["GET", "b"], &["QUIT"], &["GET", "c"]].iter() {
+            connection.tcp_connection().get_mut_input().extend(&command(arguments));
+        }
+
+        let mut buf = [0u8; 256];
+        let size = connection.read(&mut buf).unwrap();
+        let mut forwarded = command(&["GET", "a"]);
+        forwarded.extend(command(&["GET", "b"]));
+        assert_eq!(&buf[0..size], &forwarded[..]);
+
+        // Nothing goes out before the reply of the first command
+        assert!(take_output(connection.tcp_connection()).is_empty());
+
+        connection.write_all(b"$1\r\n1\r\n$1\r\n2\r\n").unwrap();
+        assert_eq!(take_output(connection.tcp_connection()), b"$1\r\n1\r\n+PONG\r\n$1\r\n2\r\n".to_vec());
+    }
+
+    #[test]
+    fn tracks_subscriptions_and_blocking_commands() {
+        let (connection, _socket) = tcp_connection(Token(1));
+        let mut connection = RedisConnection::new(connection, LocalProxy);
+        let mut buf = [0u8; 256];
+
+        connection.tcp_connection().get_mut_input().extend(&command(&["BLPOP", "queue", "0"]));
+        connection.read(&mut buf).unwrap();
+        assert!(connection.is_blocked());
+
+        connection.write_all(b"*2\r\n$5\r\nqueue\r\n$1\r\nx\r\n").unwrap();
+        assert!(!connection.is_blocked());
+        take_output(connection.tcp_connection());
+
+        connection.tcp_connection().get_mut_input().extend(&command(&["SUBSCRIBE", "a", "b"]));
+        connection.read(&mut buf).unwrap();
+        connection.write_all(b"*3\r\n$9\r\nsubscribe\r\n$1\r\na\r\n:1\r\n*3\r\n$9\r\nsubscribe\r\n$1\r\nb\r\n:2\r\n").unwrap();
+        assert!(connection.is_subscribed());
+
+        connection.write_all(b"*3\r\n$7\r\nmessage\r\n$1\r\na\r\n$2\r\nhi\r\n").unwrap();
+        let output = take_output(connection.tcp_connection());
+        assert!(output.ends_with(b"*3\r\n$7\r\nmessage\r\n$1\r\na\r\n$2\r\nhi\r\n"));
+
+        connection.tcp_connection().get_mut_input().extend(&command(&["UNSUBSCRIBE"]));
+        connection.read(&mut buf).unwrap();
+        connection.write_all(b"*3\r\n$11\r\nunsubscribe\r\n$1\r\na\r\n:1\r\n*3\r\n$11\r\nunsubscribe\r\n$1\r\nb\r\n:0\r\n").unwrap();
+        assert!(!connection.is_subscribed());
+    }
+
+    #[test]
+    fn aborted_transaction_does_not_stall_later_replies() {
+        let keyspace = MockKeyspace::new();
+        keyspace.reply_to("MULTI", ok());
+        keyspace.reply_to("SET", Value::String("QUEUED".to_string()));
+        keyspace.reply_to("DISCARD", ok());
+        let mut harness = Harness::new(RejectFlushProxy, keyspace.clone());
+
+        let replies = harness.send(&[&["MULTI"], &["SET", "a", "1"], &["FLUSHALL"], &["EXEC"], &["PING"]]);
+
+        assert_eq!(replies, vec![
+            ok(),
+            Value::String("QUEUED".to_string()),
+            Value::Error("ERR rejected".to_string()),
+            Value::Error("EXECABORT Transaction discarded because of previous errors.".to_string()),
+            Value::String("PONG".to_string()),
+        ]);
+        assert!(!harness.client.in_transaction());
+        assert_eq!(command_name(keyspace.received().last().unwrap()), Some("PING".to_string()));
+    }
+
+    #[test]
+    fn commands_refused_at_queue_time_are_answered_once() {
+        let keyspace = MockKeyspace::new();
+        let execabort = Value::Error("EXECABORT Transaction discarded because of previous errors.".to_string());
+        keyspace.reply_to("MULTI", ok());
+        keyspace.reply_to("SET", Value::String("QUEUED".to_string()));
+        keyspace.reply_to("BADCMD", Value::Error("ERR unknown command 'BADCMD'".to_string()));
+        keyspace.reply_to("EXEC", execabort.clone());
+        let answered = Rc::new(RefCell::new(Vec::new()));
+        let mut harness = Harness::new(RecordingProxy { answered: answered.clone() }, keyspace);
+
+        let replies = harness.send(&[&["MULTI"], &["SET", "a", "1"], &["BADCMD"], &["EXEC"]]);
+
+        assert_eq!(replies, vec![
+            ok(),
+            Value::String("QUEUED".to_string()),
+            Value::Error("ERR unknown command 'BADCMD'".to_string()),
+            execabort.clone(),
+        ]);
+        assert_eq!(*answered.borrow(), vec![
+            ("MULTI".to_string(), ok()),
+            ("BADCMD".to_string(), Value::Error("ERR unknown command 'BADCMD'".to_string())),
+            ("SET".to_string(), Value::Error("ERR Transaction not executed".to_string())),
+            ("EXEC".to_string(), execabort),
+        ]);
     }
 }
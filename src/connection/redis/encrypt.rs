@@ -0,0 +1,334 @@
+use connection::redis::{RedisProxy, CommandAction, Origin, InFlight, command_name, value_positions, forwards};
+use connection::redis::commands::bytes;
+use connection::glob;
+use connection::redis::resp::Value;
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use rand::{self, Rng};
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+/// Header of the encrypted values, followed by the nonce, the ciphertext and
+/// the tag.
+const MAGIC: &'static [u8] = b"\x1fRPE";
+
+/// Encrypts the string and hash values of the keys matching the patterns
+/// before they reach Redis, and decrypts them in the replies, so Redis (and
+/// its dumps) never hold them in clear.
+///
+/// Values are sealed with ChaCha20-Poly1305 under the configured key, bound
+/// to the name of their Redis key so they can not be moved to another one.
+/// They are written by `SET`, `SETNX`, `SETEX`, `PSETEX`, `GETSET`, `MSET`,
+/// `MSETNX`, `HSET`, `HSETNX` and `HMSET`, and read back through `GET`,
+/// `GETDEL`, `GETEX`, `GETSET`, `SET ... GET`, `MGET`, `HGET`, `HMGET`,
+/// `HGETALL` and `HVALS`. `APPEND` and `SETRANGE` are refused on these keys.
+/// Values stored before the key was protected are given as they are.
+pub struct EncryptionProxy {
+    key: [u8; KEY_LEN],
+    patterns: Vec<String>,
+    /// One entry per forwarded command, set for those reading protected keys.
+    readings: InFlight<Option<Reading>>,
+}
+
+/// Protected keys a forwarded command reads, named as this proxy saw them,
+/// since proxies further down the chain may rename them.
+enum Reading {
+    /// Every value of the reply comes from the key.
+    Key(Vec<u8>),
+    /// `MGET`: each value comes from the key at the same position.
+    Keys(Vec<Option<Vec<u8>>>),
+}
+
+impl EncryptionProxy {
+    /// Protects the keys matching any of the glob patterns.
+    pub fn new(key: [u8; KEY_LEN], patterns: &[&str]) -> Self {
+        EncryptionProxy {
+            key: key,
+            patterns: patterns.iter().map(|pattern| pattern.to_string()).collect(),
+            readings: InFlight::new(),
+        }
+    }
+
+    /// Name of the key when it is protected, binary names being matched
+    /// against the patterns as if they were UTF-8.
+    fn protects(&self, key: &Value) -> Option<Vec<u8>> {
+        let key = match bytes(key) {
+            Some(key) => key,
+            None => return None,
+        };
+
+        let name = String::from_utf8_lossy(key);
+        if self.patterns.iter().any(|pattern| glob::matches(pattern, &name)) {
+            Some(key.to_vec())
+        } else {
+            None
+        }
+    }
+
+    fn cipher(&self) -> ChaCha20Poly1305 {
+        ChaCha20Poly1305::new(Key::from_slice(&self.key))
+    }
+
+    fn encrypt(&self, key: &[u8], value: Value) -> Value {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+
+        let sealed = {
+            let plaintext = match value {
+                Value::Bulk(ref data) => data.as_bytes(),
+                Value::BufBulk(ref data) => &data[..],
+                _ => return value,
+            };
+
+            // Only fails for values larger than Redis accepts
+            let payload = Payload { msg: plaintext, aad: key };
+            self.cipher().encrypt(Nonce::from_slice(&nonce), payload).expect("the value is too large to be encrypted")
+        };
+
+        let mut encrypted = Vec::with_capacity(MAGIC.len() + NONCE_LEN + sealed.len());
+        encrypted.extend_from_slice(MAGIC);
+        encrypted.extend_from_slice(&nonce);
+        encrypted.extend_from_slice(&sealed);
+
+        Value::BufBulk(encrypted)
+    }
+
+    fn reading(&self, command: &Value) -> Option<Reading> {
+        let (name, input) = match (command_name(command), command) {
+            (Some(name), &Value::Array(ref input)) if input.len() > 1 => (name, input),
+            _ => return None,
+        };
+
+        match &*name {
+            "MGET" => Some(Reading::Keys(input[1..].iter().map(|key| self.protects(key)).collect())),
+            "GET" | "GETDEL" | "GETEX" | "GETSET" | "SET" | "HGET" | "HMGET" | "HGETALL" | "HVALS" => {
+                self.protects(&input[1]).map(Reading::Key)
+            },
+            _ => None,
+        }
+    }
+
+    /// Decrypts every value carrying the header, in nested replies too.
+    fn decrypt(&self, key: &[u8], value: Value) -> Value {
+        match value {
+            Value::BufBulk(data) => self.open(key, data),
+            // Sealed bytes may happen to be valid UTF-8
+            Value::Bulk(data) => {
+                if data.as_bytes().starts_with(MAGIC) {
+                    self.open(key, data.into_bytes())
+                } else {
+                    Value::Bulk(data)
+                }
+            },
+            Value::Array(values) => Value::Array(values.into_iter().map(|value| self.decrypt(key, value)).collect()),
+            Value::Map(pairs) => {
+                Value::Map(pairs.into_iter().map(|(field, value)| (field, self.decrypt(key, value))).collect())
+            },
+            Value::Attribute(attributes, value) => Value::Attribute(attributes, Box::new(self.decrypt(key, *value))),
+            value => value,
+        }
+    }
+
+    fn open(&self, key: &[u8], data: Vec<u8>) -> Value {
+        if !data.starts_with(MAGIC) {
+            return Value::BufBulk(data);
+        }
+
+        let opened = if data.len() >= MAGIC.len() + NONCE_LEN + TAG_LEN {
+            let nonce = Nonce::from_slice(&data[MAGIC.len()..MAGIC.len() + NONCE_LEN]);
+            let payload = Payload { msg: &data[MAGIC.len() + NONCE_LEN..], aad: key };
+            self.cipher().decrypt(nonce, payload).ok()
+        } else {
+            None
+        };
+
+        match opened {
+            Some(plaintext) => {
+                match String::from_utf8(plaintext) {
+                    Ok(plaintext) => Value::Bulk(plaintext),
+                    Err(error) => Value::BufBulk(error.into_bytes()),
+                }
+            },
+            None => {
+                warn!("Could not decrypt the value of {}", String::from_utf8_lossy(key));
+                Value::Error("ERR could not decrypt the value".to_string())
+            },
+        }
+    }
+
+    /// Encrypts the values the command writes to protected keys, refusing
+    /// the commands that would corrupt them.
+    fn seal_values(&self, command: Value) -> CommandAction {
+        let name = match command_name(&command) {
+            Some(name) => name,
+            None => return CommandAction::Forward(command),
+        };
+
+        let input = match command {
+            Value::Array(input) => input,
+            command => return CommandAction::Forward(command),
+        };
+
+        if (name == "APPEND" || name == "SETRANGE") && input.get(1).and_then(|key| self.protects(key)).is_some() {
+            return CommandAction::Respond(Value::Error(format!("ERR {} is not supported on encrypted keys", name)));
+        }
+
+        let keys: Vec<(usize, Vec<u8>)> = value_positions(&name, input.len()).into_iter()
+            .filter_map(|(value, key)| self.protects(&input[key]).map(|key| (value, key)))
+            .collect();
+
+        if keys.is_empty() {
+            return CommandAction::Forward(Value::Array(input));
+        }
+
+        let encrypted = input.into_iter().enumerate().map(|(position, argument)| {
+            match keys.iter().find(|&&(value, _)| value == position) {
+                Some(&(_, ref key)) => self.encrypt(key, argument),
+                None => argument,
+            }
+        }).collect();
+
+        CommandAction::Forward(Value::Array(encrypted))
+    }
+}
+
+impl RedisProxy for EncryptionProxy {
+    fn on_command(&mut self, command: Value) -> CommandAction {
+        let reading = self.reading(&command);
+        let action = self.seal_values(command);
+
+        if forwards(&action) {
+            self.readings.push(reading);
+        }
+
+        action
+    }
+
+    fn on_response(&mut self, origin: Origin, response: Value) -> Value {
+        match self.readings.answer(origin) {
+            Some(Some(Reading::Key(key))) => self.decrypt(&key, response),
+            Some(Some(Reading::Keys(keys))) => {
+                match response {
+                    Value::Array(values) => {
+                        Value::Array(values.into_iter().enumerate().map(|(position, value)| {
+                            match keys.get(position) {
+                                Some(&Some(ref key)) => self.decrypt(key, value),
+                                _ => value,
+                            }
+                        }).collect())
+                    },
+                    response => response,
+                }
+            },
+            _ => response,
+        }
+    }
+
+    fn on_unanswered(&mut self) {
+        self.readings.cancel_last();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use connection::redis::{RedisProxy, CommandAction, Origin, ComposedProxy, PrefixProxy, MockKeyspace};
+    use connection::redis::resp::Value;
+    use connection::redis::testing::{Harness, ok};
+    use super::{EncryptionProxy, MAGIC};
+
+    fn command(arguments: &[&str]) -> Value {
+        Value::Array(arguments.iter().map(|argument| Value::Bulk(argument.to_string())).collect())
+    }
+
+    fn forwarded(action: CommandAction) -> Vec<Value> {
+        match action {
+            CommandAction::Forward(Value::Array(input)) => input,
+            _ => panic!("the command should be forwarded"),
+        }
+    }
+
+    #[test]
+    fn values_are_sealed_to_their_key() {
+        let mut proxy = EncryptionProxy::new([7; 32], &["secret:*"]);
+
+        let set = forwarded(proxy.on_command(command(&["SET", "secret:1", "value"])));
+        match set[2] {
+            Value::BufBulk(ref data) => assert!(data.starts_with(MAGIC)),
+            _ => panic!("the value should be encrypted"),
+        }
+        let set = Value::Array(set);
+        proxy.on_response(Origin::Command(&set), Value::String("OK".to_string()));
+        let stored = match set {
+            Value::Array(mut input) => input.remove(2),
+            _ => unreachable!(),
+        };
+
+        let public = forwarded(proxy.on_command(command(&["SET", "public", "value"])));
+        assert_eq!(public[2], Value::Bulk("value".to_string()));
+        proxy.on_response(Origin::Command(&Value::Array(public)), Value::String("OK".to_string()));
+
+        let get = command(&["GET", "secret:1"]);
+        forwarded(proxy.on_command(get.clone()));
+        assert_eq!(proxy.on_response(Origin::Command(&get), stored.clone()), Value::Bulk("value".to_string()));
+
+        let moved = command(&["GET", "secret:2"]);
+        forwarded(proxy.on_command(moved.clone()));
+        assert_eq!(proxy.on_response(Origin::Command(&moved), stored), Value::Error("ERR could not decrypt the value".to_string()));
+
+        match proxy.on_command(command(&["APPEND", "secret:1", "x"])) {
+            CommandAction::Respond(Value::Error(_)) => (),
+            _ => panic!("APPEND should be refused"),
+        }
+    }
+
+    #[test]
+    fn decrypts_keys_renamed_down_the_chain() {
+        let keyspace = MockKeyspace::new();
+        let proxy = ComposedProxy::new(PrefixProxy, EncryptionProxy::new([7; 32], &["secret:*"]));
+        let mut harness = Harness::new(proxy, keyspace.clone());
+
+        assert_eq!(harness.send(&[&["SET", "secret:a", "hello"]]), vec![ok()]);
+
+        let sealed = match keyspace.received().pop() {
+            Some(Value::Array(mut input)) => {
+                assert_eq!(input[1], Value::Bulk("prefix:secret:a".to_string()));
+                input.remove(2)
+            },
+            command => panic!("unexpected command {:?}", command),
+        };
+        assert!(sealed != Value::Bulk("hello".to_string()));
+
+        // Sealed bytes may happen to be valid UTF-8
+        let sealed = match sealed {
+            Value::BufBulk(sealed) => sealed,
+            Value::Bulk(sealed) => sealed.into_bytes(),
+            value => panic!("unexpected value {:?}", value),
+        };
+        keyspace.set(b"prefix:secret:b", &sealed);
+        assert_eq!(harness.send(&[&["GET", "secret:a"], &["GET", "secret:b"]]), vec![
+            Value::Bulk("hello".to_string()),
+            Value::Error("ERR could not decrypt the value".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn binary_key_names_are_protected() {
+        let mut proxy = EncryptionProxy::new([7; 32], &["secret:*"]);
+        let key = b"secret:\xff".to_vec();
+
+        let set = Value::Array(vec![Value::Bulk("SET".to_string()), Value::BufBulk(key.clone()), Value::Bulk("value".to_string())]);
+        let set = forwarded(proxy.on_command(set));
+        let stored = set[2].clone();
+        match stored {
+            Value::BufBulk(ref data) => assert!(data.starts_with(MAGIC)),
+            _ => panic!("the value should be encrypted"),
+        }
+        proxy.on_response(Origin::Command(&Value::Array(set)), Value::String("OK".to_string()));
+
+        let get = Value::Array(vec![Value::Bulk("GET".to_string()), Value::BufBulk(key)]);
+        forwarded(proxy.on_command(get.clone()));
+        assert_eq!(proxy.on_response(Origin::Command(&get), stored), Value::Bulk("value".to_string()));
+    }
+}
@@ -0,0 +1,130 @@
+use connection::redis::{RedisProxy, CommandAction, Origin, command_name};
+use connection::redis::commands::keys;
+use connection::fault;
+use connection::redis::resp::Value;
+
+/// Misbehaviour injected on a matching command.
+#[derive(Clone)]
+pub enum Fault {
+    /// Answer with an error reply instead of forwarding the command.
+    Error(String),
+    /// Hold the command for the given milliseconds before forwarding it.
+    Delay(u64),
+    /// Answer with an error reply after the given milliseconds.
+    DelayedError(u64, String),
+    /// Forward the command but never deliver its reply.
+    DropReply,
+}
+
+impl Fault {
+    pub fn loading() -> Self {
+        Fault::Error("LOADING Redis is loading the dataset in memory".to_string())
+    }
+
+    pub fn busy() -> Self {
+        Fault::Error("BUSY Redis is busy running a script. You can only call SCRIPT KILL or SHUTDOWN NOSCRIPT.".to_string())
+    }
+
+    pub fn oom() -> Self {
+        Fault::Error("OOM command not allowed when used memory > 'maxmemory'.".to_string())
+    }
+
+    pub fn moved(slot: u16, address: &str) -> Self {
+        Fault::Error(format!("MOVED {} {}", slot, address))
+    }
+}
+
+/// Injects a fault on the commands matching a name and a key pattern, with
+/// the given probability.
+pub type FaultRule = fault::Rule<Fault>;
+
+impl FaultRule {
+    /// Only match the command with the given name.
+    pub fn command(self, command: &str) -> Self {
+        self.name(command)
+    }
+
+    /// Only match commands with a key matching the glob-style pattern.
+    pub fn key_pattern(self, pattern: &str) -> Self {
+        self.pattern(pattern)
+    }
+}
+
+/// Injects faults per command, so client retry logic can be exercised on
+/// specific commands and keys instead of on the whole connection. The first
+/// matching rule whose probability check passes wins.
+pub struct FaultProxy {
+    rules: Vec<FaultRule>,
+}
+
+impl FaultProxy {
+    pub fn new(rules: Vec<FaultRule>) -> Self {
+        FaultProxy {
+            rules: rules,
+        }
+    }
+}
+
+impl RedisProxy for FaultProxy {
+    fn on_command(&mut self, command: Value) -> CommandAction {
+        let fault = {
+            let keys = keys(&command);
+            let keys: Vec<&str> = keys.iter().map(|key| &**key).collect();
+            fault::select(&self.rules, command_name(&command).as_ref().map(|name| &**name), &keys)
+        };
+
+        match fault {
+            Some(Fault::Error(message)) => {
+                CommandAction::Respond(Value::Error(message))
+            },
+            Some(Fault::Delay(delay)) => {
+                CommandAction::Delay(delay, Box::new(CommandAction::Forward(command)))
+            },
+            Some(Fault::DelayedError(delay, message)) => {
+                CommandAction::Delay(delay, Box::new(CommandAction::Respond(Value::Error(message))))
+            },
+            Some(Fault::DropReply) => {
+                CommandAction::LoseReply(command)
+            },
+            None => {
+                CommandAction::Forward(command)
+            },
+        }
+    }
+
+    fn on_response(&mut self, _: Origin, response: Value) -> Value {
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use connection::redis::{RedisProxy, CommandAction};
+    use connection::redis::resp::Value;
+    use super::{FaultProxy, FaultRule, Fault};
+
+    fn command(args: &[&str]) -> Value {
+        Value::Array(args.iter().map(|arg| Value::Bulk(arg.to_string())).collect())
+    }
+
+    #[test]
+    fn faults_only_the_matching_commands() {
+        let mut proxy = FaultProxy::new(vec![
+            FaultRule::new(Fault::oom()).command("set").key_pattern("user:*"),
+            FaultRule::new(Fault::DropReply).command("get"),
+        ]);
+
+        match proxy.on_command(command(&["SET", "user:1", "x"])) {
+            CommandAction::Respond(Value::Error(ref message)) if message.starts_with("OOM ") => (),
+            _ => panic!("SET user:1 should be refused"),
+        }
+        match proxy.on_command(command(&["SET", "session:1", "x"])) {
+            CommandAction::Forward(_) => (),
+            _ => panic!("SET session:1 should be forwarded"),
+        }
+        match proxy.on_command(command(&["GET", "user:1"])) {
+            CommandAction::LoseReply(_) => (),
+            _ => panic!("GET user:1 should lose its reply"),
+        }
+    }
+}
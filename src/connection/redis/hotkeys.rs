@@ -0,0 +1,251 @@
+use connection::{Timer, TimerAction};
+use connection::redis::{RedisProxy, CommandAction, Origin, command_name};
+use connection::redis::commands;
+use connection::redis::resp::Value;
+use std::rc::Rc;
+use std::cell::RefCell;
+use rand;
+
+/// Space-Saving sketch: keeps `capacity` counters, handing the smallest one
+/// over to new keys, so the most frequent keys are found in bounded memory.
+struct TopK {
+    capacity: usize,
+    /// Key, count and overestimation of the count.
+    counters: Vec<(String, u64, u64)>,
+}
+
+impl TopK {
+    fn new(capacity: usize) -> Self {
+        TopK {
+            capacity: capacity,
+            counters: Vec::with_capacity(capacity),
+        }
+    }
+
+    fn add(&mut self, key: &str) {
+        if let Some(counter) = self.counters.iter_mut().find(|counter| counter.0 == key) {
+            counter.1 += 1;
+            return;
+        }
+
+        if self.counters.len() < self.capacity {
+            self.counters.push((key.to_string(), 1, 0));
+            return;
+        }
+
+        if let Some(smallest) = self.counters.iter_mut().min_by_key(|counter| counter.1) {
+            let count = smallest.1;
+            *smallest = (key.to_string(), count + 1, count);
+        }
+    }
+
+    fn top(&self) -> Vec<(String, u64, u64)> {
+        let mut top = self.counters.clone();
+        top.sort_by(|a, b| b.1.cmp(&a.1));
+        top
+    }
+}
+
+/// Reply larger than the threshold.
+#[derive(Clone)]
+pub struct BigValue {
+    pub command: String,
+    pub key: String,
+    pub size: usize,
+}
+
+struct HotKeysState {
+    sample_rate: f64,
+    big_value_size: usize,
+    keys: TopK,
+    /// Largest replies, largest first.
+    big_values: Vec<BigValue>,
+    interval: u64,
+}
+
+/// Hot keys and big values seen by every `HotKeyProxy` sharing it. Register
+/// it as a `Timer` to log a report, and start over, every interval.
+#[derive(Clone)]
+pub struct HotKeys {
+    state: Rc<RefCell<HotKeysState>>,
+}
+
+impl HotKeys {
+    /// Tracks the `top` hottest keys out of a `sample_rate` share of the
+    /// commands, and replies of at least `big_value_size` bytes, reporting
+    /// every `interval` milliseconds.
+    pub fn new(top: usize, sample_rate: f64, big_value_size: usize, interval: u64) -> Self {
+        HotKeys {
+            state: Rc::new(RefCell::new(HotKeysState {
+                sample_rate: sample_rate,
+                big_value_size: big_value_size,
+                keys: TopK::new(top),
+                big_values: Vec::new(),
+                interval: interval,
+            })),
+        }
+    }
+
+    /// Hottest keys with their estimated number of commands, hottest first.
+    pub fn hot_keys(&self) -> Vec<(String, u64)> {
+        let state = self.state.borrow();
+        state.keys.top().into_iter()
+            .map(|(key, count, _)| (key, (count as f64 / state.sample_rate) as u64))
+            .collect()
+    }
+
+    pub fn big_values(&self) -> Vec<BigValue> {
+        self.state.borrow().big_values.clone()
+    }
+
+    fn sample(&self, key: &str) {
+        let mut state = self.state.borrow_mut();
+        if state.sample_rate >= 1.0 || rand::random::<f64>() < state.sample_rate {
+            state.keys.add(key);
+        }
+    }
+
+    fn check_size(&self, command: &str, key: &str, size: usize) {
+        let mut state = self.state.borrow_mut();
+        if size < state.big_value_size {
+            return;
+        }
+
+        let capacity = state.keys.capacity;
+        let big_values = &mut state.big_values;
+
+        if let Some(known) = big_values.iter_mut().find(|big_value| big_value.key == key && big_value.command == command) {
+            known.size = size;
+            return;
+        }
+
+        big_values.push(BigValue {
+            command: command.to_string(),
+            key: key.to_string(),
+            size: size,
+        });
+        big_values.sort_by(|a, b| b.size.cmp(&a.size));
+        big_values.truncate(capacity);
+    }
+}
+
+impl Timer for HotKeys {
+    fn handle_timer(&mut self) -> TimerAction {
+        for (key, count) in self.hot_keys() {
+            info!("Hot key: {} (~{} commands)", key, count);
+        }
+
+        for big_value in self.big_values() {
+            info!("Big value: {} {} ({} bytes)", big_value.command, big_value.key, big_value.size);
+        }
+
+        let mut state = self.state.borrow_mut();
+        state.keys = TopK::new(state.keys.capacity);
+        state.big_values.clear();
+
+        TimerAction::Continue
+    }
+
+    fn get_frequency(&self) -> u64 {
+        self.state.borrow().interval
+    }
+}
+
+/// Bytes of data in a reply, without the protocol overhead.
+fn payload_size(value: &Value) -> usize {
+    match *value {
+        Value::String(ref data) | Value::Error(ref data) | Value::Bulk(ref data) |
+        Value::Double(ref data) | Value::BigNumber(ref data) | Value::BulkError(ref data) |
+        Value::Verbatim(_, ref data) => data.len(),
+        Value::BufBulk(ref data) => data.len(),
+        Value::Integer(_) => 8,
+        Value::Boolean(_) => 1,
+        Value::Null | Value::NullArray | Value::Nil => 0,
+        Value::Array(ref values) | Value::Set(ref values) | Value::Push(ref values) => {
+            values.iter().map(payload_size).sum()
+        },
+        Value::Map(ref pairs) => pairs.iter().map(|&(ref key, ref value)| payload_size(key) + payload_size(value)).sum(),
+        Value::Attribute(_, ref value) => payload_size(value),
+    }
+}
+
+/// Samples the keys of the commands into `HotKeys`, and reports the replies
+/// larger than its threshold, to find what makes Redis busy without running
+/// `MONITOR`.
+pub struct HotKeyProxy {
+    hot_keys: HotKeys,
+}
+
+impl HotKeyProxy {
+    pub fn new(hot_keys: HotKeys) -> Self {
+        HotKeyProxy {
+            hot_keys: hot_keys,
+        }
+    }
+}
+
+impl RedisProxy for HotKeyProxy {
+    fn on_command(&mut self, command: Value) -> CommandAction {
+        for key in commands::keys(&command) {
+            self.hot_keys.sample(key);
+        }
+
+        CommandAction::Forward(command)
+    }
+
+    fn on_response(&mut self, origin: Origin, response: Value) -> Value {
+        if let Origin::Command(command) = origin {
+            if let Some(key) = commands::keys(command).first() {
+                let name = command_name(command).unwrap_or(String::new());
+                self.hot_keys.check_size(&name, key, payload_size(&response));
+            }
+        }
+
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use connection::Timer;
+    use connection::redis::{RedisProxy, Origin};
+    use connection::redis::resp::Value;
+    use super::{HotKeys, HotKeyProxy, TopK};
+
+    fn command(arguments: &[&str]) -> Value {
+        Value::Array(arguments.iter().map(|argument| Value::Bulk(argument.to_string())).collect())
+    }
+
+    #[test]
+    fn new_keys_take_over_the_smallest_counter() {
+        let mut keys = TopK::new(2);
+        for key in ["a", "a", "a", "b", "c"].iter() {
+            keys.add(key);
+        }
+
+        assert_eq!(keys.top(), vec![("a".to_string(), 3, 0), ("c".to_string(), 2, 1)]);
+    }
+
+    #[test]
+    fn reports_hot_keys_and_big_values() {
+        let mut hot_keys = HotKeys::new(2, 1.0, 10, 1000);
+        let mut proxy = HotKeyProxy::new(hot_keys.clone());
+
+        for key in ["user:1", "user:1", "user:2"].iter() {
+            let get = command(&["GET", key]);
+            proxy.on_command(get.clone());
+            proxy.on_response(Origin::Command(&get), Value::Bulk("small".to_string()));
+        }
+        let get = command(&["GET", "blob"]);
+        proxy.on_response(Origin::Command(&get), Value::Bulk("0123456789".to_string()));
+
+        assert_eq!(hot_keys.hot_keys(), vec![("user:1".to_string(), 2), ("user:2".to_string(), 1)]);
+        let big_values = hot_keys.big_values();
+        assert_eq!(big_values.len(), 1);
+        assert_eq!((&*big_values[0].command, &*big_values[0].key, big_values[0].size), ("GET", "blob", 10));
+
+        hot_keys.handle_timer();
+        assert!(hot_keys.hot_keys().is_empty());
+        assert!(hot_keys.big_values().is_empty());
+    }
+}
@@ -0,0 +1,133 @@
+//! Consistent hashing compatible with twemproxy's `ketama` distribution and
+//! its default `fnv1a_64` hash, so keys land on the same servers as they
+//! would behind twemproxy.
+
+use md5;
+
+const POINTS_PER_SERVER: u32 = 160;
+const POINTS_PER_HASH: u32 = 4;
+
+pub struct Continuum {
+    points: Vec<(u32, usize)>,
+}
+
+impl Continuum {
+    /// Builds the continuum from the name (usually `host:port`) and weight
+    /// of each server. Servers are referred by their position on the slice.
+    pub fn new(servers: &[(String, u32)]) -> Self {
+        let total_weight: u32 = servers.iter().map(|&(_, weight)| weight).sum();
+        let mut points = Vec::new();
+
+        for (index, &(ref name, weight)) in servers.iter().enumerate() {
+            if weight == 0 {
+                continue;
+            }
+
+            let percentage = weight as f64 / total_weight as f64;
+            let server_points = (percentage * POINTS_PER_SERVER as f64 / POINTS_PER_HASH as f64 * servers.len() as f64 + 0.0000000001).floor() as u32 * POINTS_PER_HASH;
+
+            for pointer in 0..server_points / POINTS_PER_HASH {
+                let digest = md5::compute(format!("{}-{}", name, pointer).as_bytes());
+                for alignment in 0..POINTS_PER_HASH as usize {
+                    let value = (digest[3 + alignment * 4] as u32) << 24 |
+                        (digest[2 + alignment * 4] as u32) << 16 |
+                        (digest[1 + alignment * 4] as u32) << 8 |
+                        (digest[alignment * 4] as u32);
+
+                    points.push((value, index));
+                }
+            }
+        }
+
+        points.sort();
+
+        Continuum {
+            points: points,
+        }
+    }
+
+    /// Position of the server owning the given key.
+    pub fn server_for(&self, key: &[u8]) -> usize {
+        self.server_for_hash(fnv1a_64(key))
+    }
+
+    fn server_for_hash(&self, hash: u32) -> usize {
+        if self.points.is_empty() {
+            return 0;
+        }
+
+        // First point not lower than the hash
+        let (mut left, mut right) = (0, self.points.len());
+        while left < right {
+            let middle = left + (right - left) / 2;
+            if self.points[middle].0 < hash {
+                left = middle + 1;
+            } else {
+                right = middle;
+            }
+        }
+
+        let position = right;
+
+        if position == self.points.len() {
+            self.points[0].1
+        } else {
+            self.points[position].1
+        }
+    }
+}
+
+/// twemproxy's `fnv1a_64`, which truncates the 64 bits parameters to 32 bits.
+pub fn fnv1a_64(key: &[u8]) -> u32 {
+    let mut hash = 0xcbf29ce484222325u64 as u32;
+
+    for byte in key {
+        hash ^= *byte as u32;
+        hash = hash.wrapping_mul(0x100000001b3u64 as u32);
+    }
+
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Continuum, fnv1a_64};
+
+    #[test]
+    fn fnv1a_64_is_truncated_to_32_bits() {
+        assert_eq!(fnv1a_64(b""), 0x84222325);
+        assert_eq!(fnv1a_64(b"foo"), 0xfed9d577);
+    }
+
+    #[test]
+    fn servers_without_weight_get_no_keys() {
+        let continuum = Continuum::new(&[
+            ("10.0.0.1:6379".to_string(), 1),
+            ("10.0.0.2:6379".to_string(), 0),
+        ]);
+
+        assert_eq!(continuum.points.len(), 320);
+        for key in 0..100 {
+            assert_eq!(continuum.server_for(format!("key:{}", key).as_bytes()), 0);
+        }
+    }
+
+    #[test]
+    fn adding_a_server_only_moves_keys_to_it() {
+        let before = Continuum::new(&[
+            ("10.0.0.1:6379".to_string(), 1),
+            ("10.0.0.2:6379".to_string(), 1),
+        ]);
+        let after = Continuum::new(&[
+            ("10.0.0.1:6379".to_string(), 1),
+            ("10.0.0.2:6379".to_string(), 1),
+            ("10.0.0.3:6379".to_string(), 1),
+        ]);
+
+        for key in 0..1000 {
+            let key = format!("key:{}", key);
+            let server = after.server_for(key.as_bytes());
+            assert!(server == 2 || server == before.server_for(key.as_bytes()));
+        }
+    }
+}
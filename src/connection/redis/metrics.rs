@@ -0,0 +1,310 @@
+use connection::redis::{RedisProxy, CommandAction, Origin, InFlight, command_name, proxy_subcommand, elapsed_micros};
+use connection::redis::commands;
+use connection::redis::resp::Value;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+/// Upper bounds of the latency buckets, in microseconds.
+const BUCKETS: [u64; 13] = [100, 250, 500, 1000, 2500, 5000, 10000, 25000, 50000, 100000, 250000, 500000, 1000000];
+
+#[derive(Clone)]
+struct Histogram {
+    /// One count per bucket, plus the one above the last bound.
+    counts: Vec<u64>,
+    sum: u64,
+}
+
+impl Histogram {
+    fn new() -> Self {
+        Histogram {
+            counts: vec![0; BUCKETS.len() + 1],
+            sum: 0,
+        }
+    }
+
+    fn record(&mut self, micros: u64) {
+        let bucket = BUCKETS.iter().position(|&bound| micros <= bound).unwrap_or(BUCKETS.len());
+        self.counts[bucket] += 1;
+        self.sum += micros;
+    }
+
+    fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Upper bound of the bucket holding the given quantile.
+    fn quantile(&self, quantile: f64) -> u64 {
+        let target = (self.count() as f64 * quantile).ceil() as u64;
+        let mut seen = 0;
+
+        for (bucket, count) in self.counts.iter().enumerate() {
+            seen += *count;
+            if seen >= target && seen > 0 {
+                return BUCKETS.get(bucket).cloned().unwrap_or(u64::max_value());
+            }
+        }
+
+        0
+    }
+}
+
+#[derive(Clone)]
+struct CommandStats {
+    calls: u64,
+    errors: u64,
+    request_bytes: u64,
+    response_bytes: u64,
+    latency: Histogram,
+}
+
+impl CommandStats {
+    fn new() -> Self {
+        CommandStats {
+            calls: 0,
+            errors: 0,
+            request_bytes: 0,
+            response_bytes: 0,
+            latency: Histogram::new(),
+        }
+    }
+}
+
+/// Statistics recorded by `MetricsProxy`, per command name and key prefix.
+/// Clones share the same statistics, so they cover every client connection.
+#[derive(Clone)]
+pub struct RedisMetrics {
+    stats: Rc<RefCell<BTreeMap<(String, Option<String>), CommandStats>>>,
+}
+
+impl RedisMetrics {
+    pub fn new() -> Self {
+        RedisMetrics {
+            stats: Rc::new(RefCell::new(BTreeMap::new())),
+        }
+    }
+
+    pub fn reset(&self) {
+        self.stats.borrow_mut().clear();
+    }
+
+    fn record(&self, name: String, prefix: Option<String>, request_bytes: usize, response: &Value, micros: u64) {
+        let mut stats = self.stats.borrow_mut();
+        let command = stats.entry((name, prefix)).or_insert_with(CommandStats::new);
+
+        command.calls += 1;
+        if response.is_error() {
+            command.errors += 1;
+        }
+        command.request_bytes += request_bytes as u64;
+        command.response_bytes += response.encode().len() as u64;
+        command.latency.record(micros);
+    }
+
+    /// Statistics in the Prometheus text format, as served by
+    /// `PROXY METRICS`.
+    pub fn render(&self) -> String {
+        let mut output = String::new();
+        let stats = self.stats.borrow();
+
+        output.push_str("# TYPE redis_proxy_commands_total counter\n");
+        for (&(ref name, ref prefix), command) in stats.iter() {
+            output.push_str(&format!("redis_proxy_commands_total{{{}}} {}\n", labels(name, prefix), command.calls));
+        }
+
+        output.push_str("# TYPE redis_proxy_errors_total counter\n");
+        for (&(ref name, ref prefix), command) in stats.iter() {
+            output.push_str(&format!("redis_proxy_errors_total{{{}}} {}\n", labels(name, prefix), command.errors));
+        }
+
+        output.push_str("# TYPE redis_proxy_request_bytes_total counter\n");
+        for (&(ref name, ref prefix), command) in stats.iter() {
+            output.push_str(&format!("redis_proxy_request_bytes_total{{{}}} {}\n", labels(name, prefix), command.request_bytes));
+        }
+
+        output.push_str("# TYPE redis_proxy_response_bytes_total counter\n");
+        for (&(ref name, ref prefix), command) in stats.iter() {
+            output.push_str(&format!("redis_proxy_response_bytes_total{{{}}} {}\n", labels(name, prefix), command.response_bytes));
+        }
+
+        output.push_str("# TYPE redis_proxy_latency_seconds histogram\n");
+        for (&(ref name, ref prefix), command) in stats.iter() {
+            let labels = labels(name, prefix);
+            let mut cumulative = 0;
+
+            for (bucket, count) in command.latency.counts.iter().enumerate() {
+                cumulative += *count;
+                let bound = match BUCKETS.get(bucket) {
+                    Some(bound) => format!("{}", *bound as f64 / 1000000.0),
+                    None => "+Inf".to_string(),
+                };
+                output.push_str(&format!("redis_proxy_latency_seconds_bucket{{{},le=\"{}\"}} {}\n", labels, bound, cumulative));
+            }
+
+            output.push_str(&format!("redis_proxy_latency_seconds_sum{{{}}} {}\n", labels, command.latency.sum as f64 / 1000000.0));
+            output.push_str(&format!("redis_proxy_latency_seconds_count{{{}}} {}\n", labels, command.latency.count()));
+        }
+
+        output
+    }
+
+    /// Statistics in the way of `INFO commandstats`.
+    pub fn info(&self) -> String {
+        let mut output = "# Commandstats\r\n".to_string();
+
+        for (&(ref name, ref prefix), command) in self.stats.borrow().iter() {
+            let name = match *prefix {
+                Some(ref prefix) => format!("{}|{}", name.to_lowercase(), prefix),
+                None => name.to_lowercase(),
+            };
+
+            output.push_str(&format!(
+                "cmdstat_{}:calls={},failed_calls={},usec={},usec_per_call={:.2},request_bytes={},response_bytes={},p50={},p99={}\r\n",
+                name,
+                command.calls,
+                command.errors,
+                command.latency.sum,
+                command.latency.sum as f64 / command.calls as f64,
+                command.request_bytes,
+                command.response_bytes,
+                command.latency.quantile(0.5),
+                command.latency.quantile(0.99)
+            ));
+        }
+
+        output
+    }
+}
+
+fn labels(name: &str, prefix: &Option<String>) -> String {
+    match *prefix {
+        Some(ref prefix) => format!("command=\"{}\",prefix=\"{}\"", name, prefix.replace('\\', "\\\\").replace('"', "\\\"")),
+        None => format!("command=\"{}\"", name),
+    }
+}
+
+struct Pending {
+    name: String,
+    prefix: Option<String>,
+    request_bytes: usize,
+    started: Instant,
+}
+
+/// Records the calls, errors, sizes and latency of every command, and
+/// answers `PROXY STATS` (and `PROXY STATS RESET`) with them, or
+/// `PROXY METRICS` in the Prometheus text format.
+pub struct MetricsProxy {
+    metrics: RedisMetrics,
+    /// Splits the first key to group the statistics by prefix, if set.
+    separator: Option<char>,
+    pending: InFlight<Option<Pending>>,
+}
+
+impl MetricsProxy {
+    pub fn new(metrics: RedisMetrics) -> Self {
+        MetricsProxy {
+            metrics: metrics,
+            separator: None,
+            pending: InFlight::new(),
+        }
+    }
+
+    /// Groups the statistics by the part of the first key before
+    /// `separator`, as in `user` for `user:1000`.
+    pub fn by_key_prefix(mut self, separator: char) -> Self {
+        self.separator = Some(separator);
+        self
+    }
+
+    fn prefix_of(&self, command: &Value) -> Option<String> {
+        let separator = match self.separator {
+            Some(separator) => separator,
+            None => return None,
+        };
+
+        commands::keys(command).first().map(|key| {
+            match key.find(separator) {
+                Some(position) => key[0..position].to_string(),
+                None => String::new(),
+            }
+        })
+    }
+}
+
+impl RedisProxy for MetricsProxy {
+    fn on_command(&mut self, command: Value) -> CommandAction {
+        if let Some(arguments) = proxy_subcommand(&command) {
+            match arguments.first().map(|subcommand| &**subcommand) {
+                Some("STATS") => {
+                    if arguments.get(1).map(|argument| argument == "RESET").unwrap_or(false) {
+                        self.metrics.reset();
+                        return CommandAction::Respond(Value::String("OK".to_string()));
+                    }
+
+                    return CommandAction::Respond(Value::Bulk(self.metrics.info()));
+                },
+                Some("METRICS") => return CommandAction::Respond(Value::Bulk(self.metrics.render())),
+                _ => (),
+            }
+        }
+
+        let pending = command_name(&command).map(|name| {
+            Pending {
+                name: name,
+                prefix: self.prefix_of(&command),
+                request_bytes: command.encode().len(),
+                started: Instant::now(),
+            }
+        });
+        self.pending.push(pending);
+
+        CommandAction::Forward(command)
+    }
+
+    fn on_response(&mut self, origin: Origin, response: Value) -> Value {
+        if let Some(Some(pending)) = self.pending.answer(origin) {
+            let micros = elapsed_micros(pending.started);
+            self.metrics.record(pending.name, pending.prefix, pending.request_bytes, &response, micros);
+        }
+
+        response
+    }
+
+    fn on_unanswered(&mut self) {
+        self.pending.cancel_last();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use connection::redis::{ComposedProxy, RenameProxy, MockKeyspace};
+    use connection::redis::resp::Value;
+    use connection::redis::testing::{Harness, ok};
+    use super::{MetricsProxy, RedisMetrics};
+    use std::collections::HashMap;
+
+    #[test]
+    fn commands_answered_down_the_chain_are_not_recorded() {
+        let metrics = RedisMetrics::new();
+        let mut renames = HashMap::new();
+        renames.insert("FLUSHALL".to_string(), String::new());
+        let proxy = ComposedProxy::new(RenameProxy::new(renames), MetricsProxy::new(metrics.clone()));
+        let mut harness = Harness::new(proxy, MockKeyspace::new());
+
+        let replies = harness.send(&[&["FLUSHALL"], &["SET", "a", "1"], &["FLUSHALL"], &["GET", "a"]]);
+
+        assert_eq!(replies[1], ok());
+        assert_eq!(replies[3], Value::Bulk("1".to_string()));
+
+        let info = metrics.info();
+        assert!(!info.contains("cmdstat_flushall"));
+        assert!(info.contains("cmdstat_set:calls=1,failed_calls=0"));
+        assert!(info.contains("cmdstat_get:calls=1,failed_calls=0"));
+
+        match harness.send(&[&["PROXY", "METRICS"]]).pop() {
+            Some(Value::Bulk(metrics)) => assert!(metrics.contains("redis_proxy_commands_total{command=\"GET\"} 1\n")),
+            reply => panic!("unexpected reply {:?}", reply),
+        }
+    }
+}
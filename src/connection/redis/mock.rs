@@ -0,0 +1,662 @@
+use mio::{Token, Evented, EventSet};
+use mio::unix::{pipe, PipeReader, PipeWriter};
+use connection::Connection;
+use connection::ConnectionAction;
+use connection::glob;
+use connection::redis::resp::{Decoder, Value};
+use std::io;
+use std::io::Read;
+use std::cmp::min;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+use std::ascii::AsciiExt;
+use netbuf::Buf;
+
+const WRONGTYPE: &'static str = "WRONGTYPE Operation against a key holding the wrong kind of value";
+
+enum Data {
+    String(Vec<u8>),
+    Hash(HashMap<Vec<u8>, Vec<u8>>),
+    List(VecDeque<Vec<u8>>),
+    Set(HashSet<Vec<u8>>),
+}
+
+struct Entry {
+    data: Data,
+    expires: Option<Instant>,
+}
+
+struct KeyspaceState {
+    entries: HashMap<Vec<u8>, Entry>,
+    /// Replies given instead of running the command, by command name.
+    canned: HashMap<String, VecDeque<Value>>,
+    received: Vec<Value>,
+}
+
+/// In-memory data of `MockRedis`. Clones share the same data, so tests can
+/// fill it and look at it while the proxy runs, and several connections can
+/// see the same keys.
+#[derive(Clone)]
+pub struct MockKeyspace {
+    state: Rc<RefCell<KeyspaceState>>,
+}
+
+impl MockKeyspace {
+    pub fn new() -> Self {
+        MockKeyspace {
+            state: Rc::new(RefCell::new(KeyspaceState {
+                entries: HashMap::new(),
+                canned: HashMap::new(),
+                received: Vec::new(),
+            })),
+        }
+    }
+
+    /// Answers the next `command` with `reply` instead of running it. Replies
+    /// queued for the same command are given in order.
+    pub fn reply_to(&self, command: &str, reply: Value) {
+        self.state.borrow_mut().canned.entry(command.to_ascii_uppercase()).or_insert_with(VecDeque::new).push_back(reply);
+    }
+
+    /// Commands received so far, in order.
+    pub fn received(&self) -> Vec<Value> {
+        self.state.borrow().received.clone()
+    }
+
+    pub fn set(&self, key: &[u8], value: &[u8]) {
+        self.state.borrow_mut().entries.insert(key.to_vec(), Entry {
+            data: Data::String(value.to_vec()),
+            expires: None,
+        });
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let mut state = self.state.borrow_mut();
+        match live(&mut state.entries, key) {
+            Some(&mut Entry { data: Data::String(ref value), .. }) => Some(value.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn flush(&self) {
+        self.state.borrow_mut().entries.clear();
+    }
+
+    fn execute(&self, command: Value) -> Value {
+        let mut state = self.state.borrow_mut();
+        state.received.push(command.clone());
+
+        let arguments: Vec<Vec<u8>> = match command {
+            Value::Array(ref input) => input.iter().map(|argument| {
+                match *argument {
+                    Value::Bulk(ref argument) => argument.clone().into_bytes(),
+                    Value::BufBulk(ref argument) => argument.clone(),
+                    ref argument => argument.to_beautify_string().into_bytes(),
+                }
+            }).collect(),
+            _ => return Value::Error("ERR Protocol error: expected an array of bulk strings".to_string()),
+        };
+
+        if arguments.is_empty() {
+            return Value::Error("ERR empty command".to_string());
+        }
+
+        let name = String::from_utf8_lossy(&arguments[0]).to_ascii_uppercase();
+        if let Some(reply) = state.canned.get_mut(&name).and_then(|replies| replies.pop_front()) {
+            return reply;
+        }
+
+        run(&mut state.entries, &name, &arguments[1..])
+    }
+}
+
+/// Entry of the key, once expired entries are gone.
+fn live<'a>(entries: &'a mut HashMap<Vec<u8>, Entry>, key: &[u8]) -> Option<&'a mut Entry> {
+    let expired = match entries.get(key) {
+        Some(&Entry { expires: Some(expires), .. }) => expires <= Instant::now(),
+        _ => false,
+    };
+
+    if expired {
+        entries.remove(key);
+    }
+
+    entries.get_mut(key)
+}
+
+fn ok() -> Value {
+    Value::String("OK".to_string())
+}
+
+/// Bulk string reply, binary when it is not UTF-8, as the decoder gives it.
+fn bytes(value: Vec<u8>) -> Value {
+    match String::from_utf8(value) {
+        Ok(value) => Value::Bulk(value),
+        Err(error) => Value::BufBulk(error.into_bytes()),
+    }
+}
+
+fn bulk(value: Option<Vec<u8>>) -> Value {
+    match value {
+        Some(value) => bytes(value),
+        None => Value::Null,
+    }
+}
+
+fn bulks<I: Iterator<Item = Vec<u8>>>(values: I) -> Value {
+    Value::Array(values.map(bytes).collect())
+}
+
+fn wrong_arity(name: &str) -> Value {
+    Value::Error(format!("ERR wrong number of arguments for '{}' command", name.to_ascii_lowercase()))
+}
+
+fn parse_integer(value: &[u8]) -> Result<i64, Value> {
+    String::from_utf8_lossy(value).parse().map_err(|_| Value::Error("ERR value is not an integer or out of range".to_string()))
+}
+
+/// Arguments each command needs, without the name: exact, or at least.
+fn arity(name: &str) -> Option<(usize, bool)> {
+    let arity = match name {
+        "PING" | "DBSIZE" | "FLUSHDB" | "FLUSHALL" | "QUIT" => (0, false),
+        "ECHO" | "GET" | "INCR" | "DECR" | "STRLEN" | "TTL" | "PTTL" | "PERSIST" | "TYPE" | "KEYS" |
+        "SELECT" | "HGETALL" | "HLEN" | "HKEYS" | "HVALS" | "LPOP" | "RPOP" | "LLEN" |
+        "SMEMBERS" | "SCARD" => (1, false),
+        "APPEND" | "INCRBY" | "DECRBY" | "EXPIRE" | "PEXPIRE" | "HGET" | "HEXISTS" | "LINDEX" | "SISMEMBER" => (2, false),
+        "HINCRBY" | "LRANGE" => (3, false),
+        "DEL" | "EXISTS" | "MGET" | "AUTH" => (1, true),
+        "SET" | "MSET" | "HDEL" | "HMGET" | "LPUSH" | "RPUSH" | "SADD" | "SREM" => (2, true),
+        "HSET" => (3, true),
+        _ => return None,
+    };
+
+    Some(arity)
+}
+
+fn run(entries: &mut HashMap<Vec<u8>, Entry>, name: &str, arguments: &[Vec<u8>]) -> Value {
+    let (count, at_least) = match arity(name) {
+        Some(arity) => arity,
+        None => return Value::Error(format!("ERR unknown command '{}'", name.to_ascii_lowercase())),
+    };
+
+    if arguments.len() < count || (!at_least && arguments.len() != count) {
+        return wrong_arity(name);
+    }
+
+    match run_checked(entries, name, arguments) {
+        Ok(reply) => reply,
+        Err(error) => error,
+    }
+}
+
+fn run_checked(entries: &mut HashMap<Vec<u8>, Entry>, name: &str, arguments: &[Vec<u8>]) -> Result<Value, Value> {
+    let reply = match name {
+        "PING" => Value::String("PONG".to_string()),
+        "ECHO" => bytes(arguments[0].clone()),
+        "QUIT" | "AUTH" => ok(),
+        "SELECT" => {
+            match &arguments[0][..] {
+                b"0" => ok(),
+                _ => Value::Error("ERR DB index is out of range".to_string()),
+            }
+        },
+        "DBSIZE" => {
+            let keys: Vec<Vec<u8>> = entries.keys().cloned().collect();
+            Value::Integer(keys.iter().filter(|key| live(entries, key).is_some()).count() as i64)
+        },
+        "FLUSHDB" | "FLUSHALL" => {
+            entries.clear();
+            ok()
+        },
+        "KEYS" => {
+            let pattern = String::from_utf8_lossy(&arguments[0]).into_owned();
+            let keys: Vec<Vec<u8>> = entries.keys().cloned().collect();
+            let mut matching: Vec<Vec<u8>> = keys.into_iter()
+                .filter(|key| glob::matches(&pattern, &String::from_utf8_lossy(key)) && live(entries, key).is_some())
+                .collect();
+            matching.sort();
+            bulks(matching.into_iter())
+        },
+        "DEL" => {
+            Value::Integer(arguments.iter().filter(|key| live(entries, key).is_some() && entries.remove(&**key).is_some()).count() as i64)
+        },
+        "EXISTS" => {
+            Value::Integer(arguments.iter().filter(|key| live(entries, key).is_some()).count() as i64)
+        },
+        "TYPE" => {
+            let kind = match live(entries, &arguments[0]) {
+                Some(&mut Entry { data: Data::String(_), .. }) => "string",
+                Some(&mut Entry { data: Data::Hash(_), .. }) => "hash",
+                Some(&mut Entry { data: Data::List(_), .. }) => "list",
+                Some(&mut Entry { data: Data::Set(_), .. }) => "set",
+                None => "none",
+            };
+            Value::String(kind.to_string())
+        },
+        "EXPIRE" | "PEXPIRE" => {
+            let amount = try!(parse_integer(&arguments[1]));
+            let duration = if name == "EXPIRE" {
+                Duration::from_secs(amount.max(0) as u64)
+            } else {
+                Duration::from_millis(amount.max(0) as u64)
+            };
+
+            match live(entries, &arguments[0]) {
+                Some(entry) => {
+                    entry.expires = Some(Instant::now() + duration);
+                    Value::Integer(1)
+                },
+                None => Value::Integer(0),
+            }
+        },
+        "TTL" | "PTTL" => {
+            match live(entries, &arguments[0]) {
+                Some(&mut Entry { expires: Some(expires), .. }) => {
+                    let left = expires.duration_since(Instant::now());
+                    let millis = left.as_secs() * 1000 + (left.subsec_nanos() / 1000000) as u64;
+                    Value::Integer(if name == "TTL" { ((millis + 999) / 1000) as i64 } else { millis as i64 })
+                },
+                Some(_) => Value::Integer(-1),
+                None => Value::Integer(-2),
+            }
+        },
+        "PERSIST" => {
+            match live(entries, &arguments[0]) {
+                Some(ref mut entry) if entry.expires.is_some() => {
+                    entry.expires = None;
+                    Value::Integer(1)
+                },
+                _ => Value::Integer(0),
+            }
+        },
+        "GET" => bulk(try!(string(entries, &arguments[0])).map(|value| value.clone())),
+        "MGET" => {
+            Value::Array(arguments.iter().map(|key| {
+                match live(entries, key) {
+                    Some(&mut Entry { data: Data::String(ref value), .. }) => bytes(value.clone()),
+                    _ => Value::Null,
+                }
+            }).collect())
+        },
+        "SET" => {
+            let mut expires = None;
+            let mut condition = None;
+            let mut options = arguments[2..].iter();
+
+            while let Some(option) = options.next() {
+                match &*String::from_utf8_lossy(option).to_ascii_uppercase() {
+                    option @ "EX" | option @ "PX" => {
+                        let amount = try!(parse_integer(try!(options.next().ok_or(Value::Error("ERR syntax error".to_string())))));
+                        if amount <= 0 {
+                            return Err(Value::Error("ERR invalid expire time in 'set' command".to_string()));
+                        }
+                        let duration = if option == "EX" { Duration::from_secs(amount as u64) } else { Duration::from_millis(amount as u64) };
+                        expires = Some(Instant::now() + duration);
+                    },
+                    option @ "NX" | option @ "XX" => condition = Some(option == "NX"),
+                    _ => return Err(Value::Error("ERR syntax error".to_string())),
+                }
+            }
+
+            let exists = live(entries, &arguments[0]).is_some();
+            match condition {
+                Some(true) if exists => return Ok(Value::Null),
+                Some(false) if !exists => return Ok(Value::Null),
+                _ => (),
+            }
+
+            entries.insert(arguments[0].clone(), Entry {
+                data: Data::String(arguments[1].clone()),
+                expires: expires,
+            });
+            ok()
+        },
+        "MSET" => {
+            if arguments.len() % 2 != 0 {
+                return Err(wrong_arity(name));
+            }
+
+            for pair in arguments.chunks(2) {
+                entries.insert(pair[0].clone(), Entry {
+                    data: Data::String(pair[1].clone()),
+                    expires: None,
+                });
+            }
+            ok()
+        },
+        "APPEND" => {
+            let value = try!(string_or_insert(entries, &arguments[0]));
+            value.extend_from_slice(&arguments[1]);
+            Value::Integer(value.len() as i64)
+        },
+        "STRLEN" => Value::Integer(try!(string(entries, &arguments[0])).map(|value| value.len()).unwrap_or(0) as i64),
+        "INCR" | "DECR" | "INCRBY" | "DECRBY" => {
+            let amount = match name {
+                "INCR" => 1,
+                "DECR" => -1,
+                "INCRBY" => try!(parse_integer(&arguments[1])),
+                _ => -try!(parse_integer(&arguments[1])),
+            };
+
+            let value = try!(string_or_insert(entries, &arguments[0]));
+            let current = if value.is_empty() { 0 } else { try!(parse_integer(value)) };
+            let next = try!(current.checked_add(amount).ok_or(Value::Error("ERR increment or decrement would overflow".to_string())));
+            *value = next.to_string().into_bytes();
+            Value::Integer(next)
+        },
+        "HSET" => {
+            if arguments.len() % 2 != 1 {
+                return Err(wrong_arity(name));
+            }
+
+            let hash = try!(hash_or_insert(entries, &arguments[0]));
+            let added = arguments[1..].chunks(2).filter(|pair| hash.insert(pair[0].clone(), pair[1].clone()).is_none()).count();
+            Value::Integer(added as i64)
+        },
+        "HGET" => bulk(try!(hash(entries, &arguments[0])).and_then(|hash| hash.get(&arguments[1]).cloned())),
+        "HMGET" => {
+            let hash = try!(hash(entries, &arguments[0]));
+            Value::Array(arguments[1..].iter().map(|field| bulk(hash.as_ref().and_then(|hash| hash.get(field).cloned()))).collect())
+        },
+        "HDEL" => {
+            let removed = match try!(hash(entries, &arguments[0])) {
+                Some(hash) => arguments[1..].iter().filter(|field| hash.remove(&**field).is_some()).count(),
+                None => 0,
+            };
+            remove_if_empty(entries, &arguments[0]);
+            Value::Integer(removed as i64)
+        },
+        "HEXISTS" => Value::Integer(try!(hash(entries, &arguments[0])).map(|hash| hash.contains_key(&arguments[1]) as i64).unwrap_or(0)),
+        "HLEN" => Value::Integer(try!(hash(entries, &arguments[0])).map(|hash| hash.len()).unwrap_or(0) as i64),
+        "HGETALL" | "HKEYS" | "HVALS" => {
+            let mut fields: Vec<(Vec<u8>, Vec<u8>)> = try!(hash(entries, &arguments[0]))
+                .map(|hash| hash.iter().map(|(field, value)| (field.clone(), value.clone())).collect())
+                .unwrap_or(Vec::new());
+            fields.sort();
+
+            match name {
+                "HKEYS" => bulks(fields.into_iter().map(|(field, _)| field)),
+                "HVALS" => bulks(fields.into_iter().map(|(_, value)| value)),
+                _ => bulks(fields.into_iter().flat_map(|(field, value)| vec![field, value])),
+            }
+        },
+        "HINCRBY" => {
+            let amount = try!(parse_integer(&arguments[2]));
+            let hash = try!(hash_or_insert(entries, &arguments[0]));
+            let current = match hash.get(&arguments[1]) {
+                Some(value) => try!(parse_integer(value).map_err(|_| Value::Error("ERR hash value is not an integer".to_string()))),
+                None => 0,
+            };
+            let next = try!(current.checked_add(amount).ok_or(Value::Error("ERR increment or decrement would overflow".to_string())));
+            hash.insert(arguments[1].clone(), next.to_string().into_bytes());
+            Value::Integer(next)
+        },
+        "LPUSH" | "RPUSH" => {
+            let list = try!(list_or_insert(entries, &arguments[0]));
+            for value in arguments[1..].iter() {
+                if name == "LPUSH" {
+                    list.push_front(value.clone());
+                } else {
+                    list.push_back(value.clone());
+                }
+            }
+            Value::Integer(list.len() as i64)
+        },
+        "LPOP" | "RPOP" => {
+            let value = match try!(list(entries, &arguments[0])) {
+                Some(list) => if name == "LPOP" { list.pop_front() } else { list.pop_back() },
+                None => None,
+            };
+            remove_if_empty(entries, &arguments[0]);
+            bulk(value)
+        },
+        "LLEN" => Value::Integer(try!(list(entries, &arguments[0])).map(|list| list.len()).unwrap_or(0) as i64),
+        "LINDEX" => {
+            let index = try!(parse_integer(&arguments[1]));
+            let value = try!(list(entries, &arguments[0])).and_then(|list| {
+                let index = if index < 0 { list.len() as i64 + index } else { index };
+                if index < 0 { None } else { list.get(index as usize).cloned() }
+            });
+            bulk(value)
+        },
+        "LRANGE" => {
+            let start = try!(parse_integer(&arguments[1]));
+            let stop = try!(parse_integer(&arguments[2]));
+            let values: Vec<Vec<u8>> = match try!(list(entries, &arguments[0])) {
+                Some(list) => {
+                    let len = list.len() as i64;
+                    let start = if start < 0 { (len + start).max(0) } else { start };
+                    let stop = if stop < 0 { len + stop } else { min(stop, len - 1) };
+                    if start > stop {
+                        Vec::new()
+                    } else {
+                        list.iter().skip(start as usize).take((stop - start + 1) as usize).cloned().collect()
+                    }
+                },
+                None => Vec::new(),
+            };
+            bulks(values.into_iter())
+        },
+        "SADD" => {
+            let set = try!(set_or_insert(entries, &arguments[0]));
+            Value::Integer(arguments[1..].iter().filter(|member| set.insert((*member).clone())).count() as i64)
+        },
+        "SREM" => {
+            let removed = match try!(set(entries, &arguments[0])) {
+                Some(set) => arguments[1..].iter().filter(|member| set.remove(&**member)).count(),
+                None => 0,
+            };
+            remove_if_empty(entries, &arguments[0]);
+            Value::Integer(removed as i64)
+        },
+        "SISMEMBER" => Value::Integer(try!(set(entries, &arguments[0])).map(|set| set.contains(&arguments[1]) as i64).unwrap_or(0)),
+        "SCARD" => Value::Integer(try!(set(entries, &arguments[0])).map(|set| set.len()).unwrap_or(0) as i64),
+        "SMEMBERS" => {
+            let mut members: Vec<Vec<u8>> = try!(set(entries, &arguments[0])).map(|set| set.iter().cloned().collect()).unwrap_or(Vec::new());
+            members.sort();
+            bulks(members.into_iter())
+        },
+        _ => return Err(Value::Error(format!("ERR unknown command '{}'", name.to_ascii_lowercase()))),
+    };
+
+    Ok(reply)
+}
+
+fn remove_if_empty(entries: &mut HashMap<Vec<u8>, Entry>, key: &[u8]) {
+    let empty = match entries.get(key) {
+        Some(&Entry { data: Data::Hash(ref hash), .. }) => hash.is_empty(),
+        Some(&Entry { data: Data::List(ref list), .. }) => list.is_empty(),
+        Some(&Entry { data: Data::Set(ref set), .. }) => set.is_empty(),
+        _ => false,
+    };
+
+    if empty {
+        entries.remove(key);
+    }
+}
+
+macro_rules! accessors {
+    ($get:ident, $get_or_insert:ident, $variant:ident, $kind:ty, $empty:expr) => {
+        fn $get<'a>(entries: &'a mut HashMap<Vec<u8>, Entry>, key: &[u8]) -> Result<Option<&'a mut $kind>, Value> {
+            match live(entries, key) {
+                Some(&mut Entry { data: Data::$variant(ref mut data), .. }) => Ok(Some(data)),
+                Some(_) => Err(Value::Error(WRONGTYPE.to_string())),
+                None => Ok(None),
+            }
+        }
+
+        fn $get_or_insert<'a>(entries: &'a mut HashMap<Vec<u8>, Entry>, key: &[u8]) -> Result<&'a mut $kind, Value> {
+            if live(entries, key).is_none() {
+                entries.insert(key.to_vec(), Entry {
+                    data: Data::$variant($empty),
+                    expires: None,
+                });
+            }
+
+            match $get(entries, key) {
+                Ok(Some(data)) => Ok(data),
+                Ok(None) => Err(Value::Error("ERR no such key".to_string())),
+                Err(error) => Err(error),
+            }
+        }
+    }
+}
+
+accessors!(string, string_or_insert, String, Vec<u8>, Vec::new());
+accessors!(hash, hash_or_insert, Hash, HashMap<Vec<u8>, Vec<u8>>, HashMap::new());
+accessors!(list, list_or_insert, List, VecDeque<Vec<u8>>, VecDeque::new());
+accessors!(set, set_or_insert, Set, HashSet<Vec<u8>>, HashSet::new());
+
+/// In-process Redis stand-in, to test the interceptors without a server: it
+/// runs the commands written to it against a `MockKeyspace` (strings,
+/// hashes, lists, sets and expiry) or answers them with canned replies. It
+/// is registered on the event loop through a pipe it writes to when replies
+/// are ready, so it can be the upstream of a `Proxy`.
+pub struct MockRedis {
+    token: Token,
+    keyspace: MockKeyspace,
+    commands: Decoder,
+    replies: Buf,
+    wake: PipeReader,
+    waker: PipeWriter,
+}
+
+impl MockRedis {
+    pub fn new(token: Token, keyspace: MockKeyspace) -> io::Result<Self> {
+        let (reader, writer) = try!(pipe());
+
+        Ok(MockRedis {
+            token: token,
+            keyspace: keyspace,
+            commands: Decoder::new(),
+            replies: Buf::new(),
+            wake: reader,
+            waker: writer,
+        })
+    }
+
+    pub fn keyspace(&self) -> MockKeyspace {
+        self.keyspace.clone()
+    }
+}
+
+impl io::Read for MockRedis {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read_size = min(buf.len(), self.replies.len());
+        buf[0..read_size].clone_from_slice(&self.replies[0..read_size]);
+        self.replies.consume(read_size);
+
+        Ok(read_size)
+    }
+}
+
+impl io::Write for MockRedis {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        try!(self.commands.feed(buf));
+
+        let mut answered = false;
+        while let Some(command) = self.commands.read() {
+            let reply = self.keyspace.execute(command);
+            self.replies.extend(&reply.encode());
+            answered = true;
+        }
+
+        if answered {
+            // The pipe only has to become readable, a full pipe is fine
+            let _ = self.waker.write(&[0]);
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Connection for MockRedis {
+    fn get_evented(&self) -> &Evented {
+        return &self.wake;
+    }
+
+    fn get_token(&self) -> Token {
+        return self.token;
+    }
+
+    fn get_interest(&self) -> EventSet {
+        EventSet::readable()
+    }
+
+    fn handle_read(&mut self) -> ConnectionAction {
+        let mut buf = [0u8; 64];
+        loop {
+            match self.wake.read(&mut buf) {
+                Ok(0) => break,
+                Ok(_) => (),
+                Err(_) => break,
+            }
+        }
+
+        if self.replies.len() > 0 {
+            ConnectionAction::Forward
+        } else {
+            ConnectionAction::Noop
+        }
+    }
+
+    fn handle_write(&mut self) -> ConnectionAction {
+        ConnectionAction::Noop
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use connection::redis::{FaultProxy, FaultRule, Fault};
+    use connection::redis::resp::Value;
+    use connection::redis::testing::{Harness, ok};
+    use super::MockKeyspace;
+
+    fn command(arguments: &[&str]) -> Value {
+        Value::Array(arguments.iter().map(|argument| Value::Bulk(argument.to_string())).collect())
+    }
+
+    #[test]
+    fn faults_never_reach_the_upstream() {
+        let keyspace = MockKeyspace::new();
+        keyspace.set(b"user:1", b"alice");
+        keyspace.reply_to("PING", Value::Error("LOADING Redis is loading the dataset in memory".to_string()));
+
+        let rules = vec![FaultRule::new(Fault::oom()).command("get").key_pattern("user:*")];
+        let mut harness = Harness::new(FaultProxy::new(rules), keyspace.clone());
+
+        let replies = harness.send(&[&["GET", "user:1"], &["SET", "session:1", "x"], &["GET", "session:1"], &["PING"], &["PING"]]);
+        assert_eq!(replies, vec![
+            Value::Error("OOM command not allowed when used memory > 'maxmemory'.".to_string()),
+            ok(),
+            Value::Bulk("x".to_string()),
+            Value::Error("LOADING Redis is loading the dataset in memory".to_string()),
+            Value::String("PONG".to_string()),
+        ]);
+
+        assert_eq!(keyspace.received(), vec![
+            command(&["SET", "session:1", "x"]),
+            command(&["GET", "session:1"]),
+            command(&["PING"]),
+            command(&["PING"]),
+        ]);
+        assert_eq!(keyspace.get(b"user:1"), Some(b"alice".to_vec()));
+    }
+
+    #[test]
+    fn binary_values_are_kept() {
+        let keyspace = MockKeyspace::new();
+        let value = Value::BufBulk(vec![0xff, 0, 0xfe]);
+        let set = Value::Array(vec![Value::Bulk("SET".to_string()), Value::BufBulk(vec![0xff]), value.clone()]);
+
+        assert_eq!(keyspace.execute(set), ok());
+        assert_eq!(keyspace.get(&[0xff]), Some(vec![0xff, 0, 0xfe]));
+        assert_eq!(keyspace.execute(Value::Array(vec![Value::Bulk("GET".to_string()), Value::BufBulk(vec![0xff])])), value);
+    }
+}
@@ -1,23 +1,202 @@
 pub use self::connection::RedisConnection;
+pub use self::upstream::{RedisUpstream, Handshake};
+pub use self::auth::AuthProxy;
+pub use self::fault::{FaultProxy, FaultRule, Fault};
+pub use self::shard::{ShardedUpstream, Shard};
+pub use self::cluster::ClusterUpstream;
+pub use self::pool::{RedisPool, PooledUpstream};
+pub use self::rename::RenameProxy;
+pub use self::readonly::{ReadOnlyProxy, ReadOnlySwitch};
+pub use self::cache::{CacheProxy, ResponseCache};
+pub use self::shadow::ReplyComparator;
+pub use self::metrics::{MetricsProxy, RedisMetrics};
+pub use self::hotkeys::{HotKeyProxy, HotKeys, BigValue};
+pub use self::slowlog::{SlowLogProxy, SlowLog};
+pub use self::script::ScriptPolicyProxy;
+pub use self::ratelimit::{RateLimitProxy, CommandClass};
+pub use self::mock::{MockRedis, MockKeyspace};
+pub use self::compress::CompressionProxy;
+pub use self::encrypt::EncryptionProxy;
 
-use resp::{Decoder, Value};
+use connection::redis::commands::lookup_command;
+use connection::redis::resp::Value;
 use std::ascii::AsciiExt;
+use std::collections::VecDeque;
+use std::time::Instant;
+
+pub mod commands;
+pub mod resp;
 
 mod connection;
+mod upstream;
+mod auth;
+mod fault;
+mod ketama;
+mod shard;
+mod cluster;
+mod pool;
+mod rename;
+mod readonly;
+mod cache;
+mod shadow;
+mod metrics;
+mod hotkeys;
+mod slowlog;
+mod script;
+mod ratelimit;
+mod mock;
+mod compress;
+mod encrypt;
+
+#[cfg(test)]
+mod testing;
+
+/// Outcome of intercepting a command sent by a client.
+pub enum CommandAction {
+    /// Send the (possibly rewritten) command to the upstream.
+    Forward(Value),
+    /// Answer the client locally; the command never reaches the upstream and
+    /// the reply does not go through `on_response`.
+    Respond(Value),
+    /// Discard the command without answering it.
+    Drop,
+    /// Close the client connection once the replies of the commands
+    /// received before this one have been sent.
+    Close,
+    /// Forward the command but never deliver its reply. Replies of later
+    /// commands are held back as well, as if the connection had stalled.
+    LoseReply(Value),
+    /// Apply the action after the given milliseconds. The commands sent
+    /// after this one wait for it, so the pipeline order is kept.
+    Delay(u64, Box<CommandAction>),
+}
+
+/// Returns the uppercased name of a command sent as an array of bulk strings.
+fn command_name(command: &Value) -> Option<String> {
+    match *command {
+        Value::Array(ref input) if input.len() > 0 => {
+            match input[0] {
+                Value::Bulk(ref cmd) => Some(cmd.to_ascii_uppercase()),
+                _ => None,
+            }
+        },
+        _ => None,
+    }
+}
+
+/// Positions of the string and hash values written by the command, each with
+/// the position of its key.
+fn value_positions(name: &str, arguments: usize) -> Vec<(usize, usize)> {
+    match name {
+        "SET" | "SETNX" | "GETSET" if arguments >= 3 => vec![(2, 1)],
+        "SETEX" | "PSETEX" | "HSETNX" if arguments == 4 => vec![(3, 1)],
+        "MSET" | "MSETNX" => (2..arguments).filter(|position| position % 2 == 0).map(|position| (position, position - 1)).collect(),
+        "HSET" | "HMSET" => (3..arguments).filter(|position| position % 2 == 1).map(|position| (position, 1)).collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Uppercased arguments of a `PROXY ...` command, answered by the proxy
+/// itself.
+fn proxy_subcommand(command: &Value) -> Option<Vec<String>> {
+    if command_name(command).map(|name| name != "PROXY").unwrap_or(true) {
+        return None;
+    }
+
+    match *command {
+        Value::Array(ref input) => {
+            Some(input[1..].iter().map(|argument| {
+                match *argument {
+                    Value::Bulk(ref argument) => argument.to_ascii_uppercase(),
+                    _ => String::new(),
+                }
+            }).collect())
+        },
+        _ => None,
+    }
+}
+
+/// Whether the action sends the command to the upstream, now or later.
+fn forwards(action: &CommandAction) -> bool {
+    match *action {
+        CommandAction::Forward(_) | CommandAction::LoseReply(_) => true,
+        CommandAction::Delay(_, ref action) => forwards(action),
+        _ => false,
+    }
+}
+
+/// Entries a proxy keeps for the commands it forwarded, matched with their
+/// replies by order: every forwarded command gets exactly one reply with
+/// `Origin::Command`, or `on_unanswered` is called right after forwarding it.
+struct InFlight<T> {
+    entries: VecDeque<T>,
+}
+
+impl<T> InFlight<T> {
+    fn new() -> Self {
+        InFlight {
+            entries: VecDeque::new(),
+        }
+    }
+
+    fn push(&mut self, entry: T) {
+        self.entries.push_back(entry);
+    }
+
+    /// Takes back the entry of the last forwarded command, which gets no
+    /// reply.
+    fn cancel_last(&mut self) -> Option<T> {
+        self.entries.pop_back()
+    }
+
+    /// Entry of the command the reply answers.
+    fn answer(&mut self, origin: Origin) -> Option<T> {
+        match origin {
+            Origin::Command(_) => self.entries.pop_front(),
+            _ => None,
+        }
+    }
+}
+
+fn elapsed_micros(started: Instant) -> u64 {
+    let elapsed = started.elapsed();
+
+    elapsed.as_secs() * 1000000 + (elapsed.subsec_nanos() / 1000) as u64
+}
+
+/// What a response received from the upstream answers to.
+#[derive(Clone, Copy)]
+pub enum Origin<'a> {
+    /// Reply to the command, as it was forwarded, even when it never reaches
+    /// the client. Inside a transaction, each element of the `EXEC` reply
+    /// comes with its queued command before `EXEC` gets the whole array; when
+    /// the transaction fails or is discarded, queued commands get an error.
+    Command(&'a Value),
+    /// `+QUEUED` acknowledgement of a command sent inside `MULTI`.
+    Queued(&'a Value),
+    /// Message pushed by the upstream on its own, like Pub/Sub messages and
+    /// subscription confirmations.
+    Push,
+}
 
 pub trait RedisProxy {
-    fn on_command(&mut self, command: Value) -> Value;
-    fn on_response(&mut self, response: Value) -> Value;
+    fn on_command(&mut self, command: Value) -> CommandAction;
+    fn on_response(&mut self, origin: Origin, response: Value) -> Value;
+
+    /// The last command this proxy forwarded gets no reply through
+    /// `on_response`: it was answered further down the chain, or it
+    /// subscribes and its confirmations are pushed.
+    fn on_unanswered(&mut self) {}
 }
 
 pub struct NoopProxy;
 
 impl RedisProxy for NoopProxy {
-    fn on_command(&mut self, command: Value) -> Value {
-        command
+    fn on_command(&mut self, command: Value) -> CommandAction {
+        CommandAction::Forward(command)
     }
 
-    fn on_response(&mut self, response: Value) -> Value {
+    fn on_response(&mut self, _: Origin, response: Value) -> Value {
         response
     }
 }
@@ -36,25 +215,92 @@ impl<A: RedisProxy, B: RedisProxy> ComposedProxy<A, B> {
     }
 }
 
+impl<A: RedisProxy, B: RedisProxy> ComposedProxy<A, B> {
+    /// Lets `proxy_a` intercept the commands `proxy_b` decided to forward.
+    fn chain(&mut self, action: CommandAction) -> CommandAction {
+        let action = match action {
+            CommandAction::Forward(command) => self.proxy_a.on_command(command),
+            CommandAction::LoseReply(command) => {
+                match self.proxy_a.on_command(command) {
+                    CommandAction::Forward(command) => CommandAction::LoseReply(command),
+                    action => action,
+                }
+            },
+            CommandAction::Delay(delay, action) => {
+                return CommandAction::Delay(delay, Box::new(self.chain(*action)));
+            },
+            action => return action,
+        };
+
+        if !forwards(&action) {
+            self.proxy_b.on_unanswered();
+        }
+
+        action
+    }
+}
+
 impl<A: RedisProxy, B: RedisProxy> RedisProxy for ComposedProxy<A, B> {
-    fn on_command(&mut self, command: Value) -> Value {
-        self.proxy_a.on_command(
-            self.proxy_b.on_command(command)
-        )
+    fn on_command(&mut self, command: Value) -> CommandAction {
+        let action = self.proxy_b.on_command(command);
+
+        self.chain(action)
     }
 
-    fn on_response(&mut self, response: Value) -> Value {
+    fn on_response(&mut self, origin: Origin, response: Value) -> Value {
         self.proxy_b.on_response(
-            self.proxy_a.on_response(response)
+            origin,
+            self.proxy_a.on_response(origin, response)
         )
     }
+
+    fn on_unanswered(&mut self) {
+        self.proxy_a.on_unanswered();
+        self.proxy_b.on_unanswered();
+    }
 }
 
 pub struct PrefixProxy;
 
+/// Prefixes the keys a script or function declares with `numkeys`.
+fn prefix_declared_keys(command: Value) -> Value {
+    let positions = match lookup_command(&command) {
+        Some(info) => {
+            match command {
+                Value::Array(ref input) => info.key_positions(input),
+                _ => return command,
+            }
+        },
+        None => return command,
+    };
+
+    match command {
+        Value::Array(mut input) => {
+            for position in positions {
+                let prefixed = match input[position] {
+                    Value::Bulk(ref key) => Value::Bulk(format!("{}:{}", "prefix", key)),
+                    ref key => key.clone(),
+                };
+                input[position] = prefixed;
+            }
+
+            Value::Array(input)
+        },
+        command => command,
+    }
+}
+
 impl RedisProxy for PrefixProxy {
-    fn on_command(&mut self, command: Value) -> Value {
-        match command {
+    fn on_command(&mut self, command: Value) -> CommandAction {
+        match command_name(&command).as_ref().map(|name| &**name) {
+            Some("EVAL") | Some("EVALSHA") | Some("EVAL_RO") | Some("EVALSHA_RO") |
+            Some("FCALL") | Some("FCALL_RO") => {
+                return CommandAction::Forward(prefix_declared_keys(command));
+            },
+            _ => (),
+        }
+
+        let command = match command {
             Value::Array(ref input) => {
                 if input.len() == 0 {
                     command.clone()
@@ -117,10 +363,34 @@ impl RedisProxy for PrefixProxy {
             _ => {
                 command
             }
+        };
+
+        CommandAction::Forward(command)
+    }
+
+    fn on_response(&mut self, _: Origin, response: Value) -> Value {
+        response
+    }
+}
+
+/// Answers `PING` locally, so health checks do not reach the upstream.
+pub struct PingProxy;
+
+impl RedisProxy for PingProxy {
+    fn on_command(&mut self, command: Value) -> CommandAction {
+        let is_ping = match command {
+            Value::Array(ref input) => input.len() == 1 && command_name(&command) == Some("PING".to_string()),
+            _ => false,
+        };
+
+        if is_ping {
+            CommandAction::Respond(Value::String("PONG".to_string()))
+        } else {
+            CommandAction::Forward(command)
         }
     }
 
-    fn on_response(&mut self, response: Value) -> Value {
+    fn on_response(&mut self, _: Origin, response: Value) -> Value {
         response
     }
 }
@@ -128,14 +398,18 @@ impl RedisProxy for PrefixProxy {
 pub struct LogProxy;
 
 impl RedisProxy for LogProxy {
-    fn on_command(&mut self, command: Value) -> Value {
+    fn on_command(&mut self, command: Value) -> CommandAction {
         warn!("Received command: {:?}", command);
 
-        command
+        CommandAction::Forward(command)
     }
 
-    fn on_response(&mut self, response: Value) -> Value {
-        warn!("Response: {}", response.to_beautify_string());
+    fn on_response(&mut self, origin: Origin, response: Value) -> Value {
+        match origin {
+            Origin::Command(_) => warn!("Response: {}", response.to_beautify_string()),
+            Origin::Queued(_) => warn!("Queued: {}", response.to_beautify_string()),
+            Origin::Push => warn!("Pushed: {}", response.to_beautify_string()),
+        }
 
         response
     }
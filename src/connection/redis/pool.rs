@@ -0,0 +1,655 @@
+use mio::{Token, Evented, EventSet, EventLoop, Handler, PollOpt, Selector};
+use mio::unix::{pipe, PipeReader, PipeWriter};
+use connection::Connection;
+use connection::ConnectionAction;
+use connection::redis::command_name;
+use connection::redis::commands;
+use std::io;
+use std::io::{Read, Write};
+use std::cmp::min;
+use std::rc::Rc;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use connection::redis::resp::{Decoder, Value};
+use netbuf::Buf;
+
+struct PoolConnection {
+    connection: Box<Connection>,
+    responses: Decoder,
+    /// Client waiting for each reply, in the order the commands were sent.
+    routes: VecDeque<usize>,
+    /// The connection broke, or its stream can not be trusted any more.
+    lost: bool,
+}
+
+struct PoolClient {
+    connection: usize,
+    pending: VecDeque<Option<Value>>,
+    replies: Buf,
+    waker: PipeWriter,
+}
+
+impl PoolClient {
+    fn fill(&mut self, response: Value) {
+        match self.pending.iter_mut().find(|reply| reply.is_none()) {
+            Some(reply) => *reply = Some(response),
+            None => warn!("Pooled client received a response without a pending command"),
+        }
+
+        while self.pending.front().map(|reply| reply.is_some()).unwrap_or(false) {
+            if let Some(Some(reply)) = self.pending.pop_front() {
+                self.replies.extend(&reply.encode());
+            }
+        }
+    }
+
+    fn wake(&mut self) {
+        // The pipe only has to become readable, a full pipe is fine
+        let _ = self.waker.write(&[0]);
+    }
+}
+
+/// Small set of upstream connections shared by many clients. Every client
+/// is assigned one of the connections and replies are routed back following
+/// the order in which the commands were sent, since Redis answers them in
+/// order. Commands changing the state of the connection would leak to other
+/// clients, so they are rejected, unless a connector is given to open a
+/// dedicated connection for the clients sending them.
+///
+/// The connections of the pool have their own tokens and the event loop
+/// handler has to dispatch their events to `ready`.
+pub struct RedisPool {
+    connections: Vec<PoolConnection>,
+    clients: HashMap<usize, PoolClient>,
+    next_client: usize,
+    connector: Option<Box<Fn(Token) -> io::Result<Box<Connection>>>>,
+}
+
+impl RedisPool {
+    pub fn new(connections: Vec<Box<Connection>>) -> Self {
+        RedisPool {
+            connections: connections.into_iter().map(|connection| {
+                PoolConnection {
+                    connection: connection,
+                    responses: Decoder::new(),
+                    routes: VecDeque::new(),
+                    lost: false,
+                }
+            }).collect(),
+            clients: HashMap::new(),
+            next_client: 0,
+            connector: None,
+        }
+    }
+
+    /// Opens a dedicated connection, with the given token, for each client
+    /// subscribing, blocking or changing the state of its connection.
+    pub fn with_connector(mut self, connector: Box<Fn(Token) -> io::Result<Box<Connection>>>) -> Self {
+        self.connector = Some(connector);
+        self
+    }
+
+    pub fn tokens(&self) -> Vec<Token> {
+        self.connections.iter().map(|pooled| pooled.connection.get_token()).collect()
+    }
+
+    pub fn has(&self, token: &Token) -> bool {
+        self.connections.iter().any(|pooled| pooled.connection.get_token() == *token)
+    }
+
+    pub fn register<H: Handler>(&self, event_loop: &mut EventLoop<H>) -> io::Result<()> {
+        for pooled in self.connections.iter() {
+            let connection = &pooled.connection;
+            try!(event_loop.register(connection.get_evented(), connection.get_token(), connection.get_interest(), PollOpt::edge()));
+        }
+
+        Ok(())
+    }
+
+    /// Handles an event on one of the connections of the pool.
+    pub fn ready(&mut self, token: Token, event_set: EventSet) {
+        let position = match self.connections.iter().position(|pooled| pooled.connection.get_token() == token) {
+            Some(position) => position,
+            None => return,
+        };
+
+        if self.connections[position].lost {
+            return;
+        }
+
+        if event_set.is_readable() {
+            let action = self.connections[position].connection.handle_read();
+            self.dispatch(position);
+
+            if let ConnectionAction::Halt = action {
+                error!("{:?}: Lost pooled upstream connection", token);
+                self.drop_connection(position);
+                return;
+            }
+        }
+
+        if event_set.is_writable() {
+            self.connections[position].connection.handle_write();
+        }
+    }
+
+    fn dispatch(&mut self, position: usize) {
+        let mut buf = [0u8; 1024];
+        let mut woken = Vec::new();
+        let mut corrupted = false;
+
+        {
+            let pooled = &mut self.connections[position];
+            loop {
+                let amount = match pooled.connection.read(&mut buf) {
+                    Ok(amount) => amount,
+                    Err(e) => {
+                        error!("{:?}: Could not read from pooled connection: {}", pooled.connection.get_token(), e);
+                        break;
+                    },
+                };
+
+                if amount == 0 {
+                    break;
+                }
+
+                if let Err(e) = pooled.responses.feed(&buf[0..amount]) {
+                    error!("{:?}: Could not parse the response: {}", pooled.connection.get_token(), e);
+                    corrupted = true;
+                    break;
+                }
+            }
+
+            while let Some(response) = pooled.responses.read() {
+                match pooled.routes.pop_front() {
+                    Some(client) => {
+                        // The client may have gone away while waiting
+                        if let Some(pool_client) = self.clients.get_mut(&client) {
+                            pool_client.fill(response);
+                            woken.push(client);
+                        }
+                    },
+                    None => {
+                        warn!("{:?}: Received a response without a pending command", pooled.connection.get_token());
+                    },
+                }
+            }
+        }
+
+        for client in woken {
+            if let Some(pool_client) = self.clients.get_mut(&client) {
+                pool_client.wake();
+            }
+        }
+
+        // Replies can not be matched with the commands past a parse error
+        if corrupted {
+            self.drop_connection(position);
+        }
+    }
+
+    /// Stops using the connection, answering with an error every command
+    /// still waiting on it. Its clients move to the other connections.
+    fn drop_connection(&mut self, position: usize) {
+        let pooled = &mut self.connections[position];
+        error!("{:?}: Dropping pooled upstream connection", pooled.connection.get_token());
+        pooled.lost = true;
+        pooled.responses = Decoder::new();
+
+        for client in pooled.routes.drain(..) {
+            if let Some(pool_client) = self.clients.get_mut(&client) {
+                pool_client.fill(lost_connection());
+                pool_client.wake();
+            }
+        }
+    }
+
+    /// Connection of the pool with the fewest clients, if any is left.
+    fn least_used(&self) -> Option<usize> {
+        (0..self.connections.len())
+            .filter(|connection| !self.connections[*connection].lost)
+            .min_by_key(|connection| self.clients.values().filter(|pool_client| pool_client.connection == *connection).count())
+    }
+
+    fn attach(&mut self, waker: PipeWriter) -> usize {
+        let client = self.next_client;
+        self.next_client += 1;
+
+        // Spread the clients evenly over the connections
+        let connection = self.least_used().unwrap_or(0);
+
+        self.clients.insert(client, PoolClient {
+            connection: connection,
+            pending: VecDeque::new(),
+            replies: Buf::new(),
+            waker: waker,
+        });
+
+        client
+    }
+
+    fn detach(&mut self, client: usize) {
+        self.clients.remove(&client);
+    }
+
+    fn send(&mut self, client: usize, command: Value) {
+        let rejection = match rejection(&command) {
+            Some(name) => Some(Value::Error(format!("ERR {} is not supported on pooled connections", name))),
+            None => None,
+        };
+
+        let connection = match self.clients.get(&client) {
+            Some(pool_client) if self.connections[pool_client.connection].lost => self.least_used(),
+            Some(pool_client) => Some(pool_client.connection),
+            None => return,
+        };
+
+        let written = {
+            let pool_client = match self.clients.get_mut(&client) {
+                Some(pool_client) => pool_client,
+                None => return,
+            };
+
+            pool_client.pending.push_back(None);
+
+            if let Some(rejection) = rejection {
+                pool_client.fill(rejection);
+                pool_client.wake();
+                return;
+            }
+
+            let connection = match connection {
+                Some(connection) => connection,
+                None => {
+                    pool_client.fill(lost_connection());
+                    pool_client.wake();
+                    return;
+                },
+            };
+            pool_client.connection = connection;
+
+            let pooled = &mut self.connections[connection];
+            match pooled.connection.write(&command.encode()) {
+                Ok(_) => {
+                    pooled.routes.push_back(client);
+
+                    // Edge triggered writable events only come after a write would block
+                    pooled.connection.handle_write();
+                    true
+                },
+                Err(e) => {
+                    // No reply will come for this command
+                    error!("{:?}: Could not write to pooled connection: {}", pooled.connection.get_token(), e);
+                    pool_client.fill(lost_connection());
+                    pool_client.wake();
+                    false
+                },
+            }
+        };
+
+        if !written {
+            if let Some(connection) = connection {
+                self.drop_connection(connection);
+            }
+        }
+    }
+
+    fn read_replies(&mut self, client: usize, buf: &mut [u8]) -> usize {
+        match self.clients.get_mut(&client) {
+            Some(pool_client) => {
+                let read_size = min(buf.len(), pool_client.replies.len());
+                buf[0..read_size].clone_from_slice(&pool_client.replies[0..read_size]);
+                pool_client.replies.consume(read_size);
+
+                read_size
+            },
+            None => 0,
+        }
+    }
+
+    fn has_replies(&self, client: usize) -> bool {
+        self.clients.get(&client).map(|pool_client| pool_client.replies.len() > 0).unwrap_or(false)
+    }
+
+    fn has_pending(&self, client: usize) -> bool {
+        self.clients.get(&client).map(|pool_client| !pool_client.pending.is_empty()).unwrap_or(false)
+    }
+
+    fn can_pin(&self) -> bool {
+        self.connector.is_some()
+    }
+
+    fn connect(&self, token: Token) -> io::Result<Box<Connection>> {
+        match self.connector {
+            Some(ref connector) => connector(token),
+            None => Err(io::Error::new(io::ErrorKind::Other, "The pool has no connector")),
+        }
+    }
+}
+
+fn lost_connection() -> Value {
+    Value::Error("ERR lost the pooled upstream connection".to_string())
+}
+
+/// Name of the command when it can not be sent on a shared connection.
+fn rejection(command: &Value) -> Option<String> {
+    let name = match command_name(command) {
+        Some(name) => name,
+        None => return None,
+    };
+
+    match &*name {
+        "SELECT" | "SWAPDB" | "AUTH" | "HELLO" | "RESET" | "CLIENT" | "MONITOR" |
+        "MULTI" | "EXEC" | "DISCARD" | "WATCH" | "UNWATCH" | "READONLY" | "READWRITE" => {
+            return Some(name);
+        },
+        _ => (),
+    }
+
+    if commands::is_blocking(command) || commands::is_subscription(command) {
+        return Some(name);
+    }
+
+    None
+}
+
+/// Upstream of a client on a `RedisPool`. It is registered on the event loop
+/// through a pipe the pool writes to when there are replies for the client.
+///
+/// When the pool has a connector, a client sending a command that can not be
+/// shared (`SUBSCRIBE`, `BLPOP`, `SELECT`, ...) is pinned to a dedicated
+/// connection for the rest of its session: the command is held until the
+/// pool answered every earlier command, then everything goes through the
+/// dedicated connection, which is registered along with the pipe.
+pub struct PooledUpstream {
+    pool: Rc<RefCell<RedisPool>>,
+    client: usize,
+    token: Token,
+    wake: PipeReader,
+    commands: Decoder,
+    held: Vec<Value>,
+    dedicated: Option<Box<Connection>>,
+    registered: Cell<bool>,
+}
+
+impl PooledUpstream {
+    pub fn new(pool: Rc<RefCell<RedisPool>>, token: Token) -> io::Result<Self> {
+        let (reader, writer) = try!(pipe());
+        let client = pool.borrow_mut().attach(writer);
+
+        Ok(PooledUpstream {
+            pool: pool,
+            client: client,
+            token: token,
+            wake: reader,
+            commands: Decoder::new(),
+            held: Vec::new(),
+            dedicated: None,
+            registered: Cell::new(false),
+        })
+    }
+
+    /// Whether the client has its own upstream connection.
+    pub fn is_pinned(&self) -> bool {
+        self.dedicated.is_some()
+    }
+
+    /// Opens the dedicated connection once the pool is done with the client.
+    fn pin(&mut self) -> io::Result<()> {
+        if self.held.is_empty() || self.dedicated.is_some() || self.pool.borrow().has_pending(self.client) {
+            return Ok(());
+        }
+
+        let mut dedicated = try!(self.pool.borrow().connect(self.token));
+        info!("{:?}: Pinned to a dedicated upstream connection", self.token);
+
+        for command in self.held.drain(..) {
+            try!(dedicated.write(&command.encode()));
+        }
+        dedicated.handle_write();
+
+        self.dedicated = Some(dedicated);
+
+        Ok(())
+    }
+}
+
+impl Evented for PooledUpstream {
+    fn register(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        try!(self.wake.register(selector, token, interest, opts));
+
+        if let Some(ref dedicated) = self.dedicated {
+            try!(dedicated.get_evented().register(selector, token, interest, opts));
+            self.registered.set(true);
+        }
+
+        Ok(())
+    }
+
+    fn reregister(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        try!(self.wake.reregister(selector, token, interest, opts));
+
+        if let Some(ref dedicated) = self.dedicated {
+            // The dedicated connection is opened after the registration
+            if self.registered.get() {
+                try!(dedicated.get_evented().reregister(selector, token, interest, opts));
+            } else {
+                try!(dedicated.get_evented().register(selector, token, interest, opts));
+                self.registered.set(true);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        try!(self.wake.deregister(selector));
+
+        if let Some(ref dedicated) = self.dedicated {
+            if self.registered.get() {
+                try!(dedicated.get_evented().deregister(selector));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for PooledUpstream {
+    fn drop(&mut self) {
+        self.pool.borrow_mut().detach(self.client);
+    }
+}
+
+impl io::Read for PooledUpstream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Replies from the pool come before those of the dedicated connection
+        let read_size = self.pool.borrow_mut().read_replies(self.client, buf);
+        if read_size > 0 {
+            return Ok(read_size);
+        }
+
+        match self.dedicated {
+            Some(ref mut dedicated) => dedicated.read(buf),
+            None => Ok(0),
+        }
+    }
+}
+
+impl io::Write for PooledUpstream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(ref mut dedicated) = self.dedicated {
+            return dedicated.write(buf);
+        }
+
+        try!(self.commands.feed(buf));
+
+        while let Some(command) = self.commands.read() {
+            if !self.held.is_empty() || (rejection(&command).is_some() && self.pool.borrow().can_pin()) {
+                self.held.push(command);
+            } else {
+                self.pool.borrow_mut().send(self.client, command);
+            }
+        }
+
+        try!(self.pin());
+
+        // Commands decoded after the pinning are still to be sent
+        if let Some(ref mut dedicated) = self.dedicated {
+            while let Some(command) = self.commands.read() {
+                try!(dedicated.write(&command.encode()));
+            }
+            dedicated.handle_write();
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Connection for PooledUpstream {
+    fn get_evented(&self) -> &Evented {
+        return self;
+    }
+
+    fn get_token(&self) -> Token {
+        return self.token;
+    }
+
+    fn get_interest(&self) -> EventSet {
+        match self.dedicated {
+            Some(ref dedicated) => EventSet::readable() | dedicated.get_interest(),
+            None => EventSet::readable(),
+        }
+    }
+
+    fn handle_read(&mut self) -> ConnectionAction {
+        let mut buf = [0u8; 64];
+        loop {
+            match self.wake.read(&mut buf) {
+                Ok(0) => break,
+                Ok(_) => (),
+                Err(_) => break,
+            }
+        }
+
+        if let Err(e) = self.pin() {
+            error!("{:?}: Could not open a dedicated upstream connection: {}", self.token, e);
+            return ConnectionAction::Halt;
+        }
+
+        let dedicated_action = match self.dedicated {
+            Some(ref mut dedicated) => dedicated.handle_read(),
+            None => ConnectionAction::Noop,
+        };
+
+        match dedicated_action {
+            ConnectionAction::Halt => ConnectionAction::Halt,
+            ConnectionAction::Forward => ConnectionAction::Forward,
+            _ if self.pool.borrow().has_replies(self.client) => ConnectionAction::Forward,
+            _ => ConnectionAction::Noop,
+        }
+    }
+
+    fn handle_write(&mut self) -> ConnectionAction {
+        match self.dedicated {
+            Some(ref mut dedicated) => dedicated.handle_write(),
+            None => ConnectionAction::Noop,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mio::{Token, EventSet};
+    use connection::Connection;
+    use connection::redis::{MockRedis, MockKeyspace};
+    use connection::redis::resp::{Decoder, Value};
+    use connection::redis::testing::FaultyRedis;
+    use super::{RedisPool, PooledUpstream, rejection};
+    use std::io::{Read, Write};
+    use std::rc::Rc;
+    use std::cell::RefCell;
+
+    fn command(arguments: &[&str]) -> Value {
+        Value::Array(arguments.iter().map(|argument| Value::Bulk(argument.to_string())).collect())
+    }
+
+    /// Sends the commands, lets the pool read its connections, and gives
+    /// back the replies of the client.
+    fn send(pool: &Rc<RefCell<RedisPool>>, upstream: &mut PooledUpstream, commands: &[&[&str]]) -> Vec<Value> {
+        for arguments in commands {
+            upstream.write_all(&command(arguments).encode()).unwrap();
+        }
+
+        let tokens = pool.borrow().tokens();
+        for token in tokens {
+            pool.borrow_mut().ready(token, EventSet::readable());
+        }
+
+        let mut buf = [0u8; 1024];
+        let size = upstream.read(&mut buf).unwrap();
+        let mut decoder = Decoder::new();
+        decoder.feed(&buf[0..size]).unwrap();
+
+        let mut replies = Vec::new();
+        while let Some(reply) = decoder.read() {
+            replies.push(reply);
+        }
+
+        replies
+    }
+
+    fn lost() -> Value {
+        Value::Error("ERR lost the pooled upstream connection".to_string())
+    }
+
+    #[test]
+    fn clients_of_a_corrupted_connection_move_to_the_others() {
+        let keyspace = MockKeyspace::new();
+        keyspace.set(b"a", b"1");
+        let pool = Rc::new(RefCell::new(RedisPool::new(vec![
+            Box::new(FaultyRedis::garbage(Token(2), b"?garbage\r\n")) as Box<Connection>,
+            Box::new(MockRedis::new(Token(3), keyspace).unwrap()),
+        ])));
+        let mut first = PooledUpstream::new(pool.clone(), Token(4)).unwrap();
+        let mut second = PooledUpstream::new(pool.clone(), Token(5)).unwrap();
+
+        assert_eq!(send(&pool, &mut first, &[&["GET", "a"], &["GET", "a"]]), vec![lost(), lost()]);
+        assert_eq!(send(&pool, &mut second, &[&["GET", "a"]]), vec![Value::Bulk("1".to_string())]);
+        assert_eq!(send(&pool, &mut first, &[&["GET", "a"]]), vec![Value::Bulk("1".to_string())]);
+    }
+
+    #[test]
+    fn commands_fail_when_no_connection_is_left() {
+        let pool = Rc::new(RefCell::new(RedisPool::new(vec![Box::new(FaultyRedis::broken(Token(2))) as Box<Connection>])));
+        let mut upstream = PooledUpstream::new(pool.clone(), Token(3)).unwrap();
+
+        assert_eq!(send(&pool, &mut upstream, &[&["GET", "a"], &["BLPOP", "a", "0"], &["GET", "b"]]), vec![
+            lost(),
+            Value::Error("ERR BLPOP is not supported on pooled connections".to_string()),
+            lost(),
+        ]);
+    }
+
+    #[test]
+    fn commands_tied_to_the_connection_are_rejected() {
+        for arguments in [
+            &["SELECT", "1"][..], &["SWAPDB", "0", "1"], &["AUTH", "secret"], &["HELLO", "3"], &["RESET"],
+            &["CLIENT", "SETNAME", "a"], &["MONITOR"], &["MULTI"], &["EXEC"], &["DISCARD"], &["WATCH", "a"],
+            &["UNWATCH"], &["READONLY"], &["READWRITE"], &["BLPOP", "a", "0"], &["XREAD", "BLOCK", "0", "STREAMS", "a", "0"],
+            &["SUBSCRIBE", "a"], &["PSUBSCRIBE", "a*"],
+        ].iter() {
+            assert_eq!(rejection(&command(arguments)), Some(arguments[0].to_string()));
+        }
+
+        for arguments in [
+            &["GET", "a"][..], &["XREAD", "STREAMS", "a", "0"], &["PUBLISH", "a", "1"], &["PUBSUB", "CHANNELS"],
+        ].iter() {
+            assert_eq!(rejection(&command(arguments)), None);
+        }
+    }
+}
@@ -0,0 +1,207 @@
+use connection::redis::{RedisProxy, CommandAction, Origin};
+use connection::redis::commands::{self, lookup_command};
+use connection::redis::resp::Value;
+use std::io;
+use std::time::Instant;
+use std::cmp::max;
+
+/// Kind of command, to give some of them their own limit.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CommandClass {
+    Read,
+    Write,
+    Admin,
+    Other,
+}
+
+impl CommandClass {
+    pub fn of(command: &Value) -> CommandClass {
+        match lookup_command(command) {
+            Some(info) if info.has_flag(commands::ADMIN) => CommandClass::Admin,
+            Some(_) if commands::is_write(command) => CommandClass::Write,
+            Some(info) if info.is_readonly() => CommandClass::Read,
+            _ => CommandClass::Other,
+        }
+    }
+}
+
+struct TokenBucket {
+    /// Tokens added per second.
+    rate: f64,
+    burst: f64,
+    /// Goes below zero while commands are delayed.
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, burst: f64) -> io::Result<Self> {
+        if !(rate > 0.0) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "The rate has to be positive"));
+        }
+
+        if !(burst >= 1.0) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "The burst has to allow one command"));
+        }
+
+        Ok(TokenBucket {
+            rate: rate,
+            burst: burst,
+            tokens: burst,
+            updated: Instant::now(),
+        })
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated);
+        let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1000000000.0;
+
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.updated = now;
+    }
+
+    /// Milliseconds until a token taken now would be paid back, zero when
+    /// there is one.
+    fn wait(&mut self) -> u64 {
+        self.refill();
+
+        let left = self.tokens - 1.0;
+        if left >= 0.0 {
+            return 0;
+        }
+
+        (-left / self.rate * 1000.0).ceil() as u64
+    }
+
+    /// Takes a token, going in debt if there is none.
+    fn take(&mut self) {
+        self.tokens -= 1.0;
+    }
+}
+
+/// Limits the commands per second of a client connection with token buckets:
+/// one for every command, plus optional ones per command class. Commands
+/// over the limit are rejected, or delayed until they fit.
+pub struct RateLimitProxy {
+    bucket: TokenBucket,
+    classes: Vec<(CommandClass, TokenBucket)>,
+    /// Longest delay in milliseconds, when delaying.
+    max_delay: Option<u64>,
+}
+
+impl RateLimitProxy {
+    /// Allows `rate` commands per second, with bursts of up to `burst`.
+    /// The rate has to be positive and the burst at least one.
+    pub fn new(rate: f64, burst: f64) -> io::Result<Self> {
+        Ok(RateLimitProxy {
+            bucket: try!(TokenBucket::new(rate, burst)),
+            classes: Vec::new(),
+            max_delay: None,
+        })
+    }
+
+    /// Also limits the commands of the class, i.e. writes.
+    pub fn with_class(mut self, class: CommandClass, rate: f64, burst: f64) -> io::Result<Self> {
+        self.classes.push((class, try!(TokenBucket::new(rate, burst))));
+        Ok(self)
+    }
+
+    /// Delays the commands over the limit instead of rejecting them, unless
+    /// they would wait more than `max_delay` milliseconds, so a client
+    /// flooding the proxy can not pile up commands without end.
+    pub fn delaying(mut self, max_delay: u64) -> Self {
+        self.max_delay = Some(max_delay);
+        self
+    }
+}
+
+impl RedisProxy for RateLimitProxy {
+    fn on_command(&mut self, command: Value) -> CommandAction {
+        let class = CommandClass::of(&command);
+
+        let mut wait = self.bucket.wait();
+        for &mut (bucket_class, ref mut bucket) in self.classes.iter_mut() {
+            if bucket_class == class {
+                wait = max(wait, bucket.wait());
+            }
+        }
+
+        if wait > 0 && self.max_delay.map(|max_delay| wait > max_delay).unwrap_or(true) {
+            info!("Rate limiting {:?} command", class);
+            return CommandAction::Respond(Value::Error("ERR rate limited".to_string()));
+        }
+
+        // Buckets go in debt while delaying, which holds back later commands
+        self.bucket.take();
+        for &mut (bucket_class, ref mut bucket) in self.classes.iter_mut() {
+            if bucket_class == class {
+                bucket.take();
+            }
+        }
+
+        if wait > 0 {
+            info!("Delaying {:?} command by {}ms", class, wait);
+            return CommandAction::Delay(wait, Box::new(CommandAction::Forward(command)));
+        }
+
+        CommandAction::Forward(command)
+    }
+
+    fn on_response(&mut self, _: Origin, response: Value) -> Value {
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use connection::redis::{RedisProxy, CommandAction};
+    use connection::redis::resp::Value;
+    use super::{RateLimitProxy, CommandClass};
+
+    fn command(arguments: &[&str]) -> Value {
+        Value::Array(arguments.iter().map(|argument| Value::Bulk(argument.to_string())).collect())
+    }
+
+    fn is_forwarded(action: CommandAction) -> bool {
+        match action {
+            CommandAction::Forward(_) => true,
+            CommandAction::Respond(Value::Error(ref message)) if message == "ERR rate limited" => false,
+            _ => panic!("unexpected action"),
+        }
+    }
+
+    #[test]
+    fn classes_have_their_own_limit() {
+        let mut proxy = RateLimitProxy::new(0.001, 3.0).unwrap().with_class(CommandClass::Write, 0.001, 1.0).unwrap();
+
+        assert!(is_forwarded(proxy.on_command(command(&["SET", "a", "1"]))));
+        assert!(!is_forwarded(proxy.on_command(command(&["SET", "a", "2"]))));
+        assert!(is_forwarded(proxy.on_command(command(&["GET", "a"]))));
+        assert!(is_forwarded(proxy.on_command(command(&["GET", "a"]))));
+        assert!(!is_forwarded(proxy.on_command(command(&["GET", "a"]))));
+    }
+
+    #[test]
+    fn delays_commands_over_the_limit() {
+        let mut proxy = RateLimitProxy::new(10.0, 1.0).unwrap().delaying(150);
+
+        assert!(is_forwarded(proxy.on_command(command(&["GET", "a"]))));
+        match proxy.on_command(command(&["GET", "a"])) {
+            CommandAction::Delay(delay, _) => assert!(delay > 0 && delay <= 100),
+            _ => panic!("GET should be delayed"),
+        }
+
+        // A third one would wait about 200ms, and does not add to the debt
+        assert!(!is_forwarded(proxy.on_command(command(&["GET", "a"]))));
+        assert!(proxy.bucket.tokens > -1.5);
+    }
+
+    #[test]
+    fn rates_have_to_be_positive() {
+        assert!(RateLimitProxy::new(0.0, 1.0).is_err());
+        assert!(RateLimitProxy::new(-1.0, 1.0).is_err());
+        assert!(RateLimitProxy::new(1.0, 0.5).is_err());
+        assert!(RateLimitProxy::new(1.0, 1.0).unwrap().with_class(CommandClass::Write, 0.0, 1.0).is_err());
+    }
+}
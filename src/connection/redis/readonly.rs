@@ -0,0 +1,135 @@
+use connection::redis::{RedisProxy, CommandAction, Origin};
+use connection::redis::commands::{self, lookup_command};
+use connection::redis::resp::Value;
+use std::rc::Rc;
+use std::cell::Cell;
+
+/// Shared flag to turn the read-only mode on and off while the proxy runs.
+/// Clones share the same state, so a single switch can drive the proxies of
+/// every client connection.
+#[derive(Clone)]
+pub struct ReadOnlySwitch {
+    enabled: Rc<Cell<bool>>,
+}
+
+impl ReadOnlySwitch {
+    pub fn new(enabled: bool) -> Self {
+        ReadOnlySwitch {
+            enabled: Rc::new(Cell::new(enabled)),
+        }
+    }
+
+    pub fn enable(&self) {
+        self.enabled.set(true);
+    }
+
+    pub fn disable(&self) {
+        self.enabled.set(false);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.get()
+    }
+}
+
+/// Rejects the commands that may write on the keyspace while the switch is
+/// enabled. Commands missing from the command table are rejected as well,
+/// unless `forward_unknown` is set.
+pub struct ReadOnlyProxy {
+    switch: ReadOnlySwitch,
+    forward_unknown: bool,
+}
+
+impl ReadOnlyProxy {
+    pub fn new(switch: ReadOnlySwitch) -> Self {
+        ReadOnlyProxy {
+            switch: switch,
+            forward_unknown: false,
+        }
+    }
+
+    /// Forwards the commands missing from the command table, like module
+    /// commands, trusting them not to write.
+    pub fn forward_unknown(mut self) -> Self {
+        self.forward_unknown = true;
+        self
+    }
+}
+
+fn rejection() -> CommandAction {
+    CommandAction::Respond(Value::Error("READONLY You can't write against a read only proxy.".to_string()))
+}
+
+impl RedisProxy for ReadOnlyProxy {
+    fn on_command(&mut self, command: Value) -> CommandAction {
+        if !self.switch.is_enabled() {
+            return CommandAction::Forward(command);
+        }
+
+        match lookup_command(&command) {
+            Some(info) if commands::is_write(&command) => {
+                info!("Rejecting {} on read only mode", info.name);
+                rejection()
+            },
+            None if !self.forward_unknown => {
+                info!("Rejecting unknown command on read only mode");
+                rejection()
+            },
+            _ => CommandAction::Forward(command),
+        }
+    }
+
+    fn on_response(&mut self, _: Origin, response: Value) -> Value {
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use connection::redis::{RedisProxy, CommandAction};
+    use connection::redis::resp::Value;
+    use super::{ReadOnlyProxy, ReadOnlySwitch};
+
+    fn command(arguments: &[&str]) -> Value {
+        Value::Array(arguments.iter().map(|argument| Value::Bulk(argument.to_string())).collect())
+    }
+
+    fn is_forwarded(action: CommandAction) -> bool {
+        match action {
+            CommandAction::Forward(_) => true,
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn rejects_writes_while_switched_on() {
+        let switch = ReadOnlySwitch::new(false);
+        let mut proxy = ReadOnlyProxy::new(switch.clone());
+
+        assert!(is_forwarded(proxy.on_command(command(&["SET", "a", "1"]))));
+
+        switch.enable();
+        assert!(!is_forwarded(proxy.on_command(command(&["SET", "a", "1"]))));
+        assert!(is_forwarded(proxy.on_command(command(&["GET", "a"]))));
+
+        switch.disable();
+        assert!(is_forwarded(proxy.on_command(command(&["DEL", "a"]))));
+    }
+
+    #[test]
+    fn rejects_writes_and_unknown_commands() {
+        let switch = ReadOnlySwitch::new(true);
+        let mut proxy = ReadOnlyProxy::new(switch.clone());
+
+        assert!(is_forwarded(proxy.on_command(command(&["GET", "a"]))));
+        assert!(is_forwarded(proxy.on_command(command(&["FUNCTION", "LIST"]))));
+        assert!(!is_forwarded(proxy.on_command(command(&["SET", "a", "1"]))));
+        assert!(!is_forwarded(proxy.on_command(command(&["FUNCTION", "FLUSH"]))));
+        assert!(!is_forwarded(proxy.on_command(command(&["JSON.SET", "a", "$", "1"]))));
+
+        assert!(is_forwarded(ReadOnlyProxy::new(switch.clone()).forward_unknown().on_command(command(&["JSON.GET", "a"]))));
+
+        switch.disable();
+        assert!(is_forwarded(proxy.on_command(command(&["SET", "a", "1"]))));
+    }
+}
@@ -0,0 +1,272 @@
+use connection::redis::{RedisProxy, CommandAction, Origin, command_name};
+use connection::redis::resp::Value;
+use std::collections::HashMap;
+use std::ascii::AsciiExt;
+
+/// Translates between the canonical command names used by clients and the
+/// names the upstream exposes after `rename-command`. Commands renamed to an
+/// empty name are disabled on the upstream and get rejected locally. Error
+/// messages and the replies of `COMMAND` and `COMMAND INFO` name the
+/// commands by their canonical names again.
+pub struct RenameProxy {
+    renames: HashMap<String, String>,
+}
+
+impl RenameProxy {
+    /// Builds the proxy from a map of canonical names to upstream names.
+    pub fn new(renames: HashMap<String, String>) -> Self {
+        RenameProxy {
+            renames: renames.into_iter()
+                .map(|(canonical, renamed)| (canonical.to_ascii_uppercase(), renamed))
+                .collect(),
+        }
+    }
+
+    /// Gives the upstream names of the commands `COMMAND INFO` asks about.
+    fn rename_arguments(&self, command: Value) -> Value {
+        if !self.lists_commands(&command) {
+            return command;
+        }
+
+        match command {
+            Value::Array(input) => {
+                Value::Array(input.into_iter().enumerate().map(|(position, argument)| {
+                    match argument {
+                        Value::Bulk(ref name) if position > 1 => {
+                            match self.renames.get(&name.to_ascii_uppercase()) {
+                                Some(renamed) if !renamed.is_empty() => Value::Bulk(renamed.clone()),
+                                _ => Value::Bulk(name.clone()),
+                            }
+                        },
+                        argument => argument,
+                    }
+                }).collect())
+            },
+            command => command,
+        }
+    }
+
+    /// Canonical name of an upstream name, in its case.
+    fn restore_name(&self, name: &str) -> Option<String> {
+        for (canonical, renamed) in self.renames.iter() {
+            if renamed.is_empty() {
+                continue;
+            }
+
+            if name == renamed {
+                return Some(canonical.clone());
+            } else if name == renamed.to_ascii_lowercase() {
+                return Some(canonical.to_ascii_lowercase());
+            }
+        }
+
+        None
+    }
+
+    /// Restores the upstream names found as whole words in the message.
+    fn restore_names(&self, message: &str) -> String {
+        let mut restored = String::with_capacity(message.len());
+        let mut word = String::new();
+
+        for character in message.chars() {
+            if character.is_alphanumeric() || character == '_' || character == '-' {
+                word.push(character);
+                continue;
+            }
+
+            restored.push_str(&self.restore_name(&word).unwrap_or(word.clone()));
+            restored.push(character);
+            word.clear();
+        }
+        restored.push_str(&self.restore_name(&word).unwrap_or(word));
+
+        restored
+    }
+
+    /// Restores the name, and those of the subcommands, of a `COMMAND`
+    /// reply entry. Subcommands are named `command|subcommand`.
+    fn restore_entry(&self, entry: Value) -> Value {
+        let mut fields = match entry {
+            Value::Array(fields) => fields,
+            entry => return entry,
+        };
+
+        if let Some(&mut Value::Bulk(ref mut name)) = fields.get_mut(0) {
+            let restored = {
+                let mut parts = name.splitn(2, '|');
+                let command = parts.next().unwrap_or("");
+
+                self.restore_name(command).map(|command| {
+                    match parts.next() {
+                        Some(subcommand) => format!("{}|{}", command, subcommand),
+                        None => command,
+                    }
+                })
+            };
+
+            if let Some(restored) = restored {
+                *name = restored;
+            }
+        }
+
+        if let Some(&mut Value::Array(ref mut subcommands)) = fields.get_mut(SUBCOMMANDS) {
+            let entries = subcommands.drain(..).collect::<Vec<Value>>();
+            subcommands.extend(entries.into_iter().map(|entry| self.restore_entry(entry)));
+        }
+
+        Value::Array(fields)
+    }
+
+    /// Whether the command is `COMMAND` or `COMMAND INFO`, whichever name
+    /// it has upstream.
+    fn lists_commands(&self, command: &Value) -> bool {
+        let name = match command_name(command) {
+            Some(name) => self.restore_name(&name).unwrap_or(name).to_ascii_uppercase(),
+            None => return false,
+        };
+
+        match *command {
+            Value::Array(ref input) if name == "COMMAND" => {
+                match input.get(1) {
+                    Some(&Value::Bulk(ref subcommand)) => subcommand.eq_ignore_ascii_case("INFO"),
+                    Some(_) => false,
+                    None => true,
+                }
+            },
+            _ => false,
+        }
+    }
+}
+
+/// Position of the subcommands in a `COMMAND` reply entry.
+const SUBCOMMANDS: usize = 9;
+
+impl RedisProxy for RenameProxy {
+    fn on_command(&mut self, command: Value) -> CommandAction {
+        let command = self.rename_arguments(command);
+        let renamed = match command_name(&command) {
+            Some(name) => {
+                match self.renames.get(&name) {
+                    Some(renamed) => renamed.clone(),
+                    None => return CommandAction::Forward(command),
+                }
+            },
+            None => return CommandAction::Forward(command),
+        };
+
+        if renamed.is_empty() {
+            let name = command_name(&command).unwrap_or(String::new());
+            return CommandAction::Respond(Value::Error(format!("ERR unknown command '{}'", name.to_ascii_lowercase())));
+        }
+
+        match command {
+            Value::Array(mut input) => {
+                input[0] = Value::Bulk(renamed);
+
+                CommandAction::Forward(Value::Array(input))
+            },
+            _ => CommandAction::Forward(command),
+        }
+    }
+
+    fn on_response(&mut self, origin: Origin, response: Value) -> Value {
+        let lists_commands = match origin {
+            Origin::Command(command) => self.lists_commands(command),
+            _ => false,
+        };
+
+        match response {
+            Value::Error(message) => Value::Error(self.restore_names(&message)),
+            Value::Array(entries) if lists_commands => {
+                Value::Array(entries.into_iter().map(|entry| self.restore_entry(entry)).collect())
+            },
+            response => response,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use connection::redis::{RedisProxy, CommandAction, Origin};
+    use connection::redis::resp::Value;
+    use super::RenameProxy;
+    use std::collections::HashMap;
+
+    fn command(arguments: &[&str]) -> Value {
+        Value::Array(arguments.iter().map(|argument| Value::Bulk(argument.to_string())).collect())
+    }
+
+    fn proxy() -> RenameProxy {
+        let mut renames = HashMap::new();
+        renames.insert("config".to_string(), "XCONFIG".to_string());
+        renames.insert("FLUSHALL".to_string(), String::new());
+
+        RenameProxy::new(renames)
+    }
+
+    #[test]
+    fn renames_and_disables_commands() {
+        let mut proxy = proxy();
+
+        match proxy.on_command(command(&["config", "get", "maxmemory"])) {
+            CommandAction::Forward(forwarded) => assert_eq!(forwarded, command(&["XCONFIG", "get", "maxmemory"])),
+            _ => panic!("CONFIG should be forwarded"),
+        }
+
+        match proxy.on_command(command(&["FLUSHALL"])) {
+            CommandAction::Respond(reply) => assert_eq!(reply, Value::Error("ERR unknown command 'flushall'".to_string())),
+            _ => panic!("FLUSHALL should be rejected"),
+        }
+
+        match proxy.on_command(command(&["GET", "a"])) {
+            CommandAction::Forward(forwarded) => assert_eq!(forwarded, command(&["GET", "a"])),
+            _ => panic!("GET should be forwarded"),
+        }
+    }
+
+    #[test]
+    fn errors_name_the_canonical_command() {
+        let mut proxy = proxy();
+        let error = Value::Error("ERR Unknown subcommand or wrong number of arguments for 'xconfig'".to_string());
+
+        let config = Value::Array(vec![Value::Bulk("XCONFIG".to_string())]);
+
+        assert_eq!(proxy.on_response(Origin::Command(&config), error), Value::Error("ERR Unknown subcommand or wrong number of arguments for 'config'".to_string()));
+    }
+
+    #[test]
+    fn only_whole_names_are_restored() {
+        let mut proxy = proxy();
+        let error = Value::Error("ERR xconfig is not xconfigured, see XCONFIG, 'xconfig|get'".to_string());
+
+        assert_eq!(proxy.on_response(Origin::Command(&command(&["XCONFIG"])), error), Value::Error("ERR config is not xconfigured, see CONFIG, 'config|get'".to_string()));
+    }
+
+    #[test]
+    fn command_replies_name_the_canonical_commands() {
+        let mut proxy = proxy();
+
+        let info = match proxy.on_command(command(&["COMMAND", "INFO", "config", "get"])) {
+            CommandAction::Forward(forwarded) => forwarded,
+            _ => panic!("COMMAND INFO should be forwarded"),
+        };
+        assert_eq!(info, command(&["COMMAND", "INFO", "XCONFIG", "get"]));
+
+        let entry = |name: &str, subcommands: Vec<Value>| {
+            let mut fields = vec![Value::Bulk(name.to_string()), Value::Integer(-2)];
+            fields.extend((0..7).map(|_| Value::Array(Vec::new())));
+            fields.push(Value::Array(subcommands));
+            Value::Array(fields)
+        };
+
+        let reply = Value::Array(vec![entry("xconfig", vec![entry("xconfig|get", Vec::new())]), entry("get", Vec::new())]);
+        assert_eq!(proxy.on_response(Origin::Command(&info), reply), Value::Array(vec![
+            entry("config", vec![entry("config|get", Vec::new())]),
+            entry("get", Vec::new()),
+        ]));
+
+        let reply = Value::Array(vec![entry("xconfig", Vec::new())]);
+        assert_eq!(proxy.on_response(Origin::Command(&command(&["COMMAND"])), reply.clone()), Value::Array(vec![entry("config", Vec::new())]));
+        assert_eq!(proxy.on_response(Origin::Command(&command(&["COMMAND", "DOCS"])), reply.clone()), reply);
+    }
+}
@@ -0,0 +1,574 @@
+//! RESP2 and RESP3 values, as exchanged with clients and upstreams. Every
+//! value parsed is encoded back to the same bytes, so replies in RESP3 (after
+//! a `HELLO 3`) reach the client as the upstream sent them.
+
+use std::io;
+use std::str;
+use std::cmp::min;
+use std::collections::VecDeque;
+
+/// Up to 512 MB, as Redis accepts.
+const MAX_SIZE: i64 = 512 * 1024 * 1024;
+
+/// Aggregates nested deeper are refused, as no command or reply needs them.
+const MAX_DEPTH: usize = 32;
+
+/// Longest line, i.e. simple string or length header, as Redis limits inline
+/// commands.
+const MAX_LINE: usize = 64 * 1024;
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Value {
+    /// Null bulk string, `$-1`.
+    Null,
+    /// Null array, `*-1`.
+    NullArray,
+    String(String),
+    Error(String),
+    Integer(i64),
+    /// Bulk string holding UTF-8 text.
+    Bulk(String),
+    /// Bulk string holding binary data.
+    BufBulk(Vec<u8>),
+    Array(Vec<Value>),
+    /// RESP3 null, `_`.
+    Nil,
+    /// RESP3 double, kept as sent (`1.5`, `inf`, `nan`...).
+    Double(String),
+    Boolean(bool),
+    /// RESP3 big number, kept as sent.
+    BigNumber(String),
+    /// RESP3 bulk error.
+    BulkError(String),
+    /// RESP3 verbatim string: format (`txt`, `mkd`) and text.
+    Verbatim(String, String),
+    Map(Vec<(Value, Value)>),
+    Set(Vec<Value>),
+    /// RESP3 out of band message: Pub/Sub messages, invalidations...
+    Push(Vec<Value>),
+    /// RESP3 attributes, with the value they describe.
+    Attribute(Vec<(Value, Value)>, Box<Value>),
+}
+
+impl Value {
+    pub fn is_null(&self) -> bool {
+        match *self {
+            Value::Null | Value::NullArray | Value::Nil => true,
+            _ => false,
+        }
+    }
+
+    pub fn is_error(&self) -> bool {
+        match *self {
+            Value::Error(_) | Value::BulkError(_) => true,
+            Value::Attribute(_, ref value) => value.is_error(),
+            _ => false,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        encode_into(self, &mut buf);
+        buf
+    }
+
+    pub fn to_beautify_string(&self) -> String {
+        match *self {
+            Value::Null | Value::Nil => "(Null)".to_string(),
+            Value::NullArray => "(Null Array)".to_string(),
+            Value::String(ref value) => value.clone(),
+            Value::Error(ref value) | Value::BulkError(ref value) => format!("(Error) {}", value),
+            Value::Integer(value) => format!("(Integer) {}", value),
+            Value::Bulk(ref value) => format!("\"{}\"", value),
+            Value::BufBulk(ref value) => format!("(Buffer) {} bytes", value.len()),
+            Value::Double(ref value) => format!("(Double) {}", value),
+            Value::Boolean(value) => format!("(Boolean) {}", value),
+            Value::BigNumber(ref value) => format!("(Big Number) {}", value),
+            Value::Verbatim(_, ref value) => format!("\"{}\"", value),
+            Value::Array(ref values) => format!("[{}]", beautify_all(values)),
+            Value::Set(ref values) => format!("(Set) [{}]", beautify_all(values)),
+            Value::Push(ref values) => format!("(Push) [{}]", beautify_all(values)),
+            Value::Map(ref pairs) => format!("{{{}}}", beautify_pairs(pairs)),
+            Value::Attribute(ref pairs, ref value) => format!("(Attributes {{{}}}) {}", beautify_pairs(pairs), value.to_beautify_string()),
+        }
+    }
+}
+
+fn beautify_all(values: &[Value]) -> String {
+    values.iter().map(|value| value.to_beautify_string()).collect::<Vec<String>>().join(", ")
+}
+
+fn beautify_pairs(pairs: &[(Value, Value)]) -> String {
+    pairs.iter()
+        .map(|&(ref key, ref value)| format!("{}: {}", key.to_beautify_string(), value.to_beautify_string()))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+fn encode_line(buf: &mut Vec<u8>, kind: u8, line: &[u8]) {
+    buf.push(kind);
+    buf.extend_from_slice(line);
+    buf.extend_from_slice(b"\r\n");
+}
+
+fn encode_blob(buf: &mut Vec<u8>, kind: u8, blob: &[u8]) {
+    encode_line(buf, kind, blob.len().to_string().as_bytes());
+    buf.extend_from_slice(blob);
+    buf.extend_from_slice(b"\r\n");
+}
+
+fn encode_into(value: &Value, buf: &mut Vec<u8>) {
+    match *value {
+        Value::Null => buf.extend_from_slice(b"$-1\r\n"),
+        Value::NullArray => buf.extend_from_slice(b"*-1\r\n"),
+        Value::Nil => buf.extend_from_slice(b"_\r\n"),
+        Value::String(ref value) => encode_line(buf, b'+', value.as_bytes()),
+        Value::Error(ref value) => encode_line(buf, b'-', value.as_bytes()),
+        Value::Integer(value) => encode_line(buf, b':', value.to_string().as_bytes()),
+        Value::Double(ref value) => encode_line(buf, b',', value.as_bytes()),
+        Value::Boolean(value) => encode_line(buf, b'#', if value { b"t" } else { b"f" }),
+        Value::BigNumber(ref value) => encode_line(buf, b'(', value.as_bytes()),
+        Value::Bulk(ref value) => encode_blob(buf, b'$', value.as_bytes()),
+        Value::BufBulk(ref value) => encode_blob(buf, b'$', value),
+        Value::BulkError(ref value) => encode_blob(buf, b'!', value.as_bytes()),
+        Value::Verbatim(ref format, ref value) => {
+            encode_blob(buf, b'=', format!("{}:{}", format, value).as_bytes());
+        },
+        Value::Array(ref values) => encode_all(buf, b'*', values),
+        Value::Set(ref values) => encode_all(buf, b'~', values),
+        Value::Push(ref values) => encode_all(buf, b'>', values),
+        Value::Map(ref pairs) => encode_pairs(buf, b'%', pairs),
+        Value::Attribute(ref pairs, ref value) => {
+            encode_pairs(buf, b'|', pairs);
+            encode_into(value, buf);
+        },
+    }
+}
+
+fn encode_all(buf: &mut Vec<u8>, kind: u8, values: &[Value]) {
+    encode_line(buf, kind, values.len().to_string().as_bytes());
+    for value in values {
+        encode_into(value, buf);
+    }
+}
+
+fn encode_pairs(buf: &mut Vec<u8>, kind: u8, pairs: &[(Value, Value)]) {
+    encode_line(buf, kind, pairs.len().to_string().as_bytes());
+    for &(ref key, ref value) in pairs {
+        encode_into(key, buf);
+        encode_into(value, buf);
+    }
+}
+
+/// Aggregate whose elements are being parsed.
+struct Frame {
+    kind: u8,
+    /// Elements still to come.
+    remaining: usize,
+    values: Vec<Value>,
+    /// Attributes of a value, once their pairs are parsed.
+    attributes: Option<Vec<(Value, Value)>>,
+}
+
+impl Frame {
+    fn new(kind: u8, remaining: usize) -> Self {
+        Frame {
+            kind: kind,
+            remaining: remaining,
+            values: Vec::with_capacity(min(remaining, 1024)),
+            attributes: None,
+        }
+    }
+}
+
+fn into_pairs(values: Vec<Value>) -> Vec<(Value, Value)> {
+    let mut pairs = Vec::with_capacity(values.len() / 2);
+    let mut values = values.into_iter();
+    while let (Some(key), Some(value)) = (values.next(), values.next()) {
+        pairs.push((key, value));
+    }
+
+    pairs
+}
+
+/// Streaming decoder: bytes are fed as they come and values are read once
+/// complete. The elements of an aggregate are kept as they are parsed, so
+/// bytes are only parsed once however they are split.
+pub struct Decoder {
+    buf: Vec<u8>,
+    /// Aggregates being parsed, innermost last.
+    stack: Vec<Frame>,
+    /// Bytes already parsed into `stack`.
+    pending: usize,
+    /// Bytes of the line of the next token already searched for its end.
+    scanned: usize,
+    values: VecDeque<Value>,
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Decoder {
+            buf: Vec::new(),
+            stack: Vec::new(),
+            pending: 0,
+            scanned: 0,
+            values: VecDeque::new(),
+        }
+    }
+
+    /// Feeds bytes to the decoder. On a protocol error the buffered bytes
+    /// are discarded.
+    pub fn feed(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.buf.extend_from_slice(buf);
+
+        match self.decode() {
+            Ok(position) => {
+                self.buf.drain(0..position);
+                Ok(())
+            },
+            Err(message) => {
+                self.buf.clear();
+                self.stack.clear();
+                self.pending = 0;
+                self.scanned = 0;
+                Err(io::Error::new(io::ErrorKind::InvalidData, message))
+            },
+        }
+    }
+
+    /// Parses the buffered bytes, giving the position up to which they
+    /// were consumed.
+    fn decode(&mut self) -> Result<usize, String> {
+        let mut position = 0;
+
+        while let Some((token, next)) = try!(parse_token(&self.buf, position, &mut self.scanned)) {
+            self.pending += next - position;
+            self.scanned = 0;
+            position = next;
+            try!(self.push(token));
+        }
+
+        Ok(position)
+    }
+
+    fn push(&mut self, token: Token) -> Result<(), String> {
+        let mut value = match token {
+            Token::Value(value) => value,
+            Token::Aggregate(kind, count) => {
+                if self.stack.len() >= MAX_DEPTH {
+                    return Err("Too deeply nested aggregate".to_string());
+                }
+
+                self.stack.push(Frame::new(kind, count));
+                if count > 0 {
+                    return Ok(());
+                }
+
+                match self.complete() {
+                    Some(value) => value,
+                    None => return Ok(()),
+                }
+            },
+        };
+
+        loop {
+            match self.stack.last_mut() {
+                Some(frame) => {
+                    frame.values.push(value);
+                    frame.remaining -= 1;
+                    if frame.remaining > 0 {
+                        return Ok(());
+                    }
+                },
+                None => {
+                    self.values.push_back(value);
+                    self.pending = 0;
+                    return Ok(());
+                },
+            }
+
+            value = match self.complete() {
+                Some(value) => value,
+                None => return Ok(()),
+            };
+        }
+    }
+
+    /// Pops the innermost aggregate once all its elements are parsed. The
+    /// pairs of attributes wait for the value they describe instead.
+    fn complete(&mut self) -> Option<Value> {
+        let frame = match self.stack.pop() {
+            Some(frame) => frame,
+            None => return None,
+        };
+
+        let value = match frame.kind {
+            b'~' => Value::Set(frame.values),
+            b'>' => Value::Push(frame.values),
+            b'%' => Value::Map(into_pairs(frame.values)),
+            b'|' => {
+                let mut described = Frame::new(b'*', 1);
+                described.attributes = Some(into_pairs(frame.values));
+                self.stack.push(described);
+                return None;
+            },
+            _ => {
+                match frame.attributes {
+                    Some(pairs) => Value::Attribute(pairs, Box::new(frame.values.into_iter().next().unwrap())),
+                    None => Value::Array(frame.values),
+                }
+            },
+        };
+
+        Some(value)
+    }
+
+    /// Reads the next decoded value.
+    pub fn read(&mut self) -> Option<Value> {
+        self.values.pop_front()
+    }
+
+    /// Bytes waiting for the rest of a value.
+    pub fn buffer_len(&self) -> usize {
+        self.buf.len() + self.pending
+    }
+}
+
+/// Value, or header of an aggregate whose elements follow.
+enum Token {
+    Value(Value),
+    Aggregate(u8, usize),
+}
+
+type ParseResult = Result<Option<(Token, usize)>, String>;
+
+/// Line starting at `start`, with the position following it, or `None` when
+/// its end has not been received. The search resumes after the `scanned`
+/// bytes, which are updated, so a line received in pieces is only scanned
+/// once.
+fn read_line<'a>(buf: &'a [u8], start: usize, scanned: &mut usize) -> Result<Option<(&'a [u8], usize)>, String> {
+    let last = buf.len().saturating_sub(1);
+
+    match (start + *scanned..last).find(|&position| buf[position] == b'\r' && buf[position + 1] == b'\n') {
+        Some(end) if end - start <= MAX_LINE => Ok(Some((&buf[start..end], end + 2))),
+        Some(_) => Err("Line too long".to_string()),
+        None if last > start + MAX_LINE => Err("Line too long".to_string()),
+        None => {
+            // The last byte may be the '\r' of the end
+            *scanned = last.saturating_sub(start);
+            Ok(None)
+        },
+    }
+}
+
+fn parse_text(bytes: &[u8], kind: u8) -> Result<String, String> {
+    str::from_utf8(bytes)
+        .map(|text| text.to_string())
+        .map_err(|_| format!("Invalid text in '{}'", kind as char))
+}
+
+fn parse_length(bytes: &[u8], kind: u8) -> Result<i64, String> {
+    let length = try!(parse_text(bytes, kind)).parse::<i64>().map_err(|_| format!("Invalid length in '{}'", kind as char));
+
+    match length {
+        Ok(length) if length < -1 || length >= MAX_SIZE => Err(format!("Invalid length in '{}'", kind as char)),
+        length => length,
+    }
+}
+
+/// Parses the value or aggregate header starting at `start`, giving it with
+/// the position following it, or `None` when more bytes are needed.
+fn parse_token(buf: &[u8], start: usize, scanned: &mut usize) -> ParseResult {
+    if start >= buf.len() {
+        return Ok(None);
+    }
+
+    let kind = buf[start];
+    let (line, next) = match try!(read_line(buf, start + 1, scanned)) {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+
+    let value = match kind {
+        b'+' => Value::String(try!(parse_text(line, kind))),
+        b'-' => Value::Error(try!(parse_text(line, kind))),
+        b':' => {
+            let integer = try!(parse_text(line, kind)).parse::<i64>().map_err(|_| "Invalid integer".to_string());
+            Value::Integer(try!(integer))
+        },
+        b'_' => Value::Nil,
+        b',' => Value::Double(try!(parse_text(line, kind))),
+        b'(' => Value::BigNumber(try!(parse_text(line, kind))),
+        b'#' => {
+            match line {
+                b"t" => Value::Boolean(true),
+                b"f" => Value::Boolean(false),
+                _ => return Err("Invalid boolean".to_string()),
+            }
+        },
+        b'$' | b'!' | b'=' => {
+            let length = try!(parse_length(line, kind));
+            if length == -1 && kind == b'$' {
+                return Ok(Some((Token::Value(Value::Null), next)));
+            }
+            if length < 0 {
+                return Err(format!("Invalid length in '{}'", kind as char));
+            }
+
+            let end = next + length as usize;
+            if end + 2 > buf.len() {
+                return Ok(None);
+            }
+            if &buf[end..end + 2] != b"\r\n" {
+                return Err(format!("Invalid '{}'", kind as char));
+            }
+
+            let blob = &buf[next..end];
+            let value = match kind {
+                b'!' => Value::BulkError(try!(parse_text(blob, kind))),
+                b'=' => {
+                    let text = try!(parse_text(blob, kind));
+                    if text.len() < 4 || &text[3..4] != ":" {
+                        return Err("Invalid verbatim string".to_string());
+                    }
+                    Value::Verbatim(text[0..3].to_string(), text[4..].to_string())
+                },
+                _ => {
+                    match str::from_utf8(blob) {
+                        Ok(text) => Value::Bulk(text.to_string()),
+                        Err(_) => Value::BufBulk(blob.to_vec()),
+                    }
+                },
+            };
+
+            return Ok(Some((Token::Value(value), end + 2)));
+        },
+        b'*' | b'~' | b'>' | b'%' | b'|' => {
+            let length = try!(parse_length(line, kind));
+            if length == -1 && kind == b'*' {
+                return Ok(Some((Token::Value(Value::NullArray), next)));
+            }
+            if length < 0 {
+                return Err(format!("Invalid length in '{}'", kind as char));
+            }
+
+            // Maps and attributes hold a key and a value per entry
+            let count = if kind == b'%' || kind == b'|' { 2 * length } else { length };
+
+            return Ok(Some((Token::Aggregate(kind, count as usize), next)));
+        },
+        _ => return Err(format!("Invalid type '{}'", kind as char)),
+    };
+
+    Ok(Some((Token::Value(value), next)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Decoder, Value, MAX_DEPTH, MAX_LINE};
+
+    fn decode_all(input: &[u8]) -> Vec<Value> {
+        let mut decoder = Decoder::new();
+        decoder.feed(input).unwrap();
+
+        let mut values = Vec::new();
+        while let Some(value) = decoder.read() {
+            values.push(value);
+        }
+
+        values
+    }
+
+    #[test]
+    fn roundtrip() {
+        let input: &[u8] = b"+OK\r\n-ERR no\r\n:42\r\n$3\r\nfoo\r\n$-1\r\n*-1\r\n*2\r\n$1\r\na\r\n*0\r\n\
+            _\r\n,1.5\r\n#t\r\n(12345678901234567890\r\n!3\r\nbad\r\n=7\r\ntxt:abc\r\n\
+            %1\r\n+key\r\n:1\r\n~1\r\n:2\r\n>2\r\n+message\r\n$1\r\nx\r\n|1\r\n+ttl\r\n:3\r\n$1\r\nv\r\n";
+
+        let values = decode_all(input);
+        assert_eq!(values.len(), 17);
+        assert_eq!(values[6], Value::Array(vec![Value::Bulk("a".to_string()), Value::Array(vec![])]));
+        assert_eq!(values[16], Value::Attribute(
+            vec![(Value::String("ttl".to_string()), Value::Integer(3))],
+            Box::new(Value::Bulk("v".to_string()))
+        ));
+
+        let encoded: Vec<u8> = values.iter().flat_map(|value| value.encode()).collect();
+        assert_eq!(&encoded[..], input);
+    }
+
+    #[test]
+    fn byte_by_byte() {
+        let input: &[u8] = b"*2\r\n%1\r\n$3\r\nkey\r\n*1\r\n:1\r\n|1\r\n+a\r\n:1\r\n$-1\r\n+OK\r\n";
+        let mut decoder = Decoder::new();
+
+        for (position, byte) in input.iter().enumerate() {
+            if position < 42 {
+                assert_eq!(decoder.read(), None);
+            }
+            decoder.feed(&[*byte]).unwrap();
+        }
+
+        assert_eq!(decoder.read().map(|value| value.encode()), Some(input[..input.len() - 5].to_vec()));
+        assert_eq!(decoder.read(), Some(Value::String("OK".to_string())));
+        assert_eq!(decoder.buffer_len(), 0);
+    }
+
+    #[test]
+    fn binary_bulk() {
+        assert_eq!(decode_all(b"$2\r\n\xff\x00\r\n"), vec![Value::BufBulk(vec![0xff, 0x00])]);
+    }
+
+    #[test]
+    fn too_deep() {
+        let mut decoder = Decoder::new();
+        let input: Vec<u8> = (0..MAX_DEPTH + 1).flat_map(|_| b"*1\r\n".to_vec()).collect();
+
+        assert!(decoder.feed(&input).is_err());
+        assert_eq!(decoder.buffer_len(), 0);
+
+        decoder.feed(b"+OK\r\n").unwrap();
+        assert_eq!(decoder.read(), Some(Value::String("OK".to_string())));
+    }
+
+    #[test]
+    fn protocol_errors() {
+        assert!(Decoder::new().feed(b"?\r\n").is_err());
+        assert!(Decoder::new().feed(b"$3\r\nfoox\r\n").is_err());
+        assert!(Decoder::new().feed(b"*-2\r\n").is_err());
+        assert!(Decoder::new().feed(b"#x\r\n").is_err());
+    }
+
+    #[test]
+    fn lines_received_in_pieces() {
+        let mut decoder = Decoder::new();
+        let line = "a".repeat(1000);
+
+        decoder.feed(b"+").unwrap();
+        for chunk in line.as_bytes().chunks(7) {
+            decoder.feed(chunk).unwrap();
+        }
+        decoder.feed(b"\r").unwrap();
+        assert_eq!(decoder.read(), None);
+        decoder.feed(b"\n:1\r\n").unwrap();
+
+        assert_eq!(decoder.read(), Some(Value::String(line)));
+        assert_eq!(decoder.read(), Some(Value::Integer(1)));
+    }
+
+    #[test]
+    fn too_long_lines() {
+        let mut line = b"+".to_vec();
+        line.extend((0..MAX_LINE).map(|_| b'a'));
+
+        let mut decoder = Decoder::new();
+        decoder.feed(&line).unwrap();
+        assert!(decoder.feed(b"aa").is_err());
+        assert_eq!(decoder.buffer_len(), 0);
+
+        line.extend_from_slice(b"\r\n");
+        assert!(Decoder::new().feed(&line).is_ok());
+        line.insert(1, b'a');
+        assert!(Decoder::new().feed(&line).is_err());
+    }
+}
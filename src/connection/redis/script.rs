@@ -0,0 +1,311 @@
+use connection::redis::{RedisProxy, CommandAction, Origin, command_name};
+use connection::redis::commands::lookup;
+use connection::redis::resp::Value;
+use std::collections::HashSet;
+
+/// Rejects the scripts and functions reaching keys they did not declare in
+/// `KEYS`, so key based isolation (i.e. with `PrefixProxy`) also holds for
+/// them. Every `redis.call` and `redis.pcall` must name its command with a
+/// string literal, and pass `KEYS[i]` wherever the command takes a key, `i`
+/// being a number or a variable. Functions loaded with `FUNCTION LOAD` must
+/// name the keys parameter of their callbacks `keys`, and pass `keys[i]`.
+///
+/// `EVALSHA` is only accepted for scripts checked on this connection; others
+/// get `NOSCRIPT`, on which clients send the script again with `EVAL`.
+pub struct ScriptPolicyProxy {
+    approved: HashSet<String>,
+}
+
+impl ScriptPolicyProxy {
+    pub fn new() -> Self {
+        ScriptPolicyProxy {
+            approved: HashSet::new(),
+        }
+    }
+
+    /// Checks the script and remembers its digest if it passes.
+    fn check(&mut self, script: &str) -> Result<(), String> {
+        try!(check_script(script, "KEYS"));
+        self.approved.insert(sha1_hex(script.as_bytes()));
+
+        Ok(())
+    }
+}
+
+impl RedisProxy for ScriptPolicyProxy {
+    fn on_command(&mut self, command: Value) -> CommandAction {
+        let name = match command_name(&command) {
+            Some(name) => name,
+            None => return CommandAction::Forward(command),
+        };
+
+        let result = {
+            let argument = |position: usize| -> Option<String> {
+                match command {
+                    Value::Array(ref input) => {
+                        match input.get(position) {
+                            Some(&Value::Bulk(ref argument)) => Some(argument.clone()),
+                            _ => None,
+                        }
+                    },
+                    _ => None,
+                }
+            };
+
+            match &*name {
+                "EVAL" | "EVAL_RO" => {
+                    argument(1).map(|script| self.check(&script))
+                },
+                "SCRIPT" if argument(1).map(|subcommand| subcommand.eq_ignore_ascii_case("LOAD")).unwrap_or(false) => {
+                    argument(2).map(|script| self.check(&script))
+                },
+                "FUNCTION" if argument(1).map(|subcommand| subcommand.eq_ignore_ascii_case("LOAD")).unwrap_or(false) => {
+                    // The library code is the last argument, after REPLACE
+                    let count = match command {
+                        Value::Array(ref input) => input.len(),
+                        _ => 0,
+                    };
+                    argument(count - 1).map(|library| check_script(&library, "keys"))
+                },
+                "EVALSHA" | "EVALSHA_RO" => {
+                    let approved = argument(1).map(|digest| self.approved.contains(&digest.to_lowercase())).unwrap_or(false);
+                    if !approved {
+                        return CommandAction::Respond(Value::Error("NOSCRIPT No matching script. Please use EVAL.".to_string()));
+                    }
+
+                    None
+                },
+                _ => None,
+            }
+        };
+
+        match result {
+            Some(Err(reason)) => {
+                info!("Rejecting script: {}", reason);
+                CommandAction::Respond(Value::Error(format!("ERR script rejected by the proxy: {}", reason)))
+            },
+            _ => CommandAction::Forward(command),
+        }
+    }
+
+    fn on_response(&mut self, _: Origin, response: Value) -> Value {
+        response
+    }
+}
+
+/// Checks every call made by the script to the keyspace, which must take
+/// its keys from the `keys` table.
+fn check_script(script: &str, keys: &str) -> Result<(), String> {
+    let bytes = script.as_bytes();
+
+    for call in ["redis.call", "redis.pcall", "server.call", "server.pcall"].iter() {
+        let mut start = 0;
+
+        while let Some(found) = script[start..].find(call) {
+            let mut position = start + found + call.len();
+            while position < bytes.len() && (bytes[position] as char).is_whitespace() {
+                position += 1;
+            }
+
+            if position >= bytes.len() || bytes[position] != b'(' {
+                return Err(format!("{} must be called directly", call));
+            }
+
+            try!(check_call(&split_arguments(&script[position + 1..]), keys));
+            start = position;
+        }
+    }
+
+    Ok(())
+}
+
+fn check_call(arguments: &[String], keys: &str) -> Result<(), String> {
+    let name = match arguments.first().and_then(|name| string_literal(name)) {
+        Some(name) => name,
+        None => return Err("commands must be given as string literals".to_string()),
+    };
+
+    let info = match lookup(&name) {
+        Some(info) => info,
+        None => return Err(format!("unknown command '{}'", name)),
+    };
+
+    // Literal arguments stand for themselves, to find tokens like STREAMS
+    let values: Vec<Value> = arguments.iter().map(|argument| {
+        Value::Bulk(string_literal(argument).unwrap_or(argument.clone()))
+    }).collect();
+
+    for position in info.key_positions(&values) {
+        if !is_declared_key(&arguments[position], keys) {
+            return Err(format!("{} reaches a key not declared in {}: {}", info.name, keys, arguments[position]));
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether the argument is exactly an element of the keys table, like
+/// `KEYS[1]` or `KEYS[i]`.
+fn is_declared_key(argument: &str, keys: &str) -> bool {
+    if !argument.starts_with(keys) || !argument.ends_with(']') {
+        return false;
+    }
+
+    let subscript = argument[keys.len()..argument.len() - 1].trim_left();
+    if !subscript.starts_with('[') {
+        return false;
+    }
+
+    let index = subscript[1..].trim();
+    let bytes = index.as_bytes();
+    if bytes.is_empty() {
+        return false;
+    }
+
+    bytes.iter().all(|byte| (*byte as char).is_digit(10)) ||
+        (!(bytes[0] as char).is_digit(10) && bytes.iter().all(|byte| (*byte as char).is_alphanumeric() || *byte == b'_'))
+}
+
+/// Content of a Lua string literal, escapes left as they are.
+fn string_literal(argument: &str) -> Option<String> {
+    let bytes = argument.as_bytes();
+    if bytes.len() >= 2 && (bytes[0] == b'"' || bytes[0] == b'\'') && bytes[bytes.len() - 1] == bytes[0] {
+        Some(argument[1..argument.len() - 1].to_string())
+    } else {
+        None
+    }
+}
+
+/// Arguments of a call, as source text, up to its closing parenthesis.
+fn split_arguments(source: &str) -> Vec<String> {
+    let mut arguments = Vec::new();
+    let mut current = String::new();
+    let mut depth = 0;
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+
+    for c in source.chars() {
+        if let Some(open) = quote {
+            current.push(c);
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == open {
+                quote = None;
+            }
+            continue;
+        }
+
+        match c {
+            '"' | '\'' => {
+                quote = Some(c);
+                current.push(c);
+            },
+            '(' | '[' | '{' => {
+                depth += 1;
+                current.push(c);
+            },
+            ')' if depth == 0 => break,
+            ')' | ']' | '}' => {
+                depth -= 1;
+                current.push(c);
+            },
+            ',' if depth == 0 => {
+                arguments.push(current.trim().to_string());
+                current = String::new();
+            },
+            _ => current.push(c),
+        }
+    }
+
+    if !current.trim().is_empty() {
+        arguments.push(current.trim().to_string());
+    }
+
+    arguments
+}
+
+/// SHA-1 digest in hexadecimal, as Redis names the scripts.
+fn sha1_hex(data: &[u8]) -> String {
+    let mut state: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+
+    let mut message = data.to_vec();
+    let length = (data.len() as u64).wrapping_mul(8);
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    for shift in (0..8).rev() {
+        message.push((length >> (shift * 8)) as u8);
+    }
+
+    for block in message.chunks(64) {
+        let mut words = [0u32; 80];
+        for i in 0..16 {
+            words[i] = (block[4 * i] as u32) << 24 | (block[4 * i + 1] as u32) << 16 | (block[4 * i + 2] as u32) << 8 | block[4 * i + 3] as u32;
+        }
+        for i in 16..80 {
+            words[i] = (words[i - 3] ^ words[i - 8] ^ words[i - 14] ^ words[i - 16]).rotate_left(1);
+        }
+
+        let (mut a, mut b, mut c, mut d, mut e) = (state[0], state[1], state[2], state[3], state[4]);
+        for i in 0..80 {
+            let (f, k) = match i {
+                0...19 => ((b & c) | (!b & d), 0x5A827999),
+                20...39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40...59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+
+            let temp = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(words[i]);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+
+        state[0] = state[0].wrapping_add(a);
+        state[1] = state[1].wrapping_add(b);
+        state[2] = state[2].wrapping_add(c);
+        state[3] = state[3].wrapping_add(d);
+        state[4] = state[4].wrapping_add(e);
+    }
+
+    state.iter().map(|word| format!("{:08x}", word)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_script, sha1_hex};
+
+    #[test]
+    fn sha1() {
+        assert_eq!(sha1_hex(b""), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(sha1_hex(b"abc"), "a9993e364706816aba3e25717850c26c9cd0d89d");
+        assert_eq!(sha1_hex(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"), "84983e441c3bd26ebaae4aa1f95129e5e54670f1");
+        assert_eq!(sha1_hex(&vec![b'a'; 1000]), "291e9a6c66994949b57ba5e650361e98fc36b1ba");
+    }
+
+    #[test]
+    fn keys_must_be_declared() {
+        assert!(check_script("return redis.call('GET', KEYS[1])", "KEYS").is_ok());
+        assert!(check_script("for i = 1, #KEYS do redis.call('DEL', KEYS[ i ]) end", "KEYS").is_ok());
+        assert!(check_script("return redis.call('GET', KEYS[1] .. ':other')", "KEYS").is_err());
+        assert!(check_script("return redis.call('GET', KEYS[1]..KEYS[2])", "KEYS").is_err());
+        assert!(check_script("return redis.call('GET', 'user:1')", "KEYS").is_err());
+        assert!(check_script("return redis.call(name, KEYS[1])", "KEYS").is_err());
+    }
+
+    #[test]
+    fn functions_take_keys_from_their_parameter() {
+        let library = "#!lua name=lib\n\
+            redis.register_function('get', function(keys, args) return redis.call('GET', keys[1]) end)";
+        assert!(check_script(library, "keys").is_ok());
+
+        let library = "#!lua name=lib\n\
+            redis.register_function('get', function(keys, args) return redis.call('GET', args[1]) end)";
+        assert!(check_script(library, "keys").is_err());
+    }
+}
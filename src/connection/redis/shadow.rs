@@ -0,0 +1,127 @@
+use proxy::Comparator;
+use connection::redis::resp::{Decoder, Value};
+use std::collections::VecDeque;
+
+/// Replies kept while the other side lags behind.
+const MAX_BACKLOG: usize = 10000;
+
+/// Pairs the replies of the upstream and of the shadow upstream, in order,
+/// and logs those that differ. Meant to check a new Redis version against
+/// the one in production before switching over.
+pub struct ReplyComparator {
+    upstream: Decoder,
+    shadow: Decoder,
+    upstream_replies: VecDeque<Value>,
+    shadow_replies: VecDeque<Value>,
+    compared: u64,
+    mismatches: u64,
+    /// One side lagged too far behind: replies can not be paired any more.
+    gave_up: bool,
+}
+
+impl ReplyComparator {
+    pub fn new() -> Self {
+        ReplyComparator {
+            upstream: Decoder::new(),
+            shadow: Decoder::new(),
+            upstream_replies: VecDeque::new(),
+            shadow_replies: VecDeque::new(),
+            compared: 0,
+            mismatches: 0,
+            gave_up: false,
+        }
+    }
+
+    pub fn compared(&self) -> u64 {
+        self.compared
+    }
+
+    pub fn mismatches(&self) -> u64 {
+        self.mismatches
+    }
+
+    fn compare(&mut self) {
+        if self.upstream_replies.len() > MAX_BACKLOG || self.shadow_replies.len() > MAX_BACKLOG {
+            warn!("Giving up comparing the shadow replies, one side lags too far behind");
+            self.gave_up = true;
+            self.upstream_replies.clear();
+            self.shadow_replies.clear();
+        }
+
+        while !self.upstream_replies.is_empty() && !self.shadow_replies.is_empty() {
+            if let (Some(upstream), Some(shadow)) = (self.upstream_replies.pop_front(), self.shadow_replies.pop_front()) {
+                self.compared += 1;
+
+                if upstream != shadow {
+                    self.mismatches += 1;
+                    warn!("Shadow reply differs: expected {}, got {}", upstream.to_beautify_string(), shadow.to_beautify_string());
+                }
+            }
+        }
+    }
+}
+
+impl Comparator for ReplyComparator {
+    fn on_upstream(&mut self, data: &[u8]) {
+        if self.gave_up {
+            return;
+        }
+
+        if let Err(e) = self.upstream.feed(data) {
+            error!("Could not parse the upstream reply: {}", e);
+        }
+
+        while let Some(reply) = self.upstream.read() {
+            self.upstream_replies.push_back(reply);
+        }
+
+        self.compare();
+    }
+
+    fn on_shadow(&mut self, data: &[u8]) {
+        if self.gave_up {
+            return;
+        }
+
+        if let Err(e) = self.shadow.feed(data) {
+            error!("Could not parse the shadow reply: {}", e);
+        }
+
+        while let Some(reply) = self.shadow.read() {
+            self.shadow_replies.push_back(reply);
+        }
+
+        self.compare();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proxy::Comparator;
+    use super::{ReplyComparator, MAX_BACKLOG};
+
+    #[test]
+    fn pairs_replies_in_order() {
+        let mut comparator = ReplyComparator::new();
+
+        comparator.on_upstream(b"+OK\r\n$1\r\na\r\n:1");
+        comparator.on_shadow(b"+OK\r\n$1\r\nb\r\n");
+        assert_eq!((comparator.compared(), comparator.mismatches()), (2, 1));
+
+        comparator.on_shadow(b":1\r\n");
+        comparator.on_upstream(b"\r\n");
+        assert_eq!((comparator.compared(), comparator.mismatches()), (3, 1));
+    }
+
+    #[test]
+    fn gives_up_when_a_side_lags_too_far_behind() {
+        let mut comparator = ReplyComparator::new();
+
+        for _ in 0..MAX_BACKLOG + 1 {
+            comparator.on_upstream(b"+OK\r\n");
+        }
+        comparator.on_shadow(b"+OK\r\n");
+
+        assert_eq!(comparator.compared(), 0);
+    }
+}
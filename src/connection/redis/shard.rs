@@ -0,0 +1,563 @@
+use mio::{Token, Evented, EventSet, PollOpt, Selector};
+use connection::Connection;
+use connection::ConnectionAction;
+use connection::redis::command_name;
+use connection::redis::commands::{self, lookup, lookup_command};
+use connection::redis::ketama::Continuum;
+use std::io;
+use std::cmp::min;
+use std::collections::VecDeque;
+use connection::redis::resp::{Decoder, Value};
+use netbuf::Buf;
+
+/// Part of the key used for hashing: the content of the first non empty
+/// `{...}` section when there is one, the whole key otherwise.
+pub fn hash_tag(key: &[u8]) -> &[u8] {
+    if let Some(start) = key.iter().position(|&byte| byte == b'{') {
+        if let Some(length) = key[start + 1..].iter().position(|&byte| byte == b'}') {
+            if length > 0 {
+                return &key[start + 1..start + 1 + length];
+            }
+        }
+    }
+
+    key
+}
+
+pub struct Shard {
+    /// Name hashed to place the server on the continuum, usually `host:port`.
+    pub name: String,
+    pub weight: u32,
+    pub connection: Box<Connection>,
+}
+
+/// How the replies of the parts a command was split into become the reply
+/// sent to the client.
+enum Merge {
+    /// Single reply, passed through.
+    Single,
+    /// `MGET`-like: part and position on the part of each value.
+    Values(Vec<(usize, usize)>),
+    /// `DEL`-like: sum of the integers.
+    Sum,
+    /// `MSET`-like: `OK` when every part succeeded.
+    Ok,
+}
+
+struct Pending {
+    parts: Vec<Option<Value>>,
+    merge: Merge,
+}
+
+impl Pending {
+    fn is_complete(&self) -> bool {
+        self.parts.iter().all(|part| part.is_some())
+    }
+
+    fn into_reply(self) -> Value {
+        let parts: Vec<Value> = self.parts.into_iter().map(|part| part.unwrap_or(Value::Null)).collect();
+
+        if let Some(error) = parts.iter().find(|part| part.is_error()) {
+            return error.clone();
+        }
+
+        match self.merge {
+            Merge::Single => {
+                parts.into_iter().next().unwrap_or(Value::Null)
+            },
+            Merge::Values(positions) => {
+                Value::Array(positions.iter().map(|&(part, position)| {
+                    match parts[part] {
+                        Value::Array(ref values) if position < values.len() => values[position].clone(),
+                        _ => Value::Null,
+                    }
+                }).collect())
+            },
+            Merge::Sum => {
+                Value::Integer(parts.iter().map(|part| {
+                    match *part {
+                        Value::Integer(value) => value,
+                        _ => 0,
+                    }
+                }).sum())
+            },
+            Merge::Ok => {
+                Value::String("OK".to_string())
+            },
+        }
+    }
+}
+
+/// Upstream spreading the commands over several Redis servers, in the way of
+/// twemproxy: each command goes to the server owning its (hash tagged) key on
+/// a ketama continuum. `MGET`, `DEL`, `UNLINK`, `EXISTS`, `TOUCH` and `MSET`
+/// are split per server and their replies merged; other commands go to the
+/// server of their first key. Commands taking no keys, like `FLUSHALL`,
+/// `KEYS` or `SCAN`, are rejected as they would only reach one server, except
+/// `PING`, `ECHO`, `TIME` and `COMMAND` which go to the first server.
+///
+/// All the shards are registered on the event loop with the token of this
+/// connection, and replies are given back in the order of the commands.
+pub struct ShardedUpstream {
+    token: Token,
+    shards: Vec<Box<Connection>>,
+    continuum: Continuum,
+    commands: Decoder,
+    responses: Vec<Decoder>,
+    routes: Vec<VecDeque<(u64, usize)>>,
+    /// Shards whose replies could not be parsed, no longer used.
+    lost: Vec<bool>,
+    pending: VecDeque<Pending>,
+    first_sequence: u64,
+    replies: Buf,
+}
+
+impl ShardedUpstream {
+    pub fn new(token: Token, shards: Vec<Shard>) -> Self {
+        let servers: Vec<(String, u32)> = shards.iter().map(|shard| (shard.name.clone(), shard.weight)).collect();
+        let count = shards.len();
+
+        ShardedUpstream {
+            token: token,
+            shards: shards.into_iter().map(|shard| shard.connection).collect(),
+            continuum: Continuum::new(&servers),
+            commands: Decoder::new(),
+            responses: (0..count).map(|_| Decoder::new()).collect(),
+            routes: (0..count).map(|_| VecDeque::new()).collect(),
+            lost: vec![false; count],
+            pending: VecDeque::new(),
+            first_sequence: 0,
+            replies: Buf::new(),
+        }
+    }
+
+    fn shard_for(&self, key: &[u8]) -> usize {
+        self.continuum.server_for(hash_tag(key))
+    }
+
+    fn route(&mut self, command: Value) {
+        let name = command_name(&command).unwrap_or(String::new());
+        let input = match command {
+            Value::Array(input) => input,
+            _ => {
+                return self.respond(Value::Error("ERR invalid command".to_string()));
+            },
+        };
+
+        match &*name {
+            "MGET" if input.len() > 2 => {
+                self.split(input, 1, Merge::Values(Vec::new()));
+            },
+            "DEL" | "UNLINK" | "EXISTS" | "TOUCH" if input.len() > 2 => {
+                self.split(input, 1, Merge::Sum);
+            },
+            "MSET" if input.len() > 3 && input.len() % 2 == 1 => {
+                self.split(input, 2, Merge::Ok);
+            },
+            "MULTI" | "EXEC" | "DISCARD" | "WATCH" | "UNWATCH" | "SELECT" | "SWAPDB" | "MOVE" => {
+                self.respond(Value::Error(format!("ERR {} is not supported by the sharded proxy", name)));
+            },
+            _ => {
+                let command = Value::Array(input);
+                let pubsub = lookup_command(&command).map(|info| info.has_flag(commands::PUBSUB)).unwrap_or(false);
+                if pubsub {
+                    return self.respond(Value::Error(format!("ERR {} is not supported by the sharded proxy", name)));
+                }
+
+                let shard = match commands::key_bytes(&command).first() {
+                    Some(key) => self.shard_for(key),
+                    None if takes_no_keys(&name) => {
+                        return self.respond(Value::Error(format!("ERR {} is not supported by the sharded proxy", name)));
+                    },
+                    // Scripts without keys, or arguments missing
+                    None => 0,
+                };
+
+                self.send(vec![(shard, command)], Merge::Single);
+            },
+        }
+    }
+
+    /// Splits a command whose arguments are groups of `step` values starting
+    /// with a key, in one command per shard.
+    fn split(&mut self, input: Vec<Value>, step: usize, merge: Merge) {
+        let mut parts: Vec<(usize, Vec<Value>)> = Vec::new();
+        let mut positions = Vec::new();
+
+        for group in input[1..].chunks(step) {
+            let shard = match commands::bytes(&group[0]) {
+                Some(key) => self.shard_for(key),
+                None => 0,
+            };
+
+            let part = match parts.iter().position(|&(part_shard, _)| part_shard == shard) {
+                Some(part) => part,
+                None => {
+                    parts.push((shard, vec![input[0].clone()]));
+                    parts.len() - 1
+                },
+            };
+
+            positions.push((part, parts[part].1.len() - 1));
+            parts[part].1.extend(group.iter().cloned());
+        }
+
+        let merge = match merge {
+            Merge::Values(_) => Merge::Values(positions),
+            merge => merge,
+        };
+
+        let parts = parts.into_iter().map(|(shard, part)| (shard, Value::Array(part))).collect();
+        self.send(parts, merge);
+    }
+
+    fn send(&mut self, parts: Vec<(usize, Value)>, merge: Merge) {
+        let sequence = self.first_sequence + self.pending.len() as u64;
+        let mut replies = Vec::with_capacity(parts.len());
+
+        for (part, (shard, command)) in parts.into_iter().enumerate() {
+            if self.lost[shard] {
+                replies.push(Some(unreachable(shard)));
+                continue;
+            }
+
+            match self.shards[shard].write(&command.encode()) {
+                Ok(_) => {
+                    self.routes[shard].push_back((sequence, part));
+                    replies.push(None);
+                },
+                Err(e) => {
+                    // No reply will come for this part
+                    error!("{:?}: Could not write to shard {}: {}", self.token, shard, e);
+                    replies.push(Some(unreachable(shard)));
+                },
+            }
+        }
+
+        self.pending.push_back(Pending {
+            parts: replies,
+            merge: merge,
+        });
+        self.flush_replies();
+    }
+
+    fn respond(&mut self, response: Value) {
+        self.pending.push_back(Pending {
+            parts: vec![Some(response)],
+            merge: Merge::Single,
+        });
+        self.flush_replies();
+    }
+
+    fn read_shards(&mut self) {
+        let mut buf = [0u8; 1024];
+
+        for shard in 0..self.shards.len() {
+            if self.lost[shard] {
+                continue;
+            }
+
+            let mut corrupted = false;
+            loop {
+                let amount = match self.shards[shard].read(&mut buf) {
+                    Ok(amount) => amount,
+                    Err(e) => {
+                        error!("{:?}: Could not read from shard {}: {}", self.token, shard, e);
+                        break;
+                    },
+                };
+
+                if amount == 0 {
+                    break;
+                }
+
+                if let Err(e) = self.responses[shard].feed(&buf[0..amount]) {
+                    error!("{:?}: Could not parse the response of shard {}: {}", self.token, shard, e);
+                    corrupted = true;
+                    break;
+                }
+            }
+
+            while let Some(response) = self.responses[shard].read() {
+                match self.routes[shard].pop_front() {
+                    Some((sequence, part)) => {
+                        let index = (sequence - self.first_sequence) as usize;
+                        self.pending[index].parts[part] = Some(response);
+                    },
+                    None => {
+                        warn!("{:?}: Shard {} sent a response without a pending command", self.token, shard);
+                    },
+                }
+            }
+
+            if corrupted {
+                self.drop_shard(shard);
+            }
+        }
+
+        self.flush_replies();
+    }
+
+    /// Stops using a shard whose replies can not be matched with the
+    /// commands anymore, failing those still waiting for one.
+    fn drop_shard(&mut self, shard: usize) {
+        self.lost[shard] = true;
+        self.responses[shard] = Decoder::new();
+
+        for (sequence, part) in self.routes[shard].drain(..) {
+            let index = (sequence - self.first_sequence) as usize;
+            self.pending[index].parts[part] = Some(unreachable(shard));
+        }
+    }
+
+    fn flush_replies(&mut self) {
+        while self.pending.front().map(|pending| pending.is_complete()).unwrap_or(false) {
+            if let Some(pending) = self.pending.pop_front() {
+                self.first_sequence += 1;
+                self.replies.extend(&pending.into_reply().encode());
+            }
+        }
+    }
+}
+
+fn unreachable(shard: usize) -> Value {
+    Value::Error(format!("ERR could not reach shard {}", shard))
+}
+
+/// Whether the command never takes keys, so the server it would reach can
+/// not be chosen by key. Those that make sense on any server are kept.
+fn takes_no_keys(name: &str) -> bool {
+    match name {
+        "PING" | "ECHO" | "TIME" | "COMMAND" => false,
+        _ => {
+            match lookup(name) {
+                Some(info) => info.first_key <= 0 && !info.has_flag(commands::MOVABLE_KEYS),
+                None => true,
+            }
+        },
+    }
+}
+
+impl Evented for ShardedUpstream {
+    fn register(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        for shard in self.shards.iter() {
+            try!(shard.get_evented().register(selector, token, interest, opts));
+        }
+
+        Ok(())
+    }
+
+    fn reregister(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        for shard in self.shards.iter() {
+            try!(shard.get_evented().reregister(selector, token, interest, opts));
+        }
+
+        Ok(())
+    }
+
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        for shard in self.shards.iter() {
+            try!(shard.get_evented().deregister(selector));
+        }
+
+        Ok(())
+    }
+}
+
+impl io::Read for ShardedUpstream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read_size = min(buf.len(), self.replies.len());
+        buf[0..read_size].clone_from_slice(&self.replies[0..read_size]);
+        self.replies.consume(read_size);
+
+        Ok(read_size)
+    }
+}
+
+impl io::Write for ShardedUpstream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        try!(self.commands.feed(buf));
+
+        while let Some(command) = self.commands.read() {
+            self.route(command);
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Connection for ShardedUpstream {
+    fn get_evented(&self) -> &Evented {
+        return self;
+    }
+
+    fn get_token(&self) -> Token {
+        return self.token;
+    }
+
+    fn get_interest(&self) -> EventSet {
+        self.shards.iter().fold(EventSet::none(), |interest, shard| interest | shard.get_interest())
+    }
+
+    fn handle_read(&mut self) -> ConnectionAction {
+        for shard in self.shards.iter_mut() {
+            if let ConnectionAction::Halt = shard.handle_read() {
+                return ConnectionAction::Halt;
+            }
+        }
+
+        self.read_shards();
+
+        if self.replies.len() > 0 {
+            ConnectionAction::Forward
+        } else {
+            ConnectionAction::Noop
+        }
+    }
+
+    fn handle_write(&mut self) -> ConnectionAction {
+        for shard in self.shards.iter_mut() {
+            if let ConnectionAction::Halt = shard.handle_write() {
+                return ConnectionAction::Halt;
+            }
+        }
+
+        ConnectionAction::Noop
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mio::{Token, Evented, EventSet};
+    use connection::{Connection, ConnectionAction};
+    use connection::redis::{MockRedis, MockKeyspace};
+    use connection::redis::resp::{Decoder, Value};
+    use connection::redis::testing::FaultyRedis;
+    use std::io::{self, Read, Write};
+    use super::{ShardedUpstream, Shard, hash_tag};
+
+    /// Shard whose connection is lost: every write fails.
+    struct BrokenShard(MockRedis);
+
+    impl io::Read for BrokenShard {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl io::Write for BrokenShard {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::BrokenPipe, "connection lost"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Connection for BrokenShard {
+        fn get_evented(&self) -> &Evented {
+            self.0.get_evented()
+        }
+
+        fn get_token(&self) -> Token {
+            self.0.get_token()
+        }
+
+        fn get_interest(&self) -> EventSet {
+            self.0.get_interest()
+        }
+
+        fn handle_read(&mut self) -> ConnectionAction {
+            self.0.handle_read()
+        }
+
+        fn handle_write(&mut self) -> ConnectionAction {
+            self.0.handle_write()
+        }
+    }
+
+    fn send(upstream: &mut ShardedUpstream, commands: &[&[&str]]) -> Vec<Value> {
+        for command in commands {
+            let command = Value::Array(command.iter().map(|argument| Value::Bulk(argument.to_string())).collect());
+            upstream.write_all(&command.encode()).unwrap();
+        }
+        upstream.handle_read();
+
+        let mut output = Vec::new();
+        upstream.read_to_end(&mut output).unwrap();
+
+        let mut decoder = Decoder::new();
+        decoder.feed(&output).unwrap();
+
+        let mut replies = Vec::new();
+        while let Some(reply) = decoder.read() {
+            replies.push(reply);
+        }
+
+        replies
+    }
+
+    #[test]
+    fn answers_what_can_not_reach_a_shard() {
+        let keyspace = MockKeyspace::new();
+        let mut upstream = ShardedUpstream::new(Token(1), vec![
+            Shard { name: "a".to_string(), weight: 1, connection: Box::new(MockRedis::new(Token(2), keyspace).unwrap()) },
+            Shard { name: "b".to_string(), weight: 1, connection: Box::new(BrokenShard(MockRedis::new(Token(3), MockKeyspace::new()).unwrap())) },
+        ]);
+
+        let keys: Vec<String> = (0..).map(|i| format!("key{}", i)).take(100).collect();
+        let healthy = keys.iter().find(|key| upstream.shard_for(key.as_bytes()) == 0).unwrap().clone();
+        let broken = keys.iter().find(|key| upstream.shard_for(key.as_bytes()) == 1).unwrap().clone();
+
+        assert_eq!(send(&mut upstream, &[&["FLUSHALL"], &["GET", &broken], &["MGET", &healthy, &broken], &["SET", &healthy, "1"], &["PING"]]), vec![
+            Value::Error("ERR FLUSHALL is not supported by the sharded proxy".to_string()),
+            Value::Error("ERR could not reach shard 1".to_string()),
+            Value::Error("ERR could not reach shard 1".to_string()),
+            Value::String("OK".to_string()),
+            Value::String("PONG".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn stops_using_a_shard_sending_garbage() {
+        let mut upstream = ShardedUpstream::new(Token(1), vec![
+            Shard { name: "a".to_string(), weight: 1, connection: Box::new(MockRedis::new(Token(2), MockKeyspace::new()).unwrap()) },
+            Shard { name: "b".to_string(), weight: 1, connection: Box::new(FaultyRedis::garbage(Token(3), b"?garbage\r\n")) },
+        ]);
+
+        let keys: Vec<String> = (0..).map(|i| format!("key{}", i)).take(100).collect();
+        let healthy = keys.iter().find(|key| upstream.shard_for(key.as_bytes()) == 0).unwrap().clone();
+        let corrupted = keys.iter().find(|key| upstream.shard_for(key.as_bytes()) == 1).unwrap().clone();
+
+        assert_eq!(send(&mut upstream, &[&["GET", &corrupted], &["SET", &healthy, "1"]]), vec![
+            Value::Error("ERR could not reach shard 1".to_string()),
+            Value::String("OK".to_string()),
+        ]);
+        assert_eq!(send(&mut upstream, &[&["MGET", &healthy, &corrupted]]), vec![
+            Value::Error("ERR could not reach shard 1".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn binary_keys_are_hashed() {
+        let second = MockKeyspace::new();
+        let mut upstream = ShardedUpstream::new(Token(1), vec![
+            Shard { name: "a".to_string(), weight: 1, connection: Box::new(MockRedis::new(Token(2), MockKeyspace::new()).unwrap()) },
+            Shard { name: "b".to_string(), weight: 1, connection: Box::new(MockRedis::new(Token(3), second.clone()).unwrap()) },
+        ]);
+
+        assert_eq!(hash_tag(b"\xff{\xfe\x01}x"), b"\xfe\x01");
+        let key = (0..100u8).map(|i| vec![0xff, i]).find(|key| upstream.shard_for(key) == 1).unwrap();
+
+        let command = Value::Array(vec![Value::Bulk("GET".to_string()), Value::BufBulk(key.clone())]);
+        upstream.write_all(&command.encode()).unwrap();
+        upstream.handle_read();
+
+        assert_eq!(second.received(), vec![command]);
+    }
+}
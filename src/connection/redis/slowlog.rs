@@ -0,0 +1,231 @@
+use connection::redis::{RedisProxy, CommandAction, Origin, InFlight, proxy_subcommand, elapsed_micros};
+use connection::redis::resp::Value;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// Arguments and argument length kept per entry, as Redis does.
+const MAX_ARGUMENTS: usize = 32;
+const MAX_ARGUMENT_LENGTH: usize = 128;
+
+struct Entry {
+    id: i64,
+    timestamp: i64,
+    duration: i64,
+    arguments: Vec<String>,
+}
+
+impl Entry {
+    fn to_value(&self) -> Value {
+        Value::Array(vec![
+            Value::Integer(self.id),
+            Value::Integer(self.timestamp),
+            Value::Integer(self.duration),
+            Value::Array(self.arguments.iter().map(|argument| Value::Bulk(argument.clone())).collect()),
+            Value::Bulk(String::new()),
+            Value::Bulk(String::new()),
+        ])
+    }
+}
+
+struct SlowLogState {
+    threshold: u64,
+    max_len: usize,
+    next_id: i64,
+    entries: VecDeque<Entry>,
+}
+
+/// Bounded log of the slowest commands, newest first, shared by every
+/// `SlowLogProxy` holding a clone.
+#[derive(Clone)]
+pub struct SlowLog {
+    state: Rc<RefCell<SlowLogState>>,
+}
+
+impl SlowLog {
+    /// Logs the commands taking at least `threshold` microseconds, keeping
+    /// the last `max_len` of them.
+    pub fn new(threshold: u64, max_len: usize) -> Self {
+        SlowLog {
+            state: Rc::new(RefCell::new(SlowLogState {
+                threshold: threshold,
+                max_len: max_len,
+                next_id: 0,
+                entries: VecDeque::new(),
+            })),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.borrow().entries.len()
+    }
+
+    pub fn reset(&self) {
+        self.state.borrow_mut().entries.clear();
+    }
+
+    fn record(&self, command: &Value, duration: u64) {
+        let mut state = self.state.borrow_mut();
+        if duration < state.threshold || state.max_len == 0 {
+            return;
+        }
+
+        let id = state.next_id;
+        state.next_id += 1;
+
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs() as i64).unwrap_or(0);
+
+        state.entries.push_front(Entry {
+            id: id,
+            timestamp: timestamp,
+            duration: duration as i64,
+            arguments: arguments(command),
+        });
+        while state.entries.len() > state.max_len {
+            state.entries.pop_back();
+        }
+    }
+
+    /// The `count` newest entries, as `SLOWLOG GET` gives them.
+    fn get(&self, count: usize) -> Value {
+        Value::Array(self.state.borrow().entries.iter().take(count).map(Entry::to_value).collect())
+    }
+}
+
+/// Arguments of the command as logged, shortened like Redis does.
+fn arguments(command: &Value) -> Vec<String> {
+    let input = match *command {
+        Value::Array(ref input) => input,
+        _ => return Vec::new(),
+    };
+
+    let mut arguments: Vec<String> = input.iter().take(MAX_ARGUMENTS).map(|argument| {
+        let argument = match *argument {
+            Value::Bulk(ref argument) => argument.clone(),
+            Value::BufBulk(ref argument) => String::from_utf8_lossy(argument).into_owned(),
+            _ => argument.to_beautify_string(),
+        };
+
+        if argument.len() > MAX_ARGUMENT_LENGTH {
+            let mut end = MAX_ARGUMENT_LENGTH;
+            while !argument.is_char_boundary(end) {
+                end -= 1;
+            }
+
+            format!("{}... ({} more bytes)", &argument[0..end], argument.len() - end)
+        } else {
+            argument
+        }
+    }).collect();
+
+    if input.len() > MAX_ARGUMENTS {
+        let last = arguments.len() - 1;
+        arguments[last] = format!("... ({} more arguments)", input.len() - MAX_ARGUMENTS + 1);
+    }
+
+    arguments
+}
+
+/// Times commands from the moment the proxy reads them until their reply
+/// comes back, network included, and logs the slow ones into a `SlowLog`.
+/// Answers `PROXY SLOWLOG GET [count]`, `PROXY SLOWLOG LEN` and
+/// `PROXY SLOWLOG RESET` like their Redis counterparts.
+pub struct SlowLogProxy {
+    slowlog: SlowLog,
+    pending: InFlight<(Value, Instant)>,
+}
+
+impl SlowLogProxy {
+    pub fn new(slowlog: SlowLog) -> Self {
+        SlowLogProxy {
+            slowlog: slowlog,
+            pending: InFlight::new(),
+        }
+    }
+
+    fn answer(&self, arguments: &[String]) -> Option<Value> {
+        if arguments.first().map(|subcommand| subcommand != "SLOWLOG").unwrap_or(true) {
+            return None;
+        }
+
+        let answer = match arguments.get(1).map(|argument| &**argument) {
+            Some("GET") => {
+                match arguments.get(2).map(|count| count.parse::<i64>()) {
+                    None => self.slowlog.get(10),
+                    Some(Ok(count)) if count < 0 => self.slowlog.get(usize::max_value()),
+                    Some(Ok(count)) => self.slowlog.get(count as usize),
+                    Some(Err(_)) => Value::Error("ERR value is out of range, must be positive".to_string()),
+                }
+            },
+            Some("LEN") => Value::Integer(self.slowlog.len() as i64),
+            Some("RESET") => {
+                self.slowlog.reset();
+                Value::String("OK".to_string())
+            },
+            _ => Value::Error("ERR unknown subcommand, try PROXY SLOWLOG GET, LEN or RESET".to_string()),
+        };
+
+        Some(answer)
+    }
+}
+
+impl RedisProxy for SlowLogProxy {
+    fn on_command(&mut self, command: Value) -> CommandAction {
+        if let Some(arguments) = proxy_subcommand(&command) {
+            if let Some(answer) = self.answer(&arguments) {
+                return CommandAction::Respond(answer);
+            }
+        }
+
+        self.pending.push((command.clone(), Instant::now()));
+
+        CommandAction::Forward(command)
+    }
+
+    fn on_response(&mut self, origin: Origin, response: Value) -> Value {
+        if let Some((command, started)) = self.pending.answer(origin) {
+            self.slowlog.record(&command, elapsed_micros(started));
+        }
+
+        response
+    }
+
+    fn on_unanswered(&mut self) {
+        self.pending.cancel_last();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use connection::redis::{ComposedProxy, PingProxy, MockKeyspace};
+    use connection::redis::resp::Value;
+    use connection::redis::testing::Harness;
+    use super::{SlowLogProxy, SlowLog};
+
+    #[test]
+    fn logs_commands_as_the_client_sent_them() {
+        let slowlog = SlowLog::new(0, 10);
+        let proxy = ComposedProxy::new(PingProxy, SlowLogProxy::new(slowlog.clone()));
+        let mut harness = Harness::new(proxy, MockKeyspace::new());
+
+        harness.send(&[&["SET", "a", "1"], &["PING"], &["GET", "a"]]);
+        assert_eq!(slowlog.len(), 2);
+
+        let entries = match harness.send(&[&["PROXY", "SLOWLOG", "GET"]]).pop() {
+            Some(Value::Array(entries)) => entries,
+            reply => panic!("unexpected reply {:?}", reply),
+        };
+
+        let logged: Vec<Value> = entries.into_iter().map(|entry| {
+            match entry {
+                Value::Array(mut fields) => fields.remove(3),
+                entry => entry,
+            }
+        }).collect();
+        assert_eq!(logged, vec![
+            Value::Array(vec![Value::Bulk("GET".to_string()), Value::Bulk("a".to_string())]),
+            Value::Array(vec![Value::Bulk("SET".to_string()), Value::Bulk("a".to_string()), Value::Bulk("1".to_string())]),
+        ]);
+    }
+}
@@ -0,0 +1,173 @@
+//! Runs a `RedisConnection` against a `MockRedis`, without an event loop.
+
+use mio::{Token, Evented, EventSet};
+use connection::{Connection, ConnectionAction};
+use connection::testing::tcp_connection;
+use connection::redis::{RedisConnection, RedisProxy, MockRedis, MockKeyspace};
+use connection::redis::resp::{Decoder, Value};
+use std::io;
+use std::io::{Read, Write};
+use std::cmp::min;
+use std::net;
+
+pub struct Harness<P> where P: RedisProxy {
+    pub client: RedisConnection<P>,
+    pub upstream: MockRedis,
+    /// Client end of the socket, kept open.
+    _socket: net::TcpStream,
+}
+
+impl<P> Harness<P> where P: RedisProxy {
+    pub fn new(proxy: P, keyspace: MockKeyspace) -> Self {
+        let (connection, socket) = tcp_connection(Token(1));
+
+        Harness {
+            client: RedisConnection::new(connection, proxy),
+            upstream: MockRedis::new(Token(2), keyspace).unwrap(),
+            _socket: socket,
+        }
+    }
+
+    /// Sends the commands as the client would, in one pipeline, and gives
+    /// back the replies the client receives.
+    pub fn send(&mut self, commands: &[&[&str]]) -> Vec<Value> {
+        for command in commands {
+            let command = Value::Array(command.iter().map(|argument| Value::Bulk(argument.to_string())).collect());
+            self.client.tcp_connection().get_mut_input().extend(&command.encode());
+        }
+
+        self.exchange()
+    }
+
+    /// Moves the bytes between the client connection and the upstream until
+    /// both are idle, and decodes what reached the client.
+    pub fn exchange(&mut self) -> Vec<Value> {
+        let mut buf = [0u8; 4096];
+
+        loop {
+            let mut moved = false;
+
+            loop {
+                let size = self.client.read(&mut buf).unwrap();
+                if size == 0 {
+                    break;
+                }
+                self.upstream.write_all(&buf[0..size]).unwrap();
+                moved = true;
+            }
+
+            loop {
+                let size = self.upstream.read(&mut buf).unwrap();
+                if size == 0 {
+                    break;
+                }
+                self.client.write_all(&buf[0..size]).unwrap();
+                moved = true;
+            }
+
+            if !moved {
+                break;
+            }
+        }
+
+        let output = self.client.tcp_connection().get_output()[..].to_vec();
+        let len = output.len();
+        self.client.tcp_connection().get_mut_output().consume(len);
+
+        let mut decoder = Decoder::new();
+        decoder.feed(&output).unwrap();
+
+        let mut replies = Vec::new();
+        while let Some(reply) = decoder.read() {
+            replies.push(reply);
+        }
+
+        replies
+    }
+}
+
+pub fn ok() -> Value {
+    Value::String("OK".to_string())
+}
+
+/// Upstream going wrong: either its connection is lost, and every write
+/// fails and reads halt, or it sends garbage back to every command.
+pub struct FaultyRedis {
+    mock: MockRedis,
+    broken: bool,
+    garbage: Vec<u8>,
+    output: Vec<u8>,
+}
+
+impl FaultyRedis {
+    pub fn broken(token: Token) -> Self {
+        FaultyRedis {
+            mock: MockRedis::new(token, MockKeyspace::new()).unwrap(),
+            broken: true,
+            garbage: Vec::new(),
+            output: Vec::new(),
+        }
+    }
+
+    pub fn garbage(token: Token, garbage: &[u8]) -> Self {
+        FaultyRedis {
+            mock: MockRedis::new(token, MockKeyspace::new()).unwrap(),
+            broken: false,
+            garbage: garbage.to_vec(),
+            output: Vec::new(),
+        }
+    }
+}
+
+impl io::Read for FaultyRedis {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read_size = min(buf.len(), self.output.len());
+        buf[0..read_size].clone_from_slice(&self.output[0..read_size]);
+        self.output.drain(0..read_size);
+
+        Ok(read_size)
+    }
+}
+
+impl io::Write for FaultyRedis {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.broken {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "connection lost"));
+        }
+
+        let garbage = self.garbage.clone();
+        self.output.extend(garbage);
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Connection for FaultyRedis {
+    fn get_evented(&self) -> &Evented {
+        self.mock.get_evented()
+    }
+
+    fn get_token(&self) -> Token {
+        self.mock.get_token()
+    }
+
+    fn get_interest(&self) -> EventSet {
+        self.mock.get_interest()
+    }
+
+    fn handle_read(&mut self) -> ConnectionAction {
+        if self.broken {
+            return ConnectionAction::Halt;
+        }
+
+        ConnectionAction::Forward
+    }
+
+    fn handle_write(&mut self) -> ConnectionAction {
+        ConnectionAction::Noop
+    }
+}
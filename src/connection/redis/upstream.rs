@@ -0,0 +1,220 @@
+use mio::{Token, Evented, EventSet};
+use connection::Connection;
+use connection::tcp_connection::TcpConnection;
+use connection::ConnectionAction;
+use std::io;
+use std::cmp::min;
+use connection::redis::resp::{Decoder, Value};
+use netbuf::Buf;
+
+/// Commands sent on every new upstream connection before any client traffic.
+#[derive(Clone, Default)]
+pub struct Handshake {
+    /// ACL user, `AUTH` only sends the password when missing.
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub database: Option<u32>,
+}
+
+impl Handshake {
+    pub fn commands(&self) -> Vec<Value> {
+        let mut commands = Vec::new();
+
+        if let Some(ref password) = self.password {
+            let mut auth = vec![Value::Bulk("AUTH".to_string())];
+            if let Some(ref username) = self.username {
+                auth.push(Value::Bulk(username.clone()));
+            }
+            auth.push(Value::Bulk(password.clone()));
+
+            commands.push(Value::Array(auth));
+        }
+
+        if let Some(database) = self.database {
+            commands.push(Value::Array(vec![
+                Value::Bulk("SELECT".to_string()),
+                Value::Bulk(database.to_string()),
+            ]));
+        }
+
+        commands
+    }
+}
+
+/// Upstream side of a Redis proxy. Authenticates and selects the database
+/// before the client commands, which are queued behind the handshake on the
+/// same stream, and hides the handshake replies from the client.
+pub struct RedisUpstream {
+    connection: TcpConnection,
+    responses: Decoder,
+    replies: Buf,
+    pending_handshake: usize,
+    failed: bool,
+}
+
+impl RedisUpstream {
+    pub fn new(connection: TcpConnection, handshake: Handshake) -> Self {
+        let mut connection = connection;
+        let commands = handshake.commands();
+
+        for command in commands.iter() {
+            connection.get_mut_output().extend(&command.encode());
+        }
+
+        RedisUpstream {
+            connection: connection,
+            responses: Decoder::new(),
+            replies: Buf::new(),
+            pending_handshake: commands.len(),
+            failed: false,
+        }
+    }
+
+    /// Upstream side buffers, to feed and read in tests.
+    #[cfg(test)]
+    pub fn tcp_connection(&mut self) -> &mut TcpConnection {
+        &mut self.connection
+    }
+
+    fn is_decoding(&self) -> bool {
+        self.pending_handshake > 0 || self.responses.buffer_len() > 0
+    }
+
+    fn process_handshake(&mut self) {
+        let len = self.connection.get_input().len();
+        if len == 0 || !self.is_decoding() {
+            return;
+        }
+
+        let feed_result = self.responses.feed(&self.connection.get_input()[0..len]);
+        self.connection.get_mut_input().consume(len);
+
+        if let Err(e) = feed_result {
+            error!("{:?}: Could not parse upstream response: {}", self.get_token(), e);
+            self.failed = true;
+            return;
+        }
+
+        while let Some(response) = self.responses.read() {
+            if self.pending_handshake == 0 {
+                self.replies.extend(&response.encode());
+                continue;
+            }
+
+            self.pending_handshake -= 1;
+            if let Value::Error(ref message) = response {
+                error!("{:?}: Upstream handshake failed: {}", self.get_token(), message);
+                self.failed = true;
+            }
+        }
+    }
+}
+
+impl io::Read for RedisUpstream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.process_handshake();
+
+        if self.replies.len() > 0 {
+            let read_size = min(buf.len(), self.replies.len());
+            buf[0..read_size].clone_from_slice(&self.replies[0..read_size]);
+            self.replies.consume(read_size);
+
+            return Ok(read_size);
+        }
+
+        if self.is_decoding() {
+            return Ok(0);
+        }
+
+        self.connection.read(buf)
+    }
+}
+
+impl io::Write for RedisUpstream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.connection.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Connection for RedisUpstream {
+    fn get_evented(&self) -> &Evented {
+        return &*self.connection.get_evented();
+    }
+
+    fn get_token(&self) -> Token {
+        return self.connection.get_token();
+    }
+
+    fn get_interest(&self) -> EventSet {
+        return self.connection.get_interest();
+    }
+
+    fn handle_read(&mut self) -> ConnectionAction {
+        let read_response = self.connection.handle_read();
+        self.process_handshake();
+
+        if self.failed {
+            return ConnectionAction::Halt;
+        }
+
+        read_response
+    }
+
+    fn handle_write(&mut self) -> ConnectionAction {
+        return self.connection.handle_write()
+    }
+
+    fn pending_output(&self) -> usize {
+        self.connection.pending_output()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mio::Token;
+    use connection::Connection;
+    use connection::ConnectionAction;
+    use connection::testing::{tcp_connection, take_output};
+    use super::{RedisUpstream, Handshake};
+    use std::io::Read;
+
+    #[test]
+    fn handshake_replies_are_hidden() {
+        let handshake = Handshake {
+            username: Some("app".to_string()),
+            password: Some("secret".to_string()),
+            database: Some(2),
+        };
+        let (connection, _socket) = tcp_connection(Token(1));
+        let mut upstream = RedisUpstream::new(connection, handshake);
+
+        assert_eq!(take_output(upstream.tcp_connection()),
+            b"*3\r\n$4\r\nAUTH\r\n$3\r\napp\r\n$6\r\nsecret\r\n*2\r\n$6\r\nSELECT\r\n$1\r\n2\r\n".to_vec());
+
+        upstream.tcp_connection().get_mut_input().extend(b"+OK\r\n+OK\r\n$1\r\nx\r\n");
+        let mut buf = [0u8; 64];
+        let size = upstream.read(&mut buf).unwrap();
+        assert_eq!(&buf[0..size], b"$1\r\nx\r\n");
+    }
+
+    #[test]
+    fn failed_handshake_halts() {
+        let handshake = Handshake {
+            username: None,
+            password: Some("wrong".to_string()),
+            database: None,
+        };
+        let (connection, _socket) = tcp_connection(Token(1));
+        let mut upstream = RedisUpstream::new(connection, handshake);
+
+        upstream.tcp_connection().get_mut_input().extend(b"-WRONGPASS invalid username-password pair\r\n");
+        match upstream.handle_read() {
+            ConnectionAction::Halt => (),
+            action => panic!("unexpected action {:?}", action),
+        }
+    }
+}
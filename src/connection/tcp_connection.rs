@@ -84,19 +84,27 @@ impl Connection for TcpConnection {
     }
 
     fn handle_read(&mut self) -> ConnectionAction {
-        let read_result = self.input.read_from(&mut self.stream);
-        match read_result {
-            Ok(amount) => {
-                info!("Read to {:?} {} bytes on input", self.get_token(), amount);
-
-                if amount > 0 {
-                    ConnectionAction::Forward
-                } else {
-                    ConnectionAction::Noop
+        let mut action = ConnectionAction::Noop;
+
+        // Events are edge triggered, so the socket has to be drained
+        loop {
+            let read_result = self.input.read_from(&mut self.stream);
+            match read_result {
+                Ok(amount) => {
+                    info!("Read to {:?} {} bytes on input", self.get_token(), amount);
+
+                    if amount > 0 {
+                        action = ConnectionAction::Forward;
+                    } else {
+                        return action;
+                    }
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return action;
+                },
+                Err(_) => {
+                    return ConnectionAction::Halt;
                 }
-            },
-            Err(_) => {
-                ConnectionAction::Halt
             }
         }
     }
@@ -120,4 +128,8 @@ impl Connection for TcpConnection {
     fn get_interest(&self) -> EventSet {
         self.interest
     }
+
+    fn pending_output(&self) -> usize {
+        self.output.len()
+    }
 }
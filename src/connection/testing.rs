@@ -0,0 +1,26 @@
+//! Helpers for the tests of the protocol connections.
+
+use mio::Token;
+use mio::tcp::TcpListener;
+use connection::tcp_connection::TcpConnection;
+use std::net;
+
+/// Connection accepted from a local client, with the client end of the
+/// socket, to be kept open while the connection is used.
+pub fn tcp_connection(token: Token) -> (TcpConnection, net::TcpStream) {
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let socket = net::TcpStream::connect(address).unwrap();
+    let listener = TcpListener::from_listener(listener, &address).unwrap();
+    let (stream, _) = listener.accept().unwrap().unwrap();
+
+    (TcpConnection::new(stream, token), socket)
+}
+
+/// Takes what the connection has to send.
+pub fn take_output(connection: &mut TcpConnection) -> Vec<u8> {
+    let output = connection.get_output()[..].to_vec();
+    connection.get_mut_output().consume(output.len());
+
+    output
+}
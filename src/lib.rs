@@ -5,9 +5,12 @@ extern crate netbuf;
 extern crate log;
 extern crate env_logger;
 extern crate ansi_term;
+extern crate rand;
 
 #[cfg(feature = "redis")]
-extern crate resp;
+extern crate md5;
+#[cfg(feature = "redis")]
+extern crate chacha20poly1305;
 
 pub mod proxy;
 pub mod connection;
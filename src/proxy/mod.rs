@@ -5,10 +5,25 @@ use std::collections::HashMap;
 use std::rc::Rc;
 use std::cell::RefCell;
 
+/// Bytes the shadow may hold back before mirroring stops.
+const SHADOW_LIMIT: usize = 4 * 1024 * 1024;
+
+/// Looks at what the upstream and the shadow upstream send back, i.e. to
+/// report where they disagree.
+pub trait Comparator {
+    fn on_upstream(&mut self, data: &[u8]);
+    fn on_shadow(&mut self, data: &[u8]);
+}
+
 pub struct Proxy {
     downstream: Rc<RefCell<Connection>>,
     upstream: Rc<RefCell<Connection>>,
     upstream_closed: bool,
+    shadow: Option<Rc<RefCell<Connection>>>,
+    shadow_limit: usize,
+    /// The shadow fell too far behind, nothing is mirrored anymore.
+    shadow_overflowed: bool,
+    comparator: Option<Box<Comparator>>,
 }
 
 impl Proxy {
@@ -17,9 +32,47 @@ impl Proxy {
             downstream: downstream,
             upstream: upstream,
             upstream_closed: false,
+            shadow: None,
+            shadow_limit: SHADOW_LIMIT,
+            shadow_overflowed: false,
+            comparator: None,
         }
     }
 
+    /// Mirrors everything sent to the upstream to `shadow`. What the shadow
+    /// sends back never reaches the downstream.
+    pub fn with_shadow(mut self, shadow: Rc<RefCell<Connection>>) -> Self {
+        self.shadow = Some(shadow);
+        self
+    }
+
+    /// Stops mirroring for good once the shadow holds back more than `limit`
+    /// bytes, so a slow shadow can not make the proxy buffer without end.
+    pub fn with_shadow_limit(mut self, limit: usize) -> Self {
+        self.shadow_limit = limit;
+        self
+    }
+
+    /// Gives what the upstream and the shadow send back to `comparator`.
+    pub fn with_comparator(mut self, comparator: Box<Comparator>) -> Self {
+        self.comparator = Some(comparator);
+        self
+    }
+
+    pub fn get_shadow(&self) -> Option<Rc<RefCell<Connection>>> {
+        return self.shadow.clone();
+    }
+
+    pub fn shadow_token(&self) -> Option<Token> {
+        self.shadow.as_ref().map(|shadow| shadow.borrow().get_token())
+    }
+
+    /// Stops mirroring, i.e. when the shadow went away.
+    pub fn remove_shadow(&mut self) -> Option<Rc<RefCell<Connection>>> {
+        self.comparator = None;
+        self.shadow.take()
+    }
+
     pub fn get_upstream(&self) -> Rc<RefCell<Connection>> {
         return self.upstream.clone();
     }
@@ -51,6 +104,11 @@ impl Proxy {
     pub fn forward(&mut self, role: Role) {
         let ds = self.get_downstream();
         let us = self.get_upstream();
+        let shadow = match role {
+            Role::Downstream if !self.shadow_overflowed => self.get_shadow(),
+            _ => None,
+        };
+        let mut shadow_borrow = shadow.as_ref().map(|shadow| shadow.borrow_mut());
 
         let (mut read_borrow, mut write_borrow) = match role {
             Role::Upstream => {
@@ -65,21 +123,81 @@ impl Proxy {
 
                 (ds_borrow, us_borrow)
             },
+            Role::Shadow => {
+                return self.drain_shadow();
+            },
         };
 
         let mut buf: &mut [u8] = &mut [0u8; 1024];
-        let read_result = read_borrow.read(&mut buf);
-        match read_result {
-            Ok(amount) => {
-                if amount > 0 {
+        loop {
+            let read_result = read_borrow.read(&mut buf);
+            match read_result {
+                Ok(amount) => {
+                    if amount == 0 {
+                        break;
+                    }
+
                     info!("Read result with amount: {}", amount);
+                    let mut overflowed = false;
+                    if let Some(ref mut shadow) = shadow_borrow {
+                        if shadow.pending_output() + amount > self.shadow_limit {
+                            warn!("Shadow is too far behind, stopping the mirroring (token: {:?})", shadow.get_token());
+                            overflowed = true;
+                        } else if let Err(e) = shadow.write(&buf[0..amount]) {
+                            warn!("Could not write to shadow (token: {:?}): {}", shadow.get_token(), e);
+                        }
+                    }
+
+                    // Part of the traffic is missing, the shadow replies can
+                    // not be compared anymore
+                    if overflowed {
+                        shadow_borrow = None;
+                        self.shadow_overflowed = true;
+                        self.comparator = None;
+                    }
+
+                    if let Role::Upstream = role {
+                        if let Some(ref mut comparator) = self.comparator {
+                            comparator.on_upstream(&buf[0..amount]);
+                        }
+                    }
+
+                    if let Err(e) = write_borrow.write(&buf[0..amount]) {
+                        error!("Could not write to output buffer (token: {:?}): {}", write_borrow.get_token(), e);
+                        break;
+                    }
+                },
+                Err(_) => {
+                    error!("Could not read from input buffer (token: {:?})", read_borrow.get_token());
+                    break;
                 }
-                write_borrow.write(&buf[0..amount]).unwrap();
-            },
-            Err(_) => {
-                error!("Could not read from input buffer (token: {:?})", read_borrow.get_token());
-            }
+            };
+        }
+    }
+
+    /// Reads and discards what the shadow sent back.
+    fn drain_shadow(&mut self) {
+        let shadow = match self.get_shadow() {
+            Some(shadow) => shadow,
+            None => return,
         };
+        let mut shadow_borrow = shadow.borrow_mut();
+
+        let mut buf = [0u8; 1024];
+        loop {
+            match shadow_borrow.read(&mut buf) {
+                Ok(0) => break,
+                Ok(amount) => {
+                    if let Some(ref mut comparator) = self.comparator {
+                        comparator.on_shadow(&buf[0..amount]);
+                    }
+                },
+                Err(_) => {
+                    error!("Could not read from shadow (token: {:?})", shadow_borrow.get_token());
+                    break;
+                },
+            }
+        }
     }
 
     pub fn get_from_token(&self, token: Token) -> Option<Rc<RefCell<Connection>>> {
@@ -92,6 +210,10 @@ impl Proxy {
             return Some(self.get_upstream())
         }
 
+        if self.shadow_token() == Some(token) {
+            return self.get_shadow();
+        }
+
         None
     }
 }
@@ -128,3 +250,75 @@ impl ProxyLocator {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use mio::Token;
+    use connection::Role;
+    use connection::testing::{tcp_connection, take_output};
+    use super::{Proxy, Comparator};
+    use std::rc::Rc;
+    use std::cell::RefCell;
+
+    /// Records what it is given, for both sides.
+    struct Recorder(Rc<RefCell<(Vec<u8>, Vec<u8>)>>);
+
+    impl Comparator for Recorder {
+        fn on_upstream(&mut self, data: &[u8]) {
+            (self.0).borrow_mut().0.extend_from_slice(data);
+        }
+
+        fn on_shadow(&mut self, data: &[u8]) {
+            (self.0).borrow_mut().1.extend_from_slice(data);
+        }
+    }
+
+    #[test]
+    fn mirrors_to_the_shadow_and_hides_its_replies() {
+        let (downstream, _downstream_socket) = tcp_connection(Token(1));
+        let (upstream, _upstream_socket) = tcp_connection(Token(2));
+        let (shadow, _shadow_socket) = tcp_connection(Token(3));
+        let (downstream, upstream, shadow) = (Rc::new(RefCell::new(downstream)), Rc::new(RefCell::new(upstream)), Rc::new(RefCell::new(shadow)));
+        let recorded = Rc::new(RefCell::new((Vec::new(), Vec::new())));
+        let mut proxy = Proxy::new(downstream.clone(), upstream.clone())
+            .with_shadow(shadow.clone())
+            .with_comparator(Box::new(Recorder(recorded.clone())));
+
+        downstream.borrow_mut().get_mut_input().extend(b"GET a");
+        proxy.forward(Role::Downstream);
+        assert_eq!(take_output(&mut upstream.borrow_mut()), b"GET a".to_vec());
+        assert_eq!(take_output(&mut shadow.borrow_mut()), b"GET a".to_vec());
+
+        upstream.borrow_mut().get_mut_input().extend(b"1");
+        proxy.forward(Role::Upstream);
+        shadow.borrow_mut().get_mut_input().extend(b"2");
+        proxy.forward(Role::Shadow);
+
+        assert_eq!(take_output(&mut downstream.borrow_mut()), b"1".to_vec());
+        assert_eq!(*recorded.borrow(), (b"1".to_vec(), b"2".to_vec()));
+    }
+
+    #[test]
+    fn stops_mirroring_to_a_shadow_too_far_behind() {
+        let (downstream, _downstream_socket) = tcp_connection(Token(1));
+        let (upstream, _upstream_socket) = tcp_connection(Token(2));
+        let (shadow, _shadow_socket) = tcp_connection(Token(3));
+        let (downstream, upstream, shadow) = (Rc::new(RefCell::new(downstream)), Rc::new(RefCell::new(upstream)), Rc::new(RefCell::new(shadow)));
+        let mut proxy = Proxy::new(downstream.clone(), upstream.clone())
+            .with_shadow(shadow.clone())
+            .with_shadow_limit(8);
+
+        for command in [&b"GET a"[..], b"GET b", b"GET c"].iter() {
+            downstream.borrow_mut().get_mut_input().extend(command);
+            proxy.forward(Role::Downstream);
+        }
+        assert_eq!(take_output(&mut upstream.borrow_mut()), b"GET aGET bGET c".to_vec());
+        assert_eq!(take_output(&mut shadow.borrow_mut()), b"GET a".to_vec());
+
+        // Even once the shadow caught up
+        downstream.borrow_mut().get_mut_input().extend(b"GET d");
+        proxy.forward(Role::Downstream);
+        assert_eq!(take_output(&mut upstream.borrow_mut()), b"GET d".to_vec());
+        assert!(take_output(&mut shadow.borrow_mut()).is_empty());
+    }
+}